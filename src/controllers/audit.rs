@@ -0,0 +1,120 @@
+use crate::{
+    controllers::authentication::Authenticated,
+    models::audit::{AuditAction, AuditEntryResponse},
+    store::{StoreError, UnitOfWork},
+    ProgramAppState,
+};
+use actix_web::{get, web, HttpResponse};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PER_PAGE: u64 = 50;
+pub const MAX_PER_PAGE: u64 = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    /// Starts at 1.
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntryResponse>,
+    pub page: u64,
+    pub per_page: u64,
+    pub total: u64,
+}
+
+/// Records an entry in the audit log, timestamped with the NTP time.
+/// A failure is logged, it does not fail the request that was already performed.
+pub async fn record<T: serde::Serialize + Sync>(
+    app_state: &ProgramAppState,
+    actor_id: Option<ObjectId>,
+    action: AuditAction,
+    target_id: ObjectId,
+    before: Option<&T>,
+    after: Option<&T>,
+) {
+    let now = DateTime::from_millis(app_state.ntp.current_time().timestamp_millis());
+    if let Err(error) = app_state
+        .audit_log
+        .record(actor_id, action, target_id, before, after, now)
+        .await
+    {
+        log::error!("Failed to record {action:?} of {target_id} in the audit log: {error}");
+    }
+}
+
+/// Records the same action on several targets in the audit log, in a single batch.
+/// A failure is logged, it does not fail the request that was already performed.
+pub async fn record_many<T: serde::Serialize + Sync>(
+    app_state: &ProgramAppState,
+    actor_id: Option<ObjectId>,
+    action: AuditAction,
+    changes: &[(ObjectId, Option<&T>, Option<&T>)],
+) {
+    if changes.is_empty() {
+        return;
+    }
+    let now = DateTime::from_millis(app_state.ntp.current_time().timestamp_millis());
+    if let Err(error) = app_state
+        .audit_log
+        .record_many(actor_id, action, changes, now)
+        .await
+    {
+        log::error!(
+            "Failed to record {action:?} of {} targets in the audit log: {error}",
+            changes.len()
+        );
+    }
+}
+
+/// Records an entry in the audit log within the unit of work, the writes recorded failing with it.
+pub async fn record_in<T: serde::Serialize + Sync>(
+    app_state: &ProgramAppState,
+    unit: &dyn UnitOfWork,
+    actor_id: Option<ObjectId>,
+    action: AuditAction,
+    target_id: ObjectId,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> Result<(), StoreError> {
+    let now = DateTime::from_millis(app_state.ntp.current_time().timestamp_millis());
+    app_state
+        .audit_log
+        .in_unit(unit)
+        .record(actor_id, action, target_id, before, after, now)
+        .await
+}
+
+/// Lists the audit log, newest first, for super administrators only:
+/// its entries span every organization.
+#[get("/")]
+pub async fn get_audit_log(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    query: web::Query<PageQuery>,
+) -> HttpResponse {
+    if !auth.get_user().is_super_admin() {
+        return HttpResponse::Forbidden().body("Super administrators only");
+    }
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    match app_state
+        .audit_log
+        .page((page - 1) * per_page, per_page)
+        .await
+    {
+        Ok((entries, total)) => HttpResponse::Ok().json(AuditPage {
+            entries: entries.iter().map(|entry| entry.to_response()).collect(),
+            page,
+            per_page,
+            total,
+        }),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -1,6 +1,10 @@
-use crate::controllers::error::*;
-use crate::models::users::{self, User};
-use crate::{ProgramAppState, DATABASE_NAME};
+use crate::controllers::{audit, error::*};
+use crate::models::{
+    audit::AuditAction,
+    users::{self, User},
+};
+use crate::store::{Tenant, UserStore};
+use crate::ProgramAppState;
 use actix_web::{
     dev::ServiceRequest,
     http::StatusCode,
@@ -9,10 +13,7 @@ use actix_web::{
     HttpResponse, Responder,
 };
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use mongodb::{
-    bson::{doc, oid::ObjectId},
-    Collection,
-};
+use mongodb::bson::{oid::ObjectId, DateTime};
 
 use serde::{Deserialize, Serialize};
 
@@ -42,14 +43,7 @@ pub(crate) async fn authentication(
     let now = chrono::Utc::now();
     let iat = now.timestamp() as usize;
     let exp = (now + chrono::Duration::days(1)).timestamp() as usize;
-    let collection: Collection<users::User> = app_state
-        .mongo_db_client
-        .database(&DATABASE_NAME)
-        .collection(users::REPOSITORY_NAME);
-    match collection
-        .find_one(doc! { "email": &req_body.email.to_string() })
-        .await
-    {
+    match app_state.users.find_by_email(&req_body.email).await {
         Ok(Some(user)) => {
             let pwd_correct =
                 argon2::verify_encoded(user.password.as_str(), req_body.password.as_bytes())
@@ -70,8 +64,31 @@ pub(crate) async fn authentication(
                 )
                 .unwrap();
 
+                let logged_in_at =
+                    DateTime::from_millis(app_state.ntp.current_time().timestamp_millis());
+                if let Err(err) = app_state.users.record_login(&user, logged_in_at).await {
+                    log::warn!("Failed to record the login of {}: {err}", user._id);
+                }
+                audit::record::<User>(
+                    &app_state,
+                    Some(user._id),
+                    AuditAction::Login,
+                    user._id,
+                    None,
+                    None,
+                )
+                .await;
                 return HttpResponse::Ok().json(token);
             } else {
+                audit::record::<User>(
+                    &app_state,
+                    None,
+                    AuditAction::LoginFailed,
+                    user._id,
+                    None,
+                    None,
+                )
+                .await;
                 return HttpResponse::InternalServerError().body("Bad password");
             }
         }
@@ -127,10 +144,7 @@ pub struct AuthenticationInfo {
 
 #[derive(Clone, Debug)]
 pub struct AuthState {
-    pub mongo_db: mongodb::Client,
-    // Temporary method of implementing admin user
-    #[allow(dead_code)]
-    pub admin_user: Option<User>,
+    pub users: UserStore,
 }
 
 use actix_web::{FromRequest, HttpMessage, HttpRequest};
@@ -203,6 +217,11 @@ impl Authenticated {
     pub fn get_user(&self) -> &User {
         &self.0._user
     }
+
+    /// The organizations the request reaches, the stores are scoped to it.
+    pub fn tenant(&self) -> Tenant {
+        Tenant::of(self.get_user())
+    }
 }
 
 impl std::ops::Deref for Authenticated {
@@ -272,21 +291,22 @@ impl AuthState {
     }
 
     async fn get_user_info(&self, user_id: &str) -> Result<User, Error> {
-        let collection: Collection<users::User> = self
-            .mongo_db
-            .database(&DATABASE_NAME)
-            .collection(users::REPOSITORY_NAME);
         let user_object_id = ObjectId::parse_str(user_id).unwrap();
-        match collection.find_one(doc! { "_id": &user_object_id }).await {
+        match self.users.find_by_id(&user_object_id).await {
             Ok(Some(user)) => Ok(User {
                 _id: user._id,
                 first_name: user.first_name,
                 last_name: user.last_name,
                 email: user.email,
+                email_index: None,
                 role: user.role,
                 org_id: user.org_id,
                 password: "".to_string(),
                 //created: user.created,
+                deleted_at: user.deleted_at,
+                deleted_by: user.deleted_by,
+                last_login_at: user.last_login_at,
+                version: user.version,
             }),
             Ok(None) => Err(Database("User not found".to_string())),
             Err(err) => {
@@ -294,7 +314,5 @@ impl AuthState {
                 Err(Database(err.to_string()))
             }
         }
-        // let mut conn = self.pg.acquire().await?;
-        // get_user_info(&mut conn, user_id, self.admin_user.as_ref()).await
     }
 }
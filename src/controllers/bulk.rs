@@ -0,0 +1,331 @@
+use crate::{
+    controllers::{audit, authentication::Authenticated},
+    models::{audit::AuditAction, users::User},
+    store::StoreError,
+    ProgramAppState,
+};
+use actix_web::{delete, patch, post, web, HttpResponse};
+use json::JsonValue;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// The most users a bulk request can hold.
+pub const MAX_BULK_ITEMS: usize = 500;
+
+/// What became of an item of a bulk request.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkStatus {
+    Created,
+    Updated,
+    Deleted,
+    DuplicateEmail,
+    /// Malformed, or refused such as an unknown organization.
+    Invalid,
+    NotFound,
+    /// The `version` of the item is not the stored one.
+    VersionConflict,
+    Failed,
+}
+
+impl BulkStatus {
+    pub fn succeeded(&self) -> bool {
+        matches!(
+            self,
+            BulkStatus::Created | BulkStatus::Updated | BulkStatus::Deleted
+        )
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BulkResult {
+    /// The position of the item in the request.
+    pub index: usize,
+    pub status: BulkStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BulkResult {
+    fn done(index: usize, status: BulkStatus, id: ObjectId) -> Self {
+        BulkResult {
+            index,
+            status,
+            id: Some(id),
+            error: None,
+        }
+    }
+
+    fn failed(index: usize, status: BulkStatus, id: Option<ObjectId>, error: String) -> Self {
+        BulkResult {
+            index,
+            status,
+            id,
+            error: Some(error),
+        }
+    }
+
+    fn invalid(index: usize, id: Option<ObjectId>, error: impl Into<String>) -> Self {
+        BulkResult::failed(index, BulkStatus::Invalid, id, error.into())
+    }
+
+    fn store_error(index: usize, id: Option<ObjectId>, error: StoreError) -> Self {
+        match error {
+            StoreError::Duplicate(_) => BulkResult::failed(
+                index,
+                BulkStatus::DuplicateEmail,
+                id,
+                "Email already in use".into(),
+            ),
+            StoreError::InvalidDocument(error) | StoreError::Forbidden(error) => {
+                BulkResult::invalid(index, id, error)
+            }
+            StoreError::VersionConflict { expected, found } => BulkResult::failed(
+                index,
+                BulkStatus::VersionConflict,
+                id,
+                format!("Version {found} is stored, not {expected}"),
+            ),
+            StoreError::Backend(error) => {
+                log::warn!("{error}");
+                BulkResult::failed(index, BulkStatus::Failed, id, error)
+            }
+        }
+    }
+}
+
+/// The outcome of every item of a bulk request, in the order of the request.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BulkReport {
+    pub succeeded: u64,
+    pub failed: u64,
+    pub results: Vec<BulkResult>,
+}
+
+impl BulkReport {
+    fn new(mut results: Vec<BulkResult>) -> Self {
+        results.sort_by_key(|result| result.index);
+        let succeeded = results
+            .iter()
+            .filter(|result| result.status.succeeded())
+            .count() as u64;
+        BulkReport {
+            succeeded,
+            failed: results.len() as u64 - succeeded,
+            results,
+        }
+    }
+}
+
+/// Reads the JSON array of a bulk request.
+fn parse_items(body: &web::Bytes) -> Result<Vec<JsonValue>, HttpResponse> {
+    let Ok(Ok(JsonValue::Array(items))) = std::str::from_utf8(body).map(json::parse) else {
+        return Err(HttpResponse::BadRequest().body("A JSON array is expected"));
+    };
+    if items.len() > MAX_BULK_ITEMS {
+        return Err(HttpResponse::PayloadTooLarge()
+            .body(format!("At most {MAX_BULK_ITEMS} users at a time")));
+    }
+    Ok(items)
+}
+
+fn not_found(index: usize, id: ObjectId) -> BulkResult {
+    BulkResult::failed(
+        index,
+        BulkStatus::NotFound,
+        Some(id),
+        format!("No user found with id {id}"),
+    )
+}
+
+fn parse_id(index: usize, json: &JsonValue) -> Result<ObjectId, BulkResult> {
+    json.as_str()
+        .and_then(|id| ObjectId::parse_str(id).ok())
+        .ok_or_else(|| BulkResult::invalid(index, None, format!("Invalid user id {json}")))
+}
+
+/// Creates the users of a JSON array, written as a single batch, for administrators only.
+/// Each item is a user as for `POST /users/`, the failing ones do not prevent the others
+/// from being created.
+#[post("/bulk")]
+pub async fn bulk_create_users(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    let items = match parse_items(&body) {
+        Ok(items) => items,
+        Err(response) => return response,
+    };
+
+    let mut results = Vec::new();
+    let mut created = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        match User::from_json_value(item) {
+            Some(user) => created.push((index, user)),
+            None => results.push(BulkResult::invalid(index, None, "Invalid input")),
+        }
+    }
+    let users: Vec<User> = created.iter().map(|(_, user)| user.clone()).collect();
+    let outcomes = app_state
+        .users
+        .acting_as(auth.get_user())
+        .create_many(&users)
+        .await;
+    let mut changes = Vec::new();
+    for ((index, user), outcome) in created.iter().zip(outcomes) {
+        match outcome {
+            Ok(()) => {
+                changes.push((user._id, None, Some(user)));
+                results.push(BulkResult::done(*index, BulkStatus::Created, user._id));
+            }
+            Err(error) => results.push(BulkResult::store_error(*index, Some(user._id), error)),
+        }
+    }
+    let actor_id = Some(auth.get_user()._id);
+    audit::record_many(&app_state, actor_id, AuditAction::Create, &changes).await;
+    HttpResponse::Ok().json(BulkReport::new(results))
+}
+
+/// Updates some fields of the users of a JSON array, for administrators only.
+/// Each item has the `id` of the user and the fields to update as for `PATCH /users/{id}`,
+/// and optionally the `version` the user must still have.
+#[patch("/bulk")]
+pub async fn bulk_update_users(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    let items = match parse_items(&body) {
+        Ok(items) => items,
+        Err(response) => return response,
+    };
+
+    let users = app_state.users.acting_as(auth.get_user());
+    let mut results = Vec::new();
+    let mut patches = Vec::new();
+    for (index, mut item) in items.into_iter().enumerate() {
+        let id = match parse_id(index, &item.remove("id")) {
+            Ok(id) => id,
+            Err(result) => {
+                results.push(result);
+                continue;
+            }
+        };
+        let expected_version = match item.remove("version") {
+            JsonValue::Null => None,
+            version => match version.as_i64() {
+                Some(version) => Some(version),
+                None => {
+                    results.push(BulkResult::invalid(index, Some(id), "Invalid version"));
+                    continue;
+                }
+            },
+        };
+        patches.push((index, id, expected_version, item));
+    }
+    let ids: Vec<_> = patches.iter().map(|(_, id, _, _)| *id).collect();
+    let current = match users.find_many(&ids).await {
+        Ok(current) => current,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let mut updates = Vec::new();
+    for (index, id, expected_version, item) in patches {
+        let Some(before) = current.get(&id) else {
+            results.push(not_found(index, id));
+            continue;
+        };
+        match before.patched(&item) {
+            Some(user) => updates.push((index, before, user, expected_version)),
+            None => results.push(BulkResult::invalid(index, Some(id), "Invalid input")),
+        }
+    }
+    let batch: Vec<_> = updates
+        .iter()
+        .map(|(_, _, user, expected_version)| (user.clone(), *expected_version))
+        .collect();
+    let outcomes = users.update_many(&batch).await;
+    let mut changes = Vec::new();
+    for ((index, before, user, _), outcome) in updates.iter().zip(outcomes) {
+        results.push(match outcome {
+            Ok(Some(updated)) => {
+                changes.push((user._id, Some(*before), updated));
+                BulkResult::done(*index, BulkStatus::Updated, user._id)
+            }
+            Ok(None) => not_found(*index, user._id),
+            Err(error) => BulkResult::store_error(*index, Some(user._id), error),
+        });
+    }
+    let changes: Vec<_> = changes
+        .iter()
+        .map(|(id, before, after)| (*id, *before, Some(after)))
+        .collect();
+    let actor_id = Some(auth.get_user()._id);
+    audit::record_many(&app_state, actor_id, AuditAction::Update, &changes).await;
+    HttpResponse::Ok().json(BulkReport::new(results))
+}
+
+/// Soft-deletes the users whose ids are in a JSON array, for administrators only.
+#[delete("/bulk")]
+pub async fn bulk_delete_users(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    let items = match parse_items(&body) {
+        Ok(items) => items,
+        Err(response) => return response,
+    };
+
+    let users = app_state.users.acting_as(auth.get_user());
+    let actor_id = auth.get_user()._id;
+    let now = DateTime::from_millis(app_state.ntp.current_time().timestamp_millis());
+    let mut results = Vec::new();
+    let mut ids = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        match parse_id(index, item) {
+            Ok(id) => ids.push((index, id)),
+            Err(result) => results.push(result),
+        }
+    }
+    let batch: Vec<_> = ids.iter().map(|(_, id)| *id).collect();
+    let outcomes = users.soft_delete_many(&batch, &actor_id, now).await;
+    let mut deleted = Vec::new();
+    for ((index, id), outcome) in ids.into_iter().zip(outcomes) {
+        results.push(match outcome {
+            Ok(Some(user)) => {
+                deleted.push(user);
+                BulkResult::done(index, BulkStatus::Deleted, id)
+            }
+            Ok(None) => not_found(index, id),
+            Err(error) => BulkResult::store_error(index, Some(id), error),
+        });
+    }
+    let before: Vec<_> = deleted
+        .iter()
+        .map(|user| User {
+            deleted_at: None,
+            deleted_by: None,
+            ..user.clone()
+        })
+        .collect();
+    let changes: Vec<_> = before
+        .iter()
+        .zip(&deleted)
+        .map(|(before, after)| (after._id, Some(before), Some(after)))
+        .collect();
+    audit::record_many(&app_state, Some(actor_id), AuditAction::Delete, &changes).await;
+    HttpResponse::Ok().json(BulkReport::new(results))
+}
@@ -19,6 +19,9 @@ pub enum Error {
     // SqlError(#[from] sqlx::error::Error),
     #[error("DB error")]
     Database(String),
+
+    #[error("Database unavailable")]
+    Unavailable,
 }
 
 impl<T: std::error::Error> From<EnvOptionError<T>> for Error {
@@ -36,6 +39,7 @@ impl actix_web::error::ResponseError for Error {
         match self {
             Error::Authentication => StatusCode::UNAUTHORIZED,
             Error::Authorization => StatusCode::FORBIDDEN,
+            Error::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -0,0 +1,17 @@
+use crate::{controllers::authentication::Authenticated, ProgramAppState};
+use actix_web::{get, web, HttpResponse};
+
+/// The latency histograms of the database operations in the Prometheus text format,
+/// for administrators only.
+#[get("/")]
+pub async fn get_metrics(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(app_state.metrics.render_prometheus())
+}
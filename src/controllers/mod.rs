@@ -1,3 +1,8 @@
+pub mod audit;
 pub mod authentication;
+pub mod bulk;
 pub mod error;
+pub mod metrics;
+pub mod organizations;
+pub mod retention;
 pub mod users;
@@ -0,0 +1,313 @@
+use crate::{
+    controllers::{
+        audit,
+        authentication::Authenticated,
+        users::{UserPage, DEFAULT_LIMIT, MAX_LIMIT},
+    },
+    models::{
+        audit::AuditAction,
+        organizations::{Organization, OrganizationResponse},
+        users::User,
+    },
+    store::{
+        self,
+        pagination::{Cursor, PageRequest},
+        Filter, Order, StoreError, UnitOfWork,
+    },
+    ProgramAppState,
+};
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OrganizationBody {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CursorQuery {
+    /// The `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<u64>,
+}
+
+impl CursorQuery {
+    /// None if the cursor is invalid.
+    fn page_request(&self, sort: &str) -> Option<PageRequest> {
+        let after = match self.cursor.as_deref() {
+            None => None,
+            Some(cursor) => Some(Cursor::decode(cursor)?),
+        };
+        Some(PageRequest {
+            sort: sort.to_string(),
+            order: Order::Ascending,
+            after,
+            limit: self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+            with_total: false,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OrganizationPage {
+    pub organizations: Vec<OrganizationResponse>,
+    /// None on the last page.
+    pub next_cursor: Option<String>,
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, HttpResponse> {
+    ObjectId::parse_str(id)
+        .map_err(|_| HttpResponse::BadRequest().body(format!("Invalid organization id {id}")))
+}
+
+fn valid_name(body: &OrganizationBody) -> Result<String, HttpResponse> {
+    match body.name.trim() {
+        "" => Err(HttpResponse::BadRequest().body("The name cannot be empty")),
+        name => Ok(name.to_string()),
+    }
+}
+
+/// Creates an organization, for super administrators only.
+#[post("/")]
+pub async fn create_organization(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    body: web::Json<OrganizationBody>,
+) -> HttpResponse {
+    if !auth.get_user().is_super_admin() {
+        return HttpResponse::Forbidden().body("Super administrators only");
+    }
+    let name = match valid_name(&body) {
+        Ok(name) => name,
+        Err(response) => return response,
+    };
+    let organization = Organization {
+        _id: ObjectId::new(),
+        name,
+        created_at: DateTime::from_millis(app_state.ntp.current_time().timestamp_millis()),
+    };
+    match app_state.organizations.create(&organization).await {
+        Ok(()) => {
+            audit::record(
+                &app_state,
+                Some(auth.get_user()._id),
+                AuditAction::Create,
+                organization._id,
+                None,
+                Some(&organization),
+            )
+            .await;
+            HttpResponse::Created().json(organization.to_response())
+        }
+        Err(StoreError::Duplicate(_)) => HttpResponse::Conflict().body(format!(
+            "Organization name {} already in use",
+            organization.name
+        )),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Lists the organizations by name, a page at a time, only their own one for the users
+/// of an organization.
+#[get("/")]
+pub async fn list_organizations(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    query: web::Query<CursorQuery>,
+) -> HttpResponse {
+    let Some(request) = query.page_request("name") else {
+        return HttpResponse::BadRequest().body("Invalid cursor");
+    };
+    match app_state
+        .organizations
+        .scoped(auth.tenant())
+        .page(&request)
+        .await
+    {
+        Ok(page) => HttpResponse::Ok().json(OrganizationPage {
+            organizations: page.items.iter().map(Organization::to_response).collect(),
+            next_cursor: page.next.map(|cursor| cursor.encode()),
+        }),
+        Err(StoreError::InvalidDocument(err)) => HttpResponse::BadRequest().body(err),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[get("/{id}")]
+pub async fn get_organization(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let id = match parse_id(&id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    match app_state
+        .organizations
+        .scoped(auth.tenant())
+        .find_by_id(&id)
+        .await
+    {
+        Ok(Some(organization)) => HttpResponse::Ok().json(organization.to_response()),
+        Ok(None) => HttpResponse::NotFound().body(format!("No organization found with id {id}")),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Lists the active users of the organization, a page at a time.
+#[get("/{id}/members")]
+pub async fn list_members(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    id: web::Path<String>,
+    query: web::Query<CursorQuery>,
+) -> HttpResponse {
+    let id = match parse_id(&id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let Some(request) = query.page_request("_id") else {
+        return HttpResponse::BadRequest().body("Invalid cursor");
+    };
+    match app_state
+        .organizations
+        .scoped(auth.tenant())
+        .exists(&id)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::NotFound().body(format!("No organization found with id {id}"))
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+    match app_state
+        .users
+        .scoped(auth.tenant())
+        .page(Filter::new().eq("org_id", id), &request)
+        .await
+    {
+        Ok(page) => HttpResponse::Ok().json(UserPage {
+            users: page.items.iter().map(User::sanitize).collect(),
+            next_cursor: page.next.map(|cursor| cursor.encode()),
+            total: None,
+        }),
+        Err(StoreError::InvalidDocument(err)) => HttpResponse::BadRequest().body(err),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Renames an organization, for its administrators and the super administrators.
+#[put("/{id}")]
+pub async fn update_organization(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    id: web::Path<String>,
+    body: web::Json<OrganizationBody>,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    let (id, name) = match (parse_id(&id), valid_name(&body)) {
+        (Ok(id), Ok(name)) => (id, name),
+        (Err(response), _) | (_, Err(response)) => return response,
+    };
+    let organizations = app_state.organizations.scoped(auth.tenant());
+    let before = match organizations.find_by_id(&id).await {
+        Ok(Some(before)) => before,
+        Ok(None) => {
+            return HttpResponse::NotFound().body(format!("No organization found with id {id}"))
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let organization = Organization {
+        name,
+        ..before.clone()
+    };
+    match organizations.update(&organization).await {
+        Ok(true) => {
+            audit::record(
+                &app_state,
+                Some(auth.get_user()._id),
+                AuditAction::Update,
+                id,
+                Some(&before),
+                Some(&organization),
+            )
+            .await;
+            HttpResponse::Ok().json(organization.to_response())
+        }
+        Ok(false) => HttpResponse::NotFound().body(format!("No organization found with id {id}")),
+        Err(StoreError::Duplicate(_)) => HttpResponse::Conflict().body(format!(
+            "Organization name {} already in use",
+            organization.name
+        )),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Deletes an organization without members, soft-deleted users included,
+/// for super administrators only.
+#[delete("/{id}")]
+pub async fn delete_organization(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if !auth.get_user().is_super_admin() {
+        return HttpResponse::Forbidden().body("Super administrators only");
+    }
+    let id = match parse_id(&id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let before = match app_state.organizations.find_by_id(&id).await {
+        Ok(Some(before)) => before,
+        Ok(None) => {
+            return HttpResponse::NotFound().body(format!("No organization found with id {id}"))
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let unit = match app_state.metrics.begin(app_state.database.as_ref()).await {
+        Ok(unit) => unit,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    // The organization is not deleted without its audit entry. The members remaining are
+    // returned as an error. They are counted without a lock: a user created in the
+    // organization meanwhile is kept, with an `org_id` that no longer exists, as the users
+    // of an organization deleted before the check of the members.
+    let deleted: Result<Result<bool, u64>, StoreError> =
+        store::transaction(unit, async |unit: &dyn UnitOfWork| {
+            let members = app_state.users.in_unit(unit).count_members(&id).await?;
+            if members > 0 {
+                return Ok(Err(members));
+            }
+            if !app_state.organizations.in_unit(unit).delete(&id).await? {
+                return Ok(Ok(false));
+            }
+            audit::record_in(
+                &app_state,
+                unit,
+                Some(auth.get_user()._id),
+                AuditAction::Delete,
+                id,
+                Some(&before),
+                None,
+            )
+            .await?;
+            Ok(Ok(true))
+        })
+        .await;
+    match deleted {
+        Ok(Ok(true)) => HttpResponse::NoContent().finish(),
+        Ok(Err(members)) => {
+            HttpResponse::Conflict().body(format!("Organization {id} still has {members} members"))
+        }
+        Ok(Ok(false)) => {
+            HttpResponse::NotFound().body(format!("No organization found with id {id}"))
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,82 @@
+use crate::{
+    controllers::{
+        audit::{PageQuery, DEFAULT_PER_PAGE, MAX_PER_PAGE},
+        authentication::Authenticated,
+    },
+    models::retention::{PurgeEntry, PurgeEntryResponse},
+    services::retention::{self, RetentionPolicy},
+    ProgramAppState,
+};
+use actix_web::{get, post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeQuery {
+    /// Only reports what would be purged.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PurgeLogPage {
+    pub entries: Vec<PurgeEntryResponse>,
+    pub page: u64,
+    pub per_page: u64,
+    pub total: u64,
+}
+
+/// Enforces the retention policies now and responds with what each one purged,
+/// for super administrators only.
+#[post("/purge")]
+pub async fn purge(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    query: web::Query<PurgeQuery>,
+) -> HttpResponse {
+    if !auth.get_user().is_super_admin() {
+        return HttpResponse::Forbidden().body("Super administrators only");
+    }
+    let policies = match RetentionPolicy::from_env() {
+        Ok(policies) => policies,
+        Err(err) => return HttpResponse::InternalServerError().body(err),
+    };
+    match retention::enforce(&app_state, &policies, query.dry_run).await {
+        Ok(report) => HttpResponse::Ok().json(
+            report
+                .iter()
+                .map(PurgeEntry::to_response)
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Lists the purge log, newest first, for super administrators only.
+#[get("/log")]
+pub async fn get_purge_log(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    query: web::Query<PageQuery>,
+) -> HttpResponse {
+    if !auth.get_user().is_super_admin() {
+        return HttpResponse::Forbidden().body("Super administrators only");
+    }
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    match app_state
+        .purge_log
+        .page((page - 1) * per_page, per_page)
+        .await
+    {
+        Ok((entries, total)) => HttpResponse::Ok().json(PurgeLogPage {
+            entries: entries.iter().map(PurgeEntry::to_response).collect(),
+            page,
+            per_page,
+            total,
+        }),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -1,21 +1,97 @@
-use std::str::FromStr;
-
 use crate::{
-    controllers::authentication::Authenticated,
-    models::users::{self, User},
-    ProgramAppState, DATABASE_NAME,
+    controllers::{audit, authentication::Authenticated},
+    models::{
+        audit::AuditAction,
+        users::{SanitizedUser, User},
+    },
+    services::transfer::{self, Decoded, Decoder, Format, Importer},
+    store::{
+        self,
+        pagination::{Cursor, PageRequest},
+        search, Filter, Order, StoreError, UnitOfWork,
+    },
+    ProgramAppState,
 };
-use actix_web::{delete, get, post, put, web, HttpResponse};
-use json;
-use mongodb::{
-    bson::{self, doc},
-    Collection,
+use actix_web::{
+    delete, error, get,
+    http::header::{ContentDisposition, ETag, EntityTag, IfMatch},
+    patch, post, put, web, HttpResponse,
 };
+use futures::{StreamExt, TryStreamExt};
+use json;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const DEFAULT_LIMIT: u64 = 50;
+pub const MAX_LIMIT: u64 = 200;
+const DEFAULT_SEARCH_LIMIT: u64 = 20;
+/// The fields users can be sorted by.
+const SORTABLE_FIELDS: &[&str] = &["_id", "first_name", "last_name", "email", "role"];
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    pub role: Option<String>,
+    pub org_id: Option<String>,
+    /// Prefix of the first or the last name.
+    pub name: Option<String>,
+    /// Prefix of the email.
+    pub email: Option<String>,
+    /// One of [`SORTABLE_FIELDS`], `_id` by default, prefixed with `-` for a descending order.
+    pub sort: Option<String>,
+    /// The `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<u64>,
+    /// Whether to count every matching user.
+    #[serde(default)]
+    pub total: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SearchResult {
+    pub user: SanitizedUser,
+    pub score: f64,
+    /// The matching fields, the matched words in `<em>` tags and the rest HTML escaped.
+    pub highlights: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: Format,
+    /// Whether to export the password hashes, needed to import the users back.
+    #[serde(default)]
+    pub include_hashes: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub format: Format,
+    /// Only validates the records and reports the conflicts.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserPage {
+    pub users: Vec<SanitizedUser>,
+    /// None on the last page.
+    pub next_cursor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
 
 /// Adds a new user to the "users" collection in the database.
 #[post("/")]
 pub async fn create_user(
-    _auth: Authenticated,
+    auth: Authenticated,
     app_state: web::Data<ProgramAppState>,
     body: web::Bytes,
 ) -> HttpResponse {
@@ -27,25 +103,254 @@ pub async fn create_user(
     };
 
     match User::from_json_value(&user_in_json) {
-        Some(user) => {
-            let collection: Collection<User> = app_state
-                .mongo_db_client
-                .database(&DATABASE_NAME)
-                .collection(users::REPOSITORY_NAME);
-            let result = collection.insert_one(user).await;
-            match result {
-                Ok(_) => HttpResponse::Created().body(""),
-                Err(err) => {
-                    log::warn!("{}", err);
-                    //TODO: Handle duplicate keys (emails)
-                    HttpResponse::InternalServerError().body("")
-                }
+        Some(user) => match app_state
+            .users
+            .acting_as(auth.get_user())
+            .create(&user)
+            .await
+        {
+            Ok(_) => {
+                audit::record(
+                    &app_state,
+                    Some(auth.get_user()._id),
+                    AuditAction::Create,
+                    user._id,
+                    None,
+                    Some(&user),
+                )
+                .await;
+                HttpResponse::Created().body("")
             }
-        }
+            Err(StoreError::Duplicate(_)) => {
+                HttpResponse::Conflict().body(format!("Email {} already in use", user.email))
+            }
+            // An unknown organization.
+            Err(StoreError::InvalidDocument(err)) => HttpResponse::BadRequest().body(err),
+            Err(StoreError::Forbidden(err)) => HttpResponse::Forbidden().body(err),
+            Err(err) => {
+                log::warn!("{}", err);
+                HttpResponse::InternalServerError().body("")
+            }
+        },
         None => HttpResponse::InternalServerError().body("Invalid input"),
     }
 }
 
+/// Lists the users matching the query, a page at a time.
+#[get("/")]
+pub async fn list_users(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    query: web::Query<ListQuery>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let mut filter = Filter::new();
+    if let Some(role) = &query.role {
+        filter = filter.eq("role", role.as_str());
+    }
+    if let Some(org_id) = &query.org_id {
+        let Ok(org_id) = ObjectId::parse_str(org_id) else {
+            return HttpResponse::BadRequest().body(format!("Invalid organization id {org_id}"));
+        };
+        filter = filter.eq("org_id", org_id);
+    }
+    if let Some(name) = &query.name {
+        filter = filter.any(vec![
+            Filter::new().starts_with("first_name", name),
+            Filter::new().starts_with("last_name", name),
+        ]);
+    }
+    if let Some(email) = &query.email {
+        filter = filter.starts_with("email", email);
+    }
+
+    let sort = query.sort.as_deref().unwrap_or("_id");
+    let (sort, order) = match sort.strip_prefix('-') {
+        Some(field) => (field, Order::Descending),
+        None => (sort, Order::Ascending),
+    };
+    if !SORTABLE_FIELDS.contains(&sort) {
+        return HttpResponse::BadRequest().body(format!("Cannot sort by {sort}"));
+    }
+    let after = match query.cursor.as_deref().map(Cursor::decode) {
+        None => None,
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => return HttpResponse::BadRequest().body("Invalid cursor"),
+    };
+    let request = PageRequest {
+        sort: sort.to_string(),
+        order,
+        after,
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        with_total: query.total,
+    };
+
+    match app_state
+        .users
+        .acting_as(auth.get_user())
+        .page(filter, &request)
+        .await
+    {
+        Ok(page) => HttpResponse::Ok().json(UserPage {
+            users: page.items.iter().map(User::sanitize).collect(),
+            next_cursor: page.next.map(|cursor| cursor.encode()),
+            total: page.total,
+        }),
+        // The cursor was made for another sort, or an encrypted field is filtered or sorted on.
+        Err(StoreError::InvalidDocument(err)) => HttpResponse::BadRequest().body(err),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Searches the users by name and email, the most relevant first, for administrators only.
+#[get("/search")]
+pub async fn search_users(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    query: web::Query<SearchQuery>,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_LIMIT);
+    let terms = search::terms(&query.q);
+    match app_state
+        .users
+        .acting_as(auth.get_user())
+        .search(&query.q, limit)
+        .await
+    {
+        Ok(hits) => HttpResponse::Ok().json(
+            hits.into_iter()
+                .map(|hit| SearchResult {
+                    highlights: search::highlights(&hit.item, &terms),
+                    user: hit.item.sanitize(),
+                    score: hit.score,
+                })
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Exports the active users in the requested format, streamed, for administrators only.
+#[get("/export")]
+pub async fn export_users(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    let stream = transfer::export(
+        app_state.users.acting_as(auth.get_user()),
+        query.format,
+        query.include_hashes,
+    )
+    .map_err(|err| {
+        log::error!("Failed to export the users: {err}");
+        error::ErrorInternalServerError(err)
+    });
+    HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .insert_header(ContentDisposition::attachment(format!(
+            "users.{}",
+            query.format.extension()
+        )))
+        .streaming(stream)
+}
+
+/// Imports users, decoded as the body is received, for administrators only.
+/// Responds with the report of the import, the conflicts and invalid records are skipped.
+#[post("/import")]
+pub async fn import_users(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    query: web::Query<ImportQuery>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    let actor_id = auth.get_user()._id;
+    let mut decoder = Decoder::new(query.format);
+    let users = app_state.users.acting_as(auth.get_user());
+    let mut importer = Importer::new(&users, query.dry_run);
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+        };
+        let records = decoder.feed(&chunk);
+        if let Err(err) = import_records(&app_state, actor_id, &mut importer, records).await {
+            return HttpResponse::InternalServerError().body(err.to_string());
+        }
+    }
+    let records = decoder.finish();
+    if let Err(err) = import_records(&app_state, actor_id, &mut importer, records).await {
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+    HttpResponse::Ok().json(importer.report())
+}
+
+async fn import_records(
+    app_state: &ProgramAppState,
+    actor_id: ObjectId,
+    importer: &mut Importer<'_>,
+    records: Vec<Decoded>,
+) -> Result<(), StoreError> {
+    for record in records {
+        if let Some(user) = importer.import(record).await? {
+            audit::record(
+                app_state,
+                Some(actor_id),
+                AuditAction::Create,
+                user._id,
+                None,
+                Some(&user),
+            )
+            .await;
+        }
+    }
+    Ok(())
+}
+
+/// The hit and miss counters of the user cache, for administrators only.
+#[get("/cache")]
+pub async fn get_cache_stats(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    match app_state.users.cache_stats() {
+        Some(stats) => HttpResponse::Ok().json(stats),
+        None => HttpResponse::NotFound().body("The user cache is disabled"),
+    }
+}
+
+/// Re-encrypts the users with the current key, once a key is added or the encrypted fields
+/// changed, for super administrators only.
+#[post("/encryption/rotate")]
+pub async fn rotate_encryption(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+) -> HttpResponse {
+    if !auth.get_user().is_super_admin() {
+        return HttpResponse::Forbidden().body("Super administrators only");
+    }
+    match app_state.users.rotate_encryption().await {
+        Ok(Some(report)) => HttpResponse::Ok().json(report),
+        Ok(None) => HttpResponse::NotFound().body("The field encryption is disabled"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
 /// Gets the user with the supplied email.
 #[get("/{email}")]
 pub async fn get_user_by_email(
@@ -59,87 +364,223 @@ pub async fn get_user_by_email(
     log::debug!("user: {u:?}");
 
     let email = email.into_inner();
-    let collection: Collection<users::User> = app_state
-        .mongo_db_client
-        .database(&DATABASE_NAME)
-        .collection(users::REPOSITORY_NAME);
-    match collection.find_one(doc! { "email": &email }).await {
-        Ok(Some(user)) => HttpResponse::Ok().json(user.sanitize()),
+    match app_state
+        .users
+        .acting_as(auth.get_user())
+        .find_by_email(&email)
+        .await
+    {
+        Ok(Some(user)) => HttpResponse::Ok()
+            .insert_header(entity_tag(&user))
+            .json(user.sanitize()),
         Ok(None) => HttpResponse::NotFound().body(format!("No user found with email {email}")),
         Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
     }
 }
 
-/// Updates a user.
+/// Replaces a user, all its fields must be supplied.
+/// With an `If-Match` header, the user is only replaced if its `ETag` matches.
 #[put("/{id}")]
 pub async fn update_user(
-    _auth: Authenticated,
+    auth: Authenticated,
     app_state: web::Data<ProgramAppState>,
     id: web::Path<String>,
+    if_match: Option<web::Header<IfMatch>>,
     body: web::Bytes,
 ) -> HttpResponse {
-    //log::debug!("auth: {auth:?}");
-    let user_id = id.into_inner();
+    write_user(auth, app_state, id, if_match, body, |before, json| {
+        let mut user = User::from_json_value(json)?;
+        user._id = before._id;
+        user.last_login_at = before.last_login_at;
+        Some(user)
+    })
+    .await
+}
 
-    let json_parse_res = json::parse(std::str::from_utf8(&body).unwrap()); // return Result
-    let user_in_json: json::JsonValue = match json_parse_res {
-        Ok(v) => v,
-        Err(e) => json::object! {"err" => e.to_string() },
-    };
+/// Updates some fields of a user, the others are left untouched.
+/// With an `If-Match` header, the user is only updated if its `ETag` matches.
+#[patch("/{id}")]
+pub async fn patch_user(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    id: web::Path<String>,
+    if_match: Option<web::Header<IfMatch>>,
+    body: web::Bytes,
+) -> HttpResponse {
+    write_user(auth, app_state, id, if_match, body, User::patched).await
+}
 
-    match User::from_json_value(&user_in_json) {
-        Some(new_user) => {
-            let collection: Collection<User> = app_state
-                .mongo_db_client
-                .database(&DATABASE_NAME)
-                .collection(users::REPOSITORY_NAME);
-
-            let user_obj_id = mongodb::bson::oid::ObjectId::from_str(&user_id).unwrap();
-            let old_user: User = match collection.find_one(doc! { "_id": user_obj_id }).await {
-                Ok(Some(user)) => user,
-                Ok(None) => new_user.clone(),
-                Err(err) => {
-                    log::error!("No user found with email while updating: {err}");
-                    new_user.clone()
-                }
-            };
+/// The `ETag` of a user, its version.
+fn entity_tag(user: &User) -> ETag {
+    ETag(EntityTag::new_strong(user.version.to_string()))
+}
 
-            let filter = doc! {"_id": &old_user.clone()._id};
-            let mut new_user_copy = new_user.clone();
-            new_user_copy._id = old_user._id;
-            let new_user_bson = bson::to_bson(&new_user_copy).unwrap();
-            //let user_doc = new_user_bson.as_document().unwrap();
-            let update = doc! {"$set": new_user_bson };
-
-            //let update = doc! {"$set": {"first_name": new_user_copy.first_name}};
-            let result = collection.update_one(filter, update).await;
-            match result {
-                Ok(_) => HttpResponse::Ok().json(new_user),
-                Err(err) => {
-                    log::warn!("{}", err);
-                    //TODO: Handle multiple fields
-                    HttpResponse::InternalServerError().body("")
-                }
+/// Writes the user built by `build` from the stored one and the JSON body.
+async fn write_user(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    id: web::Path<String>,
+    if_match: Option<web::Header<IfMatch>>,
+    body: web::Bytes,
+    build: impl FnOnce(&User, &json::JsonValue) -> Option<User>,
+) -> HttpResponse {
+    let user_id = id.into_inner();
+    let Ok(user_obj_id) = ObjectId::parse_str(&user_id) else {
+        return HttpResponse::BadRequest().body(format!("Invalid user id {user_id}"));
+    };
+    let Ok(user_in_json) = std::str::from_utf8(&body).map(json::parse) else {
+        return HttpResponse::BadRequest().body("Invalid input");
+    };
+    let Ok(user_in_json) = user_in_json else {
+        return HttpResponse::BadRequest().body("Invalid input");
+    };
+
+    let users = app_state.users.acting_as(auth.get_user());
+    let before = match users.find_by_id(&user_obj_id).await {
+        Ok(Some(before)) => before,
+        Ok(None) => {
+            return HttpResponse::NotFound().body(format!("No user found with id {user_id}"))
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    // Without If-Match the last write wins, with it the version read must still be the stored one.
+    // A missing header is extracted as an empty list.
+    let expected_version = match if_match.map(web::Header::into_inner) {
+        None => None,
+        Some(IfMatch::Items(tags)) if tags.is_empty() => None,
+        Some(IfMatch::Any) => Some(before.version),
+        Some(IfMatch::Items(tags)) => {
+            let current = entity_tag(&before).0;
+            if !tags.iter().any(|tag| tag.strong_eq(&current)) {
+                return HttpResponse::PreconditionFailed()
+                    .insert_header(entity_tag(&before))
+                    .body(format!("User {user_id} was modified"));
             }
+            Some(before.version)
+        }
+    };
+    let Some(user) = build(&before, &user_in_json) else {
+        return HttpResponse::BadRequest().body("Invalid input");
+    };
+
+    let unit = match app_state.metrics.begin(app_state.database.as_ref()).await {
+        Ok(unit) => unit,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    // The user is not updated without its audit entry.
+    let updated = store::transaction(unit, async |unit: &dyn UnitOfWork| {
+        let Some(user) = users.in_unit(unit).update(&user, expected_version).await? else {
+            return Ok(None);
+        };
+        audit::record_in(
+            &app_state,
+            unit,
+            Some(auth.get_user()._id),
+            AuditAction::Update,
+            user._id,
+            Some(&before),
+            Some(&user),
+        )
+        .await?;
+        Ok(Some(user))
+    })
+    .await;
+    match updated {
+        Ok(Some(user)) => HttpResponse::Ok()
+            .insert_header(entity_tag(&user))
+            .json(user.sanitize()),
+        Ok(None) => HttpResponse::NotFound().body(format!("No user found with id {user_id}")),
+        Err(StoreError::VersionConflict { .. }) => {
+            HttpResponse::PreconditionFailed().body(format!("User {user_id} was modified"))
+        }
+        Err(StoreError::Duplicate(_)) => {
+            HttpResponse::Conflict().body(format!("Email {} already in use", user.email))
+        }
+        // An unknown organization.
+        Err(StoreError::InvalidDocument(err)) => HttpResponse::BadRequest().body(err),
+        Err(StoreError::Forbidden(err)) => HttpResponse::Forbidden().body(err),
+        Err(err) => {
+            log::warn!("{}", err);
+            HttpResponse::InternalServerError().body("")
         }
-        None => HttpResponse::InternalServerError().body("Invalid input"),
     }
 }
 
-/// Deletes a user.
+/// Soft-deletes a user, it can be restored until it is purged.
 #[delete("/{id}")]
 pub async fn delete_user_by_id(
+    auth: Authenticated,
     app_state: web::Data<ProgramAppState>,
     id: web::Path<String>,
 ) -> HttpResponse {
     let id = id.into_inner();
-    let user_obj_id = mongodb::bson::oid::ObjectId::from_str(&id).unwrap();
-    let collection: Collection<users::User> = app_state
-        .mongo_db_client
-        .database(&DATABASE_NAME)
-        .collection(users::REPOSITORY_NAME);
-    match collection.delete_one(doc! { "_id": &user_obj_id }).await {
-        Ok(res) => HttpResponse::Ok().body(res.deleted_count.to_string()),
+    let Ok(user_obj_id) = ObjectId::parse_str(&id) else {
+        return HttpResponse::BadRequest().body(format!("Invalid user id {id}"));
+    };
+    let now = DateTime::from_millis(app_state.ntp.current_time().timestamp_millis());
+    match app_state
+        .users
+        .acting_as(auth.get_user())
+        .soft_delete(&user_obj_id, &auth.get_user()._id, now)
+        .await
+    {
+        Ok(Some(deleted)) => {
+            let before = User {
+                deleted_at: None,
+                deleted_by: None,
+                ..deleted.clone()
+            };
+            audit::record(
+                &app_state,
+                Some(auth.get_user()._id),
+                AuditAction::Delete,
+                deleted._id,
+                Some(&before),
+                Some(&deleted),
+            )
+            .await;
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => HttpResponse::NotFound().body(format!("No user found with id {id}")),
+        Err(StoreError::Forbidden(err)) => HttpResponse::Forbidden().body(err),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Restores a soft-deleted user, for administrators only.
+#[post("/{id}/restore")]
+pub async fn restore_user_by_id(
+    auth: Authenticated,
+    app_state: web::Data<ProgramAppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if !auth.get_user().is_admin() {
+        return HttpResponse::Forbidden().body("Administrators only");
+    }
+    let id = id.into_inner();
+    let Ok(user_obj_id) = ObjectId::parse_str(&id) else {
+        return HttpResponse::BadRequest().body(format!("Invalid user id {id}"));
+    };
+    let users = app_state.users.acting_as(auth.get_user());
+    let before = match users.find_deleted_by_id(&user_obj_id).await {
+        Ok(before) => before,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    match users.restore(&user_obj_id).await {
+        Ok(Some(user)) => {
+            audit::record(
+                &app_state,
+                Some(auth.get_user()._id),
+                AuditAction::Restore,
+                user._id,
+                before.as_ref(),
+                Some(&user),
+            )
+            .await;
+            HttpResponse::Ok().json(user.sanitize())
+        }
+        Ok(None) => HttpResponse::NotFound().body(format!("No deleted user found with id {id}")),
+        Err(StoreError::Forbidden(err)) => HttpResponse::Forbidden().body(err),
         Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
     }
 }
@@ -0,0 +1,222 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix_web::rt::{self, task::JoinHandle, time::timeout};
+
+use crate::drivers::GenericDatabase;
+
+/// The delay between two pings while the database is up.
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// A ping not answered within this delay counts as a failure.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest delay between two pings while the database is down.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Connection state of a database, shared between the driver, the monitor and the handlers.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionHealth {
+    is_connected: Arc<AtomicBool>,
+    consecutive_failures: Arc<AtomicU32>,
+}
+
+impl ConnectionHealth {
+    pub fn is_connected(&self) -> bool {
+        self.is_connected.load(Ordering::Relaxed)
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn mark_up(&self) {
+        self.is_connected.store(true, Ordering::Relaxed);
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns the number of consecutive failures, this one included.
+    pub fn mark_down(&self) -> u32 {
+        self.is_connected.store(false, Ordering::Relaxed);
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// The delay before the next ping of a database down: 1s, doubling after each failure,
+/// up to [`MAX_BACKOFF`].
+pub fn backoff(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+    Duration::from_secs(1 << exponent).min(MAX_BACKOFF)
+}
+
+/// Pings the database once, updates its health and returns the delay before the next check.
+pub async fn check(database: &dyn GenericDatabase) -> Duration {
+    let health = &database.status().health;
+    let result = match timeout(PING_TIMEOUT, database.ping()).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!("no answer within {PING_TIMEOUT:?}")),
+    };
+    match result {
+        Ok(()) => {
+            if !health.is_connected() {
+                log::info!("{:?} database is reachable again", database.status().kind);
+            }
+            health.mark_up();
+            HEALTH_CHECK_INTERVAL
+        }
+        Err(error) => {
+            let failures = health.mark_down();
+            let delay = backoff(failures);
+            log::error!(
+                "{:?} database ping failed ({failures} in a row), retrying in {delay:?}: {error:?}",
+                database.status().kind
+            );
+            delay
+        }
+    }
+}
+
+/// Spawns the task pinging the database until the returned handle is aborted.
+/// It never reconnects: reconnecting is delegated to the MongoDB client and the sqlx pools,
+/// which reopen their connections on the next operation, the next ping included. The monitor
+/// only pings with a backoff until the database answers again, and tells whether it does.
+///
+/// Unlike the NTP thread, it runs on the server runtime, where the connections were opened.
+pub fn start_health_monitor(database: Arc<dyn GenericDatabase>) -> JoinHandle<()> {
+    rt::spawn(async move {
+        loop {
+            let delay = check(database.as_ref()).await;
+            rt::time::sleep(delay).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        drivers::{GenericDatabaseStatus, MemoryDatabase},
+        migrations::{Direction, Migration},
+        models::{
+            audit::AuditEntry, organizations::Organization, retention::PurgeEntry, users::User,
+        },
+        store::{Repository, UnitOfWork},
+    };
+
+    /// A database whose connections are lost until it is brought back, as the pools reopen
+    /// them once the server answers again.
+    struct UnreachableDatabase {
+        inner: MemoryDatabase,
+        reachable: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl GenericDatabase for UnreachableDatabase {
+        fn new() -> Self {
+            UnreachableDatabase {
+                inner: GenericDatabase::new(),
+                reachable: AtomicBool::new(false),
+            }
+        }
+
+        async fn connect(&mut self, uri: &str) -> anyhow::Result<()> {
+            self.inner.connect(uri).await
+        }
+
+        fn status(&self) -> &GenericDatabaseStatus {
+            self.inner.status()
+        }
+
+        fn status_mut(&mut self) -> &mut GenericDatabaseStatus {
+            self.inner.status_mut()
+        }
+
+        async fn ping(&self) -> anyhow::Result<()> {
+            match self.reachable.load(Ordering::Relaxed) {
+                true => self.inner.ping().await,
+                false => Err(anyhow::anyhow!("connection refused")),
+            }
+        }
+
+        fn users(&self) -> anyhow::Result<Arc<dyn Repository<User>>> {
+            self.inner.users()
+        }
+
+        fn audit_log(&self) -> anyhow::Result<Arc<dyn Repository<AuditEntry>>> {
+            self.inner.audit_log()
+        }
+
+        fn organizations(&self) -> anyhow::Result<Arc<dyn Repository<Organization>>> {
+            self.inner.organizations()
+        }
+
+        fn purge_log(&self) -> anyhow::Result<Arc<dyn Repository<PurgeEntry>>> {
+            self.inner.purge_log()
+        }
+
+        async fn begin(&self) -> anyhow::Result<Box<dyn UnitOfWork>> {
+            self.inner.begin().await
+        }
+
+        async fn applied_migrations(&self) -> anyhow::Result<Vec<i64>> {
+            self.inner.applied_migrations().await
+        }
+
+        async fn lock_migrations(&self, owner: &str) -> anyhow::Result<bool> {
+            self.inner.lock_migrations(owner).await
+        }
+
+        async fn unlock_migrations(&self, owner: &str) -> anyhow::Result<()> {
+            self.inner.unlock_migrations(owner).await
+        }
+
+        async fn run_migration(
+            &self,
+            migration: &dyn Migration,
+            direction: Direction,
+        ) -> anyhow::Result<()> {
+            self.inner.run_migration(migration, direction).await
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+        assert_eq!(backoff(7), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[actix_web::test]
+    async fn test_check_marks_database_up() {
+        let database: MemoryDatabase = GenericDatabase::new();
+        let health = database.status().health.clone();
+        health.mark_down();
+        assert_eq!(health.mark_down(), 2);
+
+        assert_eq!(check(&database).await, HEALTH_CHECK_INTERVAL);
+        assert!(health.is_connected());
+        assert_eq!(health.consecutive_failures(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_check_recovers_once_the_database_answers() {
+        let mut database: UnreachableDatabase = GenericDatabase::new();
+        database.connect("memory").await.unwrap();
+        let health = database.status().health.clone();
+
+        assert_eq!(check(&database).await, backoff(1));
+        assert_eq!(check(&database).await, backoff(2));
+        assert!(!health.is_connected());
+        assert_eq!(health.consecutive_failures(), 2);
+
+        database.reachable.store(true, Ordering::Relaxed);
+        assert_eq!(check(&database).await, HEALTH_CHECK_INTERVAL);
+        assert!(health.is_connected());
+        assert_eq!(health.consecutive_failures(), 0);
+    }
+}
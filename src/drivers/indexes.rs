@@ -0,0 +1,272 @@
+//! Reconciliation of the indexes declared by the models, see [`crate::models::Model::INDEXES`],
+//! with the ones found in the database. Missing indexes are created, the other differences are
+//! only reported: dropping or rebuilding an index of a live database is left to a migration.
+
+use std::time::Duration;
+
+use crate::{
+    models::{
+        audit::AuditEntry, organizations::Organization, retention::PurgeEntry, users::User, Index,
+        IndexKey, Model,
+    },
+    store::sql::SEARCH_VECTOR_COLUMN,
+};
+
+/// Every model having indexes, with its collection (or table).
+pub fn registry() -> Vec<(&'static str, &'static [Index])> {
+    vec![
+        (User::REPOSITORY_NAME, User::INDEXES),
+        (AuditEntry::REPOSITORY_NAME, AuditEntry::INDEXES),
+        (Organization::REPOSITORY_NAME, Organization::INDEXES),
+        (PurgeEntry::REPOSITORY_NAME, PurgeEntry::INDEXES),
+    ]
+}
+
+/// What makes two indexes equivalent, whatever their names.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexShape {
+    /// Text keys are sorted, their order does not matter.
+    pub keys: Vec<(String, IndexKey)>,
+    pub unique: bool,
+    pub expire_after: Option<Duration>,
+}
+
+impl IndexShape {
+    pub fn new(
+        keys: Vec<(String, IndexKey)>,
+        unique: bool,
+        expire_after: Option<Duration>,
+    ) -> Self {
+        let (mut text, mut keys): (Vec<_>, Vec<_>) = keys
+            .into_iter()
+            .partition(|(_, key)| *key == IndexKey::Text);
+        text.sort_by(|(left, _), (right, _)| left.cmp(right));
+        keys.extend(text);
+        IndexShape {
+            keys,
+            unique,
+            expire_after,
+        }
+    }
+
+    /// The index as declared, which is how MongoDB stores it.
+    pub fn declared(index: &Index) -> Self {
+        let keys = index
+            .keys
+            .iter()
+            .map(|(field, key)| (field.to_string(), *key))
+            .collect();
+        IndexShape::new(keys, index.unique, index.expire_after)
+    }
+
+    /// The index as a SQL database stores it, None if it cannot.
+    /// B-tree indexes are read both ways and there is no TTL, text indexes are a GIN index
+    /// of the search vector column on PostgreSQL.
+    pub fn sql(index: &Index, has_search_vector: bool) -> Option<Self> {
+        if index.keys.iter().any(|(_, key)| *key == IndexKey::Text) {
+            return has_search_vector.then(|| {
+                IndexShape::new(
+                    vec![(SEARCH_VECTOR_COLUMN.to_string(), IndexKey::Text)],
+                    false,
+                    None,
+                )
+            });
+        }
+        let keys = index
+            .keys
+            .iter()
+            .map(|(field, _)| (field.to_string(), IndexKey::Ascending))
+            .collect();
+        Some(IndexShape::new(keys, index.unique, None))
+    }
+}
+
+/// What [`crate::drivers::GenericDatabase::reconcile_indexes`] did, and what it left as is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexReport {
+    pub created: Vec<String>,
+    /// Declared indexes differing from the stored ones, and stored indexes nobody declared.
+    pub drift: Vec<String>,
+}
+
+impl IndexReport {
+    pub fn log(&self) {
+        for name in &self.created {
+            log::info!("Created index {name}");
+        }
+        for drift in &self.drift {
+            log::warn!("Index drift: {drift}");
+        }
+    }
+}
+
+/// Returns the declared indexes missing from the collection, and reports the drift.
+/// An index is found by its name, or by its shape under another name.
+pub fn plan<'a>(
+    collection: &str,
+    declared: Vec<(&'a Index, IndexShape)>,
+    existing: &[(String, IndexShape)],
+    report: &mut IndexReport,
+) -> Vec<(&'a Index, IndexShape)> {
+    let mut missing = Vec::new();
+    let mut matched = vec![false; existing.len()];
+    for (index, shape) in declared {
+        let position = existing
+            .iter()
+            .position(|(name, _)| name == index.name)
+            .or_else(|| existing.iter().position(|(_, other)| *other == shape));
+        match position {
+            Some(position) => {
+                matched[position] = true;
+                let (name, found) = &existing[position];
+                if *found != shape {
+                    report.drift.push(format!(
+                        "{collection}.{name} is {found:?}, {shape:?} is declared"
+                    ));
+                }
+            }
+            None => missing.push((index, shape)),
+        }
+    }
+    for ((name, _), matched) in existing.iter().zip(matched) {
+        if !matched {
+            report
+                .drift
+                .push(format!("{collection}.{name} is not declared"));
+        }
+    }
+    missing
+}
+
+/// Creates an index of a SQL table, with the shape given by [`IndexShape::sql`].
+pub fn create_sql_statement(table: &str, name: &str, shape: &IndexShape) -> String {
+    let unique = if shape.unique { "UNIQUE " } else { "" };
+    let method = match shape.keys.iter().any(|(_, key)| *key == IndexKey::Text) {
+        true => "USING GIN ",
+        false => "",
+    };
+    let columns = shape
+        .keys
+        .iter()
+        .map(|(column, _)| column.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("CREATE {unique}INDEX IF NOT EXISTS {name} ON {table} {method}({columns})")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        drivers::{GenericDatabase, SqliteDatabase},
+        migrations,
+    };
+
+    const TTL: Index = Index::new("sessions_ttl", &[("created_at", IndexKey::Ascending)])
+        .expire_after(Duration::from_secs(3600));
+
+    fn shape(keys: &[(&str, IndexKey)], unique: bool) -> IndexShape {
+        let keys = keys
+            .iter()
+            .map(|(field, key)| (field.to_string(), *key))
+            .collect();
+        IndexShape::new(keys, unique, None)
+    }
+
+    #[test]
+    fn test_shapes() {
+        let [email, _, org_role, search] = User::INDEXES else {
+            panic!("the users should have 4 indexes");
+        };
+        assert_eq!(
+            IndexShape::declared(search),
+            shape(
+                &[
+                    ("email", IndexKey::Text),
+                    ("first_name", IndexKey::Text),
+                    ("last_name", IndexKey::Text)
+                ],
+                false
+            )
+        );
+        assert_eq!(IndexShape::sql(search, false), None);
+        assert_eq!(
+            IndexShape::sql(&TTL, false),
+            Some(shape(&[("created_at", IndexKey::Ascending)], false))
+        );
+        assert_eq!(
+            IndexShape::declared(&TTL).expire_after,
+            Some(Duration::from_secs(3600))
+        );
+
+        let sql = IndexShape::sql(email, false).unwrap();
+        assert_eq!(
+            create_sql_statement("users", email.name, &sql),
+            "CREATE UNIQUE INDEX IF NOT EXISTS users_email ON users (email)"
+        );
+        let sql = IndexShape::sql(org_role, false).unwrap();
+        assert_eq!(
+            create_sql_statement("users", org_role.name, &sql),
+            "CREATE INDEX IF NOT EXISTS users_org_role ON users (org_id, role)"
+        );
+        let sql = IndexShape::sql(search, true).unwrap();
+        assert_eq!(
+            create_sql_statement("users", search.name, &sql),
+            "CREATE INDEX IF NOT EXISTS users_search ON users USING GIN (search_vector)"
+        );
+    }
+
+    #[test]
+    fn test_plan() {
+        let declared = User::INDEXES
+            .iter()
+            .map(|index| (index, IndexShape::declared(index)))
+            .collect();
+        let existing = vec![
+            // The email index created under another name.
+            (
+                "email_1".to_string(),
+                shape(&[("email", IndexKey::Ascending)], true),
+            ),
+            (
+                "users_org_role".to_string(),
+                shape(&[("org_id", IndexKey::Ascending)], false),
+            ),
+            (
+                "legacy".to_string(),
+                shape(&[("role", IndexKey::Ascending)], false),
+            ),
+        ];
+        let mut report = IndexReport::default();
+        let missing = plan("users", declared, &existing, &mut report);
+        let missing: Vec<_> = missing.iter().map(|(index, _)| index.name).collect();
+        assert_eq!(missing, ["users_email_index", "users_search"]);
+        assert_eq!(report.drift.len(), 2);
+        assert!(report.drift[0].starts_with("users.users_org_role is"));
+        assert_eq!(report.drift[1], "users.legacy is not declared");
+    }
+
+    #[actix_web::test]
+    async fn test_reconcile_sqlite() {
+        let mut db: SqliteDatabase = GenericDatabase::new();
+        db.connect("sqlite::memory:").await.unwrap();
+        migrations::migrate(&mut db).await.unwrap();
+
+        // The email index is the UNIQUE constraint of the table, the audit and purge log indexes
+        // come from their migrations.
+        let report = db.reconcile_indexes().await.unwrap();
+        assert_eq!(report.created, ["users.users_org_role"]);
+        assert!(report.drift.is_empty());
+
+        let report = db.reconcile_indexes().await.unwrap();
+        assert_eq!(report, IndexReport::default());
+
+        let pool = db.pool.as_ref().unwrap();
+        sqlx::query("CREATE INDEX users_role ON users (role)")
+            .execute(pool)
+            .await
+            .unwrap();
+        let report = db.reconcile_indexes().await.unwrap();
+        assert_eq!(report.drift, ["users.users_role is not declared"]);
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+
+use crate::{
+    drivers::{DriverKind, GenericDatabase, GenericDatabaseStatus},
+    migrations::{Direction, Migration, MigrationTarget},
+    models::{audit::AuditEntry, organizations::Organization, retention::PurgeEntry, users::User},
+    store::{
+        memory::{Collections, MemoryRepository},
+        MemoryUnitOfWork, Repository, UnitOfWork,
+    },
+};
+
+/// A database living in the process memory, for tests and local development.
+/// Everything is lost when the process stops.
+#[derive(Debug, Default)]
+pub struct MemoryDatabase {
+    pub status: GenericDatabaseStatus,
+    pub collections: Collections,
+    applied_migrations: Mutex<Vec<i64>>,
+    migrations_lock_owner: Mutex<Option<String>>,
+}
+
+#[async_trait]
+impl GenericDatabase for MemoryDatabase {
+    fn new() -> Self {
+        MemoryDatabase {
+            status: GenericDatabaseStatus {
+                kind: DriverKind::Memory,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    async fn connect(&mut self, _uri: &str) -> anyhow::Result<()> {
+        log::info!("Using an in-memory database, data will not be persisted");
+        self.status.health.mark_up();
+        Ok(())
+    }
+
+    fn status(&self) -> &GenericDatabaseStatus {
+        &self.status
+    }
+
+    fn status_mut(&mut self) -> &mut GenericDatabaseStatus {
+        &mut self.status
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn users(&self) -> anyhow::Result<Arc<dyn Repository<User>>> {
+        Ok(Arc::new(MemoryRepository::new(&self.collections)))
+    }
+
+    fn audit_log(&self) -> anyhow::Result<Arc<dyn Repository<AuditEntry>>> {
+        Ok(Arc::new(MemoryRepository::new(&self.collections)))
+    }
+
+    fn organizations(&self) -> anyhow::Result<Arc<dyn Repository<Organization>>> {
+        Ok(Arc::new(MemoryRepository::new(&self.collections)))
+    }
+
+    fn purge_log(&self) -> anyhow::Result<Arc<dyn Repository<PurgeEntry>>> {
+        Ok(Arc::new(MemoryRepository::new(&self.collections)))
+    }
+
+    async fn begin(&self) -> anyhow::Result<Box<dyn UnitOfWork>> {
+        Ok(Box::new(MemoryUnitOfWork::begin(&self.collections)?))
+    }
+
+    async fn applied_migrations(&self) -> anyhow::Result<Vec<i64>> {
+        let applied = self
+            .applied_migrations
+            .lock()
+            .map_err(|_| anyhow!("Applied migrations lock is poisoned"))?;
+        Ok(applied.clone())
+    }
+
+    async fn lock_migrations(&self, owner: &str) -> anyhow::Result<bool> {
+        let mut lock_owner = self
+            .migrations_lock_owner
+            .lock()
+            .map_err(|_| anyhow!("Migrations lock is poisoned"))?;
+        if lock_owner.is_some() {
+            return Ok(false);
+        }
+        *lock_owner = Some(owner.to_string());
+        Ok(true)
+    }
+
+    async fn unlock_migrations(&self, owner: &str) -> anyhow::Result<()> {
+        let mut lock_owner = self
+            .migrations_lock_owner
+            .lock()
+            .map_err(|_| anyhow!("Migrations lock is poisoned"))?;
+        if lock_owner.as_deref() == Some(owner) {
+            *lock_owner = None;
+        }
+        Ok(())
+    }
+
+    async fn run_migration(
+        &self,
+        migration: &dyn Migration,
+        direction: Direction,
+    ) -> anyhow::Result<()> {
+        match direction {
+            Direction::Up => migration.up(MigrationTarget::Memory).await?,
+            Direction::Down => migration.down(MigrationTarget::Memory).await?,
+        }
+        let mut applied = self
+            .applied_migrations
+            .lock()
+            .map_err(|_| anyhow!("Applied migrations lock is poisoned"))?;
+        applied.retain(|version| *version != migration.version());
+        if direction == Direction::Up {
+            applied.push(migration.version());
+        }
+        Ok(())
+    }
+}
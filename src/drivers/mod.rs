@@ -1,108 +1,157 @@
-use crate::services::emails;
+use std::sync::Arc;
+
 use anyhow::bail;
 use async_trait::async_trait;
 use lazy_static::lazy_static;
-use mongodb::{error::ErrorKind, Client as mgoClient};
-// use sqlx::postgres::PgPool;
 
-use crate::models::users::{self, User};
+use crate::migrations::{Direction, Migration};
+use crate::models::{
+    audit::AuditEntry, organizations::Organization, retention::PurgeEntry, users::User,
+};
+use crate::store::{Repository, UnitOfWork};
 
+pub mod health;
+pub mod indexes;
+pub mod memory;
 pub mod mongo;
 pub mod postgre;
+pub mod sqlite;
+pub mod validation;
+
+pub use health::ConnectionHealth;
+pub use indexes::IndexReport;
+pub use memory::MemoryDatabase;
+pub use mongo::MongoDatabase;
+pub use postgre::PostgreDatabase;
+pub use sqlite::SqliteDatabase;
+pub use validation::ValidationReport;
 
 lazy_static! {
     static ref DATABASE_NAME: String =
         std::env::var("DATABASE_NAME").unwrap_or_else(|_| "base-api".into());
 }
 
-#[derive(Debug)]
-pub struct GenericDatabaseStatus {
-    // pub kind: String,
-    pub is_connected: bool,
-    // pub last_migrations_performed: String,
+/// The id of the single migrations lock document (or row).
+const MIGRATIONS_LOCK_ID: i32 = 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DriverKind {
+    #[default]
+    Mongo,
+    Postgre,
+    Sqlite,
+    Memory,
 }
 
-#[derive(Debug)]
-pub struct MongoDatabase {
-    pub status: GenericDatabaseStatus,
-    pub client: Option<mgoClient>,
+impl DriverKind {
+    /// Uses `driver` when given, the scheme of the url otherwise.
+    pub fn from_config(driver: Option<&str>, url: &str) -> anyhow::Result<Self> {
+        let name = match driver {
+            Some(driver) => driver,
+            None => url.split(':').next().unwrap_or_default(),
+        };
+        match name {
+            "mongo" | "mongodb" | "mongodb+srv" => Ok(DriverKind::Mongo),
+            "postgre" | "postgres" | "postgresql" => Ok(DriverKind::Postgre),
+            "sqlite" => Ok(DriverKind::Sqlite),
+            "memory" => Ok(DriverKind::Memory),
+            _ => bail!("Unknown database driver: {name}"),
+        }
+    }
 }
 
-// pub struct PostgreDatabase {
-//     status: GenericDatabaseStatus,
-//     client: PgPool,
-// }
+#[derive(Debug, Default)]
+pub struct GenericDatabaseStatus {
+    pub kind: DriverKind,
+    /// Kept up to date by [`health::start_health_monitor`].
+    pub health: ConnectionHealth,
+    pub last_migrations_performed: String,
+}
 
 #[async_trait]
-pub trait GenericDatabase {
-    fn new() -> Self;
-    async fn connect(&mut self, uri: &str) -> anyhow::Result<&Self>;
-    // fn migrate(&self, reference: &str) -> Self;
-}
+pub trait GenericDatabase: Send + Sync {
+    fn new() -> Self
+    where
+        Self: Sized;
+    async fn connect(&mut self, uri: &str) -> anyhow::Result<()>;
+    fn status(&self) -> &GenericDatabaseStatus;
+    fn status_mut(&mut self) -> &mut GenericDatabaseStatus;
+    /// Checks that the database answers.
+    async fn ping(&self) -> anyhow::Result<()>;
 
-impl MongoDatabase {
-    pub async fn seed_user(&self, user: User) -> anyhow::Result<&Self> {
-        match &self.client {
-            Some(client) => {
-                let collection = client
-                    .database(&DATABASE_NAME)
-                    .collection(users::REPOSITORY_NAME);
-                match collection.insert_one(user.clone()).await {
-                    Ok(_) => {
-                        let _ = emails::send_email_with_aws_ses(&user.email, "Welcome", "Message")
-                            .await;
-                        Ok(self)
-                    }
-                    Err(error) => {
-                        match *error.kind {
-                            ErrorKind::Write(write_error) => {
-                                match write_error {
-                                mongodb::error::WriteFailure::WriteError(e) => {
-                                    if e.code != 11000 {
-                                        log::warn!("seed_user WriteFailure::WriteError: {e:?}");
-                                    }
-                                },
-                                _ => bail!("Unknown writeConcernError while seed_user: {write_error:?}"),
-                            }
-                                Ok(self)
-                            }
-                            _ => bail!("Unknown errorKind while seed_user: {error}"),
-                        }
-                    }
-                }
-            }
-            None => bail!("seed_user unable to get client"),
-        }
+    /// Creates the declared indexes missing from the database and reports the drift,
+    /// see [`indexes`]. The in-memory database enforces the unique indexes by itself.
+    async fn reconcile_indexes(&self) -> anyhow::Result<IndexReport> {
+        Ok(IndexReport::default())
+    }
+    /// Has the database validate the documents written with the schema of the models, and
+    /// reports the stored ones violating it, see [`validation`]. The SQL databases already
+    /// enforce the schema of their tables, the in-memory one only stores serialized models.
+    async fn apply_validation(&self) -> anyhow::Result<ValidationReport> {
+        Ok(ValidationReport::default())
     }
+    fn users(&self) -> anyhow::Result<Arc<dyn Repository<User>>>;
+    fn audit_log(&self) -> anyhow::Result<Arc<dyn Repository<AuditEntry>>>;
+    fn organizations(&self) -> anyhow::Result<Arc<dyn Repository<Organization>>>;
+    fn purge_log(&self) -> anyhow::Result<Arc<dyn Repository<PurgeEntry>>>;
+    /// Starts a transaction, see [`crate::store::transaction`] to commit or roll it back
+    /// depending on the outcome of the writes.
+    async fn begin(&self) -> anyhow::Result<Box<dyn UnitOfWork>>;
 
-    // pub fn aggregate(&self) {
-    //     log::info!("MongoDatabase aggregate");
-    // }
+    /// Returns the versions of the migrations applied to this database.
+    async fn applied_migrations(&self) -> anyhow::Result<Vec<i64>>;
+    /// Takes the migrations lock, returns false if another instance holds it.
+    async fn lock_migrations(&self, owner: &str) -> anyhow::Result<bool>;
+    async fn unlock_migrations(&self, owner: &str) -> anyhow::Result<()>;
+    /// Runs a migration and records it as applied, or as not applied anymore when going down.
+    async fn run_migration(
+        &self,
+        migration: &dyn Migration,
+        direction: Direction,
+    ) -> anyhow::Result<()>;
 }
 
-#[async_trait]
-impl GenericDatabase for MongoDatabase {
-    fn new() -> Self {
-        MongoDatabase {
-            status: GenericDatabaseStatus {
-                // kind: "mongo".to_string(),
-                is_connected: false,
-                // last_migrations_performed: "".to_string(),
-            },
-            client: None,
-        }
+/// Returns a disconnected database of the given kind.
+pub fn new_database(kind: DriverKind) -> Box<dyn GenericDatabase> {
+    match kind {
+        DriverKind::Mongo => Box::new(MongoDatabase::new()),
+        DriverKind::Postgre => Box::new(PostgreDatabase::new()),
+        DriverKind::Sqlite => Box::new(SqliteDatabase::new()),
+        DriverKind::Memory => Box::new(MemoryDatabase::new()),
     }
+}
 
-    async fn connect(&mut self, uri: &str) -> anyhow::Result<&Self> {
-        log::info!("Connecting to MongoDB with uri:{uri}");
-        let mongo_db_client = mgoClient::with_uri_str(uri).await?;
-        self.client = Some(mongo_db_client);
-        self.status.is_connected = true;
-        // MongoDatabase { status: (), client: mongo_db_client }
-        Ok(self)
+/// Connects to `DATABASE_URL` (or `MONGODB_URI`) with the driver named by `DATABASE_DRIVER`,
+/// or picked from the url scheme when it is not set.
+pub async fn connect_from_env() -> anyhow::Result<Box<dyn GenericDatabase>> {
+    let url = std::env::var("DATABASE_URL")
+        .or_else(|_| std::env::var("MONGODB_URI"))
+        .unwrap_or_else(|_| "mongodb://localhost:27017".into());
+    let driver = std::env::var("DATABASE_DRIVER").ok();
+    let kind = DriverKind::from_config(driver.as_deref(), &url)?;
+
+    let mut database = new_database(kind);
+    if let Err(error) = database.connect(&url).await {
+        bail!("Failed to connect {kind:?} with drivers: {error:?}")
     }
+    log::info!("successfully connected {kind:?} with drivers");
+    Ok(database)
+}
 
-    // fn migrate(&self, _reference: &str) -> Self {
-    //     todo!()
-    // }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_driver_kind_from_config() {
+        let kind = |driver, url| DriverKind::from_config(driver, url).unwrap();
+        assert_eq!(kind(None, "mongodb://localhost:27017"), DriverKind::Mongo);
+        assert_eq!(kind(None, "mongodb+srv://cluster"), DriverKind::Mongo);
+        assert_eq!(kind(None, "postgres://localhost/db"), DriverKind::Postgre);
+        assert_eq!(kind(None, "postgresql://localhost/db"), DriverKind::Postgre);
+        assert_eq!(kind(None, "sqlite://base-api.db"), DriverKind::Sqlite);
+        assert_eq!(kind(None, "memory://"), DriverKind::Memory);
+        assert_eq!(kind(Some("sqlite"), "file.db"), DriverKind::Sqlite);
+        assert!(DriverKind::from_config(None, "redis://localhost").is_err());
+    }
 }
@@ -1 +1,344 @@
+use std::sync::{Arc, OnceLock};
 
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    error::ErrorKind,
+    options::IndexOptions,
+    Client as mgoClient, IndexModel,
+};
+
+use crate::{
+    drivers::{
+        indexes::{self, IndexShape},
+        validation::{self, Violation, MAX_REPORTED_VIOLATIONS},
+        DriverKind, GenericDatabase, GenericDatabaseStatus, IndexReport, ValidationReport,
+        DATABASE_NAME, MIGRATIONS_LOCK_ID,
+    },
+    migrations::{
+        self, Direction, Migration, MigrationTarget, MIGRATIONS_LOCK_REPOSITORY_NAME,
+        MIGRATIONS_REPOSITORY_NAME,
+    },
+    models::{
+        audit::AuditEntry, organizations::Organization, retention::PurgeEntry, users::User, Index,
+        IndexKey,
+    },
+    store::{MongoRepository, MongoUnitOfWork, Repository, UnitOfWork},
+};
+
+/// Reads the shape of an index listed by MongoDB, None for the `_id` one.
+fn existing_shape(model: &IndexModel) -> Option<(String, IndexShape)> {
+    let options = model.options.clone().unwrap_or_default();
+    let name = options.name.unwrap_or_default();
+    if name == "_id_" {
+        return None;
+    }
+    let mut keys = Vec::new();
+    for (field, value) in &model.keys {
+        let key = match value {
+            // A text index is stored as these two keys, its fields are in the weights.
+            _ if field == "_fts" => {
+                let weights = options.weights.clone().unwrap_or_default();
+                keys.extend(weights.keys().map(|field| (field.clone(), IndexKey::Text)));
+                continue;
+            }
+            _ if field == "_ftsx" => continue,
+            Bson::Int32(direction) if *direction < 0 => IndexKey::Descending,
+            Bson::Int64(direction) if *direction < 0 => IndexKey::Descending,
+            Bson::Double(direction) if *direction < 0.0 => IndexKey::Descending,
+            _ => IndexKey::Ascending,
+        };
+        keys.push((field.clone(), key));
+    }
+    let shape = IndexShape::new(keys, options.unique.unwrap_or(false), options.expire_after);
+    Some((name, shape))
+}
+
+fn index_model(index: &Index) -> IndexModel {
+    let mut keys = Document::new();
+    let mut text = false;
+    for (field, key) in index.keys {
+        match key {
+            IndexKey::Ascending => keys.insert(*field, 1),
+            IndexKey::Descending => keys.insert(*field, -1),
+            IndexKey::Text => {
+                text = true;
+                keys.insert(*field, "text")
+            }
+        };
+    }
+    let options = IndexOptions::builder()
+        .name(index.name.to_string())
+        .unique(index.unique.then_some(true))
+        .expire_after(index.expire_after)
+        .sparse(index.sparse.then_some(true))
+        // The default language would stem the names as English words.
+        .default_language(text.then(|| "none".to_string()))
+        .build();
+    IndexModel::builder().keys(keys).options(options).build()
+}
+
+#[derive(Debug)]
+pub struct MongoDatabase {
+    pub status: GenericDatabaseStatus,
+    pub client: Option<mgoClient>,
+    /// Whether the server runs transactions, being a replica set or a sharded cluster,
+    /// asked once connected.
+    transactions: OnceLock<bool>,
+}
+
+impl MongoDatabase {
+    fn database(&self) -> anyhow::Result<mongodb::Database> {
+        match &self.client {
+            Some(client) => Ok(client.database(&DATABASE_NAME)),
+            None => bail!("MongoDatabase unable to get client"),
+        }
+    }
+
+    /// Standalone servers do not run transactions, the units of work then write at once.
+    async fn transactions(&self) -> anyhow::Result<bool> {
+        if let Some(transactions) = self.transactions.get() {
+            return Ok(*transactions);
+        }
+        let hello = self.database()?.run_command(doc! {"hello": 1}).await?;
+        let transactions = hello.contains_key("setName")
+            || hello.get_str("msg").is_ok_and(|msg| msg == "isdbgrid");
+        if !transactions {
+            log::warn!(
+                "MongoDB runs standalone, the units of work are written without a transaction"
+            );
+        }
+        Ok(*self.transactions.get_or_init(|| transactions))
+    }
+
+    // pub fn aggregate(&self) {
+    //     log::info!("MongoDatabase aggregate");
+    // }
+}
+
+#[async_trait]
+impl GenericDatabase for MongoDatabase {
+    fn new() -> Self {
+        MongoDatabase {
+            status: GenericDatabaseStatus {
+                kind: DriverKind::Mongo,
+                ..Default::default()
+            },
+            client: None,
+            transactions: OnceLock::new(),
+        }
+    }
+
+    async fn connect(&mut self, uri: &str) -> anyhow::Result<()> {
+        log::info!("Connecting to MongoDB with uri:{uri}");
+        let mongo_db_client = mgoClient::with_uri_str(uri).await?;
+        self.client = Some(mongo_db_client);
+        self.transactions = OnceLock::new();
+        self.status.health.mark_up();
+        Ok(())
+    }
+
+    fn status(&self) -> &GenericDatabaseStatus {
+        &self.status
+    }
+
+    fn status_mut(&mut self) -> &mut GenericDatabaseStatus {
+        &mut self.status
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        self.database()?.run_command(doc! {"ping": 1}).await?;
+        Ok(())
+    }
+
+    async fn reconcile_indexes(&self) -> anyhow::Result<IndexReport> {
+        let database = self.database()?;
+        let mut report = IndexReport::default();
+        for (collection_name, declared) in indexes::registry() {
+            let collection = database.collection::<Document>(collection_name);
+            let existing: Vec<IndexModel> = collection.list_indexes().await?.try_collect().await?;
+            let existing: Vec<_> = existing.iter().filter_map(existing_shape).collect();
+            let declared = declared
+                .iter()
+                .map(|index| (index, IndexShape::declared(index)))
+                .collect();
+            for (index, _) in indexes::plan(collection_name, declared, &existing, &mut report) {
+                collection
+                    .create_index(index_model(index))
+                    .await
+                    .with_context(|| format!("Creating index {}", index.name))?;
+                report
+                    .created
+                    .push(format!("{collection_name}.{}", index.name));
+            }
+        }
+        Ok(report)
+    }
+
+    async fn apply_validation(&self) -> anyhow::Result<ValidationReport> {
+        let database = self.database()?;
+        let existing = database.list_collection_names().await?;
+        let mut report = ValidationReport::default();
+        for (collection_name, fields) in validation::registry() {
+            let schema = validation::json_schema(fields);
+            let command = match existing.iter().any(|name| name == collection_name) {
+                true => "collMod",
+                false => "create",
+            };
+            // The documents already violating the schema can still be updated, they are
+            // reported instead.
+            database
+                .run_command(doc! {
+                    command: collection_name,
+                    "validator": { "$jsonSchema": schema.clone() },
+                    "validationLevel": "moderate",
+                    "validationAction": "error",
+                })
+                .await
+                .with_context(|| format!("Validating {collection_name}"))?;
+            report.validated.push(collection_name.to_string());
+
+            let collection = database.collection::<Document>(collection_name);
+            let invalid = doc! { "$nor": [{ "$jsonSchema": schema }] };
+            let count = collection.count_documents(invalid.clone()).await?;
+            if count == 0 {
+                continue;
+            }
+            report.violating.push((collection_name.to_string(), count));
+            let documents: Vec<Document> = collection
+                .find(invalid)
+                .limit(MAX_REPORTED_VIOLATIONS)
+                .await?
+                .try_collect()
+                .await?;
+            for document in documents {
+                report.violations.push(Violation {
+                    collection: collection_name.to_string(),
+                    id: document
+                        .get("_id")
+                        .map(|id| id.clone().into_relaxed_extjson().to_string())
+                        .unwrap_or_default(),
+                    reasons: validation::violations(fields, &document),
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    fn users(&self) -> anyhow::Result<Arc<dyn Repository<User>>> {
+        match &self.client {
+            Some(client) => Ok(Arc::new(MongoRepository::new(client, &DATABASE_NAME))),
+            None => bail!("users unable to get client"),
+        }
+    }
+
+    fn audit_log(&self) -> anyhow::Result<Arc<dyn Repository<AuditEntry>>> {
+        match &self.client {
+            Some(client) => Ok(Arc::new(MongoRepository::new(client, &DATABASE_NAME))),
+            None => bail!("audit_log unable to get client"),
+        }
+    }
+
+    fn organizations(&self) -> anyhow::Result<Arc<dyn Repository<Organization>>> {
+        match &self.client {
+            Some(client) => Ok(Arc::new(MongoRepository::new(client, &DATABASE_NAME))),
+            None => bail!("organizations unable to get client"),
+        }
+    }
+
+    fn purge_log(&self) -> anyhow::Result<Arc<dyn Repository<PurgeEntry>>> {
+        match &self.client {
+            Some(client) => Ok(Arc::new(MongoRepository::new(client, &DATABASE_NAME))),
+            None => bail!("purge_log unable to get client"),
+        }
+    }
+
+    async fn begin(&self) -> anyhow::Result<Box<dyn UnitOfWork>> {
+        match &self.client {
+            Some(client) => Ok(Box::new(
+                MongoUnitOfWork::begin(client, &DATABASE_NAME, self.transactions().await?).await?,
+            )),
+            None => bail!("begin unable to get client"),
+        }
+    }
+
+    async fn applied_migrations(&self) -> anyhow::Result<Vec<i64>> {
+        let records: Vec<Document> = self
+            .database()?
+            .collection(MIGRATIONS_REPOSITORY_NAME)
+            .find(doc! {})
+            .await?
+            .try_collect()
+            .await?;
+        Ok(records
+            .iter()
+            .filter_map(|record| record.get_i64("_id").ok())
+            .collect())
+    }
+
+    async fn lock_migrations(&self, owner: &str) -> anyhow::Result<bool> {
+        let now = DateTime::now();
+        let stale = DateTime::from_millis(
+            now.timestamp_millis() - migrations::LOCK_TIMEOUT.as_millis() as i64,
+        );
+        // The upsert conflicts on _id when a lock that is not stale already exists.
+        let result = self
+            .database()?
+            .collection::<Document>(MIGRATIONS_LOCK_REPOSITORY_NAME)
+            .update_one(
+                doc! { "_id": MIGRATIONS_LOCK_ID, "locked_at": { "$lt": stale } },
+                doc! { "$set": { "owner": owner, "locked_at": now } },
+            )
+            .upsert(true)
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(error) => match *error.kind {
+                ErrorKind::Write(mongodb::error::WriteFailure::WriteError(ref e))
+                    if e.code == 11000 =>
+                {
+                    Ok(false)
+                }
+                _ => Err(error.into()),
+            },
+        }
+    }
+
+    async fn unlock_migrations(&self, owner: &str) -> anyhow::Result<()> {
+        self.database()?
+            .collection::<Document>(MIGRATIONS_LOCK_REPOSITORY_NAME)
+            .delete_one(doc! { "_id": MIGRATIONS_LOCK_ID, "owner": owner })
+            .await?;
+        Ok(())
+    }
+
+    async fn run_migration(
+        &self,
+        migration: &dyn Migration,
+        direction: Direction,
+    ) -> anyhow::Result<()> {
+        let database = self.database()?;
+        let records = database.collection::<Document>(MIGRATIONS_REPOSITORY_NAME);
+        match direction {
+            Direction::Up => {
+                migration.up(MigrationTarget::Mongo(&database)).await?;
+                records
+                    .insert_one(doc! {
+                        "_id": migration.version(),
+                        "name": migration.name(),
+                        "applied_at": DateTime::now(),
+                    })
+                    .await?;
+            }
+            Direction::Down => {
+                migration.down(MigrationTarget::Mongo(&database)).await?;
+                records
+                    .delete_one(doc! { "_id": migration.version() })
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
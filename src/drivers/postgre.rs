@@ -1,2 +1,249 @@
-//https://blog.logrocket.com/create-backend-api-with-rust-postgres/
-//https://github.com/diesel-rs/diesel
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use sqlx::{
+    postgres::{PgPool, PgPoolOptions},
+    Row,
+};
+
+use crate::{
+    drivers::{
+        indexes::{self, IndexShape},
+        DriverKind, GenericDatabase, GenericDatabaseStatus, IndexReport, MIGRATIONS_LOCK_ID,
+    },
+    migrations::{
+        self, Direction, Migration, MigrationTarget, MIGRATIONS_LOCK_REPOSITORY_NAME,
+        MIGRATIONS_REPOSITORY_NAME,
+    },
+    models::{
+        audit::AuditEntry, organizations::Organization, retention::PurgeEntry, users::User,
+        IndexKey,
+    },
+    store::{PostgreRepository, PostgreUnitOfWork, Repository, UnitOfWork},
+};
+
+/// Lists the indexes of the table bound first, but its primary key,
+/// with their access method and their columns in order.
+const EXISTING_INDEXES: &str = "
+    SELECT index_class.relname AS name, pg_index.indisunique AS is_unique,
+        pg_am.amname AS method,
+        array_agg(pg_attribute.attname::TEXT ORDER BY keys.position) AS columns
+    FROM pg_index
+    JOIN pg_class table_class ON table_class.oid = pg_index.indrelid
+    JOIN pg_class index_class ON index_class.oid = pg_index.indexrelid
+    JOIN pg_am ON pg_am.oid = index_class.relam
+    CROSS JOIN LATERAL unnest(pg_index.indkey::INT2[]) WITH ORDINALITY AS keys(attnum, position)
+    JOIN pg_attribute ON pg_attribute.attrelid = table_class.oid
+        AND pg_attribute.attnum = keys.attnum
+    WHERE table_class.relname = $1 AND pg_table_is_visible(table_class.oid)
+        AND NOT pg_index.indisprimary
+    GROUP BY index_class.relname, pg_index.indisunique, pg_am.amname";
+
+/// The maximum number of connections kept open in the pool.
+const MAX_CONNECTIONS: u32 = 5;
+
+#[derive(Debug)]
+pub struct PostgreDatabase {
+    pub status: GenericDatabaseStatus,
+    pub pool: Option<PgPool>,
+}
+
+impl PostgreDatabase {
+    fn pool(&self) -> anyhow::Result<&PgPool> {
+        match &self.pool {
+            Some(pool) => Ok(pool),
+            None => bail!("PostgreDatabase unable to get pool"),
+        }
+    }
+
+    async fn create_migrations_tables(pool: &PgPool) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {MIGRATIONS_REPOSITORY_NAME} (
+                version BIGINT PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at BIGINT NOT NULL
+            )"
+        ))
+        .execute(pool)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {MIGRATIONS_LOCK_REPOSITORY_NAME} (
+                id INTEGER PRIMARY KEY,
+                owner TEXT NOT NULL,
+                locked_at BIGINT NOT NULL
+            )"
+        ))
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl GenericDatabase for PostgreDatabase {
+    fn new() -> Self {
+        PostgreDatabase {
+            status: GenericDatabaseStatus {
+                kind: DriverKind::Postgre,
+                ..Default::default()
+            },
+            pool: None,
+        }
+    }
+
+    async fn connect(&mut self, uri: &str) -> anyhow::Result<()> {
+        log::info!("Connecting to PostgreSQL");
+        let pool = PgPoolOptions::new()
+            .max_connections(MAX_CONNECTIONS)
+            .connect(uri)
+            .await?;
+        Self::create_migrations_tables(&pool).await?;
+        self.pool = Some(pool);
+        self.status.health.mark_up();
+        Ok(())
+    }
+
+    fn status(&self) -> &GenericDatabaseStatus {
+        &self.status
+    }
+
+    fn status_mut(&mut self) -> &mut GenericDatabaseStatus {
+        &mut self.status
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        sqlx::query("SELECT 1").execute(self.pool()?).await?;
+        Ok(())
+    }
+
+    async fn reconcile_indexes(&self) -> anyhow::Result<IndexReport> {
+        let pool = self.pool()?;
+        let mut report = IndexReport::default();
+        for (table, declared) in indexes::registry() {
+            let rows = sqlx::query(EXISTING_INDEXES)
+                .bind(table)
+                .fetch_all(pool)
+                .await?;
+            let existing = rows
+                .iter()
+                .map(|row| {
+                    let key = match row.try_get::<String, _>("method")?.as_str() {
+                        "gin" => IndexKey::Text,
+                        _ => IndexKey::Ascending,
+                    };
+                    let columns: Vec<String> = row.try_get("columns")?;
+                    let keys = columns.into_iter().map(|column| (column, key)).collect();
+                    let shape = IndexShape::new(keys, row.try_get("is_unique")?, None);
+                    Ok((row.try_get("name")?, shape))
+                })
+                .collect::<Result<Vec<_>, sqlx::Error>>()?;
+            let declared = declared
+                .iter()
+                .filter_map(|index| Some((index, IndexShape::sql(index, true)?)))
+                .collect();
+            for (index, shape) in indexes::plan(table, declared, &existing, &mut report) {
+                sqlx::query(&indexes::create_sql_statement(table, index.name, &shape))
+                    .execute(pool)
+                    .await
+                    .with_context(|| format!("Creating index {}", index.name))?;
+                report.created.push(format!("{table}.{}", index.name));
+            }
+        }
+        Ok(report)
+    }
+
+    fn users(&self) -> anyhow::Result<Arc<dyn Repository<User>>> {
+        Ok(Arc::new(PostgreRepository::new(self.pool()?)))
+    }
+
+    fn audit_log(&self) -> anyhow::Result<Arc<dyn Repository<AuditEntry>>> {
+        Ok(Arc::new(PostgreRepository::new(self.pool()?)))
+    }
+
+    fn organizations(&self) -> anyhow::Result<Arc<dyn Repository<Organization>>> {
+        Ok(Arc::new(PostgreRepository::new(self.pool()?)))
+    }
+
+    fn purge_log(&self) -> anyhow::Result<Arc<dyn Repository<PurgeEntry>>> {
+        Ok(Arc::new(PostgreRepository::new(self.pool()?)))
+    }
+
+    async fn begin(&self) -> anyhow::Result<Box<dyn UnitOfWork>> {
+        Ok(Box::new(PostgreUnitOfWork::begin(self.pool()?).await?))
+    }
+
+    async fn applied_migrations(&self) -> anyhow::Result<Vec<i64>> {
+        Ok(
+            sqlx::query_scalar(&format!("SELECT version FROM {MIGRATIONS_REPOSITORY_NAME}"))
+                .fetch_all(self.pool()?)
+                .await?,
+        )
+    }
+
+    async fn lock_migrations(&self, owner: &str) -> anyhow::Result<bool> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let stale = now - migrations::LOCK_TIMEOUT.as_millis() as i64;
+        // Nothing is written when a lock that is not stale already exists.
+        let result = sqlx::query(&format!(
+            "INSERT INTO {MIGRATIONS_LOCK_REPOSITORY_NAME} (id, owner, locked_at) VALUES ($1, $2, $3)
+             ON CONFLICT (id) DO UPDATE SET owner = EXCLUDED.owner, locked_at = EXCLUDED.locked_at
+             WHERE {MIGRATIONS_LOCK_REPOSITORY_NAME}.locked_at < $4"
+        ))
+        .bind(MIGRATIONS_LOCK_ID)
+        .bind(owner)
+        .bind(now)
+        .bind(stale)
+        .execute(self.pool()?)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn unlock_migrations(&self, owner: &str) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "DELETE FROM {MIGRATIONS_LOCK_REPOSITORY_NAME} WHERE id = $1 AND owner = $2"
+        ))
+        .bind(MIGRATIONS_LOCK_ID)
+        .bind(owner)
+        .execute(self.pool()?)
+        .await?;
+        Ok(())
+    }
+
+    async fn run_migration(
+        &self,
+        migration: &dyn Migration,
+        direction: Direction,
+    ) -> anyhow::Result<()> {
+        // Schema changes are transactional in PostgreSQL, a failed migration leaves no trace.
+        let mut transaction = self.pool()?.begin().await?;
+        match direction {
+            Direction::Up => {
+                migration
+                    .up(MigrationTarget::Postgre(&mut transaction))
+                    .await?;
+                sqlx::query(&format!(
+                    "INSERT INTO {MIGRATIONS_REPOSITORY_NAME} (version, name, applied_at) VALUES ($1, $2, $3)"
+                ))
+                .bind(migration.version())
+                .bind(migration.name())
+                .bind(chrono::Utc::now().timestamp_millis())
+                .execute(&mut *transaction)
+                .await?;
+            }
+            Direction::Down => {
+                migration
+                    .down(MigrationTarget::Postgre(&mut transaction))
+                    .await?;
+                sqlx::query(&format!(
+                    "DELETE FROM {MIGRATIONS_REPOSITORY_NAME} WHERE version = $1"
+                ))
+                .bind(migration.version())
+                .execute(&mut *transaction)
+                .await?;
+            }
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use std::str::FromStr;
+
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions},
+    Row,
+};
+
+use crate::{
+    drivers::{
+        indexes::{self, IndexShape},
+        DriverKind, GenericDatabase, GenericDatabaseStatus, IndexReport, MIGRATIONS_LOCK_ID,
+    },
+    migrations::{
+        self, Direction, Migration, MigrationTarget, MIGRATIONS_LOCK_REPOSITORY_NAME,
+        MIGRATIONS_REPOSITORY_NAME,
+    },
+    models::{
+        audit::AuditEntry, organizations::Organization, retention::PurgeEntry, users::User,
+        IndexKey,
+    },
+    store::{Repository, SqliteRepository, SqliteUnitOfWork, UnitOfWork},
+};
+
+/// The maximum number of connections kept open in the pool.
+const MAX_CONNECTIONS: u32 = 5;
+
+#[derive(Debug)]
+pub struct SqliteDatabase {
+    pub status: GenericDatabaseStatus,
+    pub pool: Option<SqlitePool>,
+}
+
+impl SqliteDatabase {
+    fn pool(&self) -> anyhow::Result<&SqlitePool> {
+        match &self.pool {
+            Some(pool) => Ok(pool),
+            None => bail!("SqliteDatabase unable to get pool"),
+        }
+    }
+
+    async fn create_migrations_tables(pool: &SqlitePool) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {MIGRATIONS_REPOSITORY_NAME} (
+                version BIGINT PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at BIGINT NOT NULL
+            )"
+        ))
+        .execute(pool)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {MIGRATIONS_LOCK_REPOSITORY_NAME} (
+                id INTEGER PRIMARY KEY,
+                owner TEXT NOT NULL,
+                locked_at BIGINT NOT NULL
+            )"
+        ))
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl GenericDatabase for SqliteDatabase {
+    fn new() -> Self {
+        SqliteDatabase {
+            status: GenericDatabaseStatus {
+                kind: DriverKind::Sqlite,
+                ..Default::default()
+            },
+            pool: None,
+        }
+    }
+
+    async fn connect(&mut self, uri: &str) -> anyhow::Result<()> {
+        log::info!("Opening SQLite database {uri}");
+        let options = SqliteConnectOptions::from_str(uri)?.create_if_missing(true);
+        // Every connection to an in-memory database opens a new, empty, one.
+        let pool = if uri.contains(":memory:") || uri.contains("mode=memory") {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new().max_connections(MAX_CONNECTIONS)
+        }
+        .connect_with(options)
+        .await?;
+        Self::create_migrations_tables(&pool).await?;
+        self.pool = Some(pool);
+        self.status.health.mark_up();
+        Ok(())
+    }
+
+    fn status(&self) -> &GenericDatabaseStatus {
+        &self.status
+    }
+
+    fn status_mut(&mut self) -> &mut GenericDatabaseStatus {
+        &mut self.status
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        sqlx::query("SELECT 1").execute(self.pool()?).await?;
+        Ok(())
+    }
+
+    /// The text indexes are left out, the search scans the table.
+    async fn reconcile_indexes(&self) -> anyhow::Result<IndexReport> {
+        let pool = self.pool()?;
+        let mut report = IndexReport::default();
+        for (table, declared) in indexes::registry() {
+            let rows = sqlx::query(
+                "SELECT name, \"unique\" FROM pragma_index_list($1) WHERE origin != 'pk'",
+            )
+            .bind(table)
+            .fetch_all(pool)
+            .await?;
+            let mut existing = Vec::new();
+            for row in rows {
+                let name: String = row.try_get("name")?;
+                let columns: Vec<String> =
+                    sqlx::query_scalar("SELECT name FROM pragma_index_info($1) ORDER BY seqno")
+                        .bind(&name)
+                        .fetch_all(pool)
+                        .await?;
+                let keys = columns
+                    .into_iter()
+                    .map(|column| (column, IndexKey::Ascending))
+                    .collect();
+                existing.push((name, IndexShape::new(keys, row.try_get("unique")?, None)));
+            }
+            let declared = declared
+                .iter()
+                .filter_map(|index| Some((index, IndexShape::sql(index, false)?)))
+                .collect();
+            for (index, shape) in indexes::plan(table, declared, &existing, &mut report) {
+                sqlx::query(&indexes::create_sql_statement(table, index.name, &shape))
+                    .execute(pool)
+                    .await
+                    .with_context(|| format!("Creating index {}", index.name))?;
+                report.created.push(format!("{table}.{}", index.name));
+            }
+        }
+        Ok(report)
+    }
+
+    fn users(&self) -> anyhow::Result<Arc<dyn Repository<User>>> {
+        Ok(Arc::new(SqliteRepository::new(self.pool()?)))
+    }
+
+    fn audit_log(&self) -> anyhow::Result<Arc<dyn Repository<AuditEntry>>> {
+        Ok(Arc::new(SqliteRepository::new(self.pool()?)))
+    }
+
+    fn organizations(&self) -> anyhow::Result<Arc<dyn Repository<Organization>>> {
+        Ok(Arc::new(SqliteRepository::new(self.pool()?)))
+    }
+
+    fn purge_log(&self) -> anyhow::Result<Arc<dyn Repository<PurgeEntry>>> {
+        Ok(Arc::new(SqliteRepository::new(self.pool()?)))
+    }
+
+    async fn begin(&self) -> anyhow::Result<Box<dyn UnitOfWork>> {
+        Ok(Box::new(SqliteUnitOfWork::begin(self.pool()?).await?))
+    }
+
+    async fn applied_migrations(&self) -> anyhow::Result<Vec<i64>> {
+        Ok(
+            sqlx::query_scalar(&format!("SELECT version FROM {MIGRATIONS_REPOSITORY_NAME}"))
+                .fetch_all(self.pool()?)
+                .await?,
+        )
+    }
+
+    async fn lock_migrations(&self, owner: &str) -> anyhow::Result<bool> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let stale = now - migrations::LOCK_TIMEOUT.as_millis() as i64;
+        // Nothing is written when a lock that is not stale already exists.
+        let result = sqlx::query(&format!(
+            "INSERT INTO {MIGRATIONS_LOCK_REPOSITORY_NAME} (id, owner, locked_at) VALUES ($1, $2, $3)
+             ON CONFLICT (id) DO UPDATE SET owner = EXCLUDED.owner, locked_at = EXCLUDED.locked_at
+             WHERE {MIGRATIONS_LOCK_REPOSITORY_NAME}.locked_at < $4"
+        ))
+        .bind(MIGRATIONS_LOCK_ID)
+        .bind(owner)
+        .bind(now)
+        .bind(stale)
+        .execute(self.pool()?)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn unlock_migrations(&self, owner: &str) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "DELETE FROM {MIGRATIONS_LOCK_REPOSITORY_NAME} WHERE id = $1 AND owner = $2"
+        ))
+        .bind(MIGRATIONS_LOCK_ID)
+        .bind(owner)
+        .execute(self.pool()?)
+        .await?;
+        Ok(())
+    }
+
+    async fn run_migration(
+        &self,
+        migration: &dyn Migration,
+        direction: Direction,
+    ) -> anyhow::Result<()> {
+        // Schema changes are transactional in SQLite, a failed migration leaves no trace.
+        let mut transaction = self.pool()?.begin().await?;
+        match direction {
+            Direction::Up => {
+                migration
+                    .up(MigrationTarget::Sqlite(&mut transaction))
+                    .await?;
+                sqlx::query(&format!(
+                    "INSERT INTO {MIGRATIONS_REPOSITORY_NAME} (version, name, applied_at) VALUES ($1, $2, $3)"
+                ))
+                .bind(migration.version())
+                .bind(migration.name())
+                .bind(chrono::Utc::now().timestamp_millis())
+                .execute(&mut *transaction)
+                .await?;
+            }
+            Direction::Down => {
+                migration
+                    .down(MigrationTarget::Sqlite(&mut transaction))
+                    .await?;
+                sqlx::query(&format!(
+                    "DELETE FROM {MIGRATIONS_REPOSITORY_NAME} WHERE version = $1"
+                ))
+                .bind(migration.version())
+                .execute(&mut *transaction)
+                .await?;
+            }
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+//! Validation of the documents by MongoDB, with a `$jsonSchema` generated from the fields declared
+//! by the models, see [`crate::models::Model::FIELDS`]. The schema only checks the declared
+//! fields, other fields are left alone as serde ignores them.
+//! The documents already stored are not rewritten, the ones violating the schema are reported.
+
+use mongodb::bson::{doc, Bson, Document};
+
+use crate::models::{
+    audit::AuditEntry, organizations::Organization, retention::PurgeEntry, users::User, Field,
+    FieldKind, Model,
+};
+
+/// How many violating documents are reported by collection, they are all counted.
+pub const MAX_REPORTED_VIOLATIONS: i64 = 100;
+
+/// Every model validated by the database, with its collection.
+pub fn registry() -> Vec<(&'static str, &'static [Field])> {
+    vec![
+        (User::REPOSITORY_NAME, User::FIELDS),
+        (AuditEntry::REPOSITORY_NAME, AuditEntry::FIELDS),
+        (Organization::REPOSITORY_NAME, Organization::FIELDS),
+        (PurgeEntry::REPOSITORY_NAME, PurgeEntry::FIELDS),
+    ]
+}
+
+/// The BSON types a field can be stored as.
+fn bson_types(field: &Field) -> Vec<&'static str> {
+    let mut types = match field.kind {
+        FieldKind::ObjectId => vec!["objectId"],
+        FieldKind::Text => vec!["string"],
+        // Written as 64 bits by the API, the shell writes small numbers as 32 bits.
+        FieldKind::Integer => vec!["int", "long"],
+        FieldKind::Boolean => vec!["bool"],
+        FieldKind::DateTime => vec!["date"],
+    };
+    if field.nullable {
+        types.push("null");
+    }
+    types
+}
+
+/// The `$jsonSchema` of the documents: the fields that are not nullable are required, and every
+/// declared field must have its type.
+pub fn json_schema(fields: &[Field]) -> Document {
+    let required: Vec<_> = fields
+        .iter()
+        .filter(|field| !field.nullable)
+        .map(|field| field.name)
+        .collect();
+    let mut properties = Document::new();
+    for field in fields {
+        properties.insert(field.name, doc! { "bsonType": bson_types(field) });
+    }
+    doc! {
+        "bsonType": "object",
+        "required": required,
+        "properties": properties,
+    }
+}
+
+fn bson_type_name(value: &Bson) -> &'static str {
+    match value {
+        Bson::ObjectId(_) => "objectId",
+        Bson::String(_) => "string",
+        Bson::Int32(_) => "int",
+        Bson::Int64(_) => "long",
+        Bson::Double(_) => "double",
+        Bson::Boolean(_) => "bool",
+        Bson::DateTime(_) => "date",
+        Bson::Null => "null",
+        Bson::Array(_) => "array",
+        Bson::Document(_) => "object",
+        _ => "other",
+    }
+}
+
+/// Why the document violates the schema of the fields, empty if it does not.
+pub fn violations(fields: &[Field], document: &Document) -> Vec<String> {
+    let mut violations = Vec::new();
+    for field in fields {
+        match document.get(field.name) {
+            None if field.nullable => {}
+            None => violations.push(format!("{} is missing", field.name)),
+            Some(value) => {
+                let found = bson_type_name(value);
+                let expected = bson_types(field);
+                if !expected.contains(&found) {
+                    violations.push(format!(
+                        "{} is {found}, {} expected",
+                        field.name,
+                        expected.join(" or ")
+                    ));
+                }
+            }
+        }
+    }
+    violations
+}
+
+/// A stored document violating the schema of its collection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub collection: String,
+    /// The `_id` of the document, as extended JSON as it may not be an ObjectId.
+    pub id: String,
+    pub reasons: Vec<String>,
+}
+
+/// What [`crate::drivers::GenericDatabase::apply_validation`] applied, and the stored documents
+/// violating it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValidationReport {
+    pub validated: Vec<String>,
+    /// At most [`MAX_REPORTED_VIOLATIONS`] by collection.
+    pub violations: Vec<Violation>,
+    /// Every violating document, by collection.
+    pub violating: Vec<(String, u64)>,
+}
+
+impl ValidationReport {
+    pub fn log(&self) {
+        for collection in &self.validated {
+            log::info!("Validating the documents of {collection}");
+        }
+        for (collection, count) in &self.violating {
+            log::warn!("{count} documents of {collection} violate its schema");
+        }
+        for violation in &self.violations {
+            log::warn!(
+                "{}.{} violates its schema: {}",
+                violation.collection,
+                violation.id,
+                violation.reasons.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::DateTime;
+
+    #[test]
+    fn test_json_schema() {
+        let schema = json_schema(User::FIELDS);
+        let required = schema.get_array("required").unwrap();
+        assert!(required.contains(&Bson::from("email")));
+        assert!(!required.contains(&Bson::from("org_id")));
+        let properties = schema.get_document("properties").unwrap();
+        assert_eq!(properties.len(), User::FIELDS.len());
+        assert_eq!(
+            properties.get_document("version").unwrap(),
+            &doc! { "bsonType": ["int", "long"] }
+        );
+        assert_eq!(
+            properties.get_document("deleted_at").unwrap(),
+            &doc! { "bsonType": ["date", "null"] }
+        );
+    }
+
+    #[test]
+    fn test_violations() {
+        let user = User {
+            deleted_at: Some(DateTime::now()),
+            ..User::fixture("jane@example.com")
+        };
+        let mut document = mongodb::bson::to_document(&user).unwrap();
+        assert!(violations(User::FIELDS, &document).is_empty());
+
+        document.remove("email");
+        document.insert("version", "1");
+        assert_eq!(
+            violations(User::FIELDS, &document),
+            [
+                "email is missing",
+                "version is string, int or long expected"
+            ]
+        );
+    }
+}
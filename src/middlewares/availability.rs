@@ -0,0 +1,66 @@
+use crate::{controllers::error::Error, ProgramAppState};
+
+use std::rc::Rc;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    web,
+};
+use futures::{
+    future::{ready, LocalBoxFuture, Ready},
+    FutureExt,
+};
+
+/// Answers 503 without calling the handler while the database is down,
+/// instead of letting the request wait for the driver timeouts.
+pub struct DatabaseAvailableMiddlewareFactory;
+
+impl<S, B> Transform<S, ServiceRequest> for DatabaseAvailableMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+
+    type Error = actix_web::Error;
+
+    type Transform = DatabaseAvailableMiddleware<S>;
+
+    type InitError = ();
+
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(DatabaseAvailableMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct DatabaseAvailableMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for DatabaseAvailableMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = Rc::clone(&self.service);
+
+        async move {
+            if let Some(app_state) = req.app_data::<web::Data<ProgramAppState>>() {
+                if !app_state.database.status().health.is_connected() {
+                    return Err(Error::Unavailable.into());
+                }
+            }
+            srv.call(req).await
+        }
+        .boxed_local()
+    }
+}
@@ -1 +1,2 @@
 pub mod authorization;
+pub mod availability;
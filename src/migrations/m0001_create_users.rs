@@ -0,0 +1,65 @@
+use async_trait::async_trait;
+
+use crate::{
+    migrations::{Migration, MigrationTarget},
+    models::users::REPOSITORY_NAME,
+};
+
+pub struct CreateUsers;
+
+#[async_trait]
+impl Migration for CreateUsers {
+    fn version(&self) -> i64 {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "create_users"
+    }
+
+    async fn up(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                let existing = database.list_collection_names().await?;
+                if !existing.iter().any(|name| name == REPOSITORY_NAME) {
+                    database.create_collection(REPOSITORY_NAME).await?;
+                }
+            }
+            // Ids are stored as their ObjectId hex representation.
+            // The unique "email" column is the "users_email" index of `User::INDEXES`.
+            mut target => {
+                target
+                    .execute_sql(&format!(
+                        "CREATE TABLE IF NOT EXISTS {REPOSITORY_NAME} (
+                            _id CHAR(24) PRIMARY KEY,
+                            first_name TEXT NOT NULL,
+                            last_name TEXT NOT NULL,
+                            role TEXT NOT NULL,
+                            org_id CHAR(24),
+                            email TEXT NOT NULL UNIQUE,
+                            password TEXT NOT NULL
+                        )"
+                    ))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                database
+                    .collection::<mongodb::bson::Document>(REPOSITORY_NAME)
+                    .drop()
+                    .await?
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!("DROP TABLE IF EXISTS {REPOSITORY_NAME}"))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+use mongodb::bson::{doc, Document};
+
+use crate::{
+    migrations::{Migration, MigrationTarget},
+    models::users::REPOSITORY_NAME,
+};
+
+pub struct SoftDeleteUsers;
+
+#[async_trait]
+impl Migration for SoftDeleteUsers {
+    fn version(&self) -> i64 {
+        2
+    }
+
+    fn name(&self) -> &'static str {
+        "soft_delete_users"
+    }
+
+    async fn up(&self, mut target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        // Missing fields are read as None, Mongo documents are left untouched.
+        // One column per statement, SQLite cannot add several at once.
+        for statement in [
+            format!("ALTER TABLE {REPOSITORY_NAME} ADD COLUMN deleted_at BIGINT"),
+            format!("ALTER TABLE {REPOSITORY_NAME} ADD COLUMN deleted_by CHAR(24)"),
+        ] {
+            target.execute_sql(&statement).await?;
+        }
+        Ok(())
+    }
+
+    /// Soft-deleted users are purged first, they would be visible again otherwise.
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .delete_many(doc! { "deleted_at": { "$ne": null } })
+                    .await?;
+            }
+            mut target => {
+                for statement in [
+                    format!("DELETE FROM {REPOSITORY_NAME} WHERE deleted_at IS NOT NULL"),
+                    format!("ALTER TABLE {REPOSITORY_NAME} DROP COLUMN deleted_by"),
+                    format!("ALTER TABLE {REPOSITORY_NAME} DROP COLUMN deleted_at"),
+                ] {
+                    target.execute_sql(&statement).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
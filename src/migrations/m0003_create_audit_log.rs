@@ -0,0 +1,79 @@
+use async_trait::async_trait;
+use mongodb::bson::{doc, Document};
+use mongodb::IndexModel;
+
+use crate::{
+    migrations::{Migration, MigrationTarget},
+    models::audit::REPOSITORY_NAME,
+};
+
+pub struct CreateAuditLog;
+
+#[async_trait]
+impl Migration for CreateAuditLog {
+    fn version(&self) -> i64 {
+        3
+    }
+
+    fn name(&self) -> &'static str {
+        "create_audit_log"
+    }
+
+    async fn up(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                let existing = database.list_collection_names().await?;
+                if !existing.iter().any(|name| name == REPOSITORY_NAME) {
+                    database.create_collection(REPOSITORY_NAME).await?;
+                }
+                // The audit log is read newest first.
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .create_index(
+                        IndexModel::builder()
+                            .keys(doc! { "created_at": -1 })
+                            .build(),
+                    )
+                    .await?;
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!(
+                        "CREATE TABLE IF NOT EXISTS {REPOSITORY_NAME} (
+                            _id CHAR(24) PRIMARY KEY,
+                            actor_id CHAR(24),
+                            action TEXT NOT NULL,
+                            target_id CHAR(24) NOT NULL,
+                            diff TEXT NOT NULL,
+                            created_at BIGINT NOT NULL
+                        )"
+                    ))
+                    .await?;
+                target
+                    .execute_sql(&format!(
+                        "CREATE INDEX IF NOT EXISTS {REPOSITORY_NAME}_created_at \
+                         ON {REPOSITORY_NAME} (created_at)"
+                    ))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .drop()
+                    .await?
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!("DROP TABLE IF EXISTS {REPOSITORY_NAME}"))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use async_trait::async_trait;
+use mongodb::bson::{doc, Document};
+
+use crate::{
+    migrations::{Migration, MigrationTarget},
+    models::users::REPOSITORY_NAME,
+};
+
+pub struct AddUsersVersion;
+
+#[async_trait]
+impl Migration for AddUsersVersion {
+    fn version(&self) -> i64 {
+        4
+    }
+
+    fn name(&self) -> &'static str {
+        "add_users_version"
+    }
+
+    async fn up(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            // Updates match on the version, it must be stored even though it is read as 0.
+            MigrationTarget::Mongo(database) => {
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .update_many(
+                        doc! { "version": { "$exists": false } },
+                        doc! { "$set": { "version": 0_i64 } },
+                    )
+                    .await?;
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!(
+                        "ALTER TABLE {REPOSITORY_NAME} ADD COLUMN version BIGINT NOT NULL DEFAULT 0"
+                    ))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .update_many(doc! {}, doc! { "$unset": { "version": "" } })
+                    .await?;
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!(
+                        "ALTER TABLE {REPOSITORY_NAME} DROP COLUMN version"
+                    ))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+
+use crate::{
+    migrations::{Migration, MigrationTarget},
+    models::users::REPOSITORY_NAME,
+    store::sql::SEARCH_VECTOR_COLUMN,
+};
+
+/// Full-text search on PostgreSQL, MongoDB uses the "users_search" text index
+/// and the other backends scan the users.
+pub struct AddUsersSearchVector;
+
+#[async_trait]
+impl Migration for AddUsersSearchVector {
+    fn version(&self) -> i64 {
+        5
+    }
+
+    fn name(&self) -> &'static str {
+        "add_users_search_vector"
+    }
+
+    async fn up(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        if let MigrationTarget::Postgre(connection) = target {
+            // The "simple" configuration does not stem, names are not English words.
+            sqlx::query(&format!(
+                "ALTER TABLE {REPOSITORY_NAME} ADD COLUMN {SEARCH_VECTOR_COLUMN} tsvector \
+                 GENERATED ALWAYS AS \
+                 (to_tsvector('simple', first_name || ' ' || last_name || ' ' || email)) STORED"
+            ))
+            .execute(&mut *connection)
+            .await?;
+            sqlx::query(&format!(
+                "CREATE INDEX IF NOT EXISTS {REPOSITORY_NAME}_{SEARCH_VECTOR_COLUMN} \
+                 ON {REPOSITORY_NAME} USING GIN ({SEARCH_VECTOR_COLUMN})"
+            ))
+            .execute(&mut *connection)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        if let MigrationTarget::Postgre(connection) = target {
+            // Dropping the column drops its index.
+            sqlx::query(&format!(
+                "ALTER TABLE {REPOSITORY_NAME} DROP COLUMN {SEARCH_VECTOR_COLUMN}"
+            ))
+            .execute(&mut *connection)
+            .await?;
+        }
+        Ok(())
+    }
+}
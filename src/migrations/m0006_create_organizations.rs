@@ -0,0 +1,61 @@
+use async_trait::async_trait;
+use mongodb::bson::Document;
+
+use crate::{
+    migrations::{Migration, MigrationTarget},
+    models::organizations::REPOSITORY_NAME,
+};
+
+pub struct CreateOrganizations;
+
+#[async_trait]
+impl Migration for CreateOrganizations {
+    fn version(&self) -> i64 {
+        6
+    }
+
+    fn name(&self) -> &'static str {
+        "create_organizations"
+    }
+
+    // The unique name index of MongoDB is declared by the model.
+    async fn up(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                let existing = database.list_collection_names().await?;
+                if !existing.iter().any(|name| name == REPOSITORY_NAME) {
+                    database.create_collection(REPOSITORY_NAME).await?;
+                }
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!(
+                        "CREATE TABLE IF NOT EXISTS {REPOSITORY_NAME} (
+                            _id CHAR(24) PRIMARY KEY,
+                            name TEXT NOT NULL UNIQUE,
+                            created_at BIGINT NOT NULL
+                        )"
+                    ))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .drop()
+                    .await?
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!("DROP TABLE IF EXISTS {REPOSITORY_NAME}"))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
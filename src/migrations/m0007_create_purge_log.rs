@@ -0,0 +1,82 @@
+use async_trait::async_trait;
+use mongodb::bson::{doc, Document};
+use mongodb::IndexModel;
+
+use crate::{
+    migrations::{Migration, MigrationTarget},
+    models::retention::REPOSITORY_NAME,
+};
+
+pub struct CreatePurgeLog;
+
+#[async_trait]
+impl Migration for CreatePurgeLog {
+    fn version(&self) -> i64 {
+        7
+    }
+
+    fn name(&self) -> &'static str {
+        "create_purge_log"
+    }
+
+    async fn up(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                let existing = database.list_collection_names().await?;
+                if !existing.iter().any(|name| name == REPOSITORY_NAME) {
+                    database.create_collection(REPOSITORY_NAME).await?;
+                }
+                // The purge log is read newest first.
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .create_index(
+                        IndexModel::builder()
+                            .keys(doc! { "created_at": -1 })
+                            .build(),
+                    )
+                    .await?;
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!(
+                        "CREATE TABLE IF NOT EXISTS {REPOSITORY_NAME} (
+                            _id CHAR(24) PRIMARY KEY,
+                            collection TEXT NOT NULL,
+                            rule TEXT NOT NULL,
+                            retention_days BIGINT NOT NULL,
+                            cutoff BIGINT NOT NULL,
+                            purged_ids TEXT NOT NULL,
+                            purged BIGINT NOT NULL,
+                            dry_run BOOLEAN NOT NULL,
+                            created_at BIGINT NOT NULL
+                        )"
+                    ))
+                    .await?;
+                target
+                    .execute_sql(&format!(
+                        "CREATE INDEX IF NOT EXISTS {REPOSITORY_NAME}_created_at \
+                         ON {REPOSITORY_NAME} (created_at)"
+                    ))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .drop()
+                    .await?
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!("DROP TABLE IF EXISTS {REPOSITORY_NAME}"))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
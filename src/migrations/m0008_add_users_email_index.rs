@@ -0,0 +1,60 @@
+use async_trait::async_trait;
+use mongodb::bson::{doc, Document};
+
+use crate::{
+    migrations::{Migration, MigrationTarget},
+    models::users::REPOSITORY_NAME,
+};
+
+pub struct AddUsersEmailIndex;
+
+#[async_trait]
+impl Migration for AddUsersEmailIndex {
+    fn version(&self) -> i64 {
+        8
+    }
+
+    fn name(&self) -> &'static str {
+        "add_users_email_index"
+    }
+
+    // The sparse unique index of MongoDB is declared by the model, the field is only set once
+    // the emails are encrypted.
+    async fn up(&self, mut target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        target
+            .execute_sql(&format!(
+                "ALTER TABLE {REPOSITORY_NAME} ADD COLUMN email_index TEXT"
+            ))
+            .await?;
+        target
+            .execute_sql(&format!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS {REPOSITORY_NAME}_email_index \
+                 ON {REPOSITORY_NAME} (email_index)"
+            ))
+            .await
+    }
+
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .update_many(doc! {}, doc! { "$unset": { "email_index": "" } })
+                    .await?;
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!(
+                        "DROP INDEX IF EXISTS {REPOSITORY_NAME}_email_index"
+                    ))
+                    .await?;
+                target
+                    .execute_sql(&format!(
+                        "ALTER TABLE {REPOSITORY_NAME} DROP COLUMN email_index"
+                    ))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use async_trait::async_trait;
+use mongodb::bson::{doc, Document};
+
+use crate::{
+    migrations::{Migration, MigrationTarget},
+    models::users::REPOSITORY_NAME,
+};
+
+pub struct AddUsersLastLogin;
+
+#[async_trait]
+impl Migration for AddUsersLastLogin {
+    fn version(&self) -> i64 {
+        9
+    }
+
+    fn name(&self) -> &'static str {
+        "add_users_last_login"
+    }
+
+    // Missing fields are read as None, Mongo documents are left untouched.
+    async fn up(&self, mut target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        target
+            .execute_sql(&format!(
+                "ALTER TABLE {REPOSITORY_NAME} ADD COLUMN last_login_at BIGINT"
+            ))
+            .await
+    }
+
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()> {
+        match target {
+            MigrationTarget::Mongo(database) => {
+                database
+                    .collection::<Document>(REPOSITORY_NAME)
+                    .update_many(doc! {}, doc! { "$unset": { "last_login_at": "" } })
+                    .await?;
+            }
+            mut target => {
+                target
+                    .execute_sql(&format!(
+                        "ALTER TABLE {REPOSITORY_NAME} DROP COLUMN last_login_at"
+                    ))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
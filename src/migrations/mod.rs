@@ -0,0 +1,224 @@
+//! Ordered, named schema and data migrations, registered in [`all`].
+//! Applied versions are tracked by each database in a "migrations" collection (or table),
+//! and a lock document (or row) prevents two instances from migrating at the same time.
+
+mod m0001_create_users;
+mod m0002_soft_delete_users;
+mod m0003_create_audit_log;
+mod m0004_add_users_version;
+mod m0005_add_users_search_vector;
+mod m0006_create_organizations;
+mod m0007_create_purge_log;
+mod m0008_add_users_email_index;
+mod m0009_add_users_last_login;
+
+use std::time::Duration;
+
+use anyhow::bail;
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+
+use crate::drivers::{DriverKind, GenericDatabase};
+
+/// The collection (or table) holding the applied migrations.
+pub const MIGRATIONS_REPOSITORY_NAME: &str = "migrations";
+/// The collection (or table) holding the migrations lock.
+pub const MIGRATIONS_LOCK_REPOSITORY_NAME: &str = "migrations_lock";
+/// A lock older than this is considered abandoned by a crashed instance.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// The connection a migration runs against.
+pub enum MigrationTarget<'a> {
+    Mongo(&'a mongodb::Database),
+    Postgre(&'a mut sqlx::PgConnection),
+    Sqlite(&'a mut sqlx::SqliteConnection),
+    /// The in-memory database has no schema, migrations usually have nothing to do.
+    Memory,
+}
+
+impl MigrationTarget<'_> {
+    /// Runs a statement understood by every SQL backend, does nothing on the other ones.
+    pub async fn execute_sql(&mut self, statement: &str) -> anyhow::Result<()> {
+        match self {
+            MigrationTarget::Postgre(connection) => {
+                sqlx::query(statement).execute(&mut **connection).await?;
+            }
+            MigrationTarget::Sqlite(connection) => {
+                sqlx::query(statement).execute(&mut **connection).await?;
+            }
+            MigrationTarget::Mongo(_) | MigrationTarget::Memory => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+#[async_trait]
+pub trait Migration: Send + Sync {
+    /// Migrations are applied by increasing version, and rolled back in reverse.
+    fn version(&self) -> i64;
+    fn name(&self) -> &'static str;
+    async fn up(&self, target: MigrationTarget<'_>) -> anyhow::Result<()>;
+    async fn down(&self, target: MigrationTarget<'_>) -> anyhow::Result<()>;
+}
+
+/// Every known migration, sorted by version.
+pub fn all() -> Vec<Box<dyn Migration>> {
+    let mut migrations: Vec<Box<dyn Migration>> = vec![
+        Box::new(m0001_create_users::CreateUsers),
+        Box::new(m0002_soft_delete_users::SoftDeleteUsers),
+        Box::new(m0003_create_audit_log::CreateAuditLog),
+        Box::new(m0004_add_users_version::AddUsersVersion),
+        Box::new(m0005_add_users_search_vector::AddUsersSearchVector),
+        Box::new(m0006_create_organizations::CreateOrganizations),
+        Box::new(m0007_create_purge_log::CreatePurgeLog),
+        Box::new(m0008_add_users_email_index::AddUsersEmailIndex),
+        Box::new(m0009_add_users_last_login::AddUsersLastLogin),
+    ];
+    migrations.sort_by_key(|migration| migration.version());
+    migrations
+}
+
+/// Returns the registered migrations not yet applied to the database.
+pub async fn pending<D: GenericDatabase + ?Sized>(
+    db: &D,
+) -> anyhow::Result<Vec<Box<dyn Migration>>> {
+    let applied = db.applied_migrations().await?;
+    Ok(all()
+        .into_iter()
+        .filter(|migration| !applied.contains(&migration.version()))
+        .collect())
+}
+
+/// Runs `operation` while holding the migrations lock.
+async fn with_lock<D, F>(db: &mut D, operation: F) -> anyhow::Result<()>
+where
+    D: GenericDatabase + ?Sized,
+    F: AsyncFnOnce(&mut D) -> anyhow::Result<()>,
+{
+    let owner = ObjectId::new().to_hex();
+    if !db.lock_migrations(&owner).await? {
+        bail!("Migrations are locked by another instance");
+    }
+    let result = operation(db).await;
+    if let Err(error) = db.unlock_migrations(&owner).await {
+        log::error!("Failed to release migrations lock: {error:?}");
+    }
+    result
+}
+
+/// Applies every pending migration in order.
+pub async fn migrate<D: GenericDatabase + ?Sized>(db: &mut D) -> anyhow::Result<()> {
+    with_lock(db, async |db: &mut D| {
+        // Pending migrations are read again once locked, another instance may have applied some.
+        for migration in pending(db).await? {
+            log::info!(
+                "Applying migration {} {}",
+                migration.version(),
+                migration.name()
+            );
+            db.run_migration(migration.as_ref(), Direction::Up).await?;
+            db.status_mut().last_migrations_performed = migration.name().to_string();
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Rolls back, newest first, every applied migration with a version above `version`.
+pub async fn rollback_to<D: GenericDatabase + ?Sized>(
+    db: &mut D,
+    version: i64,
+) -> anyhow::Result<()> {
+    with_lock(db, async |db: &mut D| {
+        let applied = db.applied_migrations().await?;
+        for migration in all().iter().rev() {
+            if migration.version() <= version || !applied.contains(&migration.version()) {
+                continue;
+            }
+            log::info!(
+                "Rolling back migration {} {}",
+                migration.version(),
+                migration.name()
+            );
+            db.run_migration(migration.as_ref(), Direction::Down)
+                .await?;
+            db.status_mut().last_migrations_performed = migration.name().to_string();
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Prepares the database before serving:
+/// - rolls back to `ROLLBACK_MIGRATIONS_TO` when set, the server must then not be started,
+/// - applies pending migrations when `APPLY_MIGRATIONS` is true, or when the database lives
+///   in memory as it always starts empty,
+/// - refuses to go on if migrations are still pending otherwise.
+///
+/// Returns whether the server can be started.
+pub async fn prepare<D: GenericDatabase + ?Sized>(db: &mut D) -> anyhow::Result<bool> {
+    if let Ok(version) = std::env::var("ROLLBACK_MIGRATIONS_TO") {
+        rollback_to(db, version.parse()?).await?;
+        log::info!("Migrations rolled back to version {version}");
+        return Ok(false);
+    }
+
+    let pending = pending(db).await?;
+    if pending.is_empty() {
+        return Ok(true);
+    }
+    let apply = db.status().kind == DriverKind::Memory
+        || std::env::var("APPLY_MIGRATIONS")
+            .map(|value| value == "true")
+            .unwrap_or(false);
+    if !apply {
+        let names: Vec<_> = pending
+            .iter()
+            .map(|migration| format!("{} {}", migration.version(), migration.name()))
+            .collect();
+        bail!(
+            "{} pending migrations ({}), set APPLY_MIGRATIONS=true to apply them",
+            names.len(),
+            names.join(", ")
+        );
+    }
+    migrate(db).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::MemoryDatabase;
+
+    #[actix_web::test]
+    async fn test_migrate_and_rollback() {
+        let mut db: MemoryDatabase = GenericDatabase::new();
+        assert_eq!(pending(&db).await.unwrap().len(), all().len());
+
+        migrate(&mut db).await.unwrap();
+        assert!(pending(&db).await.unwrap().is_empty());
+        assert_eq!(db.status.last_migrations_performed, "add_users_last_login");
+
+        rollback_to(&mut db, 0).await.unwrap();
+        assert_eq!(pending(&db).await.unwrap().len(), all().len());
+    }
+
+    #[actix_web::test]
+    async fn test_migrate_refuses_when_locked() {
+        let mut db: MemoryDatabase = GenericDatabase::new();
+        assert!(db.lock_migrations("other instance").await.unwrap());
+
+        assert!(migrate(&mut db).await.is_err());
+        assert_eq!(pending(&db).await.unwrap().len(), all().len());
+
+        db.unlock_migrations("other instance").await.unwrap();
+        migrate(&mut db).await.unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use mongodb::bson::{self, oid::ObjectId, Bson, DateTime, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Field, FieldKind, Index, IndexKey, Model};
+
+pub const REPOSITORY_NAME: &str = "audit_log";
+
+/// Fields whose values are never written to the audit log, only the fact they changed.
+const REDACTED_FIELDS: &[&str] = &["password"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    Restore,
+    Login,
+    LoginFailed,
+}
+
+/// A write, or a login attempt, recorded in the audit log.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub _id: ObjectId,
+    /// The authenticated user, None when the request was not authenticated.
+    pub actor_id: Option<ObjectId>,
+    pub action: AuditAction,
+    pub target_id: ObjectId,
+    /// The changed fields as a JSON object, `{"field": {"before": .., "after": ..}}`.
+    pub diff: String,
+    /// From [`crate::services::ntp::Ntp::current_time`].
+    pub created_at: DateTime,
+}
+
+impl Model for AuditEntry {
+    const REPOSITORY_NAME: &'static str = REPOSITORY_NAME;
+    const FIELDS: &'static [Field] = &[
+        Field::new("_id", FieldKind::ObjectId),
+        Field::nullable("actor_id", FieldKind::ObjectId),
+        Field::new("action", FieldKind::Text),
+        Field::new("target_id", FieldKind::ObjectId),
+        Field::new("diff", FieldKind::Text),
+        Field::new("created_at", FieldKind::DateTime),
+    ];
+    // The audit log is read newest first.
+    const INDEXES: &'static [Index] = &[Index::new(
+        "audit_log_created_at",
+        &[("created_at", IndexKey::Descending)],
+    )];
+
+    fn id(&self) -> ObjectId {
+        self._id
+    }
+}
+
+/// The audit entry as returned by the API, with a readable date.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AuditEntryResponse {
+    pub _id: ObjectId,
+    pub actor_id: Option<ObjectId>,
+    pub action: AuditAction,
+    pub target_id: ObjectId,
+    pub diff: String,
+    pub created_at: String,
+}
+
+impl AuditEntry {
+    pub fn to_response(&self) -> AuditEntryResponse {
+        AuditEntryResponse {
+            _id: self._id,
+            actor_id: self.actor_id,
+            action: self.action,
+            target_id: self.target_id,
+            diff: self.diff.clone(),
+            created_at: self
+                .created_at
+                .try_to_rfc3339_string()
+                .unwrap_or_else(|_| self.created_at.timestamp_millis().to_string()),
+        }
+    }
+}
+
+/// Compares two versions of a document, either of them missing for a creation or a deletion,
+/// and returns the changed fields as a JSON object, the values of the `redacted` fields hidden.
+pub fn diff<T: Serialize>(before: Option<&T>, after: Option<&T>, redacted: &[&str]) -> String {
+    let to_document = |item: Option<&T>| {
+        item.and_then(|item| bson::to_document(item).ok())
+            .unwrap_or_default()
+    };
+    let (before, after) = (to_document(before), to_document(after));
+
+    let mut changes = Document::new();
+    let fields = before
+        .keys()
+        .chain(after.keys().filter(|key| !before.contains_key(*key)));
+    for field in fields {
+        let (old, new) = (before.get(field), after.get(field));
+        if old == new {
+            continue;
+        }
+        let redacted = REDACTED_FIELDS
+            .iter()
+            .chain(redacted)
+            .any(|redacted| redacted == field);
+        let value = |value: Option<&Bson>| match value {
+            None => Bson::Null,
+            Some(_) if redacted => Bson::String("[redacted]".to_string()),
+            Some(value) => value.clone(),
+        };
+        let mut change = Document::new();
+        change.insert("before", value(old));
+        change.insert("after", value(new));
+        changes.insert(field.clone(), change);
+    }
+    Bson::Document(changes).into_relaxed_extjson().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::users::User;
+
+    #[test]
+    fn test_diff() {
+        let before = User {
+            _id: ObjectId::parse_str("65f0a1b2c3d4e5f607182930").unwrap(),
+            ..User::fixture("jane@example.com")
+        };
+        let mut after = before.clone();
+        after.first_name = "Janet".into();
+        after.password = "other hash".into();
+
+        assert_eq!(
+            diff(Some(&before), Some(&after), &[]),
+            r#"{"first_name":{"before":"Jane","after":"Janet"},"password":{"before":"[redacted]","after":"[redacted]"}}"#
+        );
+        assert_eq!(diff(Some(&before), Some(&before), &[]), "{}");
+        assert!(diff(None, Some(&after), &[])
+            .contains(r#""email":{"before":null,"after":"jane@example.com"}"#));
+        assert!(diff(None, Some(&after), &["email"])
+            .contains(r#""email":{"before":null,"after":"[redacted]"}"#));
+    }
+}
@@ -1 +1,114 @@
+pub mod audit;
+pub mod organizations;
+pub mod retention;
 pub mod users;
+
+use std::time::Duration;
+
+use mongodb::bson::oid::ObjectId;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// How a field is stored by the backends that need an explicit schema.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldKind {
+    /// Stored as the 24 characters hex representation.
+    ObjectId,
+    Text,
+    Integer,
+    Boolean,
+    /// Stored as milliseconds since the Unix epoch.
+    DateTime,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: FieldKind,
+    pub nullable: bool,
+}
+
+impl Field {
+    pub const fn new(name: &'static str, kind: FieldKind) -> Self {
+        Field {
+            name,
+            kind,
+            nullable: false,
+        }
+    }
+
+    pub const fn nullable(name: &'static str, kind: FieldKind) -> Self {
+        Field {
+            name,
+            kind,
+            nullable: true,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexKey {
+    Ascending,
+    Descending,
+    /// Full-text search, see [`Model::SEARCH_FIELDS`].
+    Text,
+}
+
+/// An index of a model, see [`Model::INDEXES`].
+#[derive(Clone, Copy, Debug)]
+pub struct Index {
+    /// Unique across the database, it names the index on every backend.
+    pub name: &'static str,
+    /// More than one key makes a compound index.
+    pub keys: &'static [(&'static str, IndexKey)],
+    pub unique: bool,
+    /// MongoDB deletes the documents this long after the date in the first key,
+    /// the other backends only get a regular index.
+    pub expire_after: Option<Duration>,
+    /// MongoDB leaves the documents without the field out of the index, the SQL unique indexes
+    /// already ignore NULL values.
+    pub sparse: bool,
+}
+
+#[allow(dead_code)]
+impl Index {
+    pub const fn new(name: &'static str, keys: &'static [(&'static str, IndexKey)]) -> Self {
+        Index {
+            name,
+            keys,
+            unique: false,
+            expire_after: None,
+            sparse: false,
+        }
+    }
+
+    pub const fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    pub const fn expire_after(mut self, delay: Duration) -> Self {
+        self.expire_after = Some(delay);
+        self
+    }
+
+    pub const fn sparse(mut self) -> Self {
+        self.sparse = true;
+        self
+    }
+}
+
+/// A document persisted through a [`crate::store::Repository`].
+pub trait Model: Serialize + DeserializeOwned + Clone + Send + Sync + Unpin + 'static {
+    /// The name of the collection (or table) holding the documents.
+    const REPOSITORY_NAME: &'static str;
+    /// Every serialized field, the first one being the `_id` primary key.
+    const FIELDS: &'static [Field];
+    /// The text fields searched by [`crate::store::Repository::search`].
+    const SEARCH_FIELDS: &'static [&'static str] = &[];
+    /// Created at startup by [`crate::drivers::GenericDatabase::reconcile_indexes`].
+    const INDEXES: &'static [Index] = &[];
+
+    fn id(&self) -> ObjectId;
+}
@@ -0,0 +1,51 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Field, FieldKind, Index, IndexKey, Model};
+
+pub const REPOSITORY_NAME: &str = "organizations";
+
+/// A group of users, who refer to it by [`crate::models::users::User::org_id`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Organization {
+    pub _id: ObjectId,
+    pub name: String,
+    /// From [`crate::services::ntp::Ntp::current_time`].
+    pub created_at: DateTime,
+}
+
+impl Model for Organization {
+    const REPOSITORY_NAME: &'static str = REPOSITORY_NAME;
+    const FIELDS: &'static [Field] = &[
+        Field::new("_id", FieldKind::ObjectId),
+        Field::new("name", FieldKind::Text),
+        Field::new("created_at", FieldKind::DateTime),
+    ];
+    const INDEXES: &'static [Index] =
+        &[Index::new("organizations_name", &[("name", IndexKey::Ascending)]).unique()];
+
+    fn id(&self) -> ObjectId {
+        self._id
+    }
+}
+
+/// The organization as returned by the API, with a readable date.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OrganizationResponse {
+    pub _id: ObjectId,
+    pub name: String,
+    pub created_at: String,
+}
+
+impl Organization {
+    pub fn to_response(&self) -> OrganizationResponse {
+        OrganizationResponse {
+            _id: self._id,
+            name: self.name.clone(),
+            created_at: self
+                .created_at
+                .try_to_rfc3339_string()
+                .unwrap_or_else(|_| self.created_at.timestamp_millis().to_string()),
+        }
+    }
+}
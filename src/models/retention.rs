@@ -0,0 +1,107 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Field, FieldKind, Index, IndexKey, Model};
+
+pub const REPOSITORY_NAME: &str = "purge_log";
+
+/// What a retention policy purges once its retention period is over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionRule {
+    /// Every document, by creation date.
+    Age,
+    /// The soft-deleted documents, by deletion date.
+    Deleted,
+    /// The accounts, by last login, which are soft-deleted and then purged as the deleted ones.
+    Inactive,
+}
+
+impl RetentionRule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetentionRule::Age => "age",
+            RetentionRule::Deleted => "deleted",
+            RetentionRule::Inactive => "inactive",
+        }
+    }
+}
+
+/// A policy enforced, or only simulated, on a collection, and what it purged.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PurgeEntry {
+    pub _id: ObjectId,
+    pub collection: String,
+    pub rule: RetentionRule,
+    pub retention_days: i64,
+    /// The documents older than this date were purged.
+    pub cutoff: DateTime,
+    /// The ids of the first purged documents as a JSON array, at most
+    /// [`crate::services::retention::MAX_LOGGED_IDS`], the documents themselves are gone.
+    pub purged_ids: String,
+    /// How many documents were purged, the ones missing from `purged_ids` included.
+    pub purged: i64,
+    /// Nothing was deleted, `purged_ids` are the documents that would have been.
+    pub dry_run: bool,
+    /// From [`crate::services::ntp::Ntp::current_time`].
+    pub created_at: DateTime,
+}
+
+impl Model for PurgeEntry {
+    const REPOSITORY_NAME: &'static str = REPOSITORY_NAME;
+    const FIELDS: &'static [Field] = &[
+        Field::new("_id", FieldKind::ObjectId),
+        Field::new("collection", FieldKind::Text),
+        Field::new("rule", FieldKind::Text),
+        Field::new("retention_days", FieldKind::Integer),
+        Field::new("cutoff", FieldKind::DateTime),
+        Field::new("purged_ids", FieldKind::Text),
+        Field::new("purged", FieldKind::Integer),
+        Field::new("dry_run", FieldKind::Boolean),
+        Field::new("created_at", FieldKind::DateTime),
+    ];
+    // The purge log is read newest first.
+    const INDEXES: &'static [Index] = &[Index::new(
+        "purge_log_created_at",
+        &[("created_at", IndexKey::Descending)],
+    )];
+
+    fn id(&self) -> ObjectId {
+        self._id
+    }
+}
+
+/// The purge log entry as returned by the API, with readable dates and ids.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PurgeEntryResponse {
+    pub _id: ObjectId,
+    pub collection: String,
+    pub rule: RetentionRule,
+    pub retention_days: i64,
+    pub cutoff: String,
+    pub purged_ids: Vec<String>,
+    pub purged: i64,
+    pub dry_run: bool,
+    pub created_at: String,
+}
+
+fn rfc3339(date: &DateTime) -> String {
+    date.try_to_rfc3339_string()
+        .unwrap_or_else(|_| date.timestamp_millis().to_string())
+}
+
+impl PurgeEntry {
+    pub fn to_response(&self) -> PurgeEntryResponse {
+        PurgeEntryResponse {
+            _id: self._id,
+            collection: self.collection.clone(),
+            rule: self.rule,
+            retention_days: self.retention_days,
+            cutoff: rfc3339(&self.cutoff),
+            purged_ids: serde_json::from_str(&self.purged_ids).unwrap_or_default(),
+            purged: self.purged,
+            dry_run: self.dry_run,
+            created_at: rfc3339(&self.created_at),
+        }
+    }
+}
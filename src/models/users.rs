@@ -1,13 +1,14 @@
+use crate::models::{Field, FieldKind, Index, IndexKey, Model};
 use argon2::Config;
 use json::JsonValue;
-use mongodb::{
-    bson::{doc, oid::ObjectId},
-    options::IndexOptions,
-    Client, IndexModel,
-};
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
 use serde::{Deserialize, Serialize};
 
 pub const REPOSITORY_NAME: &str = "users";
+/// Roles allowed to use the administration routes.
+pub const ADMIN_ROLES: &[&str] = &["admin", "god"];
+/// Roles reaching the users of every organization, the others only reach their own.
+pub const SUPER_ADMIN_ROLES: &[&str] = &["god"];
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct AuthReq {
@@ -31,8 +32,24 @@ pub struct User {
     pub role: String,
     pub org_id: Option<ObjectId>,
     pub email: String,
+    /// The blind index of the email when it is encrypted, see [`crate::store::encryption`].
+    /// It only exists at rest, users are read without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_index: Option<String>,
     pub password: String,
     //pub created: DateTime,
+    /// Set when the user is soft-deleted, it is then purged after a grace period.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_by: Option<ObjectId>,
+    /// Set by every login, the accounts inactive for too long are purged by the retention
+    /// policies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_login_at: Option<DateTime>,
+    /// Incremented by every update, see [`crate::store::UserStore::update`].
+    #[serde(default)]
+    pub version: i64,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -45,7 +62,61 @@ pub struct SanitizedUser {
     pub email: String,
 }
 
+impl Model for User {
+    const REPOSITORY_NAME: &'static str = REPOSITORY_NAME;
+    const FIELDS: &'static [Field] = &[
+        Field::new("_id", FieldKind::ObjectId),
+        Field::new("first_name", FieldKind::Text),
+        Field::new("last_name", FieldKind::Text),
+        Field::new("role", FieldKind::Text),
+        Field::nullable("org_id", FieldKind::ObjectId),
+        Field::new("email", FieldKind::Text),
+        Field::nullable("email_index", FieldKind::Text),
+        Field::new("password", FieldKind::Text),
+        Field::nullable("deleted_at", FieldKind::DateTime),
+        Field::nullable("deleted_by", FieldKind::ObjectId),
+        Field::nullable("last_login_at", FieldKind::DateTime),
+        Field::new("version", FieldKind::Integer),
+    ];
+    const SEARCH_FIELDS: &'static [&'static str] = &["first_name", "last_name", "email"];
+    const INDEXES: &'static [Index] = &[
+        Index::new("users_email", &[("email", IndexKey::Ascending)]).unique(),
+        // The emails stay unique once encrypted, as their encryption is not deterministic.
+        Index::new("users_email_index", &[("email_index", IndexKey::Ascending)])
+            .unique()
+            .sparse(),
+        Index::new(
+            "users_org_role",
+            &[
+                ("org_id", IndexKey::Ascending),
+                ("role", IndexKey::Ascending),
+            ],
+        ),
+        // On PostgreSQL, the index of the generated search vector column.
+        Index::new(
+            "users_search",
+            &[
+                ("first_name", IndexKey::Text),
+                ("last_name", IndexKey::Text),
+                ("email", IndexKey::Text),
+            ],
+        ),
+    ];
+
+    fn id(&self) -> ObjectId {
+        self._id
+    }
+}
+
 impl User {
+    pub fn is_admin(&self) -> bool {
+        ADMIN_ROLES.contains(&self.role.as_str())
+    }
+
+    pub fn is_super_admin(&self) -> bool {
+        SUPER_ADMIN_ROLES.contains(&self.role.as_str())
+    }
+
     pub fn sanitize(&self) -> SanitizedUser {
         SanitizedUser {
             _id: self._id,
@@ -65,50 +136,82 @@ impl User {
         let email = json["email"].as_str()?.to_string();
         let password = json["password"].as_str()?;
 
-        let mut org_object_id = ObjectId::new();
-        match json["org_id"].as_str() {
-            Some(org_id) => {
-                if !org_id.is_empty() {
-                    match mongodb::bson::oid::ObjectId::parse_str(org_id) {
-                        Ok(oid) => org_object_id = oid,
-                        Err(error) => log::error!("Org_id to Mongo Object ID fail: {error:?}"),
+        // Empty or null when the user belongs to no organization.
+        let org_id = match &json["org_id"] {
+            JsonValue::Null => None,
+            org_id => match org_id.as_str()? {
+                "" => None,
+                org_id => match ObjectId::parse_str(org_id) {
+                    Ok(org_id) => Some(org_id),
+                    Err(error) => {
+                        log::warn!("Invalid organization id {org_id} for {email}: {error}");
+                        return None;
                     }
-                }
-            }
-            None => {
-                log::warn!("No organization id for: {email}");
-                return None;
-            }
-        }
+                },
+            },
+        };
 
-        let salt = &std::env::var("SECRET_KEY").unwrap_or_else(|_| "thisisasupersecretkey".into());
-        let config = Config::default();
-        let hashed_password =
-            argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &config).unwrap();
+        let hashed_password = hash_password(password);
 
         Some(User {
             _id: ObjectId::new(),
             first_name,
             last_name,
             role,
-            org_id: Some(org_object_id),
+            org_id,
             email,
+            email_index: None,
             password: hashed_password,
+            deleted_at: None,
+            deleted_by: None,
+            last_login_at: None,
+            version: 0,
         })
     }
+
+    /// Returns a copy with the fields of the JSON object replaced, the password being hashed.
+    /// Returns None if a field is unknown, read-only or has an invalid value.
+    pub fn patched(&self, json: &JsonValue) -> Option<User> {
+        let mut user = self.clone();
+        for (key, value) in json.entries() {
+            match key {
+                "first_name" => user.first_name = value.as_str()?.to_string(),
+                "last_name" => user.last_name = value.as_str()?.to_string(),
+                "role" => user.role = value.as_str()?.to_string(),
+                "email" => user.email = value.as_str()?.to_string(),
+                "org_id" if value.is_null() => user.org_id = None,
+                "org_id" => user.org_id = Some(ObjectId::parse_str(value.as_str()?).ok()?),
+                "password" => user.password = hash_password(value.as_str()?),
+                _ => return None,
+            }
+        }
+        Some(user)
+    }
+}
+
+#[cfg(test)]
+impl User {
+    /// Jane Doe, a user without organization whose password hash is "hash".
+    pub fn fixture(email: &str) -> User {
+        User {
+            _id: ObjectId::new(),
+            first_name: "Jane".into(),
+            last_name: "Doe".into(),
+            role: "user".into(),
+            org_id: None,
+            email: email.into(),
+            email_index: None,
+            password: "hash".into(),
+            deleted_at: None,
+            deleted_by: None,
+            last_login_at: None,
+            version: 0,
+        }
+    }
 }
 
-/// Creates an index on the "email" field to force the values to be unique.
-pub async fn create_email_index(client: &Client, db_name: &str) {
-    let options = IndexOptions::builder().unique(true).build();
-    let model = IndexModel::builder()
-        .keys(doc! { "email": 1 })
-        .options(options)
-        .build();
-    client
-        .database(db_name)
-        .collection::<User>(REPOSITORY_NAME)
-        .create_index(model)
-        .await
-        .expect("creating an index should succeed");
+pub fn hash_password(password: &str) -> String {
+    let salt = &std::env::var("SECRET_KEY").unwrap_or_else(|_| "thisisasupersecretkey".into());
+    let config = Config::default();
+    argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &config).unwrap()
 }
@@ -1,2 +1,5 @@
 pub mod emails;
 pub mod ntp;
+pub mod retention;
+pub mod seed;
+pub mod transfer;
@@ -0,0 +1,293 @@
+//! Retention policies, enforced in the background and on demand: what each one purged, or would
+//! have purged in a dry run, is recorded in the purge log.
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::{
+    rt::{self, task::JoinHandle, time::interval},
+    web,
+};
+use futures::{pin_mut, prelude::*, select};
+use lazy_static::lazy_static;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use stoppable_thread::SimpleAtomicBool;
+
+use crate::{
+    models::{
+        audit,
+        retention::{PurgeEntry, RetentionRule},
+        users,
+    },
+    store::StoreError,
+    ProgramAppState,
+};
+
+const STOPPING_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// Whether the background purges only report what they would purge.
+    pub static ref RETENTION_DRY_RUN: bool = std::env::var("RETENTION_DRY_RUN")
+        .ok()
+        .and_then(|dry_run| dry_run.parse().ok())
+        .unwrap_or(false);
+    /// How often the policies are enforced in the background.
+    pub static ref RETENTION_INTERVAL: Duration = Duration::from_secs(
+        std::env::var("RETENTION_INTERVAL_SECONDS")
+            .ok()
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(3600)
+    );
+}
+
+/// The policies enforced when `RETENTION_POLICIES` is unset: the soft-deleted users can be
+/// restored for 30 days.
+pub const DEFAULT_RETENTION_POLICIES: &str = "users:deleted:30";
+
+/// How many ids of the purged documents a purge log entry keeps, the first ones, so that
+/// large purges stay far below the size limit of a document.
+pub const MAX_LOGGED_IDS: usize = 1000;
+
+/// The rules each collection can be purged by.
+const SUPPORTED_RULES: &[(&str, RetentionRule)] = &[
+    (users::REPOSITORY_NAME, RetentionRule::Deleted),
+    (users::REPOSITORY_NAME, RetentionRule::Inactive),
+    (audit::REPOSITORY_NAME, RetentionRule::Age),
+];
+
+/// How long a collection keeps its documents, by rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub collection: &'static str,
+    pub rule: RetentionRule,
+    pub days: i64,
+}
+
+impl RetentionPolicy {
+    /// Parses comma separated `<collection>:<rule>:<days>` policies, such as
+    /// `users:deleted:30,users:inactive:365,audit_log:age:365`.
+    pub fn parse(policies: &str) -> Result<Vec<RetentionPolicy>, String> {
+        let mut parsed: Vec<RetentionPolicy> = Vec::new();
+        for policy in policies
+            .split(',')
+            .map(str::trim)
+            .filter(|policy| !policy.is_empty())
+        {
+            let [collection, rule, days] = policy.split(':').collect::<Vec<_>>()[..] else {
+                return Err(format!(
+                    "Invalid retention policy {policy:?}, <collection>:<rule>:<days> expected"
+                ));
+            };
+            let Some(&(collection, rule)) = SUPPORTED_RULES
+                .iter()
+                .find(|(name, supported)| *name == collection && supported.as_str() == rule)
+            else {
+                return Err(format!("{collection} cannot be purged by the {rule} rule"));
+            };
+            let Some(days) = days.parse().ok().filter(|days: &i64| *days >= 0) else {
+                return Err(format!(
+                    "Invalid number of days in the retention policy {policy:?}"
+                ));
+            };
+            if parsed
+                .iter()
+                .any(|other| other.collection == collection && other.rule == rule)
+            {
+                return Err(format!("{collection}:{} is set twice", rule.as_str()));
+            }
+            parsed.push(RetentionPolicy {
+                collection,
+                rule,
+                days,
+            });
+        }
+        Ok(parsed)
+    }
+
+    /// The policies configured by `RETENTION_POLICIES`.
+    pub fn from_env() -> Result<Vec<RetentionPolicy>, String> {
+        let policies = std::env::var("RETENTION_POLICIES")
+            .unwrap_or_else(|_| DEFAULT_RETENTION_POLICIES.into());
+        RetentionPolicy::parse(&policies)
+    }
+
+    /// Purges, or only lists in a dry run, the documents older than `cutoff`.
+    async fn purge(
+        &self,
+        state: &ProgramAppState,
+        cutoff: DateTime,
+        now: DateTime,
+        dry_run: bool,
+    ) -> Result<Vec<ObjectId>, StoreError> {
+        match (self.collection, self.rule) {
+            (users::REPOSITORY_NAME, RetentionRule::Deleted) => {
+                state.users.purge_deleted(cutoff, dry_run).await
+            }
+            (users::REPOSITORY_NAME, RetentionRule::Inactive) => {
+                state.users.soft_delete_inactive(cutoff, now, dry_run).await
+            }
+            (audit::REPOSITORY_NAME, RetentionRule::Age) => {
+                state.audit_log.purge_before(cutoff, dry_run).await
+            }
+            (collection, rule) => Err(StoreError::InvalidDocument(format!(
+                "{collection} cannot be purged by the {} rule",
+                rule.as_str()
+            ))),
+        }
+    }
+}
+
+/// Enforces the policies and returns what each one purged, or would have purged in a dry run.
+/// The policies having purged something are recorded in the purge log.
+pub async fn enforce(
+    state: &ProgramAppState,
+    policies: &[RetentionPolicy],
+    dry_run: bool,
+) -> Result<Vec<PurgeEntry>, StoreError> {
+    let now = state.ntp.current_time();
+    let mut report = Vec::new();
+    let created_at = DateTime::from_millis(now.timestamp_millis());
+    for policy in policies {
+        let cutoff = now - chrono::Duration::days(policy.days);
+        let cutoff = DateTime::from_millis(cutoff.timestamp_millis());
+        let ids = policy.purge(state, cutoff, created_at, dry_run).await?;
+        let purged_ids: Vec<String> = ids
+            .iter()
+            .take(MAX_LOGGED_IDS)
+            .map(|id| id.to_hex())
+            .collect();
+        let entry = PurgeEntry {
+            _id: ObjectId::new(),
+            collection: policy.collection.to_string(),
+            rule: policy.rule,
+            retention_days: policy.days,
+            cutoff,
+            purged_ids: serde_json::to_string(&purged_ids)
+                .map_err(|err| StoreError::InvalidDocument(err.to_string()))?,
+            purged: ids.len() as i64,
+            dry_run,
+            created_at,
+        };
+        if !ids.is_empty() {
+            state.purge_log.record(&entry).await?;
+        }
+        report.push(entry);
+    }
+    Ok(report)
+}
+
+/// The background enforcement of the retention policies, until stopped.
+pub struct RetentionTask {
+    stopped: Arc<SimpleAtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl RetentionTask {
+    /// Enforces the policies now, then every `every`.
+    pub fn start(
+        state: web::Data<ProgramAppState>,
+        policies: Vec<RetentionPolicy>,
+        every: Duration,
+        dry_run: bool,
+    ) -> Self {
+        let stopped = Arc::new(SimpleAtomicBool::new(false));
+        let handle = rt::spawn(RetentionTask::retention_loop(
+            stopped.clone(),
+            state,
+            policies,
+            every,
+            dry_run,
+        ));
+        RetentionTask { stopped, handle }
+    }
+
+    /// Waits for the purge in progress, if any, to finish.
+    pub async fn stop(self) {
+        self.stopped.set(true);
+        if let Err(error) = self.handle.await {
+            log::error!("Failed to stop the retention task: {error}");
+        }
+    }
+
+    async fn retention_loop(
+        stopped: Arc<SimpleAtomicBool>,
+        state: web::Data<ProgramAppState>,
+        policies: Vec<RetentionPolicy>,
+        every: Duration,
+        dry_run: bool,
+    ) {
+        let mut purge_interval = interval(every);
+        let mut stopped_interval = interval(STOPPING_INTERVAL);
+        while !stopped.get() {
+            let purge_tick = purge_interval.tick().fuse();
+            let stopped_tick = stopped_interval.tick().fuse();
+            pin_mut!(purge_tick, stopped_tick);
+            select! {
+                _ = purge_tick => {
+                    match enforce(&state, &policies, dry_run).await {
+                        Ok(report) => log_report(&report),
+                        Err(error) => log::error!("Failed to enforce the retention policies: {error}"),
+                    }
+                },
+                _ = stopped_tick => {}
+            }
+        }
+    }
+}
+
+fn log_report(report: &[PurgeEntry]) {
+    for entry in report.iter().filter(|entry| entry.purged > 0) {
+        let verb = match (entry.dry_run, entry.rule) {
+            (true, RetentionRule::Inactive) => "Would delete",
+            (true, _) => "Would purge",
+            // The inactive users are only soft-deleted, they are purged later.
+            (false, RetentionRule::Inactive) => "Deleted",
+            (false, _) => "Purged",
+        };
+        log::info!(
+            "{verb} {} {} by the {} rule, cutoff {}",
+            entry.purged,
+            entry.collection,
+            entry.rule.as_str(),
+            entry.cutoff
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            RetentionPolicy::parse(DEFAULT_RETENTION_POLICIES),
+            Ok(vec![RetentionPolicy {
+                collection: "users",
+                rule: RetentionRule::Deleted,
+                days: 30,
+            }])
+        );
+        let policies = RetentionPolicy::parse(" users:inactive:365, audit_log:age:90,").unwrap();
+        assert_eq!(
+            policies
+                .iter()
+                .map(|policy| (policy.collection, policy.rule, policy.days))
+                .collect::<Vec<_>>(),
+            [
+                ("users", RetentionRule::Inactive, 365),
+                ("audit_log", RetentionRule::Age, 90)
+            ]
+        );
+        assert_eq!(RetentionPolicy::parse(""), Ok(vec![]));
+
+        for invalid in [
+            "users:deleted",
+            "users:age:30",
+            "audit_log:deleted:30",
+            "users:deleted:-1",
+            "users:deleted:30,users:deleted:60",
+        ] {
+            assert!(RetentionPolicy::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Seeding of the database at startup, from the fixture files of the environment
+//! (`SEED_DIR/<APP_ENV>/*.json`, `*.yaml` or `*.yml`) and from `SEED_ADMIN_*` variables.
+//! Seeding is idempotent: users whose email is taken are left untouched,
+//! the ones created get a welcome email.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use lazy_static::lazy_static;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::Deserialize;
+
+use crate::{
+    models::{
+        organizations::Organization,
+        users::{hash_password, User},
+    },
+    services::emails,
+    store::{OrganizationStore, StoreError, UserStore},
+};
+
+/// Passwords refused in production, wherever they come from.
+const DEFAULT_PASSWORDS: &[&str] = &["password", "admin", "changeme", "secret", "123456"];
+/// The shortest password accepted in production.
+const MIN_PRODUCTION_PASSWORD_LENGTH: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Environment {
+    Development,
+    Test,
+    Production,
+}
+
+impl Environment {
+    pub fn from_config(name: &str) -> anyhow::Result<Self> {
+        match name.to_lowercase().as_str() {
+            "development" | "dev" => Ok(Environment::Development),
+            "test" => Ok(Environment::Test),
+            "production" | "prod" => Ok(Environment::Production),
+            _ => bail!("Unknown environment: {name}"),
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Environment::Development => "development",
+            Environment::Test => "test",
+            Environment::Production => "production",
+        };
+        f.write_str(name)
+    }
+}
+
+lazy_static! {
+    /// The directory holding one sub-directory of fixtures per environment.
+    static ref SEED_DIR: PathBuf =
+        PathBuf::from(std::env::var("SEED_DIR").unwrap_or_else(|_| "seeds".into()));
+}
+
+/// The content of a fixture file, one list per kind of entity.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Fixtures {
+    /// Seeded before the users, which can refer to them.
+    #[serde(default)]
+    pub organizations: Vec<OrganizationFixture>,
+    #[serde(default)]
+    pub users: Vec<UserFixture>,
+}
+
+impl Fixtures {
+    fn extend(&mut self, other: Fixtures) {
+        self.organizations.extend(other.organizations);
+        self.users.extend(other.users);
+    }
+}
+
+/// An organization to seed, with the id the `org_id` of the users refer to.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OrganizationFixture {
+    pub id: String,
+    pub name: String,
+}
+
+impl OrganizationFixture {
+    fn to_organization(&self, created_at: DateTime) -> anyhow::Result<Organization> {
+        Ok(Organization {
+            _id: ObjectId::parse_str(&self.id)
+                .with_context(|| format!("Invalid id of organization {}", self.name))?,
+            name: self.name.clone(),
+            created_at,
+        })
+    }
+}
+
+/// A user to seed, with either its password or the hash of it.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UserFixture {
+    pub first_name: String,
+    pub last_name: String,
+    pub role: String,
+    pub email: String,
+    #[serde(default)]
+    pub org_id: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// An argon2 encoded hash, the only way to seed users from files in production.
+    #[serde(default)]
+    pub password_hash: Option<String>,
+}
+
+impl UserFixture {
+    fn to_user(&self, environment: Environment) -> anyhow::Result<User> {
+        let password = match (&self.password, &self.password_hash) {
+            (Some(_), None) if environment == Environment::Production => bail!(
+                "Refusing to seed {} with a clear text password in production, use password_hash",
+                self.email
+            ),
+            (Some(password), None) => hash_password(password),
+            (None, Some(hash)) => hash.clone(),
+            _ => bail!("{} needs either a password or a password_hash", self.email),
+        };
+        let org_id = match self.org_id.as_deref() {
+            None | Some("") => None,
+            Some(org_id) => Some(
+                ObjectId::parse_str(org_id)
+                    .with_context(|| format!("Invalid org_id of {}", self.email))?,
+            ),
+        };
+        Ok(User {
+            _id: ObjectId::new(),
+            first_name: self.first_name.clone(),
+            last_name: self.last_name.clone(),
+            role: self.role.clone(),
+            org_id,
+            email: self.email.clone(),
+            email_index: None,
+            password,
+            deleted_at: None,
+            deleted_by: None,
+            last_login_at: None,
+            version: 0,
+        })
+    }
+}
+
+/// Parses a fixture file, in JSON or YAML depending on its extension.
+pub fn parse(path: &Path, content: &str) -> anyhow::Result<Fixtures> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let fixtures = match extension {
+        Some("json") => serde_json::from_str(content)?,
+        Some("yaml" | "yml") => serde_yaml::from_str(content)?,
+        _ => bail!("Unsupported fixture file {}", path.display()),
+    };
+    Ok(fixtures)
+}
+
+/// Loads every fixture file of the directory, in the order of their names.
+/// A missing directory has no fixtures.
+pub fn load_dir(directory: &Path) -> anyhow::Result<Fixtures> {
+    let mut fixtures = Fixtures::default();
+    if !directory.is_dir() {
+        return Ok(fixtures);
+    }
+    let mut paths = std::fs::read_dir(directory)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.retain(|path| {
+        matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("json" | "yaml" | "yml")
+        )
+    });
+    paths.sort();
+    for path in paths {
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Reading fixture file {}", path.display()))?;
+        let file_fixtures =
+            parse(&path, &content).with_context(|| format!("Parsing {}", path.display()))?;
+        fixtures.extend(file_fixtures);
+    }
+    Ok(fixtures)
+}
+
+/// The administrator described by `SEED_ADMIN_EMAIL` and `SEED_ADMIN_PASSWORD`, if both are set.
+pub fn admin_from_env() -> Option<UserFixture> {
+    let email = std::env::var("SEED_ADMIN_EMAIL").ok()?;
+    let password = std::env::var("SEED_ADMIN_PASSWORD").ok()?;
+    let var = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.into());
+    Some(UserFixture {
+        first_name: var("SEED_ADMIN_FIRST_NAME", "Admin"),
+        last_name: var("SEED_ADMIN_LAST_NAME", "Istrator"),
+        role: var("SEED_ADMIN_ROLE", "god"),
+        email,
+        org_id: std::env::var("SEED_ADMIN_ORG_ID").ok(),
+        password: Some(password),
+        password_hash: None,
+    })
+}
+
+fn check_production_password(email: &str, password: &str) -> anyhow::Result<()> {
+    if DEFAULT_PASSWORDS.contains(&password.to_lowercase().as_str())
+        || password.chars().count() < MIN_PRODUCTION_PASSWORD_LENGTH
+    {
+        bail!(
+            "Refusing to seed {email} with a default or short password in production, \
+             {MIN_PRODUCTION_PASSWORD_LENGTH} characters at least"
+        );
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SeedReport {
+    /// The names of the organizations created.
+    pub created_organizations: Vec<String>,
+    /// The emails of the users created.
+    pub created: Vec<String>,
+    /// The emails already taken.
+    pub skipped: Vec<String>,
+}
+
+/// Creates the fixture organizations whose id or name is not taken yet,
+/// then the fixture users whose email is not taken yet.
+/// Every fixture is checked before anything is written, an invalid one seeds nothing.
+pub async fn seed(
+    users: &UserStore,
+    organizations: &OrganizationStore,
+    fixtures: &Fixtures,
+    environment: Environment,
+) -> anyhow::Result<SeedReport> {
+    let now = DateTime::now();
+    let organizations_to_create = fixtures
+        .organizations
+        .iter()
+        .map(|fixture| fixture.to_organization(now))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let to_create = fixtures
+        .users
+        .iter()
+        .map(|fixture| fixture.to_user(environment))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut report = SeedReport::default();
+    for organization in organizations_to_create {
+        match organizations.create(&organization).await {
+            Ok(()) => report.created_organizations.push(organization.name),
+            Err(StoreError::Duplicate(_)) => {}
+            Err(error) => {
+                return Err(error).with_context(|| format!("Seeding {}", organization.name));
+            }
+        }
+    }
+    for user in to_create {
+        match users.create(&user).await {
+            Ok(()) => report.created.push(user.email),
+            Err(StoreError::Duplicate(_)) => report.skipped.push(user.email),
+            Err(error) => {
+                return Err(error).with_context(|| format!("Seeding {}", user.email));
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Seeds the fixtures of the environment given by `APP_ENV`, then the `SEED_ADMIN_*` administrator.
+/// Nothing is seeded when `APP_ENV` is unset, the development fixtures must not reach a
+/// production database by mistake.
+pub async fn seed_from_env(
+    users: &UserStore,
+    organizations: &OrganizationStore,
+) -> anyhow::Result<SeedReport> {
+    let Ok(name) = std::env::var("APP_ENV") else {
+        log::info!("APP_ENV is unset, nothing is seeded");
+        return Ok(SeedReport::default());
+    };
+    let environment = Environment::from_config(&name)?;
+    let directory = SEED_DIR.join(environment.to_string());
+    let mut fixtures = load_dir(&directory)?;
+    if let Some(admin) = admin_from_env() {
+        if environment == Environment::Production {
+            check_production_password(&admin.email, admin.password.as_deref().unwrap_or(""))?;
+        }
+        fixtures.users.push(admin);
+    }
+    log::info!(
+        "Seeding {} organizations and {} users from {} and the environment ({environment})",
+        fixtures.organizations.len(),
+        fixtures.users.len(),
+        directory.display()
+    );
+    let report = seed(users, organizations, &fixtures, environment).await?;
+    for name in &report.created_organizations {
+        log::info!("Seeded organization {name}");
+    }
+    for email in &report.created {
+        log::info!("Seeded user {email}");
+        if let Err(error) = emails::send_email_with_aws_ses(email, "Welcome", "Message").await {
+            log::warn!("Failed to send the welcome email to {email}: {error:?}");
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::{Collections, MemoryRepository};
+    use std::sync::Arc;
+
+    fn fixture(email: &str, password: &str) -> UserFixture {
+        UserFixture {
+            first_name: "Jane".into(),
+            last_name: "Doe".into(),
+            role: "admin".into(),
+            email: email.into(),
+            org_id: None,
+            password: Some(password.into()),
+            password_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let yaml = "users:\n  - first_name: Jane\n    last_name: Doe\n    role: admin\n    \
+                    email: jane@example.com\n    password: secret\n";
+        let json = r#"{"users": [{"first_name": "Jane", "last_name": "Doe", "role": "admin",
+                       "email": "jane@example.com", "password": "secret"}]}"#;
+        let expected = Fixtures {
+            organizations: vec![],
+            users: vec![fixture("jane@example.com", "secret")],
+        };
+        assert_eq!(parse(Path::new("users.yaml"), yaml).unwrap(), expected);
+        assert_eq!(parse(Path::new("users.json"), json).unwrap(), expected);
+        assert!(parse(Path::new("users.toml"), yaml).is_err());
+        assert!(parse(Path::new("users.json"), r#"{"groups": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_load_development_fixtures() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("seeds/development");
+        let fixtures = load_dir(&directory).unwrap();
+        assert!(!fixtures.users.is_empty());
+        assert_eq!(load_dir(Path::new("missing")).unwrap(), Fixtures::default());
+    }
+
+    fn stores() -> (UserStore, OrganizationStore) {
+        let collections = Collections::default();
+        let organizations = OrganizationStore::new(Arc::new(MemoryRepository::new(&collections)));
+        let users = UserStore::new(Arc::new(MemoryRepository::new(&collections)))
+            .with_organizations(organizations.clone());
+        (users, organizations)
+    }
+
+    #[actix_web::test]
+    async fn test_seed_is_idempotent() {
+        let (users, organizations) = stores();
+        let organization_id = "65f0a1b2c3d4e5f607182930";
+        let mut john = fixture("john@example.com", "secret");
+        john.org_id = Some(organization_id.into());
+        let fixtures = Fixtures {
+            organizations: vec![OrganizationFixture {
+                id: organization_id.into(),
+                name: "Acme".into(),
+            }],
+            users: vec![fixture("jane@example.com", "secret"), john],
+        };
+        let report = seed(&users, &organizations, &fixtures, Environment::Development)
+            .await
+            .unwrap();
+        assert_eq!(report.created_organizations, ["Acme"]);
+        assert_eq!(report.created, ["jane@example.com", "john@example.com"]);
+        let jane = users.find_by_email("jane@example.com").await.unwrap();
+        assert!(argon2::verify_encoded(&jane.unwrap().password, b"secret").unwrap());
+
+        let report = seed(&users, &organizations, &fixtures, Environment::Development)
+            .await
+            .unwrap();
+        assert!(report.created_organizations.is_empty());
+        assert!(report.created.is_empty());
+        assert_eq!(report.skipped.len(), 2);
+
+        // Users of unknown organizations are refused.
+        let mut joe = fixture("joe@example.com", "secret");
+        joe.org_id = Some(ObjectId::new().to_hex());
+        let fixtures = Fixtures {
+            organizations: vec![],
+            users: vec![joe],
+        };
+        assert!(
+            seed(&users, &organizations, &fixtures, Environment::Development)
+                .await
+                .is_err()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_seed_refuses_default_credentials_in_production() {
+        let (users, organizations) = stores();
+        let fixtures = Fixtures {
+            organizations: vec![],
+            users: vec![fixture("jane@example.com", "password")],
+        };
+        assert!(
+            seed(&users, &organizations, &fixtures, Environment::Production)
+                .await
+                .is_err()
+        );
+        assert_eq!(users.find_by_email("jane@example.com").await.unwrap(), None);
+
+        let mut hashed = fixture("jane@example.com", "");
+        hashed.password = None;
+        hashed.password_hash = Some(hash_password("a long and random passphrase"));
+        let fixtures = Fixtures {
+            organizations: vec![],
+            users: vec![hashed],
+        };
+        let report = seed(&users, &organizations, &fixtures, Environment::Production)
+            .await
+            .unwrap();
+        assert_eq!(report.created, ["jane@example.com"]);
+
+        assert!(check_production_password("jane@example.com", "Password").is_err());
+        assert!(check_production_password("jane@example.com", "short").is_err());
+        assert!(
+            check_production_password("jane@example.com", "a long and random passphrase").is_ok()
+        );
+    }
+}
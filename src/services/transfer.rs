@@ -0,0 +1,729 @@
+//! Export and import of the users, as JSON Lines, CSV or a dump of concatenated BSON documents
+//! (the format of `mongodump`). Both are streamed: an export reads the users a page at a time,
+//! an import decodes the records as the request body arrives.
+
+use std::collections::HashSet;
+
+use actix_web::web::Bytes;
+use futures::Stream;
+use mongodb::bson::{self, oid::ObjectId, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    models::users::{SanitizedUser, User},
+    store::{pagination::PageRequest, Filter, Order, StoreError, UserStore},
+};
+
+/// The users read from the store at a time by an export.
+const EXPORT_BATCH_SIZE: u64 = 500;
+/// The conflicts and errors detailed by an import report, the next ones are only counted.
+const MAX_REPORTED_ISSUES: usize = 1000;
+/// The columns of a CSV export, the password hash is the last one and only there when asked for.
+const CSV_COLUMNS: &[&str] = &[
+    "_id",
+    "first_name",
+    "last_name",
+    "role",
+    "org_id",
+    "email",
+    "password",
+];
+/// The columns a CSV import cannot do without.
+const REQUIRED_CSV_COLUMNS: &[&str] = &["first_name", "last_name", "role", "email"];
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One JSON document per line.
+    #[default]
+    Ndjson,
+    /// A header line, then one line per user.
+    Csv,
+    /// Concatenated BSON documents.
+    Bson,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Ndjson => "application/x-ndjson",
+            Format::Csv => "text/csv",
+            Format::Bson => "application/bson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Ndjson => "ndjson",
+            Format::Csv => "csv",
+            Format::Bson => "bson",
+        }
+    }
+}
+
+/// A user as exported, sanitized unless its password hash was asked for.
+#[derive(Clone, Debug, Serialize)]
+pub struct ExportedUser {
+    #[serde(flatten)]
+    pub user: SanitizedUser,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl ExportedUser {
+    pub fn new(user: &User, include_hash: bool) -> Self {
+        ExportedUser {
+            user: user.sanitize(),
+            password: include_hash.then(|| user.password.clone()),
+        }
+    }
+
+    fn csv_cells(&self) -> Vec<String> {
+        let mut cells = vec![
+            self.user._id.to_hex(),
+            self.user.first_name.clone(),
+            self.user.last_name.clone(),
+            self.user.role.clone(),
+            self.user.org_id.map(|id| id.to_hex()).unwrap_or_default(),
+            self.user.email.clone(),
+        ];
+        cells.extend(self.password.clone());
+        cells
+    }
+}
+
+fn encode(format: Format, user: &ExportedUser, output: &mut Vec<u8>) -> Result<(), StoreError> {
+    match format {
+        Format::Ndjson => {
+            serde_json::to_writer(&mut *output, user)
+                .map_err(|error| StoreError::InvalidDocument(error.to_string()))?;
+            output.push(b'\n');
+        }
+        Format::Csv => write_csv_record(&user.csv_cells(), output),
+        Format::Bson => bson::to_document(user)?.to_writer(output)?,
+    }
+    Ok(())
+}
+
+/// Writes a CSV line, quoting the cells that need it (RFC 4180).
+fn write_csv_record<S: AsRef<str>>(cells: &[S], output: &mut Vec<u8>) {
+    for (index, cell) in cells.iter().enumerate() {
+        if index > 0 {
+            output.push(b',');
+        }
+        let cell = cell.as_ref();
+        if cell.contains([',', '"', '\n', '\r']) {
+            output.push(b'"');
+            output.extend(cell.replace('"', "\"\"").as_bytes());
+            output.push(b'"');
+        } else {
+            output.extend(cell.as_bytes());
+        }
+    }
+    output.extend(b"\r\n");
+}
+
+/// Splits a CSV line in its cells, unquoting them.
+fn parse_csv_record(line: &str) -> Result<Vec<String>, String> {
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(char) = chars.next() {
+        match (quoted, char) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                cell.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if cell.is_empty() => quoted = true,
+            (false, ',') => cells.push(std::mem::take(&mut cell)),
+            (_, char) => cell.push(char),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quoted cell".into());
+    }
+    cells.push(cell);
+    Ok(cells)
+}
+
+/// Streams the active users, oldest first, the store being read a batch at a time.
+pub fn export(
+    users: UserStore,
+    format: Format,
+    include_hashes: bool,
+) -> impl Stream<Item = Result<Bytes, StoreError>> {
+    async_stream::try_stream! {
+        if format == Format::Csv {
+            let columns = match include_hashes {
+                true => CSV_COLUMNS,
+                false => &CSV_COLUMNS[..CSV_COLUMNS.len() - 1],
+            };
+            let mut header = Vec::new();
+            write_csv_record(columns, &mut header);
+            yield Bytes::from(header);
+        }
+        let mut request = PageRequest {
+            sort: "_id".into(),
+            order: Order::Ascending,
+            after: None,
+            limit: EXPORT_BATCH_SIZE,
+            with_total: false,
+        };
+        loop {
+            let page = users.page(Filter::new(), &request).await?;
+            let mut chunk = Vec::new();
+            for user in &page.items {
+                encode(format, &ExportedUser::new(user, include_hashes), &mut chunk)?;
+            }
+            if !chunk.is_empty() {
+                yield Bytes::from(chunk);
+            }
+            match page.next {
+                Some(cursor) => request.after = Some(cursor),
+                None => break,
+            }
+        }
+    }
+}
+
+/// A user as imported, before validation. Exports with the hashes can be imported back.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct ImportedUser {
+    /// A new id is given to the user when None.
+    #[serde(default)]
+    pub _id: Option<ObjectId>,
+    pub first_name: String,
+    pub last_name: String,
+    pub role: String,
+    #[serde(default)]
+    pub org_id: Option<ObjectId>,
+    pub email: String,
+    /// The argon2 encoded hash of the password, clear text passwords are not imported.
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl ImportedUser {
+    fn from_csv(header: &[String], cells: Vec<String>) -> Result<Self, String> {
+        let missing = REQUIRED_CSV_COLUMNS
+            .iter()
+            .find(|column| !header.iter().any(|other| other == *column));
+        if let Some(column) = missing {
+            return Err(format!("Missing CSV column {column}"));
+        }
+        if cells.len() != header.len() {
+            return Err(format!(
+                "{} cells where the header has {}",
+                cells.len(),
+                header.len()
+            ));
+        }
+        let object_id = |cell: &str| match cell {
+            "" => Ok(None),
+            cell => ObjectId::parse_str(cell)
+                .map(Some)
+                .map_err(|_| format!("Invalid id {cell}")),
+        };
+        let mut user = ImportedUser::default();
+        for (column, cell) in header.iter().zip(cells) {
+            match column.as_str() {
+                "_id" => user._id = object_id(&cell)?,
+                "first_name" => user.first_name = cell,
+                "last_name" => user.last_name = cell,
+                "role" => user.role = cell,
+                "org_id" => user.org_id = object_id(&cell)?,
+                "email" => user.email = cell,
+                "password" => user.password = (!cell.is_empty()).then_some(cell),
+                _ => {}
+            }
+        }
+        Ok(user)
+    }
+
+    /// The user to create, if the record is valid.
+    pub fn into_user(self) -> Result<User, String> {
+        for (field, value) in [
+            ("first_name", &self.first_name),
+            ("last_name", &self.last_name),
+            ("role", &self.role),
+        ] {
+            if value.trim().is_empty() {
+                return Err(format!("Missing {field}"));
+            }
+        }
+        match self.email.split_once('@') {
+            Some((name, domain)) if !name.is_empty() && domain.contains('.') => {}
+            _ => return Err(format!("Invalid email {:?}", self.email)),
+        }
+        let password = match self.password {
+            Some(hash) if hash.starts_with("$argon2") => hash,
+            Some(_) => return Err("The password must be an argon2 encoded hash".into()),
+            None => return Err("Missing password hash, export with the hashes".into()),
+        };
+        Ok(User {
+            _id: self._id.unwrap_or_default(),
+            first_name: self.first_name,
+            last_name: self.last_name,
+            role: self.role,
+            org_id: self.org_id,
+            email: self.email,
+            email_index: None,
+            password,
+            deleted_at: None,
+            deleted_by: None,
+            last_login_at: None,
+            version: 0,
+        })
+    }
+}
+
+/// A decoded record, numbered from 1, and the user it holds.
+pub type Decoded = (u64, Result<ImportedUser, String>);
+
+/// Decodes the records of an import as the chunks of its body are received,
+/// only holding the bytes of the record being received.
+#[derive(Debug)]
+pub struct Decoder {
+    format: Format,
+    buffer: Vec<u8>,
+    /// The CSV header, once received.
+    header: Option<Vec<String>>,
+    records: u64,
+    /// Set by an invalid BSON document length, the rest of the dump cannot be split.
+    corrupted: bool,
+}
+
+impl Decoder {
+    pub fn new(format: Format) -> Self {
+        Decoder {
+            format,
+            buffer: Vec::new(),
+            header: None,
+            records: 0,
+            corrupted: false,
+        }
+    }
+
+    /// The records completed by the chunk.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Decoded> {
+        if !self.corrupted {
+            self.buffer.extend_from_slice(chunk);
+        }
+        self.decode(false)
+    }
+
+    /// The last record, once the body has been received.
+    pub fn finish(&mut self) -> Vec<Decoded> {
+        self.decode(true)
+    }
+
+    fn decode(&mut self, end: bool) -> Vec<Decoded> {
+        let buffer = std::mem::take(&mut self.buffer);
+        let mut decoded = Vec::new();
+        let mut start = 0;
+        while !self.corrupted {
+            let frame = match self.frame_length(&buffer[start..], end) {
+                Ok(Some(length)) => &buffer[start..start + length],
+                Ok(None) => break,
+                Err(error) => {
+                    self.corrupted = true;
+                    self.records += 1;
+                    decoded.push((self.records, Err(error)));
+                    break;
+                }
+            };
+            start += frame.len();
+            if let Some(record) = self.parse(frame) {
+                self.records += 1;
+                decoded.push((self.records, record));
+            }
+        }
+        if !self.corrupted {
+            self.buffer = buffer[start..].to_vec();
+        }
+        decoded
+    }
+
+    /// The length of the next record, None until it is fully received.
+    fn frame_length(&self, bytes: &[u8], end: bool) -> Result<Option<usize>, String> {
+        let line_end = match self.format {
+            Format::Ndjson => bytes.iter().position(|byte| *byte == b'\n'),
+            // A line break in a quoted cell does not end the record.
+            Format::Csv => {
+                let mut quoted = false;
+                bytes.iter().position(|byte| {
+                    if *byte == b'"' {
+                        quoted = !quoted;
+                    }
+                    *byte == b'\n' && !quoted
+                })
+            }
+            Format::Bson => {
+                let Some(length) = bytes.get(..4) else {
+                    return match end && !bytes.is_empty() {
+                        true => Err("Truncated BSON document".into()),
+                        false => Ok(None),
+                    };
+                };
+                let length = i32::from_le_bytes(length.try_into().expect("4 bytes"));
+                let length = usize::try_from(length)
+                    .ok()
+                    .filter(|length| *length >= 5)
+                    .ok_or_else(|| format!("Invalid BSON document length {length}"))?;
+                return match bytes.len() >= length {
+                    true => Ok(Some(length)),
+                    false if end => Err("Truncated BSON document".into()),
+                    false => Ok(None),
+                };
+            }
+        };
+        Ok(match line_end {
+            Some(position) => Some(position + 1),
+            None if end && !bytes.is_empty() => Some(bytes.len()),
+            None => None,
+        })
+    }
+
+    /// None for blank lines and the CSV header.
+    fn parse(&mut self, frame: &[u8]) -> Option<Result<ImportedUser, String>> {
+        if self.format == Format::Bson {
+            return Some(
+                Document::from_reader(frame)
+                    .map_err(|error| error.to_string())
+                    .and_then(|document| {
+                        bson::from_document(document).map_err(|error| error.to_string())
+                    }),
+            );
+        }
+        let line = match std::str::from_utf8(frame) {
+            Ok(line) => line.trim_end_matches(['\r', '\n']),
+            Err(error) => return Some(Err(error.to_string())),
+        };
+        if line.trim().is_empty() {
+            return None;
+        }
+        if self.format == Format::Ndjson {
+            return Some(serde_json::from_str(line).map_err(|error| error.to_string()));
+        }
+        let cells = match parse_csv_record(line) {
+            Ok(cells) => cells,
+            Err(error) => return Some(Err(error)),
+        };
+        match &self.header {
+            Some(header) => Some(ImportedUser::from_csv(header, cells)),
+            None => {
+                self.header = Some(cells.iter().map(|cell| cell.trim().into()).collect());
+                None
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ImportIssue {
+    pub record: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// The records received, valid or not.
+    pub records: u64,
+    /// The users created, or that would have been on a dry run.
+    pub imported: u64,
+    /// Records whose email or id is taken, by a stored user or a previous record.
+    pub conflict_count: u64,
+    /// The first [`MAX_REPORTED_ISSUES`] conflicts.
+    pub conflicts: Vec<ImportIssue>,
+    /// Records that could not be decoded or are invalid.
+    pub error_count: u64,
+    /// The first [`MAX_REPORTED_ISSUES`] errors.
+    pub errors: Vec<ImportIssue>,
+}
+
+/// Validates the decoded records and creates their users, unless on a dry run.
+/// A dry run reports the same conflicts and errors as the actual import would.
+pub struct Importer<'a> {
+    users: &'a UserStore,
+    report: ImportReport,
+    /// The emails and ids of the previous records, to report duplicates within the import.
+    emails: HashSet<String>,
+    ids: HashSet<ObjectId>,
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(users: &'a UserStore, dry_run: bool) -> Self {
+        Importer {
+            users,
+            report: ImportReport {
+                dry_run,
+                ..ImportReport::default()
+            },
+            emails: HashSet::new(),
+            ids: HashSet::new(),
+        }
+    }
+
+    /// Returns the user created, if any.
+    pub async fn import(&mut self, decoded: Decoded) -> Result<Option<User>, StoreError> {
+        let (record, imported) = decoded;
+        self.report.records += 1;
+        let email = imported.as_ref().ok().map(|user| user.email.clone());
+        let user = match imported.and_then(ImportedUser::into_user) {
+            Ok(user) => user,
+            Err(message) => {
+                self.error(record, email, message);
+                return Ok(None);
+            }
+        };
+
+        let conflict = if !self.emails.insert(user.email.clone()) {
+            Some("Email of a previous record")
+        } else if !self.ids.insert(user._id) {
+            Some("Id of a previous record")
+        } else if self.users.email_taken(&user.email).await? {
+            Some("Email already in use")
+        } else if self.users.id_taken(&user._id).await? {
+            Some("Id already in use")
+        } else {
+            None
+        };
+        if let Some(message) = conflict {
+            self.conflict(record, user.email, message.into());
+            return Ok(None);
+        }
+        if self.report.dry_run {
+            let checked = match self.users.check_role(&user, None) {
+                Ok(()) => self.users.check_organization(&user).await,
+                Err(error) => Err(error),
+            };
+            match checked {
+                Ok(()) => self.report.imported += 1,
+                Err(StoreError::InvalidDocument(message) | StoreError::Forbidden(message)) => {
+                    self.error(record, Some(user.email), message)
+                }
+                Err(error) => return Err(error),
+            }
+            return Ok(None);
+        }
+
+        match self.users.create(&user).await {
+            Ok(()) => {
+                self.report.imported += 1;
+                Ok(Some(user))
+            }
+            // Taken since it was checked.
+            Err(StoreError::Duplicate(key)) => {
+                self.conflict(record, user.email, format!("Already in use: {key}"));
+                Ok(None)
+            }
+            Err(StoreError::InvalidDocument(message) | StoreError::Forbidden(message)) => {
+                self.error(record, Some(user.email), message);
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn report(self) -> ImportReport {
+        self.report
+    }
+
+    fn conflict(&mut self, record: u64, email: String, message: String) {
+        self.report.conflict_count += 1;
+        if self.report.conflicts.len() < MAX_REPORTED_ISSUES {
+            self.report.conflicts.push(ImportIssue {
+                record,
+                email: Some(email),
+                message,
+            });
+        }
+    }
+
+    fn error(&mut self, record: u64, email: Option<String>, message: String) {
+        self.report.error_count += 1;
+        if self.report.errors.len() < MAX_REPORTED_ISSUES {
+            self.report.errors.push(ImportIssue {
+                record,
+                email,
+                message,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::users::hash_password,
+        store::memory::{Collections, MemoryRepository},
+    };
+    use futures::TryStreamExt;
+    use std::sync::Arc;
+
+    fn user(email: &str) -> User {
+        User {
+            last_name: "Doe, Jr.".into(),
+            org_id: Some(ObjectId::new()),
+            password: hash_password("secret"),
+            ..User::fixture(email)
+        }
+    }
+
+    async fn export_bytes(users: &UserStore, format: Format, include_hashes: bool) -> Vec<u8> {
+        let chunks: Vec<Bytes> = export(users.clone(), format, include_hashes)
+            .try_collect()
+            .await
+            .unwrap();
+        chunks.concat()
+    }
+
+    /// Decodes the input fed a few bytes at a time.
+    fn decode(format: Format, input: &[u8]) -> Vec<Decoded> {
+        let mut decoder = Decoder::new(format);
+        let mut decoded = Vec::new();
+        for chunk in input.chunks(7) {
+            decoded.extend(decoder.feed(chunk));
+        }
+        decoded.extend(decoder.finish());
+        decoded
+    }
+
+    #[test]
+    fn test_csv_records() {
+        let mut line = Vec::new();
+        write_csv_record(
+            &["plain", "a, b", "say \"hi\"", "two\nlines", ""],
+            &mut line,
+        );
+        assert_eq!(
+            String::from_utf8(line.clone()).unwrap(),
+            "plain,\"a, b\",\"say \"\"hi\"\"\",\"two\nlines\",\r\n"
+        );
+        let line = std::str::from_utf8(&line).unwrap().trim_end();
+        assert_eq!(
+            parse_csv_record(line).unwrap(),
+            ["plain", "a, b", "say \"hi\"", "two\nlines", ""]
+        );
+        assert!(parse_csv_record("\"unterminated").is_err());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let decoded = decode(
+            Format::Ndjson,
+            b"{\"email\": \"a@example.com\"}\n\nnot json\n",
+        );
+        assert_eq!(decoded.len(), 2);
+        assert!(decoded.iter().all(|(_, user)| user.is_err()));
+        assert_eq!(decoded[1].0, 2);
+
+        let decoded = decode(Format::Csv, b"email,role\r\na@example.com,user\r\n");
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(
+            decoded[0].1.as_ref().unwrap_err(),
+            "Missing CSV column first_name"
+        );
+
+        let decoded = decode(Format::Bson, &[0x02, 0, 0, 0, 1, 2, 3]);
+        assert_eq!(decoded.len(), 1);
+        assert!(decoded[0].1.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_round_trip() {
+        let users = UserStore::new(Arc::new(MemoryRepository::new(&Collections::default())));
+        let exported = [user("jane@example.com"), user("john@example.com")];
+        for user in &exported {
+            users.create(user).await.unwrap();
+        }
+
+        for format in [Format::Ndjson, Format::Csv, Format::Bson] {
+            let sanitized = export_bytes(&users, format, false).await;
+            let decoded = decode(format, &sanitized);
+            assert_eq!(decoded.len(), 2, "{format:?}");
+            let imported = decoded[0].1.clone().unwrap();
+            assert_eq!(imported.last_name, "Doe, Jr.");
+            assert_eq!(imported.password, None);
+
+            let dump = export_bytes(&users, format, true).await;
+            let imported: Vec<User> = decode(format, &dump)
+                .into_iter()
+                .map(|(_, user)| user.unwrap().into_user().unwrap())
+                .collect();
+            assert_eq!(imported, exported, "{format:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_import_conflicts() {
+        let users = UserStore::new(Arc::new(MemoryRepository::new(&Collections::default())));
+        let taken = user("taken@example.com");
+        users.create(&taken).await.unwrap();
+
+        let records = [
+            user("jane@example.com"),
+            user("taken@example.com"),
+            user("jane@example.com"),
+            User {
+                email: "invalid".into(),
+                ..user("")
+            },
+            User {
+                email: "john@example.com".into(),
+                ..taken.clone()
+            },
+        ];
+        let mut input = Vec::new();
+        for record in &records {
+            encode(Format::Ndjson, &ExportedUser::new(record, true), &mut input).unwrap();
+        }
+
+        let mut reports = Vec::new();
+        for dry_run in [true, false] {
+            let mut importer = Importer::new(&users, dry_run);
+            for decoded in decode(Format::Ndjson, &input) {
+                importer.import(decoded).await.unwrap();
+            }
+            reports.push(importer.report());
+        }
+        let [dry_run, report] = reports.try_into().unwrap();
+        assert!(dry_run.dry_run);
+        assert_eq!(
+            ImportReport {
+                dry_run: false,
+                ..dry_run
+            },
+            report
+        );
+        assert_eq!(report.records, 5);
+        assert_eq!(report.imported, 1);
+        let conflicts: Vec<_> = report
+            .conflicts
+            .iter()
+            .map(|conflict| (conflict.record, conflict.message.as_str()))
+            .collect();
+        assert_eq!(
+            conflicts,
+            [
+                (2, "Email already in use"),
+                (3, "Email of a previous record"),
+                (5, "Id already in use"),
+            ]
+        );
+        assert_eq!(report.error_count, 1);
+        assert_eq!(report.errors[0].record, 4);
+        assert!(users
+            .find_by_email("jane@example.com")
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(users.find_by_email("john@example.com").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::Serialize;
+
+use crate::{
+    models::audit::{self, AuditAction, AuditEntry},
+    store::{Filter, FindOptions, Order, Repository, StoreError, UnitOfWork},
+};
+
+/// The audit trail, entries are only ever added.
+#[derive(Clone)]
+pub struct AuditLog {
+    repository: Arc<dyn Repository<AuditEntry>>,
+    /// The fields whose values are not recorded, besides the passwords.
+    redacted: Vec<&'static str>,
+}
+
+impl AuditLog {
+    pub fn new(repository: Arc<dyn Repository<AuditEntry>>) -> Self {
+        AuditLog {
+            repository,
+            redacted: Vec::new(),
+        }
+    }
+
+    /// The same log, recording in the unit of work.
+    pub fn in_unit(&self, unit: &dyn UnitOfWork) -> Self {
+        AuditLog {
+            repository: unit.audit_log(),
+            ..self.clone()
+        }
+    }
+
+    /// Keeps the values of the fields out of the diffs, as for the fields encrypted at rest.
+    pub fn with_redacted_fields(mut self, fields: &[&'static str]) -> Self {
+        self.redacted = fields.to_vec();
+        self
+    }
+
+    /// Records an action on `target_id`, the diff is computed from the two versions of the target.
+    pub async fn record<T: Serialize + Sync>(
+        &self,
+        actor_id: Option<ObjectId>,
+        action: AuditAction,
+        target_id: ObjectId,
+        before: Option<&T>,
+        after: Option<&T>,
+        created_at: DateTime,
+    ) -> Result<(), StoreError> {
+        let entry = AuditEntry {
+            _id: ObjectId::new(),
+            actor_id,
+            action,
+            target_id,
+            diff: audit::diff(before, after, &self.redacted),
+            created_at,
+        };
+        self.repository.insert(&entry).await
+    }
+
+    /// Records the same action on several targets in a single batch, each with the two versions
+    /// of its target.
+    pub async fn record_many<T: Serialize + Sync>(
+        &self,
+        actor_id: Option<ObjectId>,
+        action: AuditAction,
+        changes: &[(ObjectId, Option<&T>, Option<&T>)],
+        created_at: DateTime,
+    ) -> Result<(), StoreError> {
+        let entries: Vec<_> = changes
+            .iter()
+            .map(|(target_id, before, after)| AuditEntry {
+                _id: ObjectId::new(),
+                actor_id,
+                action,
+                target_id: *target_id,
+                diff: audit::diff(*before, *after, &self.redacted),
+                created_at,
+            })
+            .collect();
+        self.repository
+            .insert_many(&entries)
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// Returns the entries, newest first, and how many there are in total.
+    pub async fn page(&self, skip: u64, limit: u64) -> Result<(Vec<AuditEntry>, u64), StoreError> {
+        let options = FindOptions::new()
+            .sort("created_at", Order::Descending)
+            .sort("_id", Order::Descending)
+            .skip(skip)
+            .limit(limit);
+        let entries = self.repository.find_with(&Filter::new(), &options).await?;
+        let total = self.repository.count(&Filter::new()).await?;
+        Ok((entries, total))
+    }
+
+    /// Deletes the entries created before the date, unless in a dry run, and returns their ids.
+    pub async fn purge_before(
+        &self,
+        before: DateTime,
+        dry_run: bool,
+    ) -> Result<Vec<ObjectId>, StoreError> {
+        let entries = self
+            .repository
+            .find(&Filter::new().lt("created_at", before))
+            .await?;
+        let ids: Vec<_> = entries.iter().map(|entry| entry._id).collect();
+        if dry_run {
+            return Ok(ids);
+        }
+        let mut purged = Vec::new();
+        for (id, deleted) in ids.iter().zip(self.repository.delete_many(&ids).await) {
+            if deleted? {
+                purged.push(*id);
+            }
+        }
+        Ok(purged)
+    }
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,206 @@
+//! A bounded in-process cache: entries expire after a TTL, and the least recently used one is
+//! evicted when it is full. Only the writes of this process invalidate entries, the TTL bounds
+//! how long the writes of other processes go unnoticed.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Lookups of absent or expired entries.
+    pub misses: u64,
+    /// Entries dropped to make room, expired ones are not counted.
+    pub evictions: u64,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+struct Entry<V> {
+    value: V,
+    expires_at: Instant,
+    /// The key of the entry in `Inner::recency`.
+    last_used: u64,
+}
+
+struct Inner<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    /// The keys, least recently used first.
+    recency: BTreeMap<u64, K>,
+    clock: u64,
+    /// Incremented by every invalidation, see [`Cache::generation`].
+    generation: u64,
+    stats: CacheStats,
+}
+
+impl<K: Clone + Eq + Hash, V> Inner<K, V> {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Entry<V>> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        Some(entry)
+    }
+}
+
+pub struct Cache<K, V> {
+    inner: Mutex<Inner<K, V>>,
+    ttl: Duration,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Cache<K, V> {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Cache {
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                generation: 0,
+                stats: CacheStats {
+                    capacity,
+                    ..CacheStats::default()
+                },
+            }),
+            ttl,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner<K, V>> {
+        // The cache is consistent between two statements, a panic cannot leave it broken.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &K, now: Instant) -> Option<V> {
+        let mut inner = self.lock();
+        let Some(entry) = inner.remove(key) else {
+            inner.stats.misses += 1;
+            return None;
+        };
+        if entry.expires_at <= now {
+            inner.stats.misses += 1;
+            return None;
+        }
+        inner.stats.hits += 1;
+        let value = entry.value.clone();
+        let last_used = inner.tick();
+        inner.recency.insert(last_used, key.clone());
+        inner
+            .entries
+            .insert(key.clone(), Entry { last_used, ..entry });
+        Some(value)
+    }
+
+    /// To be read before loading a value, then given to [`Cache::insert`]:
+    /// a value loaded before an invalidation may be stale, it is not cached.
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    pub fn insert(&self, generation: u64, key: K, value: V) {
+        self.insert_at(generation, key, value, Instant::now());
+    }
+
+    fn insert_at(&self, generation: u64, key: K, value: V, now: Instant) {
+        let mut inner = self.lock();
+        if generation != inner.generation || inner.stats.capacity == 0 {
+            return;
+        }
+        inner.remove(&key);
+        while inner.entries.len() >= inner.stats.capacity {
+            let Some((_, oldest)) = inner.recency.pop_first() else {
+                break;
+            };
+            inner.entries.remove(&oldest);
+            inner.stats.evictions += 1;
+        }
+        let last_used = inner.tick();
+        inner.recency.insert(last_used, key.clone());
+        inner.entries.insert(
+            key,
+            Entry {
+                value,
+                expires_at: now + self.ttl,
+                last_used,
+            },
+        );
+    }
+
+    pub fn invalidate(&self, key: &K) {
+        let mut inner = self.lock();
+        inner.generation += 1;
+        inner.remove(key);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.lock();
+        CacheStats {
+            size: inner.entries.len(),
+            ..inner.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry_and_eviction() {
+        let cache = Cache::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        cache.insert_at(0, "a", 1, now);
+        cache.insert_at(0, "b", 2, now);
+        // "a" is now the most recently used, "b" is evicted for "c".
+        assert_eq!(cache.get_at(&"a", now), Some(1));
+        cache.insert_at(0, "c", 3, now);
+        assert_eq!(cache.get_at(&"b", now), None);
+        assert_eq!(cache.get_at(&"c", now), Some(3));
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(cache.get_at(&"a", later), None);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                evictions: 1,
+                size: 1,
+                capacity: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let cache = Cache::new(10, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert(generation, "a", 1);
+        cache.invalidate(&"a");
+        assert_eq!(cache.get(&"a"), None);
+
+        // Loaded before the invalidation, the value may be stale.
+        cache.insert(generation, "a", 1);
+        assert_eq!(cache.get(&"a"), None);
+        cache.insert(cache.generation(), "a", 2);
+        assert_eq!(cache.get(&"a"), Some(2));
+
+        let disabled = Cache::new(0, Duration::from_secs(60));
+        disabled.insert(0, "a", 1);
+        assert_eq!(disabled.get(&"a"), None);
+    }
+}
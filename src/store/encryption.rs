@@ -0,0 +1,671 @@
+//! Encryption of the personal data of the users at rest, transparent to the rest of the store.
+//!
+//! An encrypted field is stored as `enc:<key id>:<base64 of the nonce and the ciphertext>`,
+//! encrypted with AES-256-GCM and bound to the id of the user and the name of the field.
+//! The encryption is not deterministic, the email is looked up by its blind index instead,
+//! a keyed HMAC stored in `email_index`, which also keeps the emails unique.
+//! Values stored before the encryption was enabled are read as they are, until rewritten by
+//! [`EncryptedRepository::rotate`], and the lookups by email fall back to the plain text ones
+//! without blind index when no index matches.
+//!
+//! Encrypted fields cannot be filtered by prefix nor sorted on, they are searched by reading
+//! every user.
+
+use std::{collections::HashMap, sync::Arc};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, Nonce, OsRng, Payload},
+    Aes256Gcm,
+};
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use mongodb::bson::{oid::ObjectId, Bson};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    models::users::User,
+    store::{search, Condition, Filter, FindOptions, Order, Repository, SearchHit, StoreError},
+};
+
+const PREFIX: &str = "enc:";
+const NONCE_SIZE: usize = 12;
+const ROTATION_BATCH_SIZE: u64 = 500;
+/// The fields that can be encrypted, the personal data of the users.
+pub const ENCRYPTABLE_FIELDS: &[&str] = &["first_name", "last_name", "email"];
+/// The encrypted fields looked up by value, with the field holding their blind index.
+const BLIND_INDEXES: &[(&str, &str)] = &[("email", "email_index")];
+
+fn field_mut<'a>(user: &'a mut User, field: &str) -> &'a mut String {
+    match field {
+        "first_name" => &mut user.first_name,
+        "last_name" => &mut user.last_name,
+        _ => &mut user.email,
+    }
+}
+
+/// Encrypts and decrypts the configured fields of the users.
+pub struct FieldCipher {
+    /// The id of the key encrypting, the other keys only decrypt what they encrypted.
+    current: String,
+    keys: HashMap<String, Aes256Gcm>,
+    blind_key: Vec<u8>,
+    fields: Vec<&'static str>,
+}
+
+impl FieldCipher {
+    /// The first key encrypts, the others are the previous keys, kept until every user is
+    /// re-encrypted by [`EncryptedRepository::rotate`].
+    pub fn new(
+        keys: &[(&str, [u8; 32])],
+        blind_key: &[u8],
+        fields: &[&str],
+    ) -> anyhow::Result<Self> {
+        let Some((current, _)) = keys.first() else {
+            bail!("At least one encryption key is needed");
+        };
+        let mut ciphers = HashMap::new();
+        for (id, key) in keys {
+            if id.is_empty() || id.contains(':') {
+                bail!("Invalid encryption key id {id:?}");
+            }
+            let cipher = Aes256Gcm::new_from_slice(key).map_err(|err| anyhow!("{err}"))?;
+            if ciphers.insert(id.to_string(), cipher).is_some() {
+                bail!("Duplicate encryption key id {id}");
+            }
+        }
+        if blind_key.len() < 32 {
+            bail!("The blind index key must be at least 32 bytes");
+        }
+        let fields = fields
+            .iter()
+            .map(|field| {
+                ENCRYPTABLE_FIELDS
+                    .iter()
+                    .find(|encryptable| *encryptable == field)
+                    .copied()
+                    .ok_or_else(|| anyhow!("The field {field} cannot be encrypted"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(FieldCipher {
+            current: current.to_string(),
+            keys: ciphers,
+            blind_key: blind_key.to_vec(),
+            fields,
+        })
+    }
+
+    /// Configured by `FIELD_ENCRYPTION_KEYS`, comma separated `<id>:<base64 key>` pairs with
+    /// the current key first, `FIELD_BLIND_INDEX_KEY` (base64) and `ENCRYPTED_FIELDS`.
+    /// None when there is no key.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(keys) = std::env::var("FIELD_ENCRYPTION_KEYS") else {
+            return Ok(None);
+        };
+        let decode = |name: &str, value: &str| {
+            STANDARD
+                .decode(value.trim())
+                .map_err(|err| anyhow!("{name} is not valid base64: {err}"))
+        };
+        let mut decoded = Vec::new();
+        for pair in keys.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let Some((id, key)) = pair.trim().split_once(':') else {
+                bail!("FIELD_ENCRYPTION_KEYS entries must be <id>:<base64 key>");
+            };
+            let key: [u8; 32] = decode("FIELD_ENCRYPTION_KEYS", key)?
+                .try_into()
+                .map_err(|_| anyhow!("The encryption key {id} must be 32 bytes"))?;
+            decoded.push((id, key));
+        }
+        let blind_key = std::env::var("FIELD_BLIND_INDEX_KEY")
+            .map_err(|_| anyhow!("FIELD_BLIND_INDEX_KEY is needed to encrypt fields"))?;
+        let blind_key = decode("FIELD_BLIND_INDEX_KEY", &blind_key)?;
+        let fields = std::env::var("ENCRYPTED_FIELDS")
+            .unwrap_or_else(|_| "first_name,last_name,email".into());
+        let fields: Vec<_> = fields
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .collect();
+        FieldCipher::new(&decoded, &blind_key, &fields).map(Some)
+    }
+
+    /// The encrypted fields.
+    pub fn fields(&self) -> &[&'static str] {
+        &self.fields
+    }
+
+    fn is_encrypted(&self, field: &str) -> bool {
+        self.fields.contains(&field)
+    }
+
+    /// The blind index of a value, which only tells whether two values are equal.
+    pub fn blind_index(&self, value: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.blind_key)
+            .expect("HMAC accepts keys of any size");
+        mac.update(value.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    pub fn encrypt(&self, id: &ObjectId, field: &str, value: &str) -> Result<String, StoreError> {
+        let cipher = &self.keys[&self.current];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = format!("{id}.{field}");
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value.as_bytes(),
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| StoreError::InvalidDocument(format!("Failed to encrypt {field}")))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(format!(
+            "{PREFIX}{}:{}",
+            self.current,
+            STANDARD.encode(sealed)
+        ))
+    }
+
+    /// Values that are not encrypted are returned as they are.
+    pub fn decrypt(&self, id: &ObjectId, field: &str, value: &str) -> Result<String, StoreError> {
+        let Some(key_id) = key_of(value) else {
+            return Ok(value.to_string());
+        };
+        let invalid = || StoreError::InvalidDocument(format!("Failed to decrypt {field} of {id}"));
+        let cipher = self.keys.get(key_id).ok_or_else(|| {
+            StoreError::InvalidDocument(format!("Unknown encryption key {key_id}"))
+        })?;
+        let sealed = STANDARD
+            .decode(&value[PREFIX.len() + key_id.len() + 1..])
+            .map_err(|_| invalid())?;
+        if sealed.len() < NONCE_SIZE {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let nonce: [u8; NONCE_SIZE] = nonce.try_into().map_err(|_| invalid())?;
+        let nonce = Nonce::<Aes256Gcm>::from(nonce);
+        let aad = format!("{id}.{field}");
+        let plaintext = cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| invalid())?;
+        String::from_utf8(plaintext).map_err(|_| invalid())
+    }
+
+    /// The user as stored: its configured fields encrypted, with their blind indexes.
+    pub fn seal(&self, user: &User) -> Result<User, StoreError> {
+        let mut sealed = user.clone();
+        sealed.email_index = None;
+        for field in &self.fields {
+            let value = field_mut(&mut sealed, field);
+            if let Some((_, index)) = BLIND_INDEXES.iter().find(|(indexed, _)| indexed == field) {
+                debug_assert_eq!(*index, "email_index");
+                let blind_index = self.blind_index(value);
+                *value = self.encrypt(&user._id, field, value)?;
+                sealed.email_index = Some(blind_index);
+            } else {
+                *value = self.encrypt(&user._id, field, value)?;
+            }
+        }
+        Ok(sealed)
+    }
+
+    /// The user as read: every encrypted field decrypted, configured or not anymore.
+    pub fn open(&self, mut user: User) -> Result<User, StoreError> {
+        let id = user._id;
+        for field in ENCRYPTABLE_FIELDS {
+            let value = field_mut(&mut user, field);
+            *value = self.decrypt(&id, field, value)?;
+        }
+        user.email_index = None;
+        Ok(user)
+    }
+
+    /// Whether the stored user is encrypted as configured, with the current key.
+    fn is_current(&self, stored: &mut User) -> bool {
+        ENCRYPTABLE_FIELDS.iter().all(|field| {
+            let key = key_of(field_mut(stored, field));
+            match self.is_encrypted(field) {
+                true => key == Some(self.current.as_str()),
+                false => key.is_none(),
+            }
+        }) && stored.email_index.is_some() == self.is_encrypted("email")
+    }
+
+    /// The filter matching the stored users: the encrypted values are matched by their blind
+    /// index, the other conditions on encrypted fields cannot be evaluated.
+    pub fn filter(&self, filter: &Filter) -> Result<Filter, StoreError> {
+        let unsupported = |field: &str| {
+            StoreError::InvalidDocument(format!("Cannot filter on the encrypted field {field}"))
+        };
+        let mut conditions = Vec::new();
+        for condition in &filter.conditions {
+            conditions.push(match condition {
+                Condition::Eq(field, Bson::String(value)) if self.is_encrypted(field) => {
+                    let Some((_, index)) =
+                        BLIND_INDEXES.iter().find(|(indexed, _)| indexed == field)
+                    else {
+                        return Err(unsupported(field));
+                    };
+                    Condition::Eq(index.to_string(), self.blind_index(value).into())
+                }
+                Condition::Eq(field, _)
+                | Condition::Lt(field, _)
+                | Condition::Gt(field, _)
+                | Condition::Prefix(field, _)
+                    if self.is_encrypted(field) =>
+                {
+                    return Err(unsupported(field))
+                }
+                Condition::Any(filters) => Condition::Any(
+                    filters
+                        .iter()
+                        .map(|filter| self.filter(filter))
+                        .collect::<Result<_, _>>()?,
+                ),
+                condition => condition.clone(),
+            });
+        }
+        Ok(Filter { conditions })
+    }
+
+    /// The filter matching the users stored before the encryption was enabled, whose indexed
+    /// fields are in plain text without blind index, None if no indexed field is looked up.
+    /// Called on filters accepted by [`FieldCipher::filter`].
+    pub fn plaintext_filter(&self, filter: &Filter) -> Option<Filter> {
+        let mut indexed = false;
+        let mut conditions = Vec::new();
+        for condition in &filter.conditions {
+            match condition {
+                Condition::Eq(field, Bson::String(_)) if self.is_encrypted(field) => {
+                    let (_, index) = BLIND_INDEXES.iter().find(|(indexed, _)| indexed == field)?;
+                    indexed = true;
+                    conditions.push(condition.clone());
+                    conditions.push(Condition::Eq(index.to_string(), Bson::Null));
+                }
+                Condition::Any(filters) => {
+                    let filters = filters
+                        .iter()
+                        .map(|filter| match self.plaintext_filter(filter) {
+                            Some(plaintext) => {
+                                indexed = true;
+                                plaintext
+                            }
+                            None => filter.clone(),
+                        })
+                        .collect();
+                    conditions.push(Condition::Any(filters));
+                }
+                condition => conditions.push(condition.clone()),
+            }
+        }
+        indexed.then_some(Filter { conditions })
+    }
+}
+
+impl std::fmt::Debug for FieldCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FieldCipher")
+            .field("current", &self.current)
+            .field("fields", &self.fields)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The id of the key that encrypted the value, None if it is not encrypted.
+fn key_of(value: &str) -> Option<&str> {
+    let (key_id, _) = value.strip_prefix(PREFIX)?.split_once(':')?;
+    Some(key_id)
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RotationReport {
+    /// The users read, soft-deleted ones included.
+    pub scanned: u64,
+    /// The users rewritten with the current key and fields.
+    pub rotated: u64,
+    /// The users changed while being rotated, they were written with the current key.
+    pub skipped: u64,
+}
+
+/// The users repository, encrypting the configured fields of the users it writes and
+/// decrypting the users it reads.
+pub struct EncryptedRepository {
+    inner: Arc<dyn Repository<User>>,
+    cipher: Arc<FieldCipher>,
+}
+
+impl EncryptedRepository {
+    pub fn new(inner: Arc<dyn Repository<User>>, cipher: FieldCipher) -> Self {
+        EncryptedRepository {
+            inner,
+            cipher: Arc::new(cipher),
+        }
+    }
+
+    /// The same encryption over another repository, such as the one of a unit of work.
+    pub fn over(&self, inner: Arc<dyn Repository<User>>) -> Self {
+        EncryptedRepository {
+            inner,
+            cipher: self.cipher.clone(),
+        }
+    }
+
+    fn open_all(&self, users: Vec<User>) -> Result<Vec<User>, StoreError> {
+        users
+            .into_iter()
+            .map(|user| self.cipher.open(user))
+            .collect()
+    }
+
+    /// Rewrites the users encrypted with a previous key, or with other fields, as configured.
+    /// It can be interrupted and run again, the users already rewritten are left alone.
+    pub async fn rotate(&self) -> Result<RotationReport, StoreError> {
+        let mut report = RotationReport::default();
+        let mut after: Option<ObjectId> = None;
+        loop {
+            let filter = match after {
+                Some(id) => Filter::new().gt("_id", id),
+                None => Filter::new(),
+            };
+            let options = FindOptions::new()
+                .sort("_id", Order::Ascending)
+                .limit(ROTATION_BATCH_SIZE);
+            let batch = self.inner.find_with(&filter, &options).await?;
+            let Some(last) = batch.last() else {
+                return Ok(report);
+            };
+            after = Some(last._id);
+            for mut stored in batch {
+                report.scanned += 1;
+                if self.cipher.is_current(&mut stored) {
+                    continue;
+                }
+                let version = stored.version;
+                let sealed = self.cipher.seal(&self.cipher.open(stored)?)?;
+                let version_unchanged = Filter::new().eq("version", version);
+                match self.inner.update_where(&sealed, &version_unchanged).await? {
+                    true => report.rotated += 1,
+                    false => report.skipped += 1,
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Repository<User> for EncryptedRepository {
+    async fn insert(&self, item: &User) -> Result<(), StoreError> {
+        self.inner.insert(&self.cipher.seal(item)?).await
+    }
+
+    async fn insert_many(&self, items: &[User]) -> Vec<Result<(), StoreError>> {
+        let sealed: Vec<_> = items.iter().map(|item| self.cipher.seal(item)).collect();
+        let valid: Vec<User> = sealed
+            .iter()
+            .filter_map(|user| user.as_ref().ok())
+            .cloned()
+            .collect();
+        let mut inserted = self.inner.insert_many(&valid).await.into_iter();
+        sealed
+            .into_iter()
+            .map(|user| user.and_then(|_| inserted.next().unwrap_or(Ok(()))))
+            .collect()
+    }
+
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<User>, StoreError> {
+        match self.inner.find_by_id(id).await? {
+            Some(user) => Ok(Some(self.cipher.open(user)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_one(&self, filter: &Filter) -> Result<Option<User>, StoreError> {
+        let mut user = self.inner.find_one(&self.cipher.filter(filter)?).await?;
+        if let (None, Some(plaintext)) = (&user, self.cipher.plaintext_filter(filter)) {
+            user = self.inner.find_one(&plaintext).await?;
+        }
+        user.map(|user| self.cipher.open(user)).transpose()
+    }
+
+    async fn find_with(
+        &self,
+        filter: &Filter,
+        options: &FindOptions,
+    ) -> Result<Vec<User>, StoreError> {
+        if let Some((field, _)) = options
+            .sort
+            .iter()
+            .find(|(field, _)| self.cipher.is_encrypted(field))
+        {
+            return Err(StoreError::InvalidDocument(format!(
+                "Cannot sort by the encrypted field {field}"
+            )));
+        }
+        let mut users = self
+            .inner
+            .find_with(&self.cipher.filter(filter)?, options)
+            .await?;
+        if let (true, Some(plaintext)) = (users.is_empty(), self.cipher.plaintext_filter(filter)) {
+            users = self.inner.find_with(&plaintext, options).await?;
+        }
+        self.open_all(users)
+    }
+
+    async fn count(&self, filter: &Filter) -> Result<u64, StoreError> {
+        let count = self.inner.count(&self.cipher.filter(filter)?).await?;
+        match (count, self.cipher.plaintext_filter(filter)) {
+            (0, Some(plaintext)) => self.inner.count(&plaintext).await,
+            _ => Ok(count),
+        }
+    }
+
+    async fn update_where(&self, item: &User, filter: &Filter) -> Result<bool, StoreError> {
+        self.inner
+            .update_where(&self.cipher.seal(item)?, &self.cipher.filter(filter)?)
+            .await
+    }
+
+    async fn delete(&self, id: &ObjectId) -> Result<bool, StoreError> {
+        self.inner.delete(id).await
+    }
+
+    async fn update_many(&self, items: &[(User, Filter)]) -> Vec<Result<bool, StoreError>> {
+        let sealed: Vec<_> = items
+            .iter()
+            .map(|(item, filter)| Ok((self.cipher.seal(item)?, self.cipher.filter(filter)?)))
+            .collect();
+        let valid: Vec<(User, Filter)> = sealed
+            .iter()
+            .filter_map(|item| item.as_ref().ok())
+            .cloned()
+            .collect();
+        let mut updated = self.inner.update_many(&valid).await.into_iter();
+        sealed
+            .into_iter()
+            .map(|item| item.and_then(|_| updated.next().unwrap_or(Ok(false))))
+            .collect()
+    }
+
+    async fn delete_many(&self, ids: &[ObjectId]) -> Vec<Result<bool, StoreError>> {
+        self.inner.delete_many(ids).await
+    }
+
+    // The indexes of the backend only hold ciphertexts, the users are searched once decrypted.
+    async fn search(
+        &self,
+        query: &str,
+        filter: &Filter,
+        limit: u64,
+    ) -> Result<Vec<SearchHit<User>>, StoreError> {
+        search::scan(self, query, filter, limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::{Collections, MemoryRepository};
+
+    fn user() -> User {
+        User::fixture("jane@example.com")
+    }
+
+    fn cipher(keys: &[(&str, [u8; 32])], fields: &[&str]) -> FieldCipher {
+        FieldCipher::new(keys, &[7; 32], fields).unwrap()
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let cipher = cipher(&[("k1", [1; 32])], &["first_name", "email"]);
+        let user = user();
+        let sealed = cipher.seal(&user).unwrap();
+        assert!(sealed.first_name.starts_with("enc:k1:"));
+        assert!(sealed.email.starts_with("enc:k1:"));
+        assert_eq!(sealed.last_name, "Doe");
+        assert_ne!(sealed.email, cipher.seal(&user).unwrap().email);
+        assert_eq!(
+            sealed.email_index,
+            Some(cipher.blind_index("jane@example.com"))
+        );
+        assert_eq!(cipher.open(sealed.clone()).unwrap(), user);
+
+        // Bound to its user, a value cannot be copied to another one.
+        let other = User {
+            _id: ObjectId::new(),
+            ..sealed.clone()
+        };
+        assert!(cipher.open(other).is_err());
+        // Written before the encryption, the value is read as it is.
+        assert_eq!(cipher.open(user.clone()).unwrap(), user);
+
+        assert!(FieldCipher::new(&[("k1", [1; 32])], &[7; 32], &["password"]).is_err());
+        assert!(FieldCipher::new(&[], &[7; 32], &["email"]).is_err());
+        assert!(FieldCipher::new(&[("k1", [1; 32])], &[7; 8], &["email"]).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let cipher = cipher(&[("k1", [1; 32])], &["first_name", "email"]);
+        let filter = Filter::new()
+            .eq("role", "user")
+            .any(vec![Filter::new().eq("email", "jane@example.com")]);
+        assert_eq!(
+            cipher.filter(&filter).unwrap(),
+            Filter::new().eq("role", "user").any(vec![
+                Filter::new().eq("email_index", cipher.blind_index("jane@example.com"))
+            ])
+        );
+        assert!(cipher
+            .filter(&Filter::new().starts_with("email", "jane"))
+            .is_err());
+        assert!(cipher
+            .filter(&Filter::new().eq("first_name", "Jane"))
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_repository_and_rotation() {
+        let raw = Arc::new(MemoryRepository::<User>::new(&Collections::default()));
+        let old = EncryptedRepository::new(raw.clone(), cipher(&[("k1", [1; 32])], &["email"]));
+        let jane = user();
+        old.insert(&jane).await.unwrap();
+        let duplicate = User {
+            _id: ObjectId::new(),
+            ..jane.clone()
+        };
+        assert!(matches!(
+            old.insert(&duplicate).await,
+            Err(StoreError::Duplicate(_))
+        ));
+        let by_email = Filter::new().eq("email", "jane@example.com");
+        assert_eq!(old.find_one(&by_email).await.unwrap(), Some(jane.clone()));
+        assert_eq!(old.count(&by_email).await.unwrap(), 1);
+        let hits = old.search("jane", &Filter::new(), 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        let sort = FindOptions::new().sort("email", Order::Ascending);
+        assert!(old.find_with(&Filter::new(), &sort).await.is_err());
+
+        // A new key, and the names encrypted too.
+        let new = EncryptedRepository::new(
+            raw.clone(),
+            cipher(
+                &[("k2", [2; 32]), ("k1", [1; 32])],
+                &["first_name", "last_name", "email"],
+            ),
+        );
+        assert_eq!(new.find_one(&by_email).await.unwrap(), Some(jane.clone()));
+        assert_eq!(
+            new.rotate().await.unwrap(),
+            RotationReport {
+                scanned: 1,
+                rotated: 1,
+                skipped: 0
+            }
+        );
+        let stored = raw.find_by_id(&jane._id).await.unwrap().unwrap();
+        assert!(stored.email.starts_with("enc:k2:"));
+        assert!(stored.last_name.starts_with("enc:k2:"));
+        assert_eq!(stored.version, jane.version);
+        assert_eq!(new.rotate().await.unwrap().rotated, 0);
+
+        // Without the previous key, the rotated users can still be read.
+        let rotated =
+            EncryptedRepository::new(raw, cipher(&[("k2", [2; 32])], &["first_name", "email"]));
+        assert_eq!(rotated.find_by_id(&jane._id).await.unwrap(), Some(jane));
+    }
+
+    #[actix_web::test]
+    async fn test_users_stored_before_encryption() {
+        let raw = Arc::new(MemoryRepository::<User>::new(&Collections::default()));
+        let jane = user();
+        raw.insert(&jane).await.unwrap();
+        let encrypted =
+            EncryptedRepository::new(raw.clone(), cipher(&[("k1", [1; 32])], &["email"]));
+
+        // Found by their email in plain text until rotated.
+        let by_email = Filter::new().eq("email", "jane@example.com");
+        assert_eq!(
+            encrypted.find_one(&by_email).await.unwrap(),
+            Some(jane.clone())
+        );
+        assert_eq!(encrypted.count(&by_email).await.unwrap(), 1);
+        let found = encrypted
+            .find_with(&by_email, &FindOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(found, vec![jane.clone()]);
+        let other = Filter::new().eq("email", "john@example.com");
+        assert_eq!(encrypted.count(&other).await.unwrap(), 0);
+        assert_eq!(
+            cipher(&[("k1", [1; 32])], &["email"])
+                .plaintext_filter(&Filter::new().eq("role", "user")),
+            None
+        );
+
+        assert_eq!(encrypted.rotate().await.unwrap().rotated, 1);
+        let stored = raw.find_by_id(&jane._id).await.unwrap().unwrap();
+        assert!(stored.email.starts_with("enc:k1:"));
+        assert_eq!(
+            raw.count(&Filter::new().eq("email", "jane@example.com"))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(encrypted.find_one(&by_email).await.unwrap(), Some(jane));
+    }
+}
@@ -0,0 +1,95 @@
+//! Change events published by the stores on `ProgramAppState::change_sender`, for the
+//! connected UIs to refresh live, see [`crate::websocket`].
+//! A UI is only sent the events of the organizations its user reaches.
+//!
+//! A packet is a BSON document shaped like the incoming ones:
+//! `{ id: EventId::Change, data: { collection: "users", kind: "created", id: ObjectId, version: 1 } }`.
+//! Events only carry ids, the UIs fetch what changed through the API, which checks permissions.
+
+use mongodb::bson::{self, doc, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::Sender;
+
+use crate::{
+    store::{tenant::Tenant, StoreError},
+    websocket::EventId,
+};
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    /// Soft-deleted, it can still be restored.
+    Deleted,
+    Restored,
+    /// Permanently deleted.
+    Purged,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ChangeEvent {
+    /// The collection of the changed item.
+    pub collection: String,
+    pub kind: ChangeKind,
+    pub id: ObjectId,
+    /// The version of the item after the change, None once purged.
+    pub version: Option<i64>,
+    /// The organization of the item, which decides who is sent the event, not sent itself.
+    #[serde(skip)]
+    pub org_id: Option<ObjectId>,
+}
+
+impl ChangeEvent {
+    pub fn new(
+        collection: &str,
+        kind: ChangeKind,
+        id: ObjectId,
+        org_id: Option<ObjectId>,
+        version: Option<i64>,
+    ) -> Self {
+        ChangeEvent {
+            collection: collection.to_string(),
+            kind,
+            id,
+            version,
+            org_id,
+        }
+    }
+
+    /// Whether the users of the tenant can be sent the event.
+    pub fn visible_to(&self, tenant: &Tenant) -> bool {
+        tenant.allows(self.org_id.as_ref())
+    }
+
+    /// The packet sent to the UIs.
+    pub fn encode(&self) -> Result<Vec<u8>, StoreError> {
+        let id = i32::from(u8::from(EventId::Change));
+        let packet = doc! { "id": id, "data": bson::to_document(self)? };
+        let mut bytes = Vec::new();
+        packet.to_writer(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Publishes the change events of a store, nowhere until it is given a channel.
+#[derive(Clone, Default)]
+pub struct Events {
+    sender: Option<Sender<ChangeEvent>>,
+}
+
+impl Events {
+    pub fn new(sender: Sender<ChangeEvent>) -> Self {
+        Events {
+            sender: Some(sender),
+        }
+    }
+
+    /// Publishing never fails the change, which is already stored.
+    pub fn publish(&self, event: ChangeEvent) {
+        if let Some(sender) = &self.sender {
+            // Fails when no UI is connected.
+            let _ = sender.send(event);
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use mongodb::bson::{self, oid::ObjectId, Bson, Document};
+
+use crate::{
+    models::{audit::AuditEntry, organizations::Organization, users::User, Model},
+    store::{
+        CommitHooks, Condition, Filter, FindOptions, Order, Repository, StoreError, UnitOfWork,
+    },
+};
+
+/// Documents of every collection, by collection name, in insertion order.
+pub type Collections = Arc<RwLock<HashMap<String, Vec<Document>>>>;
+
+/// Returns whether the document matches every condition of the filter.
+pub fn matches(document: &Document, filter: &Filter) -> bool {
+    filter.conditions.iter().all(|condition| match condition {
+        Condition::Eq(field, Bson::Null) => {
+            matches!(document.get(field), None | Some(Bson::Null))
+        }
+        Condition::Eq(field, value) => document.get(field) == Some(value),
+        Condition::Lt(field, value) => {
+            matches!(
+                document.get(field).and_then(|field| compare(field, value)),
+                Some(Ordering::Less)
+            )
+        }
+        Condition::Gt(field, value) => {
+            matches!(
+                document.get(field).and_then(|field| compare(field, value)),
+                Some(Ordering::Greater)
+            )
+        }
+        Condition::Prefix(field, prefix) => {
+            matches!(document.get(field), Some(Bson::String(text)) if text.starts_with(prefix.as_str()))
+        }
+        Condition::Any(filters) => filters.iter().any(|filter| matches(document, filter)),
+    })
+}
+
+/// Orders two documents by the sort fields, missing and null values first, like MongoDB.
+fn sort_order(left: &Document, right: &Document, sort: &[(String, Order)]) -> Ordering {
+    for (field, order) in sort {
+        let ordering = match (left.get(field), right.get(field)) {
+            (None | Some(Bson::Null), None | Some(Bson::Null)) => Ordering::Equal,
+            (None | Some(Bson::Null), _) => Ordering::Less,
+            (_, None | Some(Bson::Null)) => Ordering::Greater,
+            (Some(left), Some(right)) => compare(left, right).unwrap_or(Ordering::Equal),
+        };
+        let ordering = match order {
+            Order::Ascending => ordering,
+            Order::Descending => ordering.reverse(),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Orders two values of the same type, None if they cannot be compared.
+fn compare(left: &Bson, right: &Bson) -> Option<Ordering> {
+    match (left, right) {
+        (Bson::DateTime(left), Bson::DateTime(right)) => Some(left.cmp(right)),
+        (Bson::String(left), Bson::String(right)) => Some(left.cmp(right)),
+        (Bson::Int32(left), Bson::Int32(right)) => Some(left.cmp(right)),
+        (Bson::Int64(left), Bson::Int64(right)) => Some(left.cmp(right)),
+        (Bson::Int32(left), Bson::Int64(right)) => Some(i64::from(*left).cmp(right)),
+        (Bson::Int64(left), Bson::Int32(right)) => Some(left.cmp(&i64::from(*right))),
+        (Bson::Double(left), Bson::Double(right)) => left.partial_cmp(right),
+        (Bson::ObjectId(left), Bson::ObjectId(right)) => Some(left.cmp(right)),
+        (Bson::Boolean(left), Bson::Boolean(right)) => Some(left.cmp(right)),
+        _ => None,
+    }
+}
+
+fn poisoned<E>(_: E) -> StoreError {
+    StoreError::Backend("In-memory collections lock is poisoned".to_string())
+}
+
+pub struct MemoryRepository<T: Model> {
+    collections: Collections,
+    /// Fields that must be unique across the collection, from the unique indexes of the model.
+    unique_fields: Vec<&'static str>,
+    model: PhantomData<T>,
+}
+
+impl<T: Model> MemoryRepository<T> {
+    pub fn new(collections: &Collections) -> Self {
+        MemoryRepository {
+            collections: collections.clone(),
+            unique_fields: T::INDEXES
+                .iter()
+                .filter(|index| index.unique)
+                .filter_map(|index| match index.keys {
+                    [(field, _)] => Some(*field),
+                    // Compound unique indexes are not enforced.
+                    _ => None,
+                })
+                .collect(),
+            model: PhantomData,
+        }
+    }
+
+    /// Fails if another document already has the same value for one of the unique fields.
+    fn check_unique(&self, documents: &[Document], document: &Document) -> Result<(), StoreError> {
+        let id = document.get("_id");
+        for field in &self.unique_fields {
+            let Some(value) = document.get(*field) else {
+                continue;
+            };
+            if documents
+                .iter()
+                .any(|other| other.get("_id") != id && other.get(*field) == Some(value))
+            {
+                return Err(StoreError::Duplicate(format!(
+                    "{} {field}: {value}",
+                    T::REPOSITORY_NAME
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: Model> Repository<T> for MemoryRepository<T> {
+    async fn insert(&self, item: &T) -> Result<(), StoreError> {
+        let document = bson::to_document(item)?;
+        let mut collections = self.collections.write().map_err(poisoned)?;
+        let documents = collections
+            .entry(T::REPOSITORY_NAME.to_string())
+            .or_default();
+        if documents
+            .iter()
+            .any(|other| other.get("_id") == document.get("_id"))
+        {
+            return Err(StoreError::Duplicate(format!(
+                "{} _id: {}",
+                T::REPOSITORY_NAME,
+                item.id()
+            )));
+        }
+        self.check_unique(documents, &document)?;
+        documents.push(document);
+        Ok(())
+    }
+
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<T>, StoreError> {
+        self.find_one(&Filter::new().eq("_id", *id)).await
+    }
+
+    async fn find_one(&self, filter: &Filter) -> Result<Option<T>, StoreError> {
+        let collections = self.collections.read().map_err(poisoned)?;
+        let Some(documents) = collections.get(T::REPOSITORY_NAME) else {
+            return Ok(None);
+        };
+        documents
+            .iter()
+            .find(|document| matches(document, filter))
+            .map(|document| Ok(bson::from_document(document.clone())?))
+            .transpose()
+    }
+
+    async fn find_with(
+        &self,
+        filter: &Filter,
+        options: &FindOptions,
+    ) -> Result<Vec<T>, StoreError> {
+        let collections = self.collections.read().map_err(poisoned)?;
+        let Some(documents) = collections.get(T::REPOSITORY_NAME) else {
+            return Ok(Vec::new());
+        };
+        let mut found: Vec<&Document> = documents
+            .iter()
+            .filter(|document| matches(document, filter))
+            .collect();
+        found.sort_by(|left, right| sort_order(left, right, &options.sort));
+        found
+            .into_iter()
+            .skip(options.skip as usize)
+            .take(options.limit.map_or(usize::MAX, |limit| limit as usize))
+            .map(|document| Ok(bson::from_document(document.clone())?))
+            .collect()
+    }
+
+    async fn count(&self, filter: &Filter) -> Result<u64, StoreError> {
+        let collections = self.collections.read().map_err(poisoned)?;
+        let Some(documents) = collections.get(T::REPOSITORY_NAME) else {
+            return Ok(0);
+        };
+        Ok(documents
+            .iter()
+            .filter(|document| matches(document, filter))
+            .count() as u64)
+    }
+
+    async fn update_where(&self, item: &T, filter: &Filter) -> Result<bool, StoreError> {
+        let document = bson::to_document(item)?;
+        let mut collections = self.collections.write().map_err(poisoned)?;
+        let Some(documents) = collections.get_mut(T::REPOSITORY_NAME) else {
+            return Ok(false);
+        };
+        self.check_unique(documents, &document)?;
+        match documents
+            .iter_mut()
+            .find(|other| other.get("_id") == document.get("_id") && matches(other, filter))
+        {
+            Some(stored) => {
+                *stored = document;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn delete(&self, id: &ObjectId) -> Result<bool, StoreError> {
+        let mut collections = self.collections.write().map_err(poisoned)?;
+        let Some(documents) = collections.get_mut(T::REPOSITORY_NAME) else {
+            return Ok(false);
+        };
+        let count = documents.len();
+        documents.retain(|document| document.get_object_id("_id").ok() != Some(*id));
+        Ok(documents.len() < count)
+    }
+}
+
+/// Works on a copy of the collections, which replaces them on commit.
+/// Writes made outside of the unit of work while it is open are therefore lost,
+/// which is fine for the tests and local development this database is meant for.
+pub struct MemoryUnitOfWork {
+    collections: Collections,
+    working_copy: Collections,
+    hooks: CommitHooks,
+}
+
+impl MemoryUnitOfWork {
+    pub fn begin(collections: &Collections) -> Result<Self, StoreError> {
+        let copy = collections.read().map_err(poisoned)?.clone();
+        Ok(MemoryUnitOfWork {
+            collections: collections.clone(),
+            working_copy: Arc::new(RwLock::new(copy)),
+            hooks: CommitHooks::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for MemoryUnitOfWork {
+    fn users(&self) -> Arc<dyn Repository<User>> {
+        Arc::new(MemoryRepository::new(&self.working_copy))
+    }
+
+    fn organizations(&self) -> Arc<dyn Repository<Organization>> {
+        Arc::new(MemoryRepository::new(&self.working_copy))
+    }
+
+    fn audit_log(&self) -> Arc<dyn Repository<AuditEntry>> {
+        Arc::new(MemoryRepository::new(&self.working_copy))
+    }
+
+    fn commit_hooks(&self) -> CommitHooks {
+        self.hooks.clone()
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), StoreError> {
+        let copy = self.working_copy.read().map_err(poisoned)?.clone();
+        *self.collections.write().map_err(poisoned)? = copy;
+        self.hooks.run();
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store;
+
+    #[actix_web::test]
+    async fn test_transaction_commits_or_rolls_back() {
+        let collections = Collections::default();
+        let users = MemoryRepository::<User>::new(&collections);
+        let jane = User::fixture("jane@example.com");
+        let john = User::fixture("john@example.com");
+
+        let unit = Box::new(MemoryUnitOfWork::begin(&collections).unwrap());
+        store::transaction(unit, async |unit: &dyn UnitOfWork| {
+            unit.users().insert(&jane).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            users.find_by_id(&jane._id).await.unwrap(),
+            Some(jane.clone())
+        );
+
+        // The second insert fails on the email, the first one must be rolled back with it.
+        let unit = Box::new(MemoryUnitOfWork::begin(&collections).unwrap());
+        let result = store::transaction(unit, async |unit: &dyn UnitOfWork| {
+            unit.users().insert(&john).await?;
+            unit.users()
+                .insert(&User::fixture("jane@example.com"))
+                .await
+        })
+        .await;
+        assert!(matches!(result, Err(StoreError::Duplicate(_))));
+        assert_eq!(users.find_by_id(&john._id).await.unwrap(), None);
+
+        // So are the writes to the other collections.
+        let organizations = MemoryRepository::<Organization>::new(&collections);
+        let acme = Organization {
+            _id: ObjectId::new(),
+            name: "Acme".into(),
+            created_at: bson::DateTime::now(),
+        };
+        let unit = Box::new(MemoryUnitOfWork::begin(&collections).unwrap());
+        let result = store::transaction(unit, async |unit: &dyn UnitOfWork| {
+            unit.organizations().insert(&acme).await?;
+            unit.users().insert(&john).await?;
+            unit.users().insert(&john).await
+        })
+        .await;
+        assert!(matches!(result, Err(StoreError::Duplicate(_))));
+        assert_eq!(organizations.find_by_id(&acme._id).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,574 @@
+//! Timing of the database operations: every operation of an instrumented repository is counted
+//! in a latency histogram by collection and operation, and the slow ones are logged with the
+//! shape of their filter, its values redacted.
+//! The units of work are instrumented the same way, their transaction being counted as the
+//! `transaction` collection, and the migrations and the other startup operations with
+//! [`Metrics::time`].
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    marker::PhantomData,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    drivers::GenericDatabase,
+    models::{audit::AuditEntry, organizations::Organization, users::User, Model},
+    store::{
+        CommitHooks, Condition, Filter, FindOptions, Order, Repository, SearchHit, StoreError,
+        UnitOfWork,
+    },
+};
+
+lazy_static! {
+    /// The operations lasting longer are logged, with the shape of their filter.
+    pub static ref SLOW_QUERY_THRESHOLD: Duration = Duration::from_millis(
+        std::env::var("SLOW_QUERY_THRESHOLD_MS")
+            .ok()
+            .and_then(|millis| millis.parse().ok())
+            .unwrap_or(200)
+    );
+}
+
+/// The collection the transactions of the units of work are counted in.
+pub const TRANSACTION: &str = "transaction";
+/// The collection the operations made on every collection are counted in.
+pub const EVERY_COLLECTION: &str = "*";
+
+/// The upper bounds of the histogram buckets, in milliseconds, the last bucket being unbounded.
+pub const BUCKETS_MS: &[u64] = &[1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+/// The filter with its values replaced by `?`, such as `deleted_at = ? AND role = ?`.
+pub fn filter_shape(filter: &Filter) -> String {
+    if filter.conditions.is_empty() {
+        return "*".to_string();
+    }
+    filter
+        .conditions
+        .iter()
+        .map(|condition| match condition {
+            Condition::Eq(field, _) => format!("{field} = ?"),
+            Condition::Lt(field, _) => format!("{field} < ?"),
+            Condition::Gt(field, _) => format!("{field} > ?"),
+            Condition::Prefix(field, _) => format!("{field} LIKE ?%"),
+            Condition::Any(filters) => format!(
+                "({})",
+                filters
+                    .iter()
+                    .map(filter_shape)
+                    .collect::<Vec<_>>()
+                    .join(" OR ")
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+fn options_shape(options: &FindOptions) -> String {
+    let mut shape = String::new();
+    for (index, (field, order)) in options.sort.iter().enumerate() {
+        let separator = if index == 0 { " ORDER BY " } else { ", " };
+        let order = match order {
+            Order::Ascending => "ASC",
+            Order::Descending => "DESC",
+        };
+        let _ = write!(shape, "{separator}{field} {order}");
+    }
+    if options.skip > 0 {
+        shape.push_str(" SKIP ?");
+    }
+    if options.limit.is_some() {
+        shape.push_str(" LIMIT ?");
+    }
+    shape
+}
+
+/// The latencies of an operation on a collection.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Histogram {
+    /// How many operations lasted at most each of [`BUCKETS_MS`], not cumulated, and longer.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    /// The total duration of the operations, in microseconds.
+    pub sum_micros: u64,
+    pub errors: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration, failed: bool) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; BUCKETS_MS.len() + 1];
+        }
+        let millis = duration.as_secs_f64() * 1000.0;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|bound| millis <= *bound as f64)
+            .unwrap_or(BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_micros += duration.as_micros() as u64;
+        self.errors += u64::from(failed);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct OperationStats {
+    pub collection: String,
+    pub operation: String,
+    pub latency: Histogram,
+}
+
+/// The latency histograms of the instrumented repositories, shared by their clones.
+#[derive(Clone)]
+pub struct Metrics {
+    histograms: Arc<Mutex<BTreeMap<(&'static str, &'static str), Histogram>>>,
+    slow_threshold: Duration,
+}
+
+impl Metrics {
+    pub fn new(slow_threshold: Duration) -> Self {
+        Metrics {
+            histograms: Arc::default(),
+            slow_threshold,
+        }
+    }
+
+    /// The repository, with its operations timed.
+    pub fn instrument<T: Model>(
+        &self,
+        repository: Arc<dyn Repository<T>>,
+    ) -> Arc<dyn Repository<T>> {
+        Arc::new(InstrumentedRepository {
+            inner: repository,
+            metrics: self.clone(),
+            _model: PhantomData,
+        })
+    }
+
+    /// The unit of work, with the operations of its repositories, its commit and its rollback
+    /// timed.
+    pub fn instrument_unit(&self, unit: Box<dyn UnitOfWork>) -> Box<dyn UnitOfWork> {
+        Box::new(InstrumentedUnitOfWork {
+            inner: unit,
+            metrics: self.clone(),
+        })
+    }
+
+    /// Begins an instrumented unit of work on the database, the beginning being timed as well.
+    pub async fn begin(
+        &self,
+        database: &dyn GenericDatabase,
+    ) -> anyhow::Result<Box<dyn UnitOfWork>> {
+        let unit = self.time(TRANSACTION, "begin", database.begin()).await?;
+        Ok(self.instrument_unit(unit))
+    }
+
+    /// Times an operation made outside of the repositories, such as the migrations.
+    pub async fn time<R, E>(
+        &self,
+        collection: &'static str,
+        operation: &'static str,
+        future: impl Future<Output = Result<R, E>>,
+    ) -> Result<R, E> {
+        let start = Instant::now();
+        let result = future.await;
+        self.record(
+            collection,
+            operation,
+            || "*".to_string(),
+            start.elapsed(),
+            result.is_err(),
+        );
+        result
+    }
+
+    /// Counts the operation, and logs it with its shape if it is slow.
+    pub fn record(
+        &self,
+        collection: &'static str,
+        operation: &'static str,
+        shape: impl FnOnce() -> String,
+        duration: Duration,
+        failed: bool,
+    ) {
+        self.histograms
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry((collection, operation))
+            .or_default()
+            .observe(duration, failed);
+        if duration >= self.slow_threshold {
+            log::warn!(
+                "Slow {operation} on {collection} took {} ms{}: {}",
+                duration.as_millis(),
+                if failed { " and failed" } else { "" },
+                shape()
+            );
+        } else {
+            log::trace!(
+                "{operation} on {collection} took {} us",
+                duration.as_micros()
+            );
+        }
+    }
+
+    /// The histograms, by collection and operation.
+    pub fn snapshot(&self) -> Vec<OperationStats> {
+        self.histograms
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|((collection, operation), latency)| OperationStats {
+                collection: collection.to_string(),
+                operation: operation.to_string(),
+                latency: latency.clone(),
+            })
+            .collect()
+    }
+
+    /// The histograms in the Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        let mut text = String::from(
+            "# HELP db_operation_duration_seconds Duration of the database operations.\n\
+             # TYPE db_operation_duration_seconds histogram\n",
+        );
+        let mut errors = String::from(
+            "# HELP db_operation_errors_total Database operations that failed.\n\
+             # TYPE db_operation_errors_total counter\n",
+        );
+        for stats in self.snapshot() {
+            let labels = format!(
+                "collection=\"{}\",operation=\"{}\"",
+                stats.collection, stats.operation
+            );
+            let mut cumulated = 0;
+            for (bound, count) in BUCKETS_MS.iter().zip(&stats.latency.buckets) {
+                cumulated += count;
+                let _ = writeln!(
+                    text,
+                    "db_operation_duration_seconds_bucket{{{labels},le=\"{}\"}} {cumulated}",
+                    *bound as f64 / 1000.0
+                );
+            }
+            let latency = &stats.latency;
+            let _ = writeln!(
+                text,
+                "db_operation_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}\n\
+                 db_operation_duration_seconds_sum{{{labels}}} {}\n\
+                 db_operation_duration_seconds_count{{{labels}}} {}",
+                latency.count,
+                latency.sum_micros as f64 / 1_000_000.0,
+                latency.count
+            );
+            let _ = writeln!(
+                errors,
+                "db_operation_errors_total{{{labels}}} {}",
+                latency.errors
+            );
+        }
+        text + &errors
+    }
+}
+
+/// A repository timing the operations of another one, see [`Metrics::instrument`].
+struct InstrumentedRepository<T: Model> {
+    inner: Arc<dyn Repository<T>>,
+    metrics: Metrics,
+    _model: PhantomData<T>,
+}
+
+impl<T: Model> InstrumentedRepository<T> {
+    async fn time<R>(
+        &self,
+        operation: &'static str,
+        shape: impl FnOnce() -> String,
+        future: impl Future<Output = Result<R, StoreError>>,
+    ) -> Result<R, StoreError> {
+        let start = Instant::now();
+        let result = future.await;
+        self.metrics.record(
+            T::REPOSITORY_NAME,
+            operation,
+            shape,
+            start.elapsed(),
+            result.is_err(),
+        );
+        result
+    }
+}
+
+#[async_trait]
+impl<T: Model> Repository<T> for InstrumentedRepository<T> {
+    async fn insert(&self, item: &T) -> Result<(), StoreError> {
+        self.time("insert", || "1 item".to_string(), self.inner.insert(item))
+            .await
+    }
+
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<T>, StoreError> {
+        self.time(
+            "find_by_id",
+            || "_id = ?".to_string(),
+            self.inner.find_by_id(id),
+        )
+        .await
+    }
+
+    async fn find_one(&self, filter: &Filter) -> Result<Option<T>, StoreError> {
+        self.time(
+            "find_one",
+            || filter_shape(filter),
+            self.inner.find_one(filter),
+        )
+        .await
+    }
+
+    async fn find_with(
+        &self,
+        filter: &Filter,
+        options: &FindOptions,
+    ) -> Result<Vec<T>, StoreError> {
+        self.time(
+            "find",
+            || filter_shape(filter) + &options_shape(options),
+            self.inner.find_with(filter, options),
+        )
+        .await
+    }
+
+    async fn count(&self, filter: &Filter) -> Result<u64, StoreError> {
+        self.time("count", || filter_shape(filter), self.inner.count(filter))
+            .await
+    }
+
+    async fn update_where(&self, item: &T, filter: &Filter) -> Result<bool, StoreError> {
+        self.time(
+            "update",
+            || format!("_id = ? AND {}", filter_shape(filter)),
+            self.inner.update_where(item, filter),
+        )
+        .await
+    }
+
+    async fn delete(&self, id: &ObjectId) -> Result<bool, StoreError> {
+        self.time("delete", || "_id = ?".to_string(), self.inner.delete(id))
+            .await
+    }
+
+    async fn insert_many(&self, items: &[T]) -> Vec<Result<(), StoreError>> {
+        let start = Instant::now();
+        let results = self.inner.insert_many(items).await;
+        self.metrics.record(
+            T::REPOSITORY_NAME,
+            "insert_many",
+            || format!("{} items", items.len()),
+            start.elapsed(),
+            results.iter().any(Result::is_err),
+        );
+        results
+    }
+
+    async fn update_many(&self, items: &[(T, Filter)]) -> Vec<Result<bool, StoreError>> {
+        let start = Instant::now();
+        let results = self.inner.update_many(items).await;
+        self.metrics.record(
+            T::REPOSITORY_NAME,
+            "update_many",
+            || format!("{} items", items.len()),
+            start.elapsed(),
+            results.iter().any(Result::is_err),
+        );
+        results
+    }
+
+    async fn delete_many(&self, ids: &[ObjectId]) -> Vec<Result<bool, StoreError>> {
+        let start = Instant::now();
+        let results = self.inner.delete_many(ids).await;
+        self.metrics.record(
+            T::REPOSITORY_NAME,
+            "delete_many",
+            || format!("{} items", ids.len()),
+            start.elapsed(),
+            results.iter().any(Result::is_err),
+        );
+        results
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        filter: &Filter,
+        limit: u64,
+    ) -> Result<Vec<SearchHit<T>>, StoreError> {
+        self.time(
+            "search",
+            || format!("text ? AND {} LIMIT ?", filter_shape(filter)),
+            self.inner.search(query, filter, limit),
+        )
+        .await
+    }
+}
+
+/// A unit of work whose repositories and transaction are timed, see [`Metrics::instrument_unit`].
+struct InstrumentedUnitOfWork {
+    inner: Box<dyn UnitOfWork>,
+    metrics: Metrics,
+}
+
+#[async_trait]
+impl UnitOfWork for InstrumentedUnitOfWork {
+    fn users(&self) -> Arc<dyn Repository<User>> {
+        self.metrics.instrument(self.inner.users())
+    }
+
+    fn organizations(&self) -> Arc<dyn Repository<Organization>> {
+        self.metrics.instrument(self.inner.organizations())
+    }
+
+    fn audit_log(&self) -> Arc<dyn Repository<AuditEntry>> {
+        self.metrics.instrument(self.inner.audit_log())
+    }
+
+    fn commit_hooks(&self) -> CommitHooks {
+        self.inner.commit_hooks()
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), StoreError> {
+        self.metrics
+            .time(TRANSACTION, "commit", self.inner.commit())
+            .await
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), StoreError> {
+        self.metrics
+            .time(TRANSACTION, "rollback", self.inner.rollback())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        drivers::MemoryDatabase,
+        models::users::User,
+        store::memory::{Collections, MemoryRepository},
+    };
+
+    #[test]
+    fn test_filter_shape() {
+        let filter = Filter::new()
+            .eq("deleted_at", mongodb::bson::Bson::Null)
+            .any(vec![
+                Filter::new().starts_with("first_name", "Ja"),
+                Filter::new().gt("_id", ObjectId::new()),
+            ]);
+        assert_eq!(
+            filter_shape(&filter),
+            "deleted_at = ? AND (first_name LIKE ?% OR _id > ?)"
+        );
+        assert_eq!(filter_shape(&Filter::new()), "*");
+        let options = FindOptions::new()
+            .sort("last_name", Order::Descending)
+            .limit(10);
+        assert_eq!(options_shape(&options), " ORDER BY last_name DESC LIMIT ?");
+    }
+
+    #[actix_web::test]
+    async fn test_instrumented_repository() {
+        let metrics = Metrics::new(Duration::from_secs(60));
+        let users = metrics.instrument(Arc::new(MemoryRepository::<User>::new(
+            &Collections::default(),
+        )));
+        users.find_by_id(&ObjectId::new()).await.unwrap();
+        users.find_by_id(&ObjectId::new()).await.unwrap();
+        users.count(&Filter::new()).await.unwrap();
+
+        let stats = metrics.snapshot();
+        let operations: Vec<_> = stats
+            .iter()
+            .map(|stats| (stats.operation.as_str(), stats.latency.count))
+            .collect();
+        assert_eq!(operations, [("count", 1), ("find_by_id", 2)]);
+        assert_eq!(stats[1].latency.buckets.iter().sum::<u64>(), 2);
+        assert_eq!(stats[1].latency.errors, 0);
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains(
+            "db_operation_duration_seconds_bucket{collection=\"users\",operation=\"find_by_id\",le=\"+Inf\"} 2"
+        ));
+        assert!(text.contains(
+            "db_operation_duration_seconds_count{collection=\"users\",operation=\"count\"} 1"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_instrumented_unit_of_work() {
+        let metrics = Metrics::new(Duration::from_secs(60));
+        let db: MemoryDatabase = GenericDatabase::new();
+        let unit = metrics.begin(&db).await.unwrap();
+        unit.users()
+            .insert(&User::fixture("jane@example.com"))
+            .await
+            .unwrap();
+        unit.commit().await.unwrap();
+        metrics.begin(&db).await.unwrap().rollback().await.unwrap();
+        metrics
+            .time(EVERY_COLLECTION, "apply_validation", async {
+                Err::<(), _>("failing on purpose")
+            })
+            .await
+            .unwrap_err();
+
+        let operations: Vec<_> = metrics
+            .snapshot()
+            .into_iter()
+            .map(|stats| {
+                (
+                    stats.collection,
+                    stats.operation,
+                    stats.latency.count,
+                    stats.latency.errors,
+                )
+            })
+            .collect();
+        let expected = [
+            ("*", "apply_validation", 1, 1),
+            ("transaction", "begin", 2, 0),
+            ("transaction", "commit", 1, 0),
+            ("transaction", "rollback", 1, 0),
+            ("users", "insert", 1, 0),
+        ];
+        assert_eq!(
+            operations,
+            expected.map(|(collection, operation, count, errors)| (
+                collection.to_string(),
+                operation.to_string(),
+                count,
+                errors
+            ))
+        );
+    }
+
+    #[test]
+    fn test_poisoned_metrics() {
+        let metrics = Metrics::new(Duration::from_secs(60));
+        let histograms = metrics.histograms.clone();
+        let poisoning = std::thread::spawn(move || {
+            let _histograms = histograms.lock().unwrap();
+            panic!("poisoning the histograms on purpose");
+        });
+        assert!(poisoning.join().is_err());
+
+        // A panic while recording does not stop the metrics.
+        metrics.record("users", "count", String::new, Duration::ZERO, false);
+        assert_eq!(metrics.snapshot().len(), 1);
+    }
+}
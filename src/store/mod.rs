@@ -1 +1,296 @@
+pub mod audit;
+pub mod cache;
+pub mod encryption;
+pub mod events;
+pub mod memory;
+pub mod metrics;
+pub mod mongo;
+pub mod organizations;
+pub mod pagination;
+pub mod postgre;
+pub mod purge_log;
+pub mod search;
+pub mod sql;
+pub mod sqlite;
+pub mod tenant;
 pub mod users;
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use async_trait::async_trait;
+use mongodb::bson::{oid::ObjectId, Bson};
+use thiserror::Error;
+
+use crate::models::{audit::AuditEntry, organizations::Organization, users::User, Model};
+
+pub use audit::AuditLog;
+pub use events::Events;
+pub use memory::MemoryUnitOfWork;
+pub use mongo::{MongoRepository, MongoUnitOfWork};
+pub use organizations::OrganizationStore;
+pub use postgre::{PostgreRepository, PostgreUnitOfWork};
+pub use purge_log::PurgeLog;
+pub use search::SearchHit;
+pub use sqlite::{SqliteRepository, SqliteUnitOfWork};
+pub use tenant::Tenant;
+pub use users::UserStore;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("Duplicate key: {0}")]
+    Duplicate(String),
+
+    #[error("Invalid document: {0}")]
+    InvalidDocument(String),
+
+    /// The stored version is not the expected one, it was modified concurrently.
+    #[error("Version conflict: expected {expected}, found {found}")]
+    VersionConflict { expected: i64, found: i64 },
+
+    /// The user acting on the store is not allowed to write the document.
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Store backend error: {0}")]
+    Backend(String),
+}
+
+impl From<mongodb::bson::ser::Error> for StoreError {
+    fn from(e: mongodb::bson::ser::Error) -> Self {
+        Self::InvalidDocument(e.to_string())
+    }
+}
+
+impl From<mongodb::bson::de::Error> for StoreError {
+    fn from(e: mongodb::bson::de::Error) -> Self {
+        Self::InvalidDocument(e.to_string())
+    }
+}
+
+/// A single condition of a [`Filter`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    /// The field is equal to the value.
+    Eq(String, Bson),
+    /// The field is set and lower than the value.
+    Lt(String, Bson),
+    /// The field is set and greater than the value.
+    Gt(String, Bson),
+    /// The field is a string starting with the value, case included.
+    Prefix(String, String),
+    /// At least one of the filters matches.
+    Any(Vec<Filter>),
+}
+
+/// A backend agnostic query, all conditions must match.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub conditions: Vec<Condition>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn eq(mut self, field: &str, value: impl Into<Bson>) -> Self {
+        self.conditions
+            .push(Condition::Eq(field.to_string(), value.into()));
+        self
+    }
+
+    pub fn lt(mut self, field: &str, value: impl Into<Bson>) -> Self {
+        self.conditions
+            .push(Condition::Lt(field.to_string(), value.into()));
+        self
+    }
+
+    pub fn gt(mut self, field: &str, value: impl Into<Bson>) -> Self {
+        self.conditions
+            .push(Condition::Gt(field.to_string(), value.into()));
+        self
+    }
+
+    pub fn starts_with(mut self, field: &str, prefix: &str) -> Self {
+        self.conditions
+            .push(Condition::Prefix(field.to_string(), prefix.to_string()));
+        self
+    }
+
+    pub fn any(mut self, filters: Vec<Filter>) -> Self {
+        self.conditions.push(Condition::Any(filters));
+        self
+    }
+
+    /// Adds the conditions of another filter, both must match.
+    pub fn and(mut self, other: Filter) -> Self {
+        self.conditions.extend(other.conditions);
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+/// Sorting and paging of the results of a [`Filter`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FindOptions {
+    /// Fields to sort by, by decreasing priority.
+    pub sort: Vec<(String, Order)>,
+    pub skip: u64,
+    pub limit: Option<u64>,
+}
+
+impl FindOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sort(mut self, field: &str, order: Order) -> Self {
+        self.sort.push((field.to_string(), order));
+        self
+    }
+
+    pub fn skip(mut self, skip: u64) -> Self {
+        self.skip = skip;
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// Storage of one kind of [`crate::models::Model`], implemented for each database backend.
+#[async_trait]
+pub trait Repository<T: Model>: Send + Sync {
+    async fn insert(&self, item: &T) -> Result<(), StoreError>;
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<T>, StoreError>;
+    async fn find_one(&self, filter: &Filter) -> Result<Option<T>, StoreError>;
+    async fn find_with(&self, filter: &Filter, options: &FindOptions)
+        -> Result<Vec<T>, StoreError>;
+    async fn count(&self, filter: &Filter) -> Result<u64, StoreError>;
+    /// Replaces the stored document having the same id and matching the filter,
+    /// atomically, returns false if there is none.
+    async fn update_where(&self, item: &T, filter: &Filter) -> Result<bool, StoreError>;
+    /// Returns false if there was no document with this id.
+    async fn delete(&self, id: &ObjectId) -> Result<bool, StoreError>;
+
+    async fn find(&self, filter: &Filter) -> Result<Vec<T>, StoreError> {
+        self.find_with(filter, &FindOptions::new()).await
+    }
+
+    /// Inserts the items in as few writes as the backend allows and returns the outcome of each
+    /// one, the items failing do not prevent the others from being inserted.
+    async fn insert_many(&self, items: &[T]) -> Vec<Result<(), StoreError>> {
+        let mut results = Vec::with_capacity(items.len());
+        for item in items {
+            results.push(self.insert(item).await);
+        }
+        results
+    }
+
+    /// Replaces each item as [`Repository::update_where`] with its filter, in as few writes as the
+    /// backend allows, and returns the outcome of each one, the items failing do not prevent the
+    /// others from being replaced.
+    async fn update_many(&self, items: &[(T, Filter)]) -> Vec<Result<bool, StoreError>> {
+        let mut results = Vec::with_capacity(items.len());
+        for (item, filter) in items {
+            results.push(self.update_where(item, filter).await);
+        }
+        results
+    }
+
+    /// Deletes the documents with the ids in as few writes as the backend allows, and returns
+    /// for each one whether it was deleted.
+    async fn delete_many(&self, ids: &[ObjectId]) -> Vec<Result<bool, StoreError>> {
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            results.push(self.delete(id).await);
+        }
+        results
+    }
+
+    /// Replaces the stored document having the same id, returns false if there is none.
+    async fn update(&self, item: &T) -> Result<bool, StoreError> {
+        self.update_where(item, &Filter::new()).await
+    }
+
+    async fn list(&self) -> Result<Vec<T>, StoreError> {
+        self.find(&Filter::new()).await
+    }
+
+    /// The items matching the filter and at least one word of the query in their
+    /// [`Model::SEARCH_FIELDS`], the most relevant first.
+    async fn search(
+        &self,
+        query: &str,
+        filter: &Filter,
+        limit: u64,
+    ) -> Result<Vec<SearchHit<T>>, StoreError> {
+        search::scan(self, query, filter, limit).await
+    }
+}
+
+/// An action run once a unit of work is committed.
+pub type AfterCommit = Box<dyn FnOnce() + Send>;
+
+/// The actions queued on a unit of work, run once it is committed and dropped otherwise,
+/// such as the change events and the cache invalidations of the writes it makes.
+#[derive(Clone, Default)]
+pub struct CommitHooks(Arc<Mutex<Vec<AfterCommit>>>);
+
+impl CommitHooks {
+    pub fn push(&self, action: AfterCommit) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(action);
+    }
+
+    /// Runs the queued actions, in the order they were queued, to be called once committed.
+    pub fn run(&self) {
+        let actions = std::mem::take(&mut *self.0.lock().unwrap_or_else(PoisonError::into_inner));
+        for action in actions {
+            action();
+        }
+    }
+}
+
+/// Writes grouped in a single transaction, started by [`crate::drivers::GenericDatabase::begin`].
+/// The repositories it hands out read and write inside the transaction.
+/// Dropping it without committing rolls it back.
+#[async_trait]
+pub trait UnitOfWork: Send + Sync {
+    fn users(&self) -> Arc<dyn Repository<User>>;
+    fn organizations(&self) -> Arc<dyn Repository<Organization>>;
+    fn audit_log(&self) -> Arc<dyn Repository<AuditEntry>>;
+    /// The actions run by [`UnitOfWork::commit`] once it succeeds.
+    fn commit_hooks(&self) -> CommitHooks;
+    async fn commit(self: Box<Self>) -> Result<(), StoreError>;
+    async fn rollback(self: Box<Self>) -> Result<(), StoreError>;
+}
+
+/// Runs `work` in the unit of work, commits it if `work` succeeds and rolls it back otherwise.
+pub async fn transaction<R, E, F>(unit: Box<dyn UnitOfWork>, work: F) -> Result<R, E>
+where
+    E: From<StoreError>,
+    F: AsyncFnOnce(&dyn UnitOfWork) -> Result<R, E>,
+{
+    match work(unit.as_ref()).await {
+        Ok(result) => {
+            unit.commit().await?;
+            Ok(result)
+        }
+        Err(error) => {
+            if let Err(rollback_error) = unit.rollback().await {
+                log::error!("Failed to roll back transaction: {rollback_error:?}");
+            }
+            Err(error)
+        }
+    }
+}
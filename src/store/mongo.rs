@@ -0,0 +1,563 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Bson, Document},
+    error::{BulkWriteError, ErrorKind, InsertManyError, PartialBulkWriteResult, WriteFailure},
+    options::{DeleteOneModel, WriteModel},
+    results::VerboseBulkWriteResult,
+    Client, ClientSession, Collection,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    models::{audit::AuditEntry, organizations::Organization, users::User, Model},
+    store::{
+        search, CommitHooks, Condition, Filter, FindOptions, Order, Repository, SearchHit,
+        StoreError, UnitOfWork,
+    },
+};
+
+/// The error code MongoDB returns when a unique index is violated.
+const DUPLICATE_KEY_CODE: i32 = 11000;
+/// The error code MongoDB returns when a document violates the schema of its collection.
+const DOCUMENT_VALIDATION_FAILURE_CODE: i32 = 121;
+
+fn write_error(code: i32, message: &str) -> StoreError {
+    match code {
+        DUPLICATE_KEY_CODE => StoreError::Duplicate(message.to_string()),
+        DOCUMENT_VALIDATION_FAILURE_CODE => StoreError::InvalidDocument(message.to_string()),
+        _ => StoreError::Backend(message.to_string()),
+    }
+}
+
+impl From<mongodb::error::Error> for StoreError {
+    fn from(error: mongodb::error::Error) -> Self {
+        match *error.kind {
+            ErrorKind::Write(WriteFailure::WriteError(ref e)) => write_error(e.code, &e.message),
+            _ => Self::Backend(error.to_string()),
+        }
+    }
+}
+
+/// Where the text score is projected, next to the fields of the model.
+const SCORE_FIELD: &str = "_text_score";
+
+/// Adds a condition on a field to the query document. A field can only appear once in a document,
+/// the conditions on a field already constrained go to `$and` rather than overwrite it.
+fn add_condition(document: &mut Document, field: &str, condition: Bson) {
+    if !document.contains_key(field) && field != "$and" {
+        document.insert(field, condition);
+        return;
+    }
+    let mut all = match document.remove("$and") {
+        Some(Bson::Array(all)) => all,
+        _ => Vec::new(),
+    };
+    all.push(Bson::Document(doc! { field: condition }));
+    document.insert("$and", all);
+}
+
+/// Converts a [`Filter`] to a MongoDB query document.
+pub fn filter_document(filter: &Filter) -> Document {
+    let mut document = Document::new();
+    for condition in &filter.conditions {
+        match condition {
+            Condition::Eq(field, value) => add_condition(&mut document, field, value.clone()),
+            Condition::Lt(field, value) => {
+                add_condition(&mut document, field, doc! { "$lt": value.clone() }.into())
+            }
+            Condition::Gt(field, value) => {
+                add_condition(&mut document, field, doc! { "$gt": value.clone() }.into())
+            }
+            Condition::Prefix(field, prefix) => add_condition(
+                &mut document,
+                field,
+                doc! { "$regex": format!("^{}", escape_regex(prefix)) }.into(),
+            ),
+            // $or refuses an empty array, and then nothing matches.
+            Condition::Any(filters) if filters.is_empty() => {
+                add_condition(&mut document, "_id", doc! { "$exists": false }.into())
+            }
+            // Several $or conditions would overwrite each other.
+            Condition::Any(filters) => add_condition(
+                &mut document,
+                "$or",
+                filters
+                    .iter()
+                    .map(filter_document)
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+        }
+    }
+    document
+}
+
+/// Escapes the characters having a meaning in a regular expression.
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+pub struct MongoRepository<T: Model> {
+    collection: Collection<T>,
+    /// The session of the [`MongoUnitOfWork`] the repository belongs to, if any.
+    session: Option<Arc<Mutex<ClientSession>>>,
+}
+
+impl<T: Model> MongoRepository<T> {
+    pub fn new(client: &Client, db_name: &str) -> Self {
+        MongoRepository {
+            collection: client.database(db_name).collection(T::REPOSITORY_NAME),
+            session: None,
+        }
+    }
+
+    /// Runs the writes in a single unordered bulk write, or one by one before MongoDB 8.0, and
+    /// returns for each one whether it matched a document.
+    async fn bulk_write(
+        &self,
+        models: Vec<Result<WriteModel, StoreError>>,
+    ) -> Vec<Result<bool, StoreError>> {
+        let valid: Vec<WriteModel> = models
+            .iter()
+            .filter_map(|model| model.as_ref().ok())
+            .cloned()
+            .collect();
+        let mut written = self.bulk_write_valid(valid).await.into_iter();
+        models
+            .into_iter()
+            .map(|model| model.and_then(|_| written.next().unwrap_or(Ok(false))))
+            .collect()
+    }
+
+    async fn bulk_write_valid(&self, models: Vec<WriteModel>) -> Vec<Result<bool, StoreError>> {
+        let count = models.len();
+        if count == 0 {
+            return Vec::new();
+        }
+        let write = self
+            .collection
+            .client()
+            .bulk_write(models.clone())
+            .ordered(false)
+            .verbose_results();
+        let result = match &self.session {
+            Some(session) => write.session(&mut *session.lock().await).await,
+            None => write.await,
+        };
+        let (written, write_errors) = match result {
+            Ok(written) => (written, HashMap::new()),
+            // Refused before anything is sent.
+            Err(error) if matches!(*error.kind, ErrorKind::IncompatibleServer { .. }) => {
+                return self.write_each(models).await
+            }
+            Err(error) => match *error.kind {
+                ErrorKind::BulkWrite(BulkWriteError {
+                    ref write_errors,
+                    ref write_concern_errors,
+                    ref partial_result,
+                    ..
+                }) if write_concern_errors.is_empty() => {
+                    let written = match partial_result {
+                        Some(PartialBulkWriteResult::Verbose(written)) => written.clone(),
+                        _ => VerboseBulkWriteResult::default(),
+                    };
+                    (written, write_errors.clone())
+                }
+                // Nothing tells which writes were performed.
+                _ => {
+                    return (0..count)
+                        .map(|_| Err(StoreError::Backend(error.to_string())))
+                        .collect()
+                }
+            },
+        };
+        (0..count)
+            .map(|index| {
+                if let Some(failure) = write_errors.get(&index) {
+                    return Err(write_error(failure.code, &failure.message));
+                }
+                if let Some(updated) = written.update_results.get(&index) {
+                    return Ok(updated.matched_count > 0);
+                }
+                if let Some(deleted) = written.delete_results.get(&index) {
+                    return Ok(deleted.deleted_count > 0);
+                }
+                Err(StoreError::Backend(format!(
+                    "Write {index} of the batch was not performed"
+                )))
+            })
+            .collect()
+    }
+
+    /// Runs the writes one at a time, for the servers not supporting bulk writes.
+    async fn write_each(&self, models: Vec<WriteModel>) -> Vec<Result<bool, StoreError>> {
+        let collection = self.collection.clone_with_type::<Document>();
+        let mut results = Vec::with_capacity(models.len());
+        for model in models {
+            results.push(match model {
+                WriteModel::ReplaceOne(model) => {
+                    let replace = collection.replace_one(model.filter, model.replacement);
+                    match &self.session {
+                        Some(session) => replace.session(&mut *session.lock().await).await,
+                        None => replace.await,
+                    }
+                    .map(|replaced| replaced.matched_count > 0)
+                    .map_err(StoreError::from)
+                }
+                WriteModel::DeleteOne(model) => {
+                    let delete = collection.delete_one(model.filter);
+                    match &self.session {
+                        Some(session) => delete.session(&mut *session.lock().await).await,
+                        None => delete.await,
+                    }
+                    .map(|deleted| deleted.deleted_count > 0)
+                    .map_err(StoreError::from)
+                }
+                model => Err(StoreError::Backend(format!("Unsupported write {model:?}"))),
+            });
+        }
+        results
+    }
+}
+
+#[async_trait]
+impl<T: Model> Repository<T> for MongoRepository<T> {
+    async fn insert(&self, item: &T) -> Result<(), StoreError> {
+        let insert = self.collection.insert_one(item);
+        match &self.session {
+            Some(session) => insert.session(&mut *session.lock().await).await?,
+            None => insert.await?,
+        };
+        Ok(())
+    }
+
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<T>, StoreError> {
+        self.find_one(&Filter::new().eq("_id", *id)).await
+    }
+
+    // A single unordered write, the items following a failing one are still inserted.
+    async fn insert_many(&self, items: &[T]) -> Vec<Result<(), StoreError>> {
+        if items.is_empty() {
+            return Vec::new();
+        }
+        let insert = self.collection.insert_many(items).ordered(false);
+        let result = match &self.session {
+            Some(session) => insert.session(&mut *session.lock().await).await,
+            None => insert.await,
+        };
+        let Err(error) = result else {
+            return items.iter().map(|_| Ok(())).collect();
+        };
+        match *error.kind {
+            ErrorKind::InsertMany(InsertManyError {
+                write_errors: Some(ref write_errors),
+                write_concern_error: None,
+                ..
+            }) => {
+                let mut results: Vec<_> = items.iter().map(|_| Ok(())).collect();
+                for failure in write_errors {
+                    results[failure.index] = Err(write_error(failure.code, &failure.message));
+                }
+                results
+            }
+            // Nothing tells which items were written.
+            _ => items
+                .iter()
+                .map(|_| Err(StoreError::Backend(error.to_string())))
+                .collect(),
+        }
+    }
+
+    async fn update_many(&self, items: &[(T, Filter)]) -> Vec<Result<bool, StoreError>> {
+        let models = items
+            .iter()
+            .map(|(item, filter)| {
+                let query = filter_document(&filter.clone().eq("_id", item.id()));
+                Ok(self.collection.replace_one_model(query, item)?.into())
+            })
+            .collect();
+        self.bulk_write(models).await
+    }
+
+    async fn delete_many(&self, ids: &[ObjectId]) -> Vec<Result<bool, StoreError>> {
+        let models = ids
+            .iter()
+            .map(|id| {
+                Ok(DeleteOneModel::builder()
+                    .namespace(self.collection.namespace())
+                    .filter(doc! { "_id": id })
+                    .build()
+                    .into())
+            })
+            .collect();
+        self.bulk_write(models).await
+    }
+
+    async fn find_one(&self, filter: &Filter) -> Result<Option<T>, StoreError> {
+        let find = self.collection.find_one(filter_document(filter));
+        Ok(match &self.session {
+            Some(session) => find.session(&mut *session.lock().await).await?,
+            None => find.await?,
+        })
+    }
+
+    async fn find_with(
+        &self,
+        filter: &Filter,
+        options: &FindOptions,
+    ) -> Result<Vec<T>, StoreError> {
+        let mut sort = Document::new();
+        for (field, order) in &options.sort {
+            let direction = match order {
+                Order::Ascending => 1,
+                Order::Descending => -1,
+            };
+            sort.insert(field.clone(), direction);
+        }
+        let find = self
+            .collection
+            .find(filter_document(filter))
+            .sort(sort)
+            .skip(options.skip)
+            .limit(options.limit.map_or(0, |limit| limit as i64));
+        Ok(match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                let mut cursor = find.session(&mut *session).await?;
+                cursor.stream(&mut session).try_collect().await?
+            }
+            None => find.await?.try_collect().await?,
+        })
+    }
+
+    async fn count(&self, filter: &Filter) -> Result<u64, StoreError> {
+        let count = self.collection.count_documents(filter_document(filter));
+        Ok(match &self.session {
+            Some(session) => count.session(&mut *session.lock().await).await?,
+            None => count.await?,
+        })
+    }
+
+    async fn update_where(&self, item: &T, filter: &Filter) -> Result<bool, StoreError> {
+        let query = filter_document(&filter.clone().eq("_id", item.id()));
+        let replace = self.collection.replace_one(query, item);
+        let result = match &self.session {
+            Some(session) => replace.session(&mut *session.lock().await).await?,
+            None => replace.await?,
+        };
+        Ok(result.matched_count > 0)
+    }
+
+    async fn delete(&self, id: &ObjectId) -> Result<bool, StoreError> {
+        let delete = self.collection.delete_one(doc! { "_id": id });
+        let result = match &self.session {
+            Some(session) => delete.session(&mut *session.lock().await).await?,
+            None => delete.await?,
+        };
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Uses the text index of the collection, declared in [`Model::INDEXES`].
+    async fn search(
+        &self,
+        query: &str,
+        filter: &Filter,
+        limit: u64,
+    ) -> Result<Vec<SearchHit<T>>, StoreError> {
+        let Some(text_search) = text_search(query) else {
+            return Ok(Vec::new());
+        };
+        let mut text_filter = filter_document(filter);
+        text_filter.insert("$text", doc! { "$search": text_search });
+        let score = doc! { "$meta": "textScore" };
+        let collection = self.collection.clone_with_type::<Document>();
+        let find = collection
+            .find(text_filter)
+            .projection(doc! { SCORE_FIELD: score.clone() })
+            .sort(doc! { SCORE_FIELD: score })
+            .limit(limit as i64);
+        let documents: Vec<Document> = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                let mut cursor = find.session(&mut *session).await?;
+                cursor.stream(&mut session).try_collect().await?
+            }
+            None => find.await?.try_collect().await?,
+        };
+        documents
+            .into_iter()
+            .map(|mut document| {
+                let score = match document.remove(SCORE_FIELD) {
+                    Some(Bson::Double(score)) => score,
+                    _ => 0.0,
+                };
+                Ok(SearchHit {
+                    item: bson::from_document(document)?,
+                    score,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The `$search` of a `$text` query: the words of the query, any of which matches.
+/// Only their letters and digits are kept, so that quotes and dashes do not make phrases and
+/// negations. Unlike the other backends, the text index only matches whole words, once
+/// stemmed: `jan` does not match Jane, `janes` does.
+fn text_search(query: &str) -> Option<String> {
+    let terms = search::terms(query);
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// A MongoDB multi-document transaction, which needs a replica set or a sharded cluster.
+/// On a standalone server the writes are made at once, and are not undone by a rollback.
+pub struct MongoUnitOfWork {
+    client: Client,
+    db_name: String,
+    /// None on a standalone server.
+    session: Option<Arc<Mutex<ClientSession>>>,
+    hooks: CommitHooks,
+}
+
+impl MongoUnitOfWork {
+    /// Begins a transaction, or only groups the writes when not `transactional`.
+    pub async fn begin(
+        client: &Client,
+        db_name: &str,
+        transactional: bool,
+    ) -> Result<Self, StoreError> {
+        let session = match transactional {
+            true => {
+                let mut session = client.start_session().await?;
+                session.start_transaction().await?;
+                Some(Arc::new(Mutex::new(session)))
+            }
+            false => None,
+        };
+        Ok(MongoUnitOfWork {
+            client: client.clone(),
+            db_name: db_name.to_string(),
+            session,
+            hooks: CommitHooks::default(),
+        })
+    }
+
+    fn repository<T: Model>(&self) -> Arc<dyn Repository<T>> {
+        Arc::new(MongoRepository {
+            collection: self
+                .client
+                .database(&self.db_name)
+                .collection(T::REPOSITORY_NAME),
+            session: self.session.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for MongoUnitOfWork {
+    fn users(&self) -> Arc<dyn Repository<User>> {
+        self.repository()
+    }
+
+    fn organizations(&self) -> Arc<dyn Repository<Organization>> {
+        self.repository()
+    }
+
+    fn audit_log(&self) -> Arc<dyn Repository<AuditEntry>> {
+        self.repository()
+    }
+
+    fn commit_hooks(&self) -> CommitHooks {
+        self.hooks.clone()
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), StoreError> {
+        if let Some(session) = &self.session {
+            session.lock().await.commit_transaction().await?;
+        }
+        self.hooks.run();
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), StoreError> {
+        match &self.session {
+            Some(session) => Ok(session.lock().await.abort_transaction().await?),
+            None => {
+                log::warn!("The writes made on a standalone MongoDB server are not rolled back");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_search() {
+        assert_eq!(
+            text_search(r#""Mary Jane" -Doe jane@example.com"#).as_deref(),
+            Some("com doe example jane mary")
+        );
+        assert_eq!(text_search(r#" "-" "#), None);
+    }
+
+    #[test]
+    fn test_filter_document() {
+        let id = ObjectId::new();
+        let filter = Filter::new()
+            .eq("deleted_at", Bson::Null)
+            .starts_with("email", "jane.")
+            .gt("version", 1_i64);
+        assert_eq!(
+            filter_document(&filter),
+            doc! {
+                "deleted_at": Bson::Null,
+                "email": { "$regex": "^jane\\." },
+                "version": { "$gt": 1_i64 },
+            }
+        );
+        assert_eq!(
+            filter_document(&Filter::new().any(vec![])),
+            doc! { "_id": { "$exists": false } }
+        );
+
+        // The tenant condition is kept when the request constrains the same field.
+        let org_id = ObjectId::new();
+        let filter = Filter::new()
+            .eq("org_id", org_id)
+            .eq("org_id", ObjectId::new());
+        let document = filter_document(&filter);
+        assert_eq!(document.get("org_id"), Some(&Bson::ObjectId(org_id)));
+        assert_eq!(document.get_array("$and").unwrap().len(), 1);
+        let filter = Filter::new()
+            .eq("_id", org_id)
+            .eq("_id", id)
+            .lt("_id", id)
+            .any(vec![Filter::new().eq("role", "admin")])
+            .any(vec![Filter::new().eq("role", "user")]);
+        assert_eq!(
+            filter_document(&filter),
+            doc! {
+                "_id": org_id,
+                "$and": [
+                    { "_id": id },
+                    { "_id": { "$lt": id } },
+                    { "$or": [{ "role": "user" }] },
+                ],
+                "$or": [{ "role": "admin" }],
+            }
+        );
+    }
+}
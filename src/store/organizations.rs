@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use mongodb::bson::oid::ObjectId;
+
+use crate::{
+    models::organizations::{Organization, REPOSITORY_NAME},
+    store::{
+        events::{ChangeEvent, ChangeKind, Events},
+        pagination::{self, Page, PageRequest},
+        tenant::Tenant,
+        CommitHooks, Filter, Repository, StoreError, UnitOfWork,
+    },
+};
+
+/// Organizations persistence, independent of the database backend.
+/// Every change is published as a [`ChangeEvent`].
+/// A store scoped to a [`Tenant`] only reaches the organization of the tenant.
+#[derive(Clone)]
+pub struct OrganizationStore {
+    repository: Arc<dyn Repository<Organization>>,
+    events: Events,
+    tenant: Tenant,
+    /// The hooks of the unit of work the store writes in, which publish the events once
+    /// committed.
+    commit_hooks: Option<CommitHooks>,
+}
+
+impl OrganizationStore {
+    pub fn new(repository: Arc<dyn Repository<Organization>>) -> Self {
+        OrganizationStore {
+            repository,
+            events: Events::default(),
+            tenant: Tenant::Any,
+            commit_hooks: None,
+        }
+    }
+
+    /// The same store, limited to the organization of the tenant.
+    pub fn scoped(&self, tenant: Tenant) -> Self {
+        OrganizationStore {
+            tenant,
+            ..self.clone()
+        }
+    }
+
+    /// The same store, reading and writing in the unit of work.
+    pub fn in_unit(&self, unit: &dyn UnitOfWork) -> Self {
+        OrganizationStore {
+            repository: unit.organizations(),
+            commit_hooks: Some(unit.commit_hooks()),
+            ..self.clone()
+        }
+    }
+
+    fn visible(&self) -> Filter {
+        self.tenant.filter("_id")
+    }
+
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Publishes the change once the unit of work the store writes in is committed, at once
+    /// outside of a unit of work.
+    fn publish(&self, kind: ChangeKind, id: ObjectId) {
+        let event = ChangeEvent::new(REPOSITORY_NAME, kind, id, Some(id), None);
+        match &self.commit_hooks {
+            Some(hooks) => {
+                let events = self.events.clone();
+                hooks.push(Box::new(move || events.publish(event)));
+            }
+            None => self.events.publish(event),
+        }
+    }
+
+    pub async fn create(&self, organization: &Organization) -> Result<(), StoreError> {
+        self.repository.insert(organization).await?;
+        self.publish(ChangeKind::Created, organization._id);
+        Ok(())
+    }
+
+    pub async fn find_by_id(&self, id: &ObjectId) -> Result<Option<Organization>, StoreError> {
+        self.repository
+            .find_one(&self.visible().eq("_id", *id))
+            .await
+    }
+
+    pub async fn exists(&self, id: &ObjectId) -> Result<bool, StoreError> {
+        Ok(self
+            .repository
+            .count(&self.visible().eq("_id", *id))
+            .await?
+            > 0)
+    }
+
+    /// Lists the organizations, see [`pagination::find_page`].
+    pub async fn page(&self, request: &PageRequest) -> Result<Page<Organization>, StoreError> {
+        pagination::find_page(self.repository.as_ref(), &self.visible(), request).await
+    }
+
+    /// Replaces the organization, returns false if there is no such organization.
+    pub async fn update(&self, organization: &Organization) -> Result<bool, StoreError> {
+        let updated = self
+            .repository
+            .update_where(organization, &self.visible())
+            .await?;
+        if updated {
+            self.publish(ChangeKind::Updated, organization._id);
+        }
+        Ok(updated)
+    }
+
+    /// Deletes the organization, its members are left to the caller.
+    pub async fn delete(&self, id: &ObjectId) -> Result<bool, StoreError> {
+        if !self.exists(id).await? {
+            return Ok(false);
+        }
+        let deleted = self.repository.delete(id).await?;
+        if deleted {
+            self.publish(ChangeKind::Purged, *id);
+        }
+        Ok(deleted)
+    }
+}
+
+impl std::fmt::Debug for OrganizationStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OrganizationStore").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{
+        memory::{Collections, MemoryRepository},
+        MemoryUnitOfWork,
+    };
+
+    #[actix_web::test]
+    async fn test_delete_publishes_once_committed() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(16);
+        let collections = Collections::default();
+        let organizations = OrganizationStore::new(Arc::new(
+            MemoryRepository::<Organization>::new(&collections),
+        ))
+        .with_events(Events::new(sender));
+        let acme = Organization {
+            _id: ObjectId::new(),
+            name: "Acme".into(),
+            created_at: mongodb::bson::DateTime::now(),
+        };
+        organizations.create(&acme).await.unwrap();
+        receiver.try_recv().unwrap();
+
+        let unit = MemoryUnitOfWork::begin(&collections).unwrap();
+        assert!(organizations
+            .in_unit(&unit)
+            .delete(&acme._id)
+            .await
+            .unwrap());
+        Box::new(unit).rollback().await.unwrap();
+        assert!(receiver.try_recv().is_err());
+        assert!(organizations.exists(&acme._id).await.unwrap());
+
+        let unit = MemoryUnitOfWork::begin(&collections).unwrap();
+        assert!(organizations
+            .in_unit(&unit)
+            .delete(&acme._id)
+            .await
+            .unwrap());
+        assert!(receiver.try_recv().is_err());
+        Box::new(unit).commit().await.unwrap();
+        let event = receiver.try_recv().unwrap();
+        assert_eq!((event.kind, event.id), (ChangeKind::Purged, acme._id));
+        assert!(!organizations.exists(&acme._id).await.unwrap());
+    }
+}
@@ -0,0 +1,257 @@
+//! Cursor-based pagination over any [`Repository`].
+//! Items are sorted by one field then by `_id`, a page starts right after the last item
+//! of the previous one, so inserts and deletes between two requests neither skip nor repeat items.
+
+use mongodb::bson::{self, doc, oid::ObjectId, Bson, Document};
+
+use crate::{
+    models::{FieldKind, Model},
+    store::{sql, Filter, FindOptions, Order, Repository, StoreError},
+};
+
+/// Where a page starts: the sort value and the id of the last item of the previous page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cursor {
+    pub field: String,
+    pub value: Bson,
+    pub id: ObjectId,
+}
+
+impl Cursor {
+    fn after<T: Model>(item: &T, field: &str) -> Result<Self, StoreError> {
+        let document = bson::to_document(item)?;
+        Ok(Cursor {
+            field: field.to_string(),
+            value: document.get(field).cloned().unwrap_or(Bson::Null),
+            id: item.id(),
+        })
+    }
+
+    /// The opaque representation handed to clients, hex encoded BSON.
+    pub fn encode(&self) -> String {
+        let document = doc! { "f": &self.field, "v": self.value.clone(), "id": self.id };
+        let mut bytes = Vec::new();
+        document
+            .to_writer(&mut bytes)
+            .expect("writing to a vector should succeed");
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// None if it was not made by [`Cursor::encode`].
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let bytes = (0..encoded.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(encoded.get(index..index + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let document = Document::from_reader(bytes.as_slice()).ok()?;
+        Some(Cursor {
+            field: document.get_str("f").ok()?.to_string(),
+            value: document.get("v")?.clone(),
+            id: document.get_object_id("id").ok()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageRequest {
+    /// The field to sort by, it must not be nullable.
+    pub sort: String,
+    pub order: Order,
+    /// None for the first page.
+    pub after: Option<Cursor>,
+    pub limit: u64,
+    /// Whether to count every item matching the filter, an extra query.
+    pub with_total: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// None on the last page.
+    pub next: Option<Cursor>,
+    pub total: Option<u64>,
+}
+
+/// Returns the page of items matching the filter described by the request.
+/// Fails with [`StoreError::InvalidDocument`] if the sort field cannot be used,
+/// or if the cursor was made for another one or holds a value of another kind.
+pub async fn find_page<T: Model>(
+    repository: &dyn Repository<T>,
+    filter: &Filter,
+    request: &PageRequest,
+) -> Result<Page<T>, StoreError> {
+    let field = sql::field::<T>(&request.sort)?;
+    // Null values would need their own ordering rules on each backend.
+    if field.nullable {
+        return Err(StoreError::InvalidDocument(format!(
+            "Cannot paginate on the nullable field {}",
+            request.sort
+        )));
+    }
+    let mut page_filter = filter.clone();
+    if let Some(cursor) = &request.after {
+        if cursor.field != request.sort {
+            return Err(StoreError::InvalidDocument(format!(
+                "The cursor is for a sort on {}, not {}",
+                cursor.field, request.sort
+            )));
+        }
+        // The value goes as it is into the filter, where a document would be an operator.
+        if !fits(field.kind, &cursor.value) {
+            return Err(StoreError::InvalidDocument(format!(
+                "The cursor value does not fit the field {}",
+                request.sort
+            )));
+        }
+        page_filter = page_filter.any(beyond(cursor, request.order));
+    }
+    let mut options = FindOptions::new().sort(&request.sort, request.order);
+    if request.sort != "_id" {
+        options = options.sort("_id", request.order);
+    }
+    // One more item tells whether there is a next page.
+    let mut items = repository
+        .find_with(&page_filter, &options.limit(request.limit + 1))
+        .await?;
+    let mut next = None;
+    if items.len() as u64 > request.limit {
+        items.truncate(request.limit as usize);
+        if let Some(last) = items.last() {
+            next = Some(Cursor::after(last, &request.sort)?);
+        }
+    }
+    let total = match request.with_total {
+        true => Some(repository.count(filter).await?),
+        false => None,
+    };
+    Ok(Page { items, next, total })
+}
+
+/// Whether the value is of the kind of the field.
+fn fits(kind: FieldKind, value: &Bson) -> bool {
+    matches!(
+        (kind, value),
+        (FieldKind::ObjectId, Bson::ObjectId(_))
+            | (FieldKind::Text, Bson::String(_))
+            | (FieldKind::Integer, Bson::Int32(_) | Bson::Int64(_))
+            | (FieldKind::Boolean, Bson::Boolean(_))
+            | (FieldKind::DateTime, Bson::DateTime(_))
+    )
+}
+
+/// The alternatives matching the items sorted after the cursor.
+fn beyond(cursor: &Cursor, order: Order) -> Vec<Filter> {
+    let past = |filter: Filter, field: &str, value: Bson| match order {
+        Order::Ascending => filter.gt(field, value),
+        Order::Descending => filter.lt(field, value),
+    };
+    if cursor.field == "_id" {
+        return vec![past(Filter::new(), "_id", cursor.id.into())];
+    }
+    vec![
+        past(Filter::new(), &cursor.field, cursor.value.clone()),
+        past(
+            Filter::new().eq(&cursor.field, cursor.value.clone()),
+            "_id",
+            cursor.id.into(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::users::User,
+        store::memory::{Collections, MemoryRepository},
+    };
+
+    fn user(first_name: &str, last_name: &str) -> User {
+        User {
+            first_name: first_name.into(),
+            last_name: last_name.into(),
+            ..User::fixture(&format!("{first_name}.{last_name}@example.com").to_lowercase())
+        }
+    }
+
+    #[test]
+    fn test_cursor_encoding() {
+        let cursor = Cursor {
+            field: "last_name".into(),
+            value: "Doe".into(),
+            id: ObjectId::new(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(Cursor::decode("not a cursor"), None);
+        assert_eq!(Cursor::decode("abc"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_find_page() {
+        let repository = MemoryRepository::<User>::new(&Collections::default());
+        for (first_name, last_name) in [
+            ("Jane", "Doe"),
+            ("John", "Doe"),
+            ("Ada", "Lovelace"),
+            ("Alan", "Turing"),
+            ("Grace", "Hopper"),
+        ] {
+            repository
+                .insert(&user(first_name, last_name))
+                .await
+                .unwrap();
+        }
+
+        let mut request = PageRequest {
+            sort: "last_name".into(),
+            order: Order::Descending,
+            after: None,
+            limit: 2,
+            with_total: true,
+        };
+        let mut last_names = Vec::new();
+        loop {
+            let page = find_page(&repository, &Filter::new(), &request)
+                .await
+                .unwrap();
+            assert_eq!(page.total, Some(5));
+            assert!(page.items.len() <= 2);
+            last_names.extend(page.items.into_iter().map(|user| user.last_name));
+            match page.next {
+                Some(next) => request.after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(last_names, ["Turing", "Lovelace", "Hopper", "Doe", "Doe"]);
+
+        let filter = Filter::new().starts_with("first_name", "A");
+        request.after = None;
+        let page = find_page(&repository, &filter, &request).await.unwrap();
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.next, None);
+
+        request.sort = "org_id".into();
+        assert!(find_page(&repository, &filter, &request).await.is_err());
+        request.sort = "first_name".into();
+        request.after = Some(Cursor::after(&page.items[0], "last_name").unwrap());
+        assert!(find_page(&repository, &filter, &request).await.is_err());
+
+        // A value of another kind, such as an operator, is refused.
+        for (sort, value) in [
+            ("last_name", Bson::Document(doc! { "$ne": Bson::Null })),
+            ("last_name", Bson::Int32(1)),
+            ("_id", Bson::String("Doe".into())),
+        ] {
+            request.sort = sort.into();
+            request.after = Some(Cursor {
+                field: sort.into(),
+                value,
+                id: page.items[0]._id,
+            });
+            assert!(matches!(
+                find_page(&repository, &filter, &request).await,
+                Err(StoreError::InvalidDocument(_))
+            ));
+        }
+    }
+}
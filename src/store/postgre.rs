@@ -0,0 +1,362 @@
+use std::{marker::PhantomData, sync::Arc};
+
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use sqlx::{
+    postgres::{PgArguments, PgConnection, PgPool, PgQueryResult, PgRow},
+    query::Query,
+    Connection, Postgres, Row, Transaction,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    models::{audit::AuditEntry, organizations::Organization, users::User, FieldKind, Model},
+    store::{
+        search,
+        sql::{self, SqlValue},
+        CommitHooks, Filter, FindOptions, Repository, SearchHit, StoreError, UnitOfWork,
+    },
+};
+
+/// A transaction shared by the repositories of a [`PostgreUnitOfWork`],
+/// None once committed or rolled back.
+type SharedTransaction = Arc<Mutex<Option<Transaction<'static, Postgres>>>>;
+
+/// Where the statements of a repository are executed.
+#[derive(Clone)]
+enum Executor {
+    Pool(PgPool),
+    Transaction(SharedTransaction),
+}
+
+pub fn bind(
+    query: Query<'_, Postgres, PgArguments>,
+    value: SqlValue,
+) -> Query<'_, Postgres, PgArguments> {
+    match value {
+        SqlValue::Text(text) => query.bind(text),
+        SqlValue::Integer(integer) => query.bind(integer),
+        SqlValue::Boolean(boolean) => query.bind(boolean),
+    }
+}
+
+pub fn read_row<T: Model>(row: &PgRow) -> Result<T, StoreError> {
+    let values = T::FIELDS
+        .iter()
+        .map(|field| {
+            Ok(match field.kind {
+                FieldKind::ObjectId | FieldKind::Text => SqlValue::Text(row.try_get(field.name)?),
+                FieldKind::Integer | FieldKind::DateTime => {
+                    SqlValue::Integer(row.try_get(field.name)?)
+                }
+                FieldKind::Boolean => SqlValue::Boolean(row.try_get(field.name)?),
+            })
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    sql::from_values(values)
+}
+
+pub struct PostgreRepository<T: Model> {
+    executor: Executor,
+    model: PhantomData<T>,
+}
+
+impl<T: Model> PostgreRepository<T> {
+    pub fn new(pool: &PgPool) -> Self {
+        PostgreRepository {
+            executor: Executor::Pool(pool.clone()),
+            model: PhantomData,
+        }
+    }
+
+    fn in_transaction(transaction: &SharedTransaction) -> Self {
+        PostgreRepository {
+            executor: Executor::Transaction(transaction.clone()),
+            model: PhantomData,
+        }
+    }
+
+    async fn execute(
+        &self,
+        query: Query<'_, Postgres, PgArguments>,
+    ) -> Result<PgQueryResult, StoreError> {
+        match &self.executor {
+            Executor::Pool(pool) => Ok(query.execute(pool).await?),
+            Executor::Transaction(transaction) => {
+                let mut transaction = transaction.lock().await;
+                let transaction = transaction.as_mut().ok_or_else(finished)?;
+                Ok(query.execute(&mut **transaction).await?)
+            }
+        }
+    }
+
+    async fn fetch_optional(
+        &self,
+        query: Query<'_, Postgres, PgArguments>,
+    ) -> Result<Option<PgRow>, StoreError> {
+        match &self.executor {
+            Executor::Pool(pool) => Ok(query.fetch_optional(pool).await?),
+            Executor::Transaction(transaction) => {
+                let mut transaction = transaction.lock().await;
+                let transaction = transaction.as_mut().ok_or_else(finished)?;
+                Ok(query.fetch_optional(&mut **transaction).await?)
+            }
+        }
+    }
+
+    async fn fetch_all(
+        &self,
+        query: Query<'_, Postgres, PgArguments>,
+    ) -> Result<Vec<PgRow>, StoreError> {
+        match &self.executor {
+            Executor::Pool(pool) => Ok(query.fetch_all(pool).await?),
+            Executor::Transaction(transaction) => {
+                let mut transaction = transaction.lock().await;
+                let transaction = transaction.as_mut().ok_or_else(finished)?;
+                Ok(query.fetch_all(&mut **transaction).await?)
+            }
+        }
+    }
+
+    /// Executes the statements in a single transaction, the one of the repository if it has one,
+    /// and returns for each one whether it affected a row. Each statement is behind a savepoint,
+    /// a failing one does not abort the others.
+    async fn execute_each(
+        &self,
+        statements: Vec<Result<(String, Vec<SqlValue>), StoreError>>,
+    ) -> Vec<Result<bool, StoreError>> {
+        let count = statements.len();
+        let failed = |error: StoreError| {
+            let message = error.to_string();
+            (0..count)
+                .map(|_| Err(StoreError::Backend(message.clone())))
+                .collect()
+        };
+        match &self.executor {
+            Executor::Pool(pool) => {
+                let mut transaction = match pool.begin().await {
+                    Ok(transaction) => transaction,
+                    Err(error) => return failed(error.into()),
+                };
+                let results = execute_in(&mut transaction, statements).await;
+                match transaction.commit().await {
+                    Ok(()) => results,
+                    Err(error) => failed(error.into()),
+                }
+            }
+            Executor::Transaction(transaction) => {
+                let mut transaction = transaction.lock().await;
+                match transaction.as_mut() {
+                    Some(transaction) => execute_in(transaction, statements).await,
+                    None => failed(finished()),
+                }
+            }
+        }
+    }
+}
+
+async fn execute_in(
+    connection: &mut PgConnection,
+    statements: Vec<Result<(String, Vec<SqlValue>), StoreError>>,
+) -> Vec<Result<bool, StoreError>> {
+    let mut results = Vec::with_capacity(statements.len());
+    for statement in statements {
+        results.push(match statement {
+            Ok((statement, values)) => execute_savepoint(connection, &statement, values).await,
+            Err(error) => Err(error),
+        });
+    }
+    results
+}
+
+async fn execute_savepoint(
+    connection: &mut PgConnection,
+    statement: &str,
+    values: Vec<SqlValue>,
+) -> Result<bool, StoreError> {
+    let mut savepoint = connection.begin().await?;
+    let query = values.into_iter().fold(sqlx::query(statement), bind);
+    match query.execute(&mut *savepoint).await {
+        Ok(result) => {
+            savepoint.commit().await?;
+            Ok(result.rows_affected() > 0)
+        }
+        Err(error) => {
+            savepoint.rollback().await?;
+            Err(error.into())
+        }
+    }
+}
+
+fn finished() -> StoreError {
+    StoreError::Backend("Transaction is already committed or rolled back".to_string())
+}
+
+#[async_trait]
+impl<T: Model> Repository<T> for PostgreRepository<T> {
+    async fn insert(&self, item: &T) -> Result<(), StoreError> {
+        let statement = sql::insert_statement::<T>();
+        let query = sql::to_values(item)?
+            .into_iter()
+            .fold(sqlx::query(&statement), bind);
+        self.execute(query).await?;
+        Ok(())
+    }
+
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<T>, StoreError> {
+        self.find_one(&Filter::new().eq("_id", *id)).await
+    }
+
+    async fn find_one(&self, filter: &Filter) -> Result<Option<T>, StoreError> {
+        let (where_clause, values) = sql::where_clause::<T>(filter, 1)?;
+        let statement = format!("{} LIMIT 1", sql::select_statement::<T>(&where_clause));
+        let query = values.into_iter().fold(sqlx::query(&statement), bind);
+        self.fetch_optional(query)
+            .await?
+            .map(|row| read_row(&row))
+            .transpose()
+    }
+
+    async fn find_with(
+        &self,
+        filter: &Filter,
+        options: &FindOptions,
+    ) -> Result<Vec<T>, StoreError> {
+        let (where_clause, values) = sql::where_clause::<T>(filter, 1)?;
+        let statement = format!(
+            "{}{}",
+            sql::select_statement::<T>(&where_clause),
+            sql::options_clause::<T>(options)?
+        );
+        let query = values.into_iter().fold(sqlx::query(&statement), bind);
+        self.fetch_all(query).await?.iter().map(read_row).collect()
+    }
+
+    async fn count(&self, filter: &Filter) -> Result<u64, StoreError> {
+        let (where_clause, values) = sql::where_clause::<T>(filter, 1)?;
+        let statement = sql::count_statement::<T>(&where_clause);
+        let query = values.into_iter().fold(sqlx::query(&statement), bind);
+        let row = self.fetch_optional(query).await?;
+        let count: i64 = match row {
+            Some(row) => row.try_get(0)?,
+            None => 0,
+        };
+        Ok(count as u64)
+    }
+
+    async fn update_where(&self, item: &T, filter: &Filter) -> Result<bool, StoreError> {
+        let (statement, values) = sql::replace_statement(item, filter)?;
+        let query = values.into_iter().fold(sqlx::query(&statement), bind);
+        let result = self.execute(query).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // A single transaction, see [`execute_each`].
+    async fn update_many(&self, items: &[(T, Filter)]) -> Vec<Result<bool, StoreError>> {
+        let statements = items
+            .iter()
+            .map(|(item, filter)| sql::replace_statement(item, filter))
+            .collect();
+        self.execute_each(statements).await
+    }
+
+    async fn delete_many(&self, ids: &[ObjectId]) -> Vec<Result<bool, StoreError>> {
+        let statement = sql::delete_statement::<T>();
+        let statements = ids
+            .iter()
+            .map(|id| Ok((statement.clone(), vec![sql::id_value(id)])))
+            .collect();
+        self.execute_each(statements).await
+    }
+
+    async fn delete(&self, id: &ObjectId) -> Result<bool, StoreError> {
+        let statement = sql::delete_statement::<T>();
+        let result = self
+            .execute(bind(sqlx::query(&statement), sql::id_value(id)))
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Uses the [`sql::SEARCH_VECTOR_COLUMN`] of the table, matching the words by prefix.
+    async fn search(
+        &self,
+        query: &str,
+        filter: &Filter,
+        limit: u64,
+    ) -> Result<Vec<SearchHit<T>>, StoreError> {
+        if T::SEARCH_FIELDS.is_empty() {
+            return search::scan(self, query, filter, limit).await;
+        }
+        let terms = search::terms(query);
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        // The terms are only letters and digits, nothing to escape.
+        let text_query = terms
+            .iter()
+            .map(|term| format!("{term}:*"))
+            .collect::<Vec<_>>()
+            .join(" | ");
+        let (predicates, values) = sql::predicates::<T>(filter, 2)?;
+        let statement = sql::search_statement::<T>(&predicates, limit);
+        let query = std::iter::once(SqlValue::Text(Some(text_query)))
+            .chain(values)
+            .fold(sqlx::query(&statement), bind);
+        self.fetch_all(query)
+            .await?
+            .iter()
+            .map(|row| {
+                let score: f32 = row.try_get("text_score")?;
+                Ok(SearchHit {
+                    item: read_row(row)?,
+                    score: f64::from(score),
+                })
+            })
+            .collect()
+    }
+}
+
+pub struct PostgreUnitOfWork {
+    transaction: SharedTransaction,
+    hooks: CommitHooks,
+}
+
+impl PostgreUnitOfWork {
+    pub async fn begin(pool: &PgPool) -> Result<Self, StoreError> {
+        Ok(PostgreUnitOfWork {
+            transaction: Arc::new(Mutex::new(Some(pool.begin().await?))),
+            hooks: CommitHooks::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for PostgreUnitOfWork {
+    fn users(&self) -> Arc<dyn Repository<User>> {
+        Arc::new(PostgreRepository::in_transaction(&self.transaction))
+    }
+
+    fn organizations(&self) -> Arc<dyn Repository<Organization>> {
+        Arc::new(PostgreRepository::in_transaction(&self.transaction))
+    }
+
+    fn audit_log(&self) -> Arc<dyn Repository<AuditEntry>> {
+        Arc::new(PostgreRepository::in_transaction(&self.transaction))
+    }
+
+    fn commit_hooks(&self) -> CommitHooks {
+        self.hooks.clone()
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), StoreError> {
+        let transaction = self.transaction.lock().await.take().ok_or_else(finished)?;
+        transaction.commit().await?;
+        self.hooks.run();
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), StoreError> {
+        let transaction = self.transaction.lock().await.take().ok_or_else(finished)?;
+        Ok(transaction.rollback().await?)
+    }
+}
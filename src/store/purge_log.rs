@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use crate::{
+    models::retention::PurgeEntry,
+    store::{Filter, FindOptions, Order, Repository, StoreError},
+};
+
+/// What the retention policies purged, or would have purged in a dry run.
+#[derive(Clone)]
+pub struct PurgeLog {
+    repository: Arc<dyn Repository<PurgeEntry>>,
+}
+
+impl PurgeLog {
+    pub fn new(repository: Arc<dyn Repository<PurgeEntry>>) -> Self {
+        PurgeLog { repository }
+    }
+
+    pub async fn record(&self, entry: &PurgeEntry) -> Result<(), StoreError> {
+        self.repository.insert(entry).await
+    }
+
+    /// Returns the entries, newest first, and how many there are in total.
+    pub async fn page(&self, skip: u64, limit: u64) -> Result<(Vec<PurgeEntry>, u64), StoreError> {
+        let options = FindOptions::new()
+            .sort("created_at", Order::Descending)
+            .sort("_id", Order::Descending)
+            .skip(skip)
+            .limit(limit);
+        let entries = self.repository.find_with(&Filter::new(), &options).await?;
+        let total = self.repository.count(&Filter::new()).await?;
+        Ok((entries, total))
+    }
+}
+
+impl std::fmt::Debug for PurgeLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PurgeLog").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,155 @@
+//! Full-text search over the [`Model::SEARCH_FIELDS`] of a model.
+//! MongoDB and PostgreSQL rank with their own text indexes, the other backends scan the
+//! matching documents with [`scan`]. Highlighting is done the same way for every backend.
+//! Every backend only searches the [`terms`] of the query, which match words by prefix except
+//! on MongoDB, whose text index matches whole stemmed words.
+
+use std::collections::BTreeMap;
+
+use mongodb::bson::{self, Document};
+
+use crate::{
+    models::Model,
+    store::{Filter, Repository, StoreError},
+};
+
+/// A search result, the higher the score the more relevant.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit<T> {
+    pub item: T,
+    pub score: f64,
+}
+
+/// The lowercased words of a query, anything but letters and digits separates them.
+pub fn terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = words(query).map(|(_, word)| word.to_lowercase()).collect();
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+/// The words of a text, with their byte offsets.
+fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split(|character: char| !character.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(move |word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+}
+
+fn is_match(word: &str, terms: &[String]) -> bool {
+    let word = word.to_lowercase();
+    terms.iter().any(|term| word.starts_with(term.as_str()))
+}
+
+/// Counts the words of the search fields starting with one of the terms.
+pub fn score<T: Model>(document: &Document, terms: &[String]) -> f64 {
+    T::SEARCH_FIELDS
+        .iter()
+        .filter_map(|field| document.get_str(field).ok())
+        .flat_map(words)
+        .filter(|(_, word)| is_match(word, terms))
+        .count() as f64
+}
+
+/// The search fields having a word starting with one of the terms, those words in `<em>` tags.
+/// The rest of the text is HTML escaped, the result can be displayed as is.
+pub fn highlights<T: Model>(item: &T, terms: &[String]) -> BTreeMap<String, String> {
+    let Ok(document) = bson::to_document(item) else {
+        return BTreeMap::new();
+    };
+    T::SEARCH_FIELDS
+        .iter()
+        .filter_map(|field| {
+            let text = document.get_str(field).ok()?;
+            let mut highlighted = String::new();
+            let mut end = 0;
+            for (start, word) in words(text).filter(|(_, word)| is_match(word, terms)) {
+                highlighted.push_str(&escape_html(&text[end..start]));
+                highlighted.push_str(&format!("<em>{}</em>", escape_html(word)));
+                end = start + word.len();
+            }
+            if end == 0 {
+                return None;
+            }
+            highlighted.push_str(&escape_html(&text[end..]));
+            Some((field.to_string(), highlighted))
+        })
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Searches by reading every document matching the filter, for the backends without text index.
+pub async fn scan<T: Model>(
+    repository: &(impl Repository<T> + ?Sized),
+    query: &str,
+    filter: &Filter,
+    limit: u64,
+) -> Result<Vec<SearchHit<T>>, StoreError> {
+    let terms = terms(query);
+    if terms.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut hits = Vec::new();
+    for item in repository.find(filter).await? {
+        let score = score::<T>(&bson::to_document(&item)?, &terms);
+        if score > 0.0 {
+            hits.push(SearchHit { item, score });
+        }
+    }
+    // Stable, equal scores keep the order of the backend.
+    hits.sort_by(|left, right| right.score.total_cmp(&left.score));
+    hits.truncate(limit as usize);
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::users::User;
+
+    fn user() -> User {
+        User {
+            first_name: "Mary Jane".into(),
+            last_name: "Watson <MJ>".into(),
+            ..User::fixture("mj@example.com")
+        }
+    }
+
+    #[test]
+    fn test_terms() {
+        assert_eq!(
+            terms("  Jane, jane@EXAMPLE.com "),
+            ["com", "example", "jane"]
+        );
+        assert!(terms("@ -").is_empty());
+    }
+
+    #[test]
+    fn test_score_and_highlights() {
+        let user = user();
+        let document = bson::to_document(&user).unwrap();
+        assert_eq!(score::<User>(&document, &terms("jan wat")), 2.0);
+        assert_eq!(score::<User>(&document, &terms("example")), 1.0);
+        assert_eq!(score::<User>(&document, &terms("ane")), 0.0);
+        // The password is not searchable.
+        assert_eq!(score::<User>(&document, &terms("hash")), 0.0);
+
+        let highlights = highlights(&user, &terms("jane mj"));
+        assert_eq!(
+            highlights,
+            BTreeMap::from([
+                ("email".to_string(), "<em>mj</em>@example.com".to_string()),
+                ("first_name".to_string(), "Mary <em>Jane</em>".to_string()),
+                (
+                    "last_name".to_string(),
+                    "Watson &lt;<em>MJ</em>&gt;".to_string()
+                ),
+            ])
+        );
+    }
+}
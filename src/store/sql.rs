@@ -0,0 +1,372 @@
+//! Statements and value conversions shared by the SQL backends.
+//! Columns are named after the serialized fields of the model, see [`Model::FIELDS`].
+
+use mongodb::bson::{self, oid::ObjectId, Bson, DateTime, Document};
+
+use crate::{
+    models::{Field, FieldKind, Model},
+    store::{Condition, Filter, FindOptions, Order, StoreError},
+};
+
+/// A value bound to, or read from, a SQL statement.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Text(Option<String>),
+    Integer(Option<i64>),
+    Boolean(Option<bool>),
+}
+
+impl SqlValue {
+    fn null(kind: FieldKind) -> Self {
+        match kind {
+            FieldKind::ObjectId | FieldKind::Text => SqlValue::Text(None),
+            FieldKind::Integer | FieldKind::DateTime => SqlValue::Integer(None),
+            FieldKind::Boolean => SqlValue::Boolean(None),
+        }
+    }
+}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::Database(ref e) if e.is_unique_violation() => {
+                Self::Duplicate(e.message().to_string())
+            }
+            _ => Self::Backend(error.to_string()),
+        }
+    }
+}
+
+pub fn field<T: Model>(name: &str) -> Result<&'static Field, StoreError> {
+    T::FIELDS
+        .iter()
+        .find(|field| field.name == name)
+        .ok_or_else(|| {
+            StoreError::InvalidDocument(format!("Unknown field {name} in {}", T::REPOSITORY_NAME))
+        })
+}
+
+pub fn to_sql_value(field: &Field, value: Option<&Bson>) -> Result<SqlValue, StoreError> {
+    let value = match (field.kind, value) {
+        (_, None | Some(Bson::Null)) if field.nullable => SqlValue::null(field.kind),
+        (FieldKind::ObjectId, Some(Bson::ObjectId(oid))) => SqlValue::Text(Some(oid.to_hex())),
+        (FieldKind::Text, Some(Bson::String(text))) => SqlValue::Text(Some(text.clone())),
+        (FieldKind::Integer, Some(Bson::Int32(integer))) => {
+            SqlValue::Integer(Some(i64::from(*integer)))
+        }
+        (FieldKind::Integer, Some(Bson::Int64(integer))) => SqlValue::Integer(Some(*integer)),
+        (FieldKind::Boolean, Some(Bson::Boolean(boolean))) => SqlValue::Boolean(Some(*boolean)),
+        (FieldKind::DateTime, Some(Bson::DateTime(date))) => {
+            SqlValue::Integer(Some(date.timestamp_millis()))
+        }
+        (_, value) => {
+            return Err(StoreError::InvalidDocument(format!(
+                "Unexpected value for {}: {value:?}",
+                field.name
+            )))
+        }
+    };
+    Ok(value)
+}
+
+pub fn from_sql_value(field: &Field, value: SqlValue) -> Result<Bson, StoreError> {
+    let value = match (field.kind, value) {
+        (FieldKind::ObjectId, SqlValue::Text(Some(hex))) => {
+            Bson::ObjectId(ObjectId::parse_str(hex.trim()).map_err(|error| {
+                StoreError::InvalidDocument(format!("Invalid {}: {error}", field.name))
+            })?)
+        }
+        (FieldKind::Text, SqlValue::Text(Some(text))) => Bson::String(text),
+        (FieldKind::Integer, SqlValue::Integer(Some(integer))) => Bson::Int64(integer),
+        (FieldKind::Boolean, SqlValue::Boolean(Some(boolean))) => Bson::Boolean(boolean),
+        (FieldKind::DateTime, SqlValue::Integer(Some(millis))) => {
+            Bson::DateTime(DateTime::from_millis(millis))
+        }
+        _ => Bson::Null,
+    };
+    Ok(value)
+}
+
+/// Serializes an item to one value per column, in the order of [`Model::FIELDS`].
+pub fn to_values<T: Model>(item: &T) -> Result<Vec<SqlValue>, StoreError> {
+    let document = bson::to_document(item)?;
+    T::FIELDS
+        .iter()
+        .map(|field| to_sql_value(field, document.get(field.name)))
+        .collect()
+}
+
+/// Deserializes an item from one value per column, in the order of [`Model::FIELDS`].
+pub fn from_values<T: Model>(values: Vec<SqlValue>) -> Result<T, StoreError> {
+    let mut document = Document::new();
+    for (field, value) in T::FIELDS.iter().zip(values) {
+        document.insert(field.name, from_sql_value(field, value)?);
+    }
+    Ok(bson::from_document(document)?)
+}
+
+pub fn id_value(id: &ObjectId) -> SqlValue {
+    SqlValue::Text(Some(id.to_hex()))
+}
+
+fn columns<T: Model>() -> String {
+    T::FIELDS
+        .iter()
+        .map(|field| field.name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Builds a WHERE clause (empty if there are no conditions) with numbered placeholders,
+/// the first one being `$first_placeholder`.
+pub fn where_clause<T: Model>(
+    filter: &Filter,
+    first_placeholder: usize,
+) -> Result<(String, Vec<SqlValue>), StoreError> {
+    let (predicates, values) = predicates::<T>(filter, first_placeholder)?;
+    if predicates.is_empty() {
+        return Ok((String::new(), values));
+    }
+    Ok((format!(" WHERE {}", predicates.join(" AND ")), values))
+}
+
+/// One predicate per condition of the filter, see [`where_clause`].
+pub fn predicates<T: Model>(
+    filter: &Filter,
+    first_placeholder: usize,
+) -> Result<(Vec<String>, Vec<SqlValue>), StoreError> {
+    let mut predicates = Vec::new();
+    let mut values = Vec::new();
+    for condition in &filter.conditions {
+        let placeholder = |values: &Vec<SqlValue>| first_placeholder + values.len() - 1;
+        match condition {
+            Condition::Eq(name, value) => {
+                let field = field::<T>(name)?;
+                if matches!(value, Bson::Null) {
+                    predicates.push(format!("{name} IS NULL"));
+                    continue;
+                }
+                values.push(to_sql_value(field, Some(value))?);
+                predicates.push(format!("{name} = ${}", placeholder(&values)));
+            }
+            Condition::Lt(name, value) => {
+                values.push(to_sql_value(field::<T>(name)?, Some(value))?);
+                predicates.push(format!("{name} < ${}", placeholder(&values)));
+            }
+            Condition::Gt(name, value) => {
+                values.push(to_sql_value(field::<T>(name)?, Some(value))?);
+                predicates.push(format!("{name} > ${}", placeholder(&values)));
+            }
+            // Unlike LIKE, case sensitive on every backend and without wildcards to escape.
+            Condition::Prefix(name, prefix) => {
+                field::<T>(name)?;
+                values.push(SqlValue::Text(Some(prefix.clone())));
+                let length = placeholder(&values);
+                values.push(SqlValue::Text(Some(prefix.clone())));
+                predicates.push(format!(
+                    "substr({name}, 1, length(${length})) = ${}",
+                    placeholder(&values)
+                ));
+            }
+            Condition::Any(filters) => {
+                let mut alternatives = Vec::new();
+                for filter in filters {
+                    let (inner, inner_values) =
+                        self::predicates::<T>(filter, first_placeholder + values.len())?;
+                    values.extend(inner_values);
+                    alternatives.push(match inner.is_empty() {
+                        true => "1 = 1".to_string(),
+                        false => format!("({})", inner.join(" AND ")),
+                    });
+                }
+                predicates.push(match alternatives.is_empty() {
+                    true => "1 = 0".to_string(),
+                    false => format!("({})", alternatives.join(" OR ")),
+                });
+            }
+        }
+    }
+    Ok((predicates, values))
+}
+
+pub fn insert_statement<T: Model>() -> String {
+    let placeholders = (1..=T::FIELDS.len())
+        .map(|index| format!("${index}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "INSERT INTO {} ({}) VALUES ({placeholders})",
+        T::REPOSITORY_NAME,
+        columns::<T>()
+    )
+}
+
+pub fn select_statement<T: Model>(where_clause: &str) -> String {
+    format!(
+        "SELECT {} FROM {}{where_clause}",
+        columns::<T>(),
+        T::REPOSITORY_NAME
+    )
+}
+
+/// Builds the ORDER BY, LIMIT and OFFSET clauses, empty if there are no options.
+pub fn options_clause<T: Model>(options: &FindOptions) -> Result<String, StoreError> {
+    let mut clause = String::new();
+    if !options.sort.is_empty() {
+        let orderings = options
+            .sort
+            .iter()
+            .map(|(name, order)| {
+                let field = field::<T>(name)?;
+                Ok(match order {
+                    Order::Ascending => format!("{} ASC", field.name),
+                    Order::Descending => format!("{} DESC", field.name),
+                })
+            })
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        clause.push_str(&format!(" ORDER BY {}", orderings.join(", ")));
+    }
+    // SQLite does not accept an OFFSET without a LIMIT.
+    match (options.limit, options.skip) {
+        (Some(limit), skip) => clause.push_str(&format!(" LIMIT {limit} OFFSET {skip}")),
+        (None, 0) => {}
+        (None, skip) => clause.push_str(&format!(" LIMIT {} OFFSET {skip}", i64::MAX)),
+    }
+    Ok(clause)
+}
+
+/// The PostgreSQL `tsvector` column generated from the [`Model::SEARCH_FIELDS`].
+pub const SEARCH_VECTOR_COLUMN: &str = "search_vector";
+
+/// Selects the rows whose search vector matches the `tsquery` bound first, and their rank,
+/// as a last `text_score` column, the highest first. PostgreSQL only.
+pub fn search_statement<T: Model>(predicates: &[String], limit: u64) -> String {
+    let conditions: String = predicates
+        .iter()
+        .map(|predicate| format!(" AND {predicate}"))
+        .collect();
+    format!(
+        "SELECT {}, ts_rank({SEARCH_VECTOR_COLUMN}, text_query) AS text_score \
+         FROM {}, to_tsquery('simple', $1) text_query \
+         WHERE {SEARCH_VECTOR_COLUMN} @@ text_query{conditions} \
+         ORDER BY text_score DESC LIMIT {limit}",
+        columns::<T>(),
+        T::REPOSITORY_NAME
+    )
+}
+
+pub fn count_statement<T: Model>(where_clause: &str) -> String {
+    format!("SELECT COUNT(*) FROM {}{where_clause}", T::REPOSITORY_NAME)
+}
+
+/// Updates every column but `_id`, which is bound first, of the row also matching `predicates`.
+pub fn update_statement<T: Model>(predicates: &[String]) -> String {
+    let assignments = T::FIELDS
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(index, field)| format!("{} = ${}", field.name, index + 1))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let conditions: String = predicates
+        .iter()
+        .map(|predicate| format!(" AND {predicate}"))
+        .collect();
+    format!(
+        "UPDATE {} SET {assignments} WHERE _id = $1{conditions}",
+        T::REPOSITORY_NAME
+    )
+}
+
+/// The statement replacing the item if its row matches the filter, with its values.
+pub fn replace_statement<T: Model>(
+    item: &T,
+    filter: &Filter,
+) -> Result<(String, Vec<SqlValue>), StoreError> {
+    let (predicates, values) = predicates::<T>(filter, T::FIELDS.len() + 1)?;
+    let values = to_values(item)?.into_iter().chain(values).collect();
+    Ok((update_statement::<T>(&predicates), values))
+}
+
+pub fn delete_statement<T: Model>() -> String {
+    format!("DELETE FROM {} WHERE _id = $1", T::REPOSITORY_NAME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::users::User;
+
+    fn user() -> User {
+        User {
+            role: "admin".into(),
+            ..User::fixture("jane@example.com")
+        }
+    }
+
+    #[test]
+    fn test_values_round_trip() {
+        let user = user();
+        let values = to_values(&user).unwrap();
+        assert_eq!(values[0], id_value(&user._id));
+        assert_eq!(values[4], SqlValue::Text(None));
+        assert_eq!(from_values::<User>(values).unwrap(), user);
+    }
+
+    #[test]
+    fn test_where_clause() {
+        let filter = Filter::new()
+            .eq("email", "jane@example.com")
+            .eq("org_id", Bson::Null)
+            .eq("role", "admin")
+            .lt("deleted_at", DateTime::from_millis(1000));
+        let (clause, values) = where_clause::<User>(&filter, 2).unwrap();
+        assert_eq!(
+            clause,
+            " WHERE email = $2 AND org_id IS NULL AND role = $3 AND deleted_at < $4"
+        );
+        assert_eq!(
+            values,
+            vec![
+                SqlValue::Text(Some("jane@example.com".into())),
+                SqlValue::Text(Some("admin".into())),
+                SqlValue::Integer(Some(1000))
+            ]
+        );
+        assert!(where_clause::<User>(&Filter::new().eq("unknown", 1), 1).is_err());
+    }
+
+    #[test]
+    fn test_where_clause_alternatives() {
+        let filter = Filter::new().eq("role", "admin").any(vec![
+            Filter::new().gt("last_name", "Doe"),
+            Filter::new()
+                .eq("last_name", "Doe")
+                .starts_with("email", "ja"),
+        ]);
+        let (clause, values) = where_clause::<User>(&filter, 1).unwrap();
+        assert_eq!(
+            clause,
+            " WHERE role = $1 AND ((last_name > $2) OR (last_name = $3 AND substr(email, 1, length($4)) = $5))"
+        );
+        assert_eq!(values.len(), 5);
+        let (clause, values) = where_clause::<User>(&Filter::new().any(vec![]), 1).unwrap();
+        assert_eq!((clause.as_str(), values), (" WHERE 1 = 0", vec![]));
+    }
+
+    #[test]
+    fn test_options_clause() {
+        assert_eq!(options_clause::<User>(&FindOptions::new()).unwrap(), "");
+        let options = FindOptions::new()
+            .sort("last_name", Order::Ascending)
+            .sort("_id", Order::Descending)
+            .skip(20)
+            .limit(10);
+        assert_eq!(
+            options_clause::<User>(&options).unwrap(),
+            " ORDER BY last_name ASC, _id DESC LIMIT 10 OFFSET 20"
+        );
+        let options = FindOptions::new().sort("unknown", Order::Ascending);
+        assert!(options_clause::<User>(&options).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use std::{marker::PhantomData, sync::Arc};
+
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use sqlx::{
+    query::Query,
+    sqlite::{SqliteArguments, SqliteConnection, SqlitePool, SqliteQueryResult, SqliteRow},
+    Connection, Row, Sqlite, Transaction,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    models::{audit::AuditEntry, organizations::Organization, users::User, FieldKind, Model},
+    store::{
+        sql::{self, SqlValue},
+        CommitHooks, Filter, FindOptions, Repository, StoreError, UnitOfWork,
+    },
+};
+
+/// A transaction shared by the repositories of a [`SqliteUnitOfWork`],
+/// None once committed or rolled back.
+type SharedTransaction = Arc<Mutex<Option<Transaction<'static, Sqlite>>>>;
+
+/// Where the statements of a repository are executed.
+#[derive(Clone)]
+enum Executor {
+    Pool(SqlitePool),
+    Transaction(SharedTransaction),
+}
+
+pub fn bind<'q>(
+    query: Query<'q, Sqlite, SqliteArguments<'q>>,
+    value: SqlValue,
+) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    match value {
+        SqlValue::Text(text) => query.bind(text),
+        SqlValue::Integer(integer) => query.bind(integer),
+        SqlValue::Boolean(boolean) => query.bind(boolean),
+    }
+}
+
+pub fn read_row<T: Model>(row: &SqliteRow) -> Result<T, StoreError> {
+    let values = T::FIELDS
+        .iter()
+        .map(|field| {
+            Ok(match field.kind {
+                FieldKind::ObjectId | FieldKind::Text => SqlValue::Text(row.try_get(field.name)?),
+                FieldKind::Integer | FieldKind::DateTime => {
+                    SqlValue::Integer(row.try_get(field.name)?)
+                }
+                FieldKind::Boolean => SqlValue::Boolean(row.try_get(field.name)?),
+            })
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    sql::from_values(values)
+}
+
+pub struct SqliteRepository<T: Model> {
+    executor: Executor,
+    model: PhantomData<T>,
+}
+
+impl<T: Model> SqliteRepository<T> {
+    pub fn new(pool: &SqlitePool) -> Self {
+        SqliteRepository {
+            executor: Executor::Pool(pool.clone()),
+            model: PhantomData,
+        }
+    }
+
+    fn in_transaction(transaction: &SharedTransaction) -> Self {
+        SqliteRepository {
+            executor: Executor::Transaction(transaction.clone()),
+            model: PhantomData,
+        }
+    }
+
+    async fn execute<'q>(
+        &self,
+        query: Query<'q, Sqlite, SqliteArguments<'q>>,
+    ) -> Result<SqliteQueryResult, StoreError> {
+        match &self.executor {
+            Executor::Pool(pool) => Ok(query.execute(pool).await?),
+            Executor::Transaction(transaction) => {
+                let mut transaction = transaction.lock().await;
+                let transaction = transaction.as_mut().ok_or_else(finished)?;
+                Ok(query.execute(&mut **transaction).await?)
+            }
+        }
+    }
+
+    async fn fetch_optional<'q>(
+        &self,
+        query: Query<'q, Sqlite, SqliteArguments<'q>>,
+    ) -> Result<Option<SqliteRow>, StoreError> {
+        match &self.executor {
+            Executor::Pool(pool) => Ok(query.fetch_optional(pool).await?),
+            Executor::Transaction(transaction) => {
+                let mut transaction = transaction.lock().await;
+                let transaction = transaction.as_mut().ok_or_else(finished)?;
+                Ok(query.fetch_optional(&mut **transaction).await?)
+            }
+        }
+    }
+
+    async fn fetch_all<'q>(
+        &self,
+        query: Query<'q, Sqlite, SqliteArguments<'q>>,
+    ) -> Result<Vec<SqliteRow>, StoreError> {
+        match &self.executor {
+            Executor::Pool(pool) => Ok(query.fetch_all(pool).await?),
+            Executor::Transaction(transaction) => {
+                let mut transaction = transaction.lock().await;
+                let transaction = transaction.as_mut().ok_or_else(finished)?;
+                Ok(query.fetch_all(&mut **transaction).await?)
+            }
+        }
+    }
+
+    /// Executes the statements in a single transaction, the one of the repository if it has one,
+    /// and returns for each one whether it affected a row. Each statement is behind a savepoint,
+    /// a failing one does not abort the others.
+    async fn execute_each(
+        &self,
+        statements: Vec<Result<(String, Vec<SqlValue>), StoreError>>,
+    ) -> Vec<Result<bool, StoreError>> {
+        let count = statements.len();
+        let failed = |error: StoreError| {
+            let message = error.to_string();
+            (0..count)
+                .map(|_| Err(StoreError::Backend(message.clone())))
+                .collect()
+        };
+        match &self.executor {
+            Executor::Pool(pool) => {
+                let mut transaction = match pool.begin().await {
+                    Ok(transaction) => transaction,
+                    Err(error) => return failed(error.into()),
+                };
+                let results = execute_in(&mut transaction, statements).await;
+                match transaction.commit().await {
+                    Ok(()) => results,
+                    Err(error) => failed(error.into()),
+                }
+            }
+            Executor::Transaction(transaction) => {
+                let mut transaction = transaction.lock().await;
+                match transaction.as_mut() {
+                    Some(transaction) => execute_in(transaction, statements).await,
+                    None => failed(finished()),
+                }
+            }
+        }
+    }
+}
+
+async fn execute_in(
+    connection: &mut SqliteConnection,
+    statements: Vec<Result<(String, Vec<SqlValue>), StoreError>>,
+) -> Vec<Result<bool, StoreError>> {
+    let mut results = Vec::with_capacity(statements.len());
+    for statement in statements {
+        results.push(match statement {
+            Ok((statement, values)) => execute_savepoint(connection, &statement, values).await,
+            Err(error) => Err(error),
+        });
+    }
+    results
+}
+
+async fn execute_savepoint(
+    connection: &mut SqliteConnection,
+    statement: &str,
+    values: Vec<SqlValue>,
+) -> Result<bool, StoreError> {
+    let mut savepoint = connection.begin().await?;
+    let query = values.into_iter().fold(sqlx::query(statement), bind);
+    match query.execute(&mut *savepoint).await {
+        Ok(result) => {
+            savepoint.commit().await?;
+            Ok(result.rows_affected() > 0)
+        }
+        Err(error) => {
+            savepoint.rollback().await?;
+            Err(error.into())
+        }
+    }
+}
+
+fn finished() -> StoreError {
+    StoreError::Backend("Transaction is already committed or rolled back".to_string())
+}
+
+#[async_trait]
+impl<T: Model> Repository<T> for SqliteRepository<T> {
+    async fn insert(&self, item: &T) -> Result<(), StoreError> {
+        let statement = sql::insert_statement::<T>();
+        let query = sql::to_values(item)?
+            .into_iter()
+            .fold(sqlx::query(&statement), bind);
+        self.execute(query).await?;
+        Ok(())
+    }
+
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<T>, StoreError> {
+        self.find_one(&Filter::new().eq("_id", *id)).await
+    }
+
+    async fn find_one(&self, filter: &Filter) -> Result<Option<T>, StoreError> {
+        let (where_clause, values) = sql::where_clause::<T>(filter, 1)?;
+        let statement = format!("{} LIMIT 1", sql::select_statement::<T>(&where_clause));
+        let query = values.into_iter().fold(sqlx::query(&statement), bind);
+        self.fetch_optional(query)
+            .await?
+            .map(|row| read_row(&row))
+            .transpose()
+    }
+
+    async fn find_with(
+        &self,
+        filter: &Filter,
+        options: &FindOptions,
+    ) -> Result<Vec<T>, StoreError> {
+        let (where_clause, values) = sql::where_clause::<T>(filter, 1)?;
+        let statement = format!(
+            "{}{}",
+            sql::select_statement::<T>(&where_clause),
+            sql::options_clause::<T>(options)?
+        );
+        let query = values.into_iter().fold(sqlx::query(&statement), bind);
+        self.fetch_all(query).await?.iter().map(read_row).collect()
+    }
+
+    async fn count(&self, filter: &Filter) -> Result<u64, StoreError> {
+        let (where_clause, values) = sql::where_clause::<T>(filter, 1)?;
+        let statement = sql::count_statement::<T>(&where_clause);
+        let query = values.into_iter().fold(sqlx::query(&statement), bind);
+        let row = self.fetch_optional(query).await?;
+        let count: i64 = match row {
+            Some(row) => row.try_get(0)?,
+            None => 0,
+        };
+        Ok(count as u64)
+    }
+
+    async fn update_where(&self, item: &T, filter: &Filter) -> Result<bool, StoreError> {
+        let (statement, values) = sql::replace_statement(item, filter)?;
+        let query = values.into_iter().fold(sqlx::query(&statement), bind);
+        let result = self.execute(query).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // A single transaction, see [`execute_each`].
+    async fn update_many(&self, items: &[(T, Filter)]) -> Vec<Result<bool, StoreError>> {
+        let statements = items
+            .iter()
+            .map(|(item, filter)| sql::replace_statement(item, filter))
+            .collect();
+        self.execute_each(statements).await
+    }
+
+    async fn delete_many(&self, ids: &[ObjectId]) -> Vec<Result<bool, StoreError>> {
+        let statement = sql::delete_statement::<T>();
+        let statements = ids
+            .iter()
+            .map(|id| Ok((statement.clone(), vec![sql::id_value(id)])))
+            .collect();
+        self.execute_each(statements).await
+    }
+
+    async fn delete(&self, id: &ObjectId) -> Result<bool, StoreError> {
+        let statement = sql::delete_statement::<T>();
+        let result = self
+            .execute(bind(sqlx::query(&statement), sql::id_value(id)))
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+pub struct SqliteUnitOfWork {
+    transaction: SharedTransaction,
+    hooks: CommitHooks,
+}
+
+impl SqliteUnitOfWork {
+    pub async fn begin(pool: &SqlitePool) -> Result<Self, StoreError> {
+        Ok(SqliteUnitOfWork {
+            transaction: Arc::new(Mutex::new(Some(pool.begin().await?))),
+            hooks: CommitHooks::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for SqliteUnitOfWork {
+    fn users(&self) -> Arc<dyn Repository<User>> {
+        Arc::new(SqliteRepository::in_transaction(&self.transaction))
+    }
+
+    fn organizations(&self) -> Arc<dyn Repository<Organization>> {
+        Arc::new(SqliteRepository::in_transaction(&self.transaction))
+    }
+
+    fn audit_log(&self) -> Arc<dyn Repository<AuditEntry>> {
+        Arc::new(SqliteRepository::in_transaction(&self.transaction))
+    }
+
+    fn commit_hooks(&self) -> CommitHooks {
+        self.hooks.clone()
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), StoreError> {
+        let transaction = self.transaction.lock().await.take().ok_or_else(finished)?;
+        transaction.commit().await?;
+        self.hooks.run();
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), StoreError> {
+        let transaction = self.transaction.lock().await.take().ok_or_else(finished)?;
+        Ok(transaction.rollback().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        drivers::{GenericDatabase, SqliteDatabase},
+        migrations,
+        models::users::User,
+        store,
+    };
+
+    #[actix_web::test]
+    async fn test_users_repository() {
+        let mut db: SqliteDatabase = GenericDatabase::new();
+        db.connect("sqlite::memory:").await.unwrap();
+        migrations::migrate(&mut db).await.unwrap();
+        let repository = SqliteRepository::<User>::new(db.pool.as_ref().unwrap());
+
+        let mut user = User {
+            org_id: Some(ObjectId::new()),
+            ..User::fixture("jane@example.com")
+        };
+        repository.insert(&user).await.unwrap();
+        assert_eq!(
+            repository.find_by_id(&user._id).await.unwrap(),
+            Some(user.clone())
+        );
+
+        let mut duplicate = user.clone();
+        duplicate._id = ObjectId::new();
+        assert!(matches!(
+            repository.insert(&duplicate).await,
+            Err(StoreError::Duplicate(_))
+        ));
+
+        user.first_name = "Janet".into();
+        assert!(repository.update(&user).await.unwrap());
+        let found = repository
+            .find_one(&Filter::new().eq("email", "jane@example.com"))
+            .await
+            .unwrap();
+        assert_eq!(found, Some(user.clone()));
+
+        let filter = Filter::new().any(vec![
+            Filter::new().starts_with("first_name", "Jan%"),
+            Filter::new()
+                .starts_with("last_name", "D")
+                .gt("_id", user._id),
+        ]);
+        assert!(repository.find(&filter).await.unwrap().is_empty());
+        let filter = Filter::new().any(vec![
+            Filter::new().starts_with("first_name", "jan"),
+            Filter::new().starts_with("first_name", "Jan"),
+        ]);
+        assert_eq!(repository.find(&filter).await.unwrap(), vec![user.clone()]);
+
+        assert!(repository.delete(&user._id).await.unwrap());
+        assert!(repository.list().await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_batches() {
+        let mut db: SqliteDatabase = GenericDatabase::new();
+        db.connect("sqlite::memory:").await.unwrap();
+        migrations::migrate(&mut db).await.unwrap();
+        let repository = SqliteRepository::<User>::new(db.pool.as_ref().unwrap());
+        let jane = User::fixture("jane@example.com");
+        let john = User::fixture("john@example.com");
+        repository.insert(&jane).await.unwrap();
+        repository.insert(&john).await.unwrap();
+
+        let renamed = User {
+            first_name: "Janet".into(),
+            ..jane.clone()
+        };
+        let duplicate = User {
+            email: jane.email.clone(),
+            ..john.clone()
+        };
+        let missing = User::fixture("jim@example.com");
+        let outcomes = repository
+            .update_many(&[
+                (renamed.clone(), Filter::new()),
+                (duplicate, Filter::new()),
+                (missing.clone(), Filter::new()),
+            ])
+            .await;
+        assert!(matches!(outcomes[0], Ok(true)));
+        assert!(matches!(outcomes[1], Err(StoreError::Duplicate(_))));
+        assert!(matches!(outcomes[2], Ok(false)));
+        assert_eq!(
+            repository.find_by_id(&jane._id).await.unwrap(),
+            Some(renamed)
+        );
+        assert_eq!(
+            repository.find_by_id(&john._id).await.unwrap(),
+            Some(john.clone())
+        );
+
+        let outcomes = repository.delete_many(&[jane._id, missing._id]).await;
+        assert!(matches!(outcomes[..], [Ok(true), Ok(false)]));
+        assert_eq!(repository.list().await.unwrap(), vec![john]);
+    }
+
+    #[actix_web::test]
+    async fn test_unit_of_work() {
+        let mut db: SqliteDatabase = GenericDatabase::new();
+        db.connect("sqlite::memory:").await.unwrap();
+        migrations::migrate(&mut db).await.unwrap();
+        let user = User::fixture("jane@example.com");
+
+        let result: Result<(), StoreError> =
+            store::transaction(db.begin().await.unwrap(), async |unit: &dyn UnitOfWork| {
+                unit.users().insert(&user).await?;
+                assert!(unit.users().find_by_id(&user._id).await?.is_some());
+                Err(StoreError::Backend("failing on purpose".into()))
+            })
+            .await;
+        assert!(result.is_err());
+        assert!(db.users().unwrap().list().await.unwrap().is_empty());
+
+        let unit = db.begin().await.unwrap();
+        unit.users().insert(&user).await.unwrap();
+        unit.commit().await.unwrap();
+        assert_eq!(
+            db.users().unwrap().find_by_id(&user._id).await.unwrap(),
+            Some(user)
+        );
+    }
+}
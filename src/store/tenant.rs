@@ -0,0 +1,69 @@
+//! Multi-tenancy: the users of an organization only reach the users of their organization,
+//! the super administrators reach every organization.
+
+use mongodb::bson::{oid::ObjectId, Bson};
+
+use crate::{models::users::User, store::Filter};
+
+/// The organizations a store reaches, see `UserStore::scoped` and `OrganizationStore::scoped`.
+/// It has no default, every caller chooses the organizations it reaches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tenant {
+    /// Every organization, for the super administrators and the background tasks.
+    Any,
+    /// A single organization, None being the users without organization.
+    Organization(Option<ObjectId>),
+}
+
+impl Tenant {
+    /// The tenant of the requests made by the user.
+    pub fn of(user: &User) -> Self {
+        match user.is_super_admin() {
+            true => Tenant::Any,
+            false => Tenant::Organization(user.org_id),
+        }
+    }
+
+    /// Whether the tenant reaches the users with the given `org_id`.
+    pub fn allows(&self, org_id: Option<&ObjectId>) -> bool {
+        match self {
+            Tenant::Any => true,
+            Tenant::Organization(tenant) => tenant.as_ref() == org_id,
+        }
+    }
+
+    /// Matches the documents of the tenant, whose organization is stored in `field`.
+    pub fn filter(&self, field: &str) -> Filter {
+        match self {
+            Tenant::Any => Filter::new(),
+            Tenant::Organization(Some(org_id)) => Filter::new().eq(field, *org_id),
+            Tenant::Organization(None) => Filter::new().eq(field, Bson::Null),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_of() {
+        let org_id = ObjectId::new();
+        let mut user = User {
+            role: "admin".into(),
+            org_id: Some(org_id),
+            ..User::fixture("jane@example.com")
+        };
+        let tenant = Tenant::of(&user);
+        assert_eq!(tenant, Tenant::Organization(Some(org_id)));
+        assert!(tenant.allows(Some(&org_id)));
+        assert!(!tenant.allows(Some(&ObjectId::new())));
+        assert!(!tenant.allows(None));
+        assert!(!Tenant::Organization(None).allows(Some(&org_id)));
+
+        user.role = "god".into();
+        assert_eq!(Tenant::of(&user), Tenant::Any);
+        assert!(Tenant::Any.allows(None));
+        assert_eq!(Tenant::Any.filter("org_id"), Filter::new());
+    }
+}
@@ -1 +1,1009 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
+use lazy_static::lazy_static;
+use mongodb::bson::{oid::ObjectId, Bson, DateTime};
+
+use crate::{
+    models::users::{User, REPOSITORY_NAME, SUPER_ADMIN_ROLES},
+    store::{
+        cache::{Cache, CacheStats},
+        encryption::{EncryptedRepository, FieldCipher, RotationReport},
+        events::{ChangeEvent, ChangeKind, Events},
+        organizations::OrganizationStore,
+        pagination::{self, Page, PageRequest},
+        tenant::Tenant,
+        CommitHooks, Filter, Repository, SearchHit, StoreError, UnitOfWork,
+    },
+};
+
+lazy_static! {
+    /// How many users are cached by id, 0 disables the cache.
+    pub static ref USER_CACHE_CAPACITY: usize = std::env::var("USER_CACHE_CAPACITY")
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .unwrap_or(10_000);
+    /// How long a cached user is used, the changes made by other instances are seen after it.
+    pub static ref USER_CACHE_TTL: Duration = Duration::from_secs(
+        std::env::var("USER_CACHE_TTL_SECONDS")
+            .ok()
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(60)
+    );
+}
+
+/// Filters out the soft-deleted users.
+fn active() -> Filter {
+    Filter::new().eq("deleted_at", Bson::Null)
+}
+
+/// Users persistence, independent of the database backend.
+/// Soft-deleted users are hidden from every lookup until restored or purged.
+/// Every change is published as a [`ChangeEvent`].
+/// The lookups by id, made by every authenticated request, can be cached.
+/// A store scoped to a [`Tenant`] neither reads nor writes the users of other organizations.
+/// The personal data of the users can be encrypted at rest, see [`crate::store::encryption`].
+#[derive(Clone)]
+pub struct UserStore {
+    repository: Arc<dyn Repository<User>>,
+    /// The repository, when the users are encrypted by it.
+    encryption: Option<Arc<EncryptedRepository>>,
+    events: Events,
+    cache: Option<Arc<Cache<ObjectId, User>>>,
+    /// Checks the `org_id` of the users written, when set.
+    organizations: Option<OrganizationStore>,
+    tenant: Tenant,
+    /// The user making the writes, whose role limits the roles written, None for the
+    /// background tasks.
+    actor: Option<User>,
+    /// The hooks of the unit of work the store writes in, which publish the events and
+    /// invalidate the cache once committed.
+    commit_hooks: Option<CommitHooks>,
+}
+
+impl UserStore {
+    pub fn new(repository: Arc<dyn Repository<User>>) -> Self {
+        UserStore {
+            repository,
+            encryption: None,
+            events: Events::default(),
+            cache: None,
+            organizations: None,
+            tenant: Tenant::Any,
+            actor: None,
+            commit_hooks: None,
+        }
+    }
+
+    /// The same store, limited to the users of the tenant.
+    pub fn scoped(&self, tenant: Tenant) -> Self {
+        UserStore {
+            tenant,
+            ..self.clone()
+        }
+    }
+
+    /// The same store, limited to the tenant of the user and to the roles the user can grant.
+    pub fn acting_as(&self, actor: &User) -> Self {
+        UserStore {
+            tenant: Tenant::of(actor),
+            actor: Some(actor.clone()),
+            ..self.clone()
+        }
+    }
+
+    /// Fails with [`StoreError::Forbidden`] if the actor cannot write the user, `current` being
+    /// the stored user on an update: only the administrators create users, only the super
+    /// administrators grant the super administrator roles and change roles.
+    pub fn check_role(&self, user: &User, current: Option<&User>) -> Result<(), StoreError> {
+        let Some(actor) = self.actor.as_ref().filter(|actor| !actor.is_super_admin()) else {
+            return Ok(());
+        };
+        match current {
+            Some(current) => self.check_target(current)?,
+            None if !actor.is_admin() => {
+                return Err(StoreError::Forbidden(
+                    "Only administrators create users".to_string(),
+                ))
+            }
+            None => {}
+        }
+        if current.is_some_and(|current| current.role == user.role) {
+            return Ok(());
+        }
+        if SUPER_ADMIN_ROLES.contains(&user.role.as_str()) {
+            return Err(StoreError::Forbidden(format!(
+                "Only super administrators grant the role {}",
+                user.role
+            )));
+        }
+        if current.is_some() {
+            return Err(StoreError::Forbidden(match user._id == actor._id {
+                true => "Only super administrators change their role".to_string(),
+                false => format!("Only super administrators change the role of {}", user._id),
+            }));
+        }
+        Ok(())
+    }
+
+    /// Fails with [`StoreError::Forbidden`] if the actor cannot update, delete or restore the
+    /// stored user: the other users only write themselves, the administrators the users who
+    /// are not super administrators.
+    pub fn check_target(&self, current: &User) -> Result<(), StoreError> {
+        let Some(actor) = self.actor.as_ref().filter(|actor| !actor.is_super_admin()) else {
+            return Ok(());
+        };
+        if current._id == actor._id {
+            return Ok(());
+        }
+        if !actor.is_admin() {
+            return Err(StoreError::Forbidden(format!(
+                "Only administrators write the user {}",
+                current._id
+            )));
+        }
+        if current.is_super_admin() {
+            return Err(StoreError::Forbidden(format!(
+                "Only super administrators write the user {}",
+                current._id
+            )));
+        }
+        Ok(())
+    }
+
+    /// The same store, reading and writing in the unit of work, as its organizations store.
+    pub fn in_unit(&self, unit: &dyn UnitOfWork) -> Self {
+        let mut store = self.clone();
+        store.repository = unit.users();
+        store.commit_hooks = Some(unit.commit_hooks());
+        if let Some(encryption) = &self.encryption {
+            let encrypted = Arc::new(encryption.over(store.repository));
+            store.repository = encrypted.clone();
+            store.encryption = Some(encrypted);
+        }
+        store.organizations = self
+            .organizations
+            .as_ref()
+            .map(|organizations| organizations.in_unit(unit));
+        store
+    }
+
+    /// The active users of the tenant.
+    fn visible(&self) -> Filter {
+        active().and(self.tenant.filter("org_id"))
+    }
+
+    /// Refuses users whose `org_id` is not an existing organization.
+    pub fn with_organizations(mut self, organizations: OrganizationStore) -> Self {
+        self.organizations = Some(organizations);
+        self
+    }
+
+    /// Fails with [`StoreError::InvalidDocument`] if the organization of the user is unknown,
+    /// or is not the one of the tenant.
+    pub async fn check_organization(&self, user: &User) -> Result<(), StoreError> {
+        if !self.tenant.allows(user.org_id.as_ref()) {
+            return Err(StoreError::InvalidDocument(match user.org_id {
+                Some(org_id) => format!("Organization {org_id} is out of reach"),
+                None => "Users without organization are out of reach".to_string(),
+            }));
+        }
+        let (Some(organizations), Some(org_id)) = (&self.organizations, &user.org_id) else {
+            return Ok(());
+        };
+        if !organizations.exists(org_id).await? {
+            return Err(StoreError::InvalidDocument(format!(
+                "Unknown organization {org_id}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Caches the active users found by id, invalidated by the changes made through this store.
+    pub fn with_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.cache = (capacity > 0).then(|| Arc::new(Cache::new(capacity, ttl)));
+        self
+    }
+
+    /// None without a cache.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    fn invalidate(&self, id: &ObjectId) {
+        if let Some(cache) = self.cache.clone() {
+            let id = *id;
+            self.after_commit(move || cache.invalidate(&id));
+        }
+    }
+
+    /// Encrypts the users written and decrypts the users read, to be set before the other
+    /// options.
+    pub fn with_encryption(mut self, cipher: FieldCipher) -> Self {
+        let encrypted = Arc::new(EncryptedRepository::new(self.repository.clone(), cipher));
+        self.repository = encrypted.clone();
+        self.encryption = Some(encrypted);
+        self
+    }
+
+    /// Re-encrypts the users with the current key and fields, None without encryption.
+    pub async fn rotate_encryption(&self) -> Result<Option<RotationReport>, StoreError> {
+        match &self.encryption {
+            Some(encryption) => encryption.rotate().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Runs the action once the unit of work the store writes in is committed, at once
+    /// outside of a unit of work.
+    fn after_commit(&self, action: impl FnOnce() + Send + 'static) {
+        match &self.commit_hooks {
+            Some(hooks) => hooks.push(Box::new(action)),
+            None => action(),
+        }
+    }
+
+    fn publish(&self, kind: ChangeKind, user: &User, version: Option<i64>) {
+        let events = self.events.clone();
+        let event = ChangeEvent::new(REPOSITORY_NAME, kind, user._id, user.org_id, version);
+        self.after_commit(move || events.publish(event));
+    }
+
+    pub async fn create(&self, user: &User) -> Result<(), StoreError> {
+        self.check_role(user, None)?;
+        self.check_organization(user).await?;
+        self.repository.insert(user).await?;
+        self.publish(ChangeKind::Created, user, Some(user.version));
+        Ok(())
+    }
+
+    /// Creates the users in a single batch and returns the outcome of each one, the users
+    /// failing do not prevent the others from being created.
+    pub async fn create_many(&self, users: &[User]) -> Vec<Result<(), StoreError>> {
+        let mut checked = Vec::with_capacity(users.len());
+        for user in users {
+            checked.push(match self.check_role(user, None) {
+                Ok(()) => self.check_organization(user).await,
+                Err(error) => Err(error),
+            });
+        }
+        let valid: Vec<User> = users
+            .iter()
+            .zip(&checked)
+            .filter(|(_, checked)| checked.is_ok())
+            .map(|(user, _)| user.clone())
+            .collect();
+        let mut inserted = self.repository.insert_many(&valid).await.into_iter();
+        let results: Vec<_> = checked
+            .into_iter()
+            .map(|checked| checked.and_then(|()| inserted.next().unwrap_or(Ok(()))))
+            .collect();
+        for (user, result) in users.iter().zip(&results) {
+            if result.is_ok() {
+                self.publish(ChangeKind::Created, user, Some(user.version));
+            }
+        }
+        results
+    }
+
+    pub async fn find_by_id(&self, id: &ObjectId) -> Result<Option<User>, StoreError> {
+        let user = self.find_active_by_id(id).await?;
+        Ok(user.filter(|user| self.tenant.allows(user.org_id.as_ref())))
+    }
+
+    /// The cache is shared by every tenant, the caller checks the tenant of the user.
+    async fn find_active_by_id(&self, id: &ObjectId) -> Result<Option<User>, StoreError> {
+        let Some(cache) = &self.cache else {
+            return self.repository.find_one(&active().eq("_id", *id)).await;
+        };
+        if let Some(user) = cache.get(id) {
+            return Ok(Some(user));
+        }
+        let generation = cache.generation();
+        let user = self.repository.find_one(&active().eq("_id", *id)).await?;
+        if let Some(user) = &user {
+            cache.insert(generation, *id, user.clone());
+        }
+        Ok(user)
+    }
+
+    pub async fn find_by_email(&self, email: &str) -> Result<Option<User>, StoreError> {
+        self.repository
+            .find_one(&self.visible().eq("email", email))
+            .await
+    }
+
+    /// Counts the users of the organization, soft-deleted ones included, whatever the tenant:
+    /// an organization is never deleted while another tenant sees no member in it.
+    pub async fn count_members(&self, org_id: &ObjectId) -> Result<u64, StoreError> {
+        self.repository
+            .count(&Filter::new().eq("org_id", *org_id))
+            .await
+    }
+
+    /// Whether a user, active or soft-deleted, already has the email, in any organization
+    /// since emails are unique across them.
+    pub async fn email_taken(&self, email: &str) -> Result<bool, StoreError> {
+        Ok(self
+            .repository
+            .count(&Filter::new().eq("email", email))
+            .await?
+            > 0)
+    }
+
+    /// Whether a user, active or soft-deleted, already has the id, in any organization.
+    pub async fn id_taken(&self, id: &ObjectId) -> Result<bool, StoreError> {
+        Ok(self.repository.find_by_id(id).await?.is_some())
+    }
+
+    /// Lists the active users matching the filter, see [`pagination::find_page`].
+    pub async fn page(
+        &self,
+        filter: Filter,
+        request: &PageRequest,
+    ) -> Result<Page<User>, StoreError> {
+        pagination::find_page(
+            self.repository.as_ref(),
+            &self.visible().and(filter),
+            request,
+        )
+        .await
+    }
+
+    /// Searches the active users by name and email, the most relevant first.
+    pub async fn search(
+        &self,
+        query: &str,
+        limit: u64,
+    ) -> Result<Vec<SearchHit<User>>, StoreError> {
+        self.repository.search(query, &self.visible(), limit).await
+    }
+
+    /// Replaces the user if its stored version is `expected_version`, or whatever it is when None,
+    /// and returns it with its version incremented.
+    /// Returns None if there is no such user, or if it is soft-deleted.
+    pub async fn update(
+        &self,
+        user: &User,
+        expected_version: Option<i64>,
+    ) -> Result<Option<User>, StoreError> {
+        let current = self.find_by_id(&user._id).await?;
+        let Some(expected) = self
+            .check_update(user, expected_version, current.as_ref())
+            .await?
+        else {
+            return Ok(None);
+        };
+        let visible = self.visible();
+        let updated = self.replace(user.clone(), &visible, expected).await?;
+        self.published(ChangeKind::Updated, updated)
+    }
+
+    /// Updates the users as [`UserStore::update`] with a single lookup and a single batch of
+    /// writes, and returns the outcome of each one.
+    pub async fn update_many(
+        &self,
+        users: &[(User, Option<i64>)],
+    ) -> Vec<Result<Option<User>, StoreError>> {
+        let ids: Vec<_> = users.iter().map(|(user, _)| user._id).collect();
+        let current = match self.find_many(&ids).await {
+            Ok(current) => current,
+            Err(error) => return failed(users.len(), error),
+        };
+        let mut checked = Vec::with_capacity(users.len());
+        for (user, expected_version) in users {
+            let expected = self
+                .check_update(user, *expected_version, current.get(&user._id))
+                .await;
+            checked
+                .push(expected.map(|expected| expected.map(|expected| (user.clone(), expected))));
+        }
+        self.replace_many(checked, &self.visible())
+            .await
+            .into_iter()
+            .map(|updated| updated.and_then(|user| self.published(ChangeKind::Updated, user)))
+            .collect()
+    }
+
+    /// The active users of the tenant with the ids, by id, read in a single lookup.
+    pub async fn find_many(&self, ids: &[ObjectId]) -> Result<HashMap<ObjectId, User>, StoreError> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let any = ids.iter().map(|id| Filter::new().eq("_id", *id)).collect();
+        let users = self.repository.find(&self.visible().any(any)).await?;
+        Ok(users.into_iter().map(|user| (user._id, user)).collect())
+    }
+
+    /// Checks that the user can replace the stored one, and returns the version expected to be
+    /// replaced, None if there is no stored user.
+    async fn check_update(
+        &self,
+        user: &User,
+        expected_version: Option<i64>,
+        current: Option<&User>,
+    ) -> Result<Option<i64>, StoreError> {
+        let Some(current) = current else {
+            return Ok(None);
+        };
+        let expected = expected_version.unwrap_or(current.version);
+        if current.version != expected {
+            return Err(StoreError::VersionConflict {
+                expected,
+                found: current.version,
+            });
+        }
+        self.check_role(user, Some(current))?;
+        // Users kept in an organization that was deleted can still be updated.
+        if user.org_id != current.org_id {
+            self.check_organization(user).await?;
+        }
+        Ok(Some(expected))
+    }
+
+    /// Publishes the change of the user if there is one, and returns it.
+    fn published(&self, kind: ChangeKind, user: Option<User>) -> Result<Option<User>, StoreError> {
+        if let Some(user) = &user {
+            self.publish(kind, user, Some(user.version));
+        }
+        Ok(user)
+    }
+
+    /// Replaces the stored user matching the filter if its version is still `expected`,
+    /// the check and the write being done atomically by the backend.
+    async fn replace(
+        &self,
+        mut user: User,
+        filter: &Filter,
+        expected: i64,
+    ) -> Result<Option<User>, StoreError> {
+        user.version = expected + 1;
+        let replaced = self
+            .repository
+            .update_where(&user, &filter.clone().eq("version", expected))
+            .await;
+        self.replaced(user, filter, expected, replaced).await
+    }
+
+    /// Replaces the users as [`UserStore::replace`] in a single batch of writes, with the version
+    /// each one is expected to have, the other outcomes being returned as they are.
+    async fn replace_many(
+        &self,
+        users: Vec<Result<Option<(User, i64)>, StoreError>>,
+        filter: &Filter,
+    ) -> Vec<Result<Option<User>, StoreError>> {
+        let writes: Vec<(User, Filter)> = users
+            .iter()
+            .filter_map(|user| match user {
+                Ok(Some((user, expected))) => Some((
+                    User {
+                        version: expected + 1,
+                        ..user.clone()
+                    },
+                    filter.clone().eq("version", *expected),
+                )),
+                _ => None,
+            })
+            .collect();
+        let mut replaced = self.repository.update_many(&writes).await.into_iter();
+        let mut results = Vec::with_capacity(users.len());
+        for user in users {
+            results.push(match user {
+                Ok(Some((mut user, expected))) => {
+                    user.version = expected + 1;
+                    let outcome = replaced.next().unwrap_or(Ok(false));
+                    self.replaced(user, filter, expected, outcome).await
+                }
+                other => other.map(|_| None),
+            });
+        }
+        results
+    }
+
+    /// The user if it was replaced, otherwise a version conflict if it still matches the filter,
+    /// or None if it does not anymore.
+    async fn replaced(
+        &self,
+        user: User,
+        filter: &Filter,
+        expected: i64,
+        replaced: Result<bool, StoreError>,
+    ) -> Result<Option<User>, StoreError> {
+        // After the write, or its commit, so that a lookup made meanwhile cannot cache the
+        // previous user.
+        self.invalidate(&user._id);
+        if replaced? {
+            return Ok(Some(user));
+        }
+        match self
+            .repository
+            .find_one(&filter.clone().eq("_id", user._id))
+            .await?
+        {
+            Some(current) => Err(StoreError::VersionConflict {
+                expected,
+                found: current.version,
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the user only if it is soft-deleted.
+    pub async fn find_deleted_by_id(&self, id: &ObjectId) -> Result<Option<User>, StoreError> {
+        let user = self.repository.find_by_id(id).await?;
+        Ok(user
+            .filter(|user| user.deleted_at.is_some() && self.tenant.allows(user.org_id.as_ref())))
+    }
+
+    /// Marks the user as deleted and returns it, None if there is no such active user.
+    /// The email stays taken until the user is purged.
+    pub async fn soft_delete(
+        &self,
+        id: &ObjectId,
+        deleted_by: &ObjectId,
+        deleted_at: DateTime,
+    ) -> Result<Option<User>, StoreError> {
+        let Some(mut user) = self.find_by_id(id).await? else {
+            return Ok(None);
+        };
+        self.check_target(&user)?;
+        let expected = user.version;
+        user.deleted_at = Some(deleted_at);
+        user.deleted_by = Some(*deleted_by);
+        let visible = self.visible();
+        let deleted = self.replace(user, &visible, expected).await?;
+        self.published(ChangeKind::Deleted, deleted)
+    }
+
+    /// Soft-deletes the users as [`UserStore::soft_delete`] with a single lookup and a single
+    /// batch of writes, and returns the outcome of each one.
+    pub async fn soft_delete_many(
+        &self,
+        ids: &[ObjectId],
+        deleted_by: &ObjectId,
+        deleted_at: DateTime,
+    ) -> Vec<Result<Option<User>, StoreError>> {
+        let current = match self.find_many(ids).await {
+            Ok(current) => current,
+            Err(error) => return failed(ids.len(), error),
+        };
+        let deletions = ids.iter().map(|id| Ok(current.get(id).cloned())).collect();
+        self.soft_deleted_many(deletions, Some(*deleted_by), deleted_at)
+            .await
+    }
+
+    /// Soft-deletes the users found, the other outcomes being returned as they are.
+    async fn soft_deleted_many(
+        &self,
+        users: Vec<Result<Option<User>, StoreError>>,
+        deleted_by: Option<ObjectId>,
+        deleted_at: DateTime,
+    ) -> Vec<Result<Option<User>, StoreError>> {
+        let deletions = users
+            .into_iter()
+            .map(|user| {
+                user.and_then(|user| {
+                    user.map(|user| {
+                        self.check_target(&user)?;
+                        let expected = user.version;
+                        let deleted = User {
+                            deleted_at: Some(deleted_at),
+                            deleted_by,
+                            ..user
+                        };
+                        Ok((deleted, expected))
+                    })
+                    .transpose()
+                })
+            })
+            .collect();
+        self.replace_many(deletions, &self.visible())
+            .await
+            .into_iter()
+            .map(|deleted| deleted.and_then(|user| self.published(ChangeKind::Deleted, user)))
+            .collect()
+    }
+
+    /// Records the login of the user, without changing its version as the user itself is
+    /// unchanged. A login racing with an update is not recorded.
+    pub async fn record_login(
+        &self,
+        user: &User,
+        logged_in_at: DateTime,
+    ) -> Result<(), StoreError> {
+        let user = User {
+            last_login_at: Some(logged_in_at),
+            ..user.clone()
+        };
+        self.repository
+            .update_where(&user, &active().eq("version", user.version))
+            .await?;
+        self.invalidate(&user._id);
+        Ok(())
+    }
+
+    /// Soft-deletes the active users who did not log in since the given date, or were created
+    /// before it when they never logged in, unless in a dry run, and returns their ids.
+    /// They are then purged with the other deleted users by [`UserStore::purge_deleted`].
+    pub async fn soft_delete_inactive(
+        &self,
+        before: DateTime,
+        deleted_at: DateTime,
+        dry_run: bool,
+    ) -> Result<Vec<ObjectId>, StoreError> {
+        // The ObjectIds start with their creation time, in seconds.
+        let mut created_before = [0; 12];
+        let seconds = (before.timestamp_millis() / 1000).clamp(0, u32::MAX as i64) as u32;
+        created_before[..4].copy_from_slice(&seconds.to_be_bytes());
+        let inactive = self.visible().any(vec![
+            Filter::new().lt("last_login_at", before),
+            Filter::new()
+                .eq("last_login_at", Bson::Null)
+                .lt("_id", ObjectId::from_bytes(created_before)),
+        ]);
+        let users = self.repository.find(&inactive).await?;
+        if dry_run {
+            return Ok(users.iter().map(|user| user._id).collect());
+        }
+        let deletions = users.into_iter().map(|user| Ok(Some(user))).collect();
+        let mut deleted = Vec::new();
+        // A user failing to be deleted is left for the next enforcement.
+        for user in self.soft_deleted_many(deletions, None, deleted_at).await {
+            match user {
+                Ok(Some(user)) => deleted.push(user._id),
+                Ok(None) => {}
+                Err(error) => log::warn!("Failed to delete an inactive user: {error}"),
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Brings a soft-deleted user back, returns None if there is no such deleted user.
+    pub async fn restore(&self, id: &ObjectId) -> Result<Option<User>, StoreError> {
+        let Some(mut user) = self.find_deleted_by_id(id).await? else {
+            return Ok(None);
+        };
+        self.check_target(&user)?;
+        let expected = user.version;
+        user.deleted_at = None;
+        user.deleted_by = None;
+        let restored = self
+            .replace(user, &self.tenant.filter("org_id"), expected)
+            .await?;
+        self.published(ChangeKind::Restored, restored)
+    }
+
+    /// Permanently deletes the users soft-deleted before the given date, unless in a dry run,
+    /// and returns their ids.
+    pub async fn purge_deleted(
+        &self,
+        before: DateTime,
+        dry_run: bool,
+    ) -> Result<Vec<ObjectId>, StoreError> {
+        let users = self
+            .repository
+            .find(&self.tenant.filter("org_id").lt("deleted_at", before))
+            .await?;
+        let ids: Vec<_> = users.iter().map(|user| user._id).collect();
+        if dry_run {
+            return Ok(ids);
+        }
+        let mut purged = Vec::new();
+        for (user, deleted) in users.iter().zip(self.repository.delete_many(&ids).await) {
+            self.invalidate(&user._id);
+            if deleted? {
+                self.publish(ChangeKind::Purged, user, None);
+                purged.push(user._id);
+            }
+        }
+        Ok(purged)
+    }
+}
+
+/// The same error for each of the `count` items of a batch.
+fn failed<R>(count: usize, error: StoreError) -> Vec<Result<R, StoreError>> {
+    let message = error.to_string();
+    (0..count)
+        .map(|_| Err(StoreError::Backend(message.clone())))
+        .collect()
+}
+
+impl std::fmt::Debug for UserStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserStore").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{
+        memory::{Collections, MemoryRepository},
+        MemoryUnitOfWork,
+    };
+
+    #[actix_web::test]
+    async fn test_soft_delete_restore_and_purge() {
+        let users = UserStore::new(Arc::new(MemoryRepository::<User>::new(
+            &Collections::default(),
+        )));
+        let admin_id = ObjectId::new();
+        let user = User::fixture("jane@example.com");
+        users.create(&user).await.unwrap();
+
+        let deleted_at = DateTime::from_millis(1_000);
+        let deleted = users
+            .soft_delete(&user._id, &admin_id, deleted_at)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(deleted.deleted_by, Some(admin_id));
+        assert_eq!(
+            users.find_deleted_by_id(&user._id).await.unwrap(),
+            Some(deleted)
+        );
+        assert_eq!(
+            users
+                .soft_delete(&user._id, &admin_id, deleted_at)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(users.find_by_id(&user._id).await.unwrap(), None);
+        assert_eq!(users.find_by_email(&user.email).await.unwrap(), None);
+        assert_eq!(users.update(&user, None).await.unwrap(), None);
+
+        let restored = users.restore(&user._id).await.unwrap().unwrap();
+        assert_eq!(
+            restored,
+            User {
+                version: 2,
+                ..user.clone()
+            }
+        );
+        assert_eq!(users.restore(&user._id).await.unwrap(), None);
+        assert_eq!(users.find_by_id(&user._id).await.unwrap(), Some(restored));
+
+        users
+            .soft_delete(&user._id, &admin_id, deleted_at)
+            .await
+            .unwrap();
+        assert_eq!(users.purge_deleted(deleted_at, false).await.unwrap(), []);
+        let later = DateTime::from_millis(2_000);
+        assert_eq!(users.purge_deleted(later, true).await.unwrap(), [user._id]);
+        assert!(users.find_deleted_by_id(&user._id).await.unwrap().is_some());
+        assert_eq!(users.purge_deleted(later, false).await.unwrap(), [user._id]);
+        assert_eq!(users.restore(&user._id).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_soft_delete_inactive() {
+        let users = UserStore::new(Arc::new(MemoryRepository::<User>::new(
+            &Collections::default(),
+        )));
+        let created_long_ago = ObjectId::from_bytes([0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let never_logged_in = User {
+            _id: created_long_ago,
+            ..User::fixture("jane@example.com")
+        };
+        let logged_in_long_ago = User::fixture("john@example.com");
+        let logged_in = User::fixture("jim@example.com");
+        let created = User::fixture("joan@example.com");
+        for user in [&never_logged_in, &logged_in_long_ago, &logged_in, &created] {
+            users.create(user).await.unwrap();
+        }
+        users
+            .record_login(&logged_in_long_ago, DateTime::from_millis(1_000))
+            .await
+            .unwrap();
+        users
+            .record_login(&logged_in, DateTime::now())
+            .await
+            .unwrap();
+        let found = users.find_by_id(&logged_in._id).await.unwrap().unwrap();
+        assert_eq!(found.version, logged_in.version);
+
+        let before = DateTime::from_millis(DateTime::now().timestamp_millis() - 60_000);
+        let deleted_at = before;
+        let mut inactive = users
+            .soft_delete_inactive(before, deleted_at, true)
+            .await
+            .unwrap();
+        inactive.sort();
+        let mut expected = vec![never_logged_in._id, logged_in_long_ago._id];
+        expected.sort();
+        assert_eq!(inactive, expected);
+        assert!(users
+            .find_by_id(&never_logged_in._id)
+            .await
+            .unwrap()
+            .is_some());
+
+        let mut deleted = users
+            .soft_delete_inactive(before, deleted_at, false)
+            .await
+            .unwrap();
+        deleted.sort();
+        assert_eq!(deleted, expected);
+        let deleted = users
+            .find_deleted_by_id(&never_logged_in._id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (deleted.deleted_at, deleted.deleted_by),
+            (Some(deleted_at), None)
+        );
+        // Purged with the other deleted users once the grace period is over.
+        assert_eq!(
+            users
+                .purge_deleted(DateTime::now(), false)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(users.find_by_id(&logged_in._id).await.unwrap(), Some(found));
+        assert!(users.find_by_id(&created._id).await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_update_checks_version() {
+        let users = UserStore::new(Arc::new(MemoryRepository::<User>::new(
+            &Collections::default(),
+        )));
+        let mut user = User::fixture("jane@example.com");
+        users.create(&user).await.unwrap();
+
+        user.first_name = "Janet".into();
+        let updated = users.update(&user, Some(0)).await.unwrap().unwrap();
+        assert_eq!(updated.version, 1);
+
+        // Another client still holding version 0.
+        user.first_name = "Joan".into();
+        assert!(matches!(
+            users.update(&user, Some(0)).await,
+            Err(StoreError::VersionConflict {
+                expected: 0,
+                found: 1
+            })
+        ));
+        assert_eq!(users.find_by_id(&user._id).await.unwrap(), Some(updated));
+
+        assert_eq!(users.update(&user, None).await.unwrap().unwrap().version, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_cached_lookups() {
+        let repository = Arc::new(MemoryRepository::<User>::new(&Collections::default()));
+        let users = UserStore::new(repository.clone()).with_cache(10, Duration::from_secs(60));
+        let mut user = User::fixture("jane@example.com");
+        users.create(&user).await.unwrap();
+        assert_eq!(users.find_by_id(&user._id).await.unwrap().unwrap(), user);
+
+        // Written behind the back of the store, the cached user is still returned.
+        let mut renamed = user.clone();
+        renamed.first_name = "Janet".into();
+        repository
+            .update_where(&renamed, &Filter::new())
+            .await
+            .unwrap();
+        assert_eq!(users.find_by_id(&user._id).await.unwrap().unwrap(), user);
+
+        user.last_name = "Smith".into();
+        let updated = users.update(&user, None).await.unwrap().unwrap();
+        assert_eq!(users.find_by_id(&user._id).await.unwrap(), Some(updated));
+        users
+            .soft_delete(&user._id, &ObjectId::new(), DateTime::from_millis(1_000))
+            .await
+            .unwrap();
+        assert_eq!(users.find_by_id(&user._id).await.unwrap(), None);
+
+        let stats = users.cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.size), (3, 3, 0));
+        assert_eq!(UserStore::new(repository).cache_stats(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_change_events() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(16);
+        let users = UserStore::new(Arc::new(MemoryRepository::<User>::new(
+            &Collections::default(),
+        )))
+        .with_events(Events::new(sender));
+        let user = User::fixture("jane@example.com");
+        users.create(&user).await.unwrap();
+        // Duplicates change nothing, they publish nothing.
+        assert!(users.create(&user).await.is_err());
+        users.update(&user, None).await.unwrap();
+        let deleted_at = DateTime::from_millis(1_000);
+        users
+            .soft_delete(&user._id, &ObjectId::new(), deleted_at)
+            .await
+            .unwrap();
+        users.restore(&user._id).await.unwrap();
+        users
+            .soft_delete(&user._id, &ObjectId::new(), deleted_at)
+            .await
+            .unwrap();
+        users
+            .purge_deleted(DateTime::from_millis(2_000), false)
+            .await
+            .unwrap();
+
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            assert_eq!((event.collection.as_str(), event.id), ("users", user._id));
+            assert!(event.visible_to(&Tenant::of(&user)));
+            assert!(!event.visible_to(&Tenant::Organization(Some(ObjectId::new()))));
+            let packet = event.encode().unwrap();
+            let packet = mongodb::bson::Document::from_reader(packet.as_slice()).unwrap();
+            assert_eq!(packet.get_i32("id").unwrap(), 0);
+            let decoded: ChangeEvent =
+                mongodb::bson::from_document(packet.get_document("data").unwrap().clone()).unwrap();
+            assert_eq!(
+                decoded,
+                ChangeEvent {
+                    org_id: None,
+                    ..event.clone()
+                }
+            );
+            events.push((event.kind, event.version));
+        }
+        assert_eq!(
+            events,
+            [
+                (ChangeKind::Created, Some(0)),
+                (ChangeKind::Updated, Some(1)),
+                (ChangeKind::Deleted, Some(2)),
+                (ChangeKind::Restored, Some(3)),
+                (ChangeKind::Deleted, Some(4)),
+                (ChangeKind::Purged, None),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_events_wait_for_the_commit() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(16);
+        let collections = Collections::default();
+        let users = UserStore::new(Arc::new(MemoryRepository::<User>::new(&collections)))
+            .with_cache(10, Duration::from_secs(60))
+            .with_events(Events::new(sender));
+        let user = User::fixture("jane@example.com");
+        users.create(&user).await.unwrap();
+        receiver.try_recv().unwrap();
+        users.find_by_id(&user._id).await.unwrap();
+
+        // Rolled back, the update publishes nothing and leaves the cached user.
+        let unit = MemoryUnitOfWork::begin(&collections).unwrap();
+        users.in_unit(&unit).update(&user, None).await.unwrap();
+        Box::new(unit).rollback().await.unwrap();
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(
+            users.find_by_id(&user._id).await.unwrap(),
+            Some(user.clone())
+        );
+
+        let unit = MemoryUnitOfWork::begin(&collections).unwrap();
+        let updated = users
+            .in_unit(&unit)
+            .update(&user, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(
+            users.find_by_id(&user._id).await.unwrap(),
+            Some(user.clone())
+        );
+        Box::new(unit).commit().await.unwrap();
+        let event = receiver.try_recv().unwrap();
+        assert_eq!((event.kind, event.version), (ChangeKind::Updated, Some(1)));
+        assert_eq!(users.find_by_id(&user._id).await.unwrap(), Some(updated));
+    }
+}
@@ -1,61 +1,1685 @@
 use actix_web::{
-    test::{call_and_read_body, call_and_read_body_json, init_service, TestRequest},
-    web::Bytes,
+    http::StatusCode,
+    test::{call_service, init_service, read_body, read_body_json, try_call_service, TestRequest},
 };
-use mongodb::Client;
+use argon2::Config;
+use futures::{SinkExt, StreamExt};
+use mongodb::bson::{doc, oid::ObjectId};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
 
 use crate::{
-    controllers::users::{create_user, get_user_by_email},
-    models::users,
+    controllers::{
+        audit::AuditPage,
+        bulk::{BulkReport, BulkStatus},
+        organizations::{OrganizationBody, OrganizationPage},
+        retention::PurgeLogPage,
+        users::{SearchResult, UserPage},
+    },
+    drivers::{MemoryDatabase, SqliteDatabase},
+    models::{
+        audit::AuditAction,
+        organizations::{Organization, OrganizationResponse},
+        retention::{PurgeEntryResponse, RetentionRule},
+        users::{AuthReq, SanitizedUser, User},
+    },
+    services::{
+        retention::{self, RetentionPolicy, RetentionTask, MAX_LOGGED_IDS},
+        transfer::ImportReport,
+    },
+    store::{cache::CacheStats, metrics::Metrics},
 };
 
 use super::*;
 
+const ADMIN_EMAIL: &str = "admin@example.com";
+const ADMIN_PASSWORD: &str = "password";
+
+/// Builds the application state on top of a migrated in-memory database holding an admin.
+async fn memory_app_state() -> (web::Data<ProgramAppState>, AuthState) {
+    let memory_db: MemoryDatabase = GenericDatabase::new();
+    app_state(memory_db, "memory://").await
+}
+
+/// An application on an in-memory SQLite database, to go through the translation of the queries.
+async fn sqlite_app_state() -> (web::Data<ProgramAppState>, AuthState) {
+    let sqlite_db: SqliteDatabase = GenericDatabase::new();
+    app_state(sqlite_db, "sqlite::memory:").await
+}
+
+async fn app_state<D: GenericDatabase + 'static>(
+    mut db: D,
+    uri: &str,
+) -> (web::Data<ProgramAppState>, AuthState) {
+    db.connect(uri)
+        .await
+        .expect("connecting the database should succeed");
+    migrations::migrate(&mut db)
+        .await
+        .expect("migrations should succeed");
+
+    let salt = "thisisasupersecretkey";
+    let organization = Organization {
+        _id: ObjectId::new(),
+        name: "Administration".into(),
+        created_at: mongodb::bson::DateTime::now(),
+    };
+    let admin_user = User {
+        first_name: "Admin".into(),
+        last_name: "Istrator".into(),
+        role: "god".into(),
+        org_id: Some(organization._id),
+        password: argon2::hash_encoded(
+            ADMIN_PASSWORD.as_bytes(),
+            salt.as_bytes(),
+            &Config::original(),
+        )
+        .unwrap(),
+        ..User::fixture(ADMIN_EMAIL)
+    };
+    let database: Arc<dyn GenericDatabase> = Arc::new(db);
+    let (ui_sender_channel, _) = broadcast::channel(32);
+    let (change_sender, _) = broadcast::channel(32);
+    let events = Events::new(change_sender.clone());
+    let organizations = OrganizationStore::new(
+        database
+            .organizations()
+            .expect("organizations should be available"),
+    )
+    .with_events(events.clone());
+    organizations
+        .create(&organization)
+        .await
+        .expect("seeding should succeed");
+    let metrics = Metrics::new(Duration::from_secs(1));
+    let users =
+        UserStore::new(metrics.instrument(database.users().expect("users should be available")))
+            .with_events(events)
+            .with_cache(100, Duration::from_secs(60))
+            .with_organizations(organizations.clone());
+    users
+        .create(&admin_user)
+        .await
+        .expect("seeding should succeed");
+    let audit_log = AuditLog::new(database.audit_log().expect("audit log should be available"));
+    let purge_log = PurgeLog::new(database.purge_log().expect("purge log should be available"));
+    let auth_data = AuthState {
+        users: users.clone(),
+    };
+    let app_state = web::Data::new(ProgramAppState {
+        ntp: Ntp::new(),
+        database,
+        users,
+        organizations,
+        audit_log,
+        purge_log,
+        metrics,
+        ui_sender_channel,
+        change_sender,
+    });
+    (app_state, auth_data)
+}
+
 #[actix_web::test]
-#[ignore = "requires MongoDB instance running"]
-async fn test() {
-    let uri = std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".into());
+async fn test_users_routes() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .uri(&format!("/users/{ADMIN_EMAIL}"))
+        .to_request();
+    let error = try_call_service(&app, req).await.unwrap_err();
+    assert_eq!(
+        error.as_response_error().status_code(),
+        StatusCode::UNAUTHORIZED
+    );
+
+    let req = TestRequest::post()
+        .uri("/auth")
+        .set_json(AuthReq {
+            email: ADMIN_EMAIL.into(),
+            password: ADMIN_PASSWORD.into(),
+        })
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token: String = read_body_json(resp).await;
+    let authorization = ("Authorization", format!("Bearer {token}"));
+
+    let new_user = json::object! {
+        "first_name": "Jane",
+        "last_name": "Doe",
+        "role": "user",
+        "org_id": "",
+        "email": "jane@example.com",
+        "password": "secret",
+    };
+    let req = TestRequest::post()
+        .uri("/users/")
+        .insert_header(authorization.clone())
+        .set_payload(new_user.dump())
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let req = TestRequest::post()
+        .uri("/users/")
+        .insert_header(authorization.clone())
+        .set_payload(new_user.dump())
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CONFLICT);
+
+    let req = TestRequest::get()
+        .uri("/users/jane@example.com")
+        .insert_header(authorization.clone())
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let jane: SanitizedUser = read_body_json(resp).await;
+    assert_eq!(jane.first_name, "Jane");
 
-    let client = Client::with_uri_str(uri).await.expect("failed to connect");
+    let mut renamed_user = new_user.clone();
+    renamed_user["first_name"] = "Janet".into();
+    let req = TestRequest::put()
+        .uri(&format!("/users/{}", jane._id))
+        .insert_header(authorization.clone())
+        .set_payload(renamed_user.dump())
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
 
-    // Clear any data currently in the users collection.
-    client
-        .database(&DATABASE_NAME)
-        .collection::<User>(users::REPOSITORY_NAME)
-        .drop()
+    let req = TestRequest::get()
+        .uri("/users/jane@example.com")
+        .insert_header(authorization.clone())
+        .to_request();
+    let janet: SanitizedUser = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(janet._id, jane._id);
+    assert_eq!(janet.first_name, "Janet");
+
+    let req = TestRequest::delete()
+        .uri(&format!("/users/{}", jane._id))
+        .insert_header(authorization.clone())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NO_CONTENT
+    );
+
+    let req = TestRequest::get()
+        .uri("/users/jane@example.com")
+        .insert_header(authorization.clone())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let req = TestRequest::post()
+        .uri(&format!("/users/{}/restore", jane._id))
+        .insert_header(authorization.clone())
+        .to_request();
+    let restored: SanitizedUser = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(restored.first_name, "Janet");
+
+    let req = TestRequest::get()
+        .uri("/users/jane@example.com")
+        .insert_header(authorization)
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+/// Logs in and returns the authorization header.
+async fn login<B: actix_web::body::MessageBody>(
+    app: &impl actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    email: &str,
+    password: &str,
+) -> (&'static str, String) {
+    let req = TestRequest::post()
+        .uri("/auth")
+        .set_json(AuthReq {
+            email: email.into(),
+            password: password.into(),
+        })
+        .to_request();
+    let token: String = read_body_json(call_service(app, req).await).await;
+    ("Authorization", format!("Bearer {token}"))
+}
+
+#[actix_web::test]
+async fn test_user_etags() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+
+    let req = TestRequest::get()
+        .uri(&format!("/users/{ADMIN_EMAIL}"))
+        .insert_header(authorization.clone())
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.headers().get("ETag").unwrap(), "\"0\"");
+    let admin: SanitizedUser = read_body_json(resp).await;
+
+    let req = TestRequest::patch()
+        .uri(&format!("/users/{}", admin._id))
+        .insert_header(authorization.clone())
+        .insert_header(("If-Match", "\"0\""))
+        .set_payload(json::object! { "first_name": "Ada" }.dump())
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("ETag").unwrap(), "\"1\"");
+    let patched: SanitizedUser = read_body_json(resp).await;
+    assert_eq!(patched.first_name, "Ada");
+    assert_eq!(patched.last_name, "Istrator");
+
+    // A client still holding the first version.
+    let req = TestRequest::patch()
+        .uri(&format!("/users/{}", admin._id))
+        .insert_header(authorization.clone())
+        .insert_header(("If-Match", "\"0\""))
+        .set_payload(json::object! { "first_name": "Grace" }.dump())
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(resp.headers().get("ETag").unwrap(), "\"1\"");
+
+    let req = TestRequest::patch()
+        .uri(&format!("/users/{}", admin._id))
+        .insert_header(authorization.clone())
+        .set_payload(json::object! { "unknown": "field" }.dump())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+
+    let replacement = json::object! {
+        "first_name": "Grace",
+        "last_name": "Hopper",
+        "role": "god",
+        "org_id": "",
+        "email": ADMIN_EMAIL,
+        "password": ADMIN_PASSWORD,
+    };
+    let req = TestRequest::put()
+        .uri(&format!("/users/{}", admin._id))
+        .insert_header(authorization.clone())
+        .insert_header(("If-Match", "\"1\""))
+        .set_payload(replacement.dump())
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("ETag").unwrap(), "\"2\"");
+
+    // Without If-Match, the last write wins.
+    let req = TestRequest::patch()
+        .uri(&format!("/users/{}", admin._id))
+        .insert_header(authorization)
+        .set_payload(json::object! { "last_name": "Lovelace" }.dump())
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("ETag").unwrap(), "\"3\"");
+}
+
+#[actix_web::test]
+async fn test_bulk_users() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let users = app_state.users.clone();
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+    let user = |email: &str| {
+        json::object! {
+            "first_name": "Jane",
+            "last_name": "Doe",
+            "role": "user",
+            "email": email,
+            "password": "password",
+        }
+    };
+    let statuses = |report: &BulkReport| -> Vec<BulkStatus> {
+        report.results.iter().map(|result| result.status).collect()
+    };
+
+    let items = json::array![
+        user("jane@example.com"),
+        user(ADMIN_EMAIL),
+        json::object! { "first_name": "John" },
+        user("jane@example.com"),
+        user("john@example.com"),
+    ];
+    let req = TestRequest::post()
+        .uri("/users/bulk")
+        .insert_header(authorization.clone())
+        .set_payload(items.dump())
+        .to_request();
+    let report: BulkReport = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(
+        statuses(&report),
+        [
+            BulkStatus::Created,
+            BulkStatus::DuplicateEmail,
+            BulkStatus::Invalid,
+            BulkStatus::DuplicateEmail,
+            BulkStatus::Created,
+        ]
+    );
+    assert_eq!((report.succeeded, report.failed), (2, 3));
+    let jane = report.results[0].id.unwrap();
+    let john = report.results[4].id.unwrap();
+    assert!(users
+        .find_by_email("john@example.com")
         .await
-        .expect("drop collection should succeed");
+        .unwrap()
+        .is_some());
+
+    let items = json::array![
+        { "id": jane.to_hex(), "first_name": "Janet", "version": 0 },
+        { "id": john.to_hex(), "first_name": "Johnny", "version": 7 },
+        { "id": ObjectId::new().to_hex(), "first_name": "Nobody" },
+        { "id": john.to_hex(), "email": "jane@example.com" },
+        { "first_name": "No id" },
+    ];
+    let req = TestRequest::patch()
+        .uri("/users/bulk")
+        .insert_header(authorization.clone())
+        .set_payload(items.dump())
+        .to_request();
+    let report: BulkReport = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(
+        statuses(&report),
+        [
+            BulkStatus::Updated,
+            BulkStatus::VersionConflict,
+            BulkStatus::NotFound,
+            BulkStatus::DuplicateEmail,
+            BulkStatus::Invalid,
+        ]
+    );
+    let janet = users.find_by_id(&jane).await.unwrap().unwrap();
+    assert_eq!((janet.first_name.as_str(), janet.version), ("Janet", 1));
 
+    let items = json::array![jane.to_hex(), jane.to_hex(), "not an id"];
+    let req = TestRequest::delete()
+        .uri("/users/bulk")
+        .insert_header(authorization.clone())
+        .set_payload(items.dump())
+        .to_request();
+    let report: BulkReport = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(
+        statuses(&report),
+        [
+            BulkStatus::Deleted,
+            BulkStatus::NotFound,
+            BulkStatus::Invalid
+        ]
+    );
+    assert_eq!(users.find_by_id(&jane).await.unwrap(), None);
+
+    let req = TestRequest::post()
+        .uri("/users/bulk")
+        .insert_header(authorization)
+        .set_payload(user("single@example.com").dump())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_web::test]
+async fn test_cors() {
+    let (app_state, auth_data) = memory_app_state().await;
     let app = init_service(
         App::new()
-            .app_data(web::Data::new(client))
-            .service(create_user)
-            .service(get_user_by_email),
+            .app_data(app_state)
+            .wrap(cors())
+            .configure(configure_routes(auth_data)),
     )
     .await;
+    let req = TestRequest::default()
+        .method(actix_web::http::Method::OPTIONS)
+        .uri(&format!("/users/{}", ObjectId::new()))
+        .insert_header(("Origin", "https://example.com"))
+        .insert_header(("Access-Control-Request-Method", "PATCH"))
+        .insert_header(("Access-Control-Request-Headers", "authorization, if-match"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let allowed = |name: &str| {
+        resp.headers()
+            .get(name)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_lowercase()
+    };
+    assert!(allowed("access-control-allow-methods").contains("patch"));
+    assert!(allowed("access-control-allow-headers").contains("if-match"));
 
-    let user = User {
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+    let req = TestRequest::get()
+        .uri(&format!("/users/{ADMIN_EMAIL}"))
+        .insert_header(("Origin", "https://example.com"))
+        .insert_header(authorization)
+        .to_request();
+    let resp = call_service(&app, req).await;
+    let exposed = resp
+        .headers()
+        .get("access-control-expose-headers")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(exposed.to_lowercase().contains("etag"), "{exposed}");
+}
+
+#[actix_web::test]
+async fn test_metrics() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    // The login looks the admin up by email.
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+
+    let req = TestRequest::get()
+        .uri("/metrics/")
+        .insert_header(authorization)
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = read_body(resp).await;
+    let text = std::str::from_utf8(&body).unwrap();
+    assert!(text.contains("# TYPE db_operation_duration_seconds histogram"));
+    assert!(text.contains(
+        "db_operation_duration_seconds_count{collection=\"users\",operation=\"find_one\"}"
+    ));
+}
+
+#[actix_web::test]
+async fn test_list_users() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let org_id = ObjectId::new();
+    let organization = Organization {
+        _id: org_id,
+        name: "Acme".into(),
+        created_at: mongodb::bson::DateTime::now(),
+    };
+    app_state.organizations.create(&organization).await.unwrap();
+    for (first_name, last_name, role) in [
+        ("Jane", "Doe", "user"),
+        ("John", "Doe", "user"),
+        ("Ada", "Lovelace", "user"),
+        ("Alan", "Turing", "admin"),
+    ] {
+        let user = User {
+            first_name: first_name.into(),
+            last_name: last_name.into(),
+            role: role.into(),
+            org_id: Some(org_id),
+            ..User::fixture(&format!("{}@example.com", first_name.to_lowercase()))
+        };
+        app_state.users.create(&user).await.unwrap();
+    }
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+
+    let mut uri = "/users/?sort=-last_name&limit=2&total=true".to_string();
+    let mut last_names = Vec::new();
+    loop {
+        let req = TestRequest::get()
+            .uri(&uri)
+            .insert_header(authorization.clone())
+            .to_request();
+        let page: UserPage = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(page.total, Some(5));
+        last_names.extend(page.users.into_iter().map(|user| user.last_name));
+        match page.next_cursor {
+            Some(cursor) => {
+                uri = format!("/users/?sort=-last_name&limit=2&total=true&cursor={cursor}")
+            }
+            None => break,
+        }
+    }
+    assert_eq!(last_names, ["Turing", "Lovelace", "Istrator", "Doe", "Doe"]);
+
+    let req = TestRequest::get()
+        .uri(&format!(
+            "/users/?org_id={org_id}&role=user&name=J&sort=first_name"
+        ))
+        .insert_header(authorization.clone())
+        .to_request();
+    let page: UserPage = read_body_json(call_service(&app, req).await).await;
+    let first_names: Vec<_> = page
+        .users
+        .iter()
+        .map(|user| user.first_name.as_str())
+        .collect();
+    assert_eq!(first_names, ["Jane", "John"]);
+    assert_eq!(page.next_cursor, None);
+    assert_eq!(page.total, None);
+
+    let req = TestRequest::get()
+        .uri("/users/?email=ada")
+        .insert_header(authorization.clone())
+        .to_request();
+    let page: UserPage = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(page.users.len(), 1);
+
+    for uri in [
+        "/users/?sort=password",
+        "/users/?cursor=nope",
+        "/users/?org_id=nope",
+    ] {
+        let req = TestRequest::get()
+            .uri(uri)
+            .insert_header(authorization.clone())
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
+
+#[actix_web::test]
+async fn test_search_users() {
+    let (app_state, auth_data) = memory_app_state().await;
+    check_search_users(app_state, auth_data).await;
+}
+
+#[actix_web::test]
+async fn test_search_users_sqlite() {
+    let (app_state, auth_data) = sqlite_app_state().await;
+    check_search_users(app_state, auth_data).await;
+}
+
+/// The words match by prefix, see `mongo::text_search` for MongoDB.
+async fn check_search_users(app_state: web::Data<ProgramAppState>, auth_data: AuthState) {
+    for (first_name, last_name, email) in [
+        ("Jane", "Doe", "jane@example.com"),
+        ("Mary Jane", "Janeway", "mary.jane@example.com"),
+        ("John", "Smith", "john@example.com"),
+    ] {
+        let user = User {
+            first_name: first_name.into(),
+            last_name: last_name.into(),
+            ..User::fixture(email)
+        };
+        app_state.users.create(&user).await.unwrap();
+    }
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+
+    let req = TestRequest::get()
+        .uri("/users/search?q=jane")
+        .insert_header(authorization.clone())
+        .to_request();
+    let results: Vec<SearchResult> = read_body_json(call_service(&app, req).await).await;
+    let emails: Vec<_> = results
+        .iter()
+        .map(|result| result.user.email.as_str())
+        .collect();
+    assert_eq!(emails, ["mary.jane@example.com", "jane@example.com"]);
+    assert!(results[0].score > results[1].score);
+    assert_eq!(results[0].highlights["last_name"], "<em>Janeway</em>");
+    assert_eq!(results[1].highlights["email"], "<em>jane</em>@example.com");
+
+    // Quotes and dashes are not operators, only the words of the query are searched.
+    for query in ["jan", "%22jane%22", "-jane"] {
+        let req = TestRequest::get()
+            .uri(&format!("/users/search?q={query}"))
+            .insert_header(authorization.clone())
+            .to_request();
+        let results: Vec<SearchResult> = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(results.len(), 2, "{query}");
+    }
+
+    let req = TestRequest::get()
+        .uri("/users/search?q=nobody")
+        .insert_header(authorization)
+        .to_request();
+    let results: Vec<SearchResult> = read_body_json(call_service(&app, req).await).await;
+    assert!(results.is_empty());
+
+    let req = TestRequest::post()
+        .uri("/users/")
+        .insert_header(login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await)
+        .set_payload(
+            json::object! {
+                "first_name": "Joe",
+                "last_name": "User",
+                "role": "user",
+                "org_id": "",
+                "email": "joe@example.com",
+                "password": "secret",
+            }
+            .dump(),
+        )
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CREATED);
+    let req = TestRequest::get()
+        .uri("/users/search?q=jane")
+        .insert_header(login(&app, "joe@example.com", "secret").await)
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_web::test]
+async fn test_export_import_users() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let jane = User {
+        password: argon2::hash_encoded(b"secret", b"thisisasupersecretkey", &Config::original())
+            .unwrap(),
+        ..User::fixture("jane@example.com")
+    };
+    app_state.users.create(&jane).await.unwrap();
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+
+    let req = TestRequest::get()
+        .uri("/users/export?format=csv")
+        .insert_header(authorization.clone())
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "text/csv");
+    let csv = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines[0], "_id,first_name,last_name,role,org_id,email");
+    assert_eq!(lines.len(), 3);
+    assert!(!csv.contains("$argon2"));
+
+    let req = TestRequest::get()
+        .uri("/users/export?include_hashes=true")
+        .insert_header(authorization)
+        .to_request();
+    let dump = read_body(call_service(&app, req).await).await;
+
+    // Imported into another deployment, where the administrator already exists.
+    let (app_state, auth_data) = memory_app_state().await;
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+    for dry_run in [true, false] {
+        let req = TestRequest::post()
+            .uri(&format!("/users/import?format=ndjson&dry_run={dry_run}"))
+            .insert_header(authorization.clone())
+            .set_payload(dump.clone())
+            .to_request();
+        let report: ImportReport = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(report.dry_run, dry_run);
+        assert_eq!((report.records, report.imported), (2, 1));
+        assert_eq!(
+            report.conflicts[0].email.as_deref(),
+            Some(ADMIN_EMAIL),
+            "{report:?}"
+        );
+    }
+
+    let req = TestRequest::post()
+        .uri("/users/import")
+        .insert_header(login(&app, "jane@example.com", "secret").await)
+        .set_payload(dump)
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_web::test]
+async fn test_user_cache() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let users = app_state.users.clone();
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+    let stats = || {
+        TestRequest::get()
+            .uri("/users/cache")
+            .insert_header(authorization.clone())
+            .to_request()
+    };
+
+    // The first authenticated request loads the administrator, the next one finds it cached.
+    let first: CacheStats = read_body_json(call_service(&app, stats()).await).await;
+    let second: CacheStats = read_body_json(call_service(&app, stats()).await).await;
+    assert_eq!((first.hits, first.misses, first.size), (0, 1, 1));
+    assert_eq!((second.hits, second.misses), (1, 1));
+
+    // Demoted, the administrator is no longer allowed in.
+    let mut admin = users.find_by_email(ADMIN_EMAIL).await.unwrap().unwrap();
+    admin.role = "user".into();
+    users.update(&admin, None).await.unwrap();
+    assert_eq!(
+        call_service(&app, stats()).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_web::test]
+async fn test_organizations_routes() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+
+    let req = TestRequest::post()
+        .uri("/organizations/")
+        .insert_header(authorization.clone())
+        .set_json(OrganizationBody {
+            name: "Acme".into(),
+        })
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let acme: OrganizationResponse = read_body_json(resp).await;
+    let req = TestRequest::post()
+        .uri("/organizations/")
+        .insert_header(authorization.clone())
+        .set_json(OrganizationBody {
+            name: "Acme".into(),
+        })
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CONFLICT);
+
+    // Users can only join existing organizations.
+    let new_user = |email: &str, org_id: &str| {
+        TestRequest::post()
+            .uri("/users/")
+            .insert_header(authorization.clone())
+            .set_payload(
+                json::object! {
+                    "first_name": "Jane",
+                    "last_name": "Doe",
+                    "role": "user",
+                    "org_id": org_id,
+                    "email": email,
+                    "password": "secret",
+                }
+                .dump(),
+            )
+            .to_request()
+    };
+    let req = new_user("jane@example.com", &ObjectId::new().to_hex());
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    let req = new_user("jane@example.com", &acme._id.to_hex());
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CREATED);
+    let req = new_user("joe@example.com", "");
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let req = TestRequest::get()
+        .uri(&format!("/organizations/{}/members", acme._id))
+        .insert_header(authorization.clone())
+        .to_request();
+    let page: UserPage = read_body_json(call_service(&app, req).await).await;
+    let emails: Vec<_> = page.users.iter().map(|user| user.email.as_str()).collect();
+    assert_eq!(emails, ["jane@example.com"]);
+    let jane_id = page.users[0]._id;
+
+    let req = TestRequest::put()
+        .uri(&format!("/organizations/{}", acme._id))
+        .insert_header(authorization.clone())
+        .set_json(OrganizationBody {
+            name: "Acme Corp".into(),
+        })
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    let req = TestRequest::get()
+        .uri("/organizations/")
+        .insert_header(authorization.clone())
+        .to_request();
+    let page: OrganizationPage = read_body_json(call_service(&app, req).await).await;
+    let names: Vec<_> = page
+        .organizations
+        .iter()
+        .map(|organization| organization.name.as_str())
+        .collect();
+    assert_eq!(names, ["Acme Corp", "Administration"]);
+
+    // Not while it has members.
+    let delete = || {
+        TestRequest::delete()
+            .uri(&format!("/organizations/{}", acme._id))
+            .insert_header(authorization.clone())
+            .to_request()
+    };
+    assert_eq!(
+        call_service(&app, delete()).await.status(),
+        StatusCode::CONFLICT
+    );
+    let req = TestRequest::patch()
+        .uri(&format!("/users/{jane_id}"))
+        .insert_header(authorization.clone())
+        .set_payload(json::object! { "org_id": null }.dump())
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    assert_eq!(
+        call_service(&app, delete()).await.status(),
+        StatusCode::NO_CONTENT
+    );
+    let req = TestRequest::get()
+        .uri(&format!("/organizations/{}", acme._id))
+        .insert_header(authorization)
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[actix_web::test]
+async fn test_tenant_isolation() {
+    let (app_state, auth_data) = memory_app_state().await;
+    check_tenant_isolation(app_state, auth_data).await;
+}
+
+#[actix_web::test]
+async fn test_tenant_isolation_sqlite() {
+    let (app_state, auth_data) = sqlite_app_state().await;
+    check_tenant_isolation(app_state, auth_data).await;
+}
+
+async fn check_tenant_isolation(app_state: web::Data<ProgramAppState>, auth_data: AuthState) {
+    let mut members = Vec::new();
+    for (name, email, role) in [
+        ("Acme", "acme@example.com", "admin"),
+        ("Globex", "globex@example.com", "user"),
+    ] {
+        let organization = Organization {
+            _id: ObjectId::new(),
+            name: name.into(),
+            created_at: mongodb::bson::DateTime::now(),
+        };
+        app_state.organizations.create(&organization).await.unwrap();
+        let user = User {
+            last_name: name.into(),
+            role: role.into(),
+            org_id: Some(organization._id),
+            password: argon2::hash_encoded(
+                ADMIN_PASSWORD.as_bytes(),
+                b"thisisasupersecretkey",
+                &Config::original(),
+            )
+            .unwrap(),
+            ..User::fixture(email)
+        };
+        app_state.users.create(&user).await.unwrap();
+        members.push(user);
+    }
+    let (acme, globex) = (&members[0], &members[1]);
+    let acme_org = acme.org_id.unwrap();
+    let globex_org = globex.org_id.unwrap();
+    let users = app_state.users.clone();
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, &acme.email, ADMIN_PASSWORD).await;
+    let get = |uri: String| {
+        TestRequest::get()
+            .uri(&uri)
+            .insert_header(authorization.clone())
+            .to_request()
+    };
+
+    // The administrator of Acme only sees Acme.
+    let page: UserPage = read_body_json(call_service(&app, get("/users/".into())).await).await;
+    let emails: Vec<_> = page.users.iter().map(|user| user.email.as_str()).collect();
+    assert_eq!(emails, [acme.email.as_str()]);
+    let uri = format!("/users/?org_id={globex_org}");
+    let page: UserPage = read_body_json(call_service(&app, get(uri)).await).await;
+    assert!(page.users.is_empty());
+    let hits: Vec<SearchResult> =
+        read_body_json(call_service(&app, get("/users/search?q=Jane".into())).await).await;
+    assert_eq!(hits.len(), 1);
+    let body = read_body(call_service(&app, get("/users/export".into())).await).await;
+    assert_eq!(body.split(|byte| *byte == b'\n').count(), 2);
+    let page: OrganizationPage =
+        read_body_json(call_service(&app, get("/organizations/".into())).await).await;
+    let names: Vec<_> = page
+        .organizations
+        .iter()
+        .map(|organization| organization.name.as_str())
+        .collect();
+    assert_eq!(names, ["Acme"]);
+
+    // Globex is out of reach, as if it did not exist.
+    for uri in [
+        format!("/users/{}", globex.email),
+        format!("/organizations/{globex_org}"),
+        format!("/organizations/{globex_org}/members"),
+    ] {
+        assert_eq!(
+            call_service(&app, get(uri)).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+    let req = TestRequest::patch()
+        .uri(&format!("/users/{}", globex._id))
+        .insert_header(authorization.clone())
+        .set_payload(json::object! { "first_name": "Mallory" }.dump())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let req = TestRequest::delete()
+        .uri(&format!("/users/{}", globex._id))
+        .insert_header(authorization.clone())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let req = TestRequest::put()
+        .uri(&format!("/organizations/{globex_org}"))
+        .insert_header(authorization.clone())
+        .set_json(OrganizationBody {
+            name: "Mallory".into(),
+        })
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        users.find_by_id(&globex._id).await.unwrap().as_ref(),
+        Some(globex)
+    );
+
+    // Nor can users be written into it.
+    let req = TestRequest::post()
+        .uri("/users/")
+        .insert_header(authorization.clone())
+        .set_payload(
+            json::object! {
+                "first_name": "Joe",
+                "last_name": "Doe",
+                "role": "user",
+                "org_id": globex_org.to_hex(),
+                "email": "joe@example.com",
+                "password": "secret",
+            }
+            .dump(),
+        )
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    let req = TestRequest::patch()
+        .uri(&format!("/users/{}", acme._id))
+        .insert_header(authorization.clone())
+        .set_payload(json::object! { "org_id": globex_org.to_hex() }.dump())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        users.find_by_id(&acme._id).await.unwrap().unwrap().org_id,
+        Some(acme_org)
+    );
+
+    // What spans every organization is left to the super administrators.
+    assert_eq!(
+        call_service(&app, get("/audit/".into())).await.status(),
+        StatusCode::FORBIDDEN
+    );
+    let req = TestRequest::post()
+        .uri("/organizations/")
+        .insert_header(authorization.clone())
+        .set_json(OrganizationBody {
+            name: "Initech".into(),
+        })
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+    let req = TestRequest::get()
+        .uri(&format!("/users/{}", globex.email))
+        .insert_header(authorization)
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_role_escalation() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let acme = Organization {
         _id: ObjectId::new(),
-        first_name: "Jane".into(),
-        last_name: "Doe".into(),
-        email: "example@example.com".into(),
-        role: "".to_string(),
-        org_id: Some(ObjectId::new()),
-        password: "".to_string(),
+        name: "Acme".into(),
+        created_at: mongodb::bson::DateTime::now(),
+    };
+    app_state.organizations.create(&acme).await.unwrap();
+    let password = argon2::hash_encoded(
+        ADMIN_PASSWORD.as_bytes(),
+        b"thisisasupersecretkey",
+        &Config::original(),
+    )
+    .unwrap();
+    let admin = User {
+        role: "admin".into(),
+        org_id: Some(acme._id),
+        password: password.clone(),
+        ..User::fixture("acme@example.com")
+    };
+    let member = User {
+        org_id: Some(acme._id),
+        ..User::fixture("member@example.com")
+    };
+    let colleague = User {
+        org_id: Some(acme._id),
+        password: password.clone(),
+        ..User::fixture("colleague@example.com")
+    };
+    for user in [&admin, &member, &colleague] {
+        app_state.users.create(user).await.unwrap();
+    }
+    let users = app_state.users.clone();
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, &admin.email, ADMIN_PASSWORD).await;
+    let new_user = |email: &str, role: &str| {
+        json::object! {
+            "first_name": "Joe",
+            "last_name": "Doe",
+            "role": role,
+            "org_id": acme._id.to_hex(),
+            "email": email,
+            "password": "secret",
+        }
+    };
+    let statuses = |report: &BulkReport| -> Vec<BulkStatus> {
+        report.results.iter().map(|result| result.status).collect()
     };
 
+    // The administrator of an organization neither grants the super administrator roles...
+    let req = TestRequest::post()
+        .uri("/users/")
+        .insert_header(authorization.clone())
+        .set_payload(new_user("joe@example.com", "god").dump())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+    let items = json::array![
+        new_user("joe@example.com", "god"),
+        new_user("jim@example.com", "user"),
+    ];
     let req = TestRequest::post()
-        .uri("/add_user")
-        .set_form(&user)
+        .uri("/users/bulk")
+        .insert_header(authorization.clone())
+        .set_payload(items.dump())
+        .to_request();
+    let report: BulkReport = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(
+        statuses(&report),
+        [BulkStatus::Invalid, BulkStatus::Created]
+    );
+    let csv = format!(
+        "first_name,last_name,role,org_id,email,password\n\
+         Joe,Doe,god,{},joe@example.com,{password}\n",
+        acme._id
+    );
+    for dry_run in [true, false] {
+        let req = TestRequest::post()
+            .uri(&format!("/users/import?format=csv&dry_run={dry_run}"))
+            .insert_header(authorization.clone())
+            .set_payload(csv.clone())
+            .to_request();
+        let report: ImportReport = read_body_json(call_service(&app, req).await).await;
+        assert_eq!((report.imported, report.error_count), (0, 1), "{report:?}");
+    }
+    assert_eq!(users.find_by_email("joe@example.com").await.unwrap(), None);
+
+    // ...nor changes roles, its own included.
+    for (id, role) in [
+        (member._id, "admin"),
+        (admin._id, "god"),
+        (admin._id, "user"),
+    ] {
+        let req = TestRequest::patch()
+            .uri(&format!("/users/{id}"))
+            .insert_header(authorization.clone())
+            .set_payload(json::object! { "role": role }.dump())
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+    let items = json::array![
+        { "id": member._id.to_hex(), "role": "admin" },
+        { "id": member._id.to_hex(), "first_name": "Janet" },
+    ];
+    let req = TestRequest::patch()
+        .uri("/users/bulk")
+        .insert_header(authorization.clone())
+        .set_payload(items.dump())
         .to_request();
+    let report: BulkReport = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(
+        statuses(&report),
+        [BulkStatus::Invalid, BulkStatus::Updated]
+    );
+    let stored = users.find_by_id(&member._id).await.unwrap().unwrap();
+    assert_eq!(
+        (stored.first_name.as_str(), stored.role.as_str()),
+        ("Janet", "user")
+    );
 
-    let response = call_and_read_body(&app, req).await;
-    assert_eq!(response, Bytes::from_static(b"user added"));
+    // That is left to the super administrators.
+    let req = TestRequest::patch()
+        .uri(&format!("/users/{}", member._id))
+        .insert_header(login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await)
+        .set_payload(json::object! { "role": "admin" }.dump())
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
 
+    // The other members of an organization only write themselves.
+    let authorization = login(&app, &colleague.email, ADMIN_PASSWORD).await;
+    let req = TestRequest::post()
+        .uri("/users/")
+        .insert_header(authorization.clone())
+        .set_payload(new_user("jack@example.com", "admin").dump())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+    for payload in [
+        json::object! { "email": "mallory@example.com" },
+        json::object! { "password": "hijacked" },
+    ] {
+        let req = TestRequest::patch()
+            .uri(&format!("/users/{}", admin._id))
+            .insert_header(authorization.clone())
+            .set_payload(payload.dump())
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+    let req = TestRequest::delete()
+        .uri(&format!("/users/{}", member._id))
+        .insert_header(authorization.clone())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(users.find_by_email("jack@example.com").await.unwrap(), None);
+    assert_eq!(
+        users.find_by_id(&admin._id).await.unwrap().unwrap().email,
+        admin.email
+    );
+    assert!(users.find_by_id(&member._id).await.unwrap().is_some());
+    let req = TestRequest::patch()
+        .uri(&format!("/users/{}", colleague._id))
+        .insert_header(authorization)
+        .set_payload(json::object! { "first_name": "Jane" }.dump())
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_audit_log() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+
+    let new_user = json::object! {
+        "first_name": "Jane",
+        "last_name": "Doe",
+        "role": "user",
+        "org_id": "",
+        "email": "jane@example.com",
+        "password": "secret",
+    };
+    let req = TestRequest::post()
+        .uri("/users/")
+        .insert_header(authorization.clone())
+        .set_payload(new_user.dump())
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let req = TestRequest::get()
+        .uri("/users/jane@example.com")
+        .insert_header(authorization.clone())
+        .to_request();
+    let jane: SanitizedUser = read_body_json(call_service(&app, req).await).await;
+    let mut renamed_user = new_user.clone();
+    renamed_user["first_name"] = "Janet".into();
+    let req = TestRequest::put()
+        .uri(&format!("/users/{}", jane._id))
+        .insert_header(authorization.clone())
+        .set_payload(renamed_user.dump())
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = TestRequest::get()
+        .uri("/audit/?per_page=2")
+        .insert_header(authorization.clone())
+        .to_request();
+    let page: AuditPage = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(page.total, 3);
+    assert_eq!(page.entries.len(), 2);
+    let update = &page.entries[0];
+    assert_eq!(update.action, AuditAction::Update);
+    assert_eq!(update.target_id, jane._id);
+    assert!(update
+        .diff
+        .contains(r#""first_name":{"before":"Jane","after":"Janet"}"#));
+
+    let req = TestRequest::get()
+        .uri("/audit/?page=2&per_page=2")
+        .insert_header(authorization)
+        .to_request();
+    let page: AuditPage = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(page.entries.len(), 1);
+    assert_eq!(page.entries[0].action, AuditAction::Login);
+
+    let authorization = login(&app, "jane@example.com", "secret").await;
+    let req = TestRequest::get()
+        .uri("/audit/")
+        .insert_header(authorization)
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_web::test]
+async fn test_retention_routes() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let jane = User {
+        password: argon2::hash_encoded(
+            ADMIN_PASSWORD.as_bytes(),
+            b"thisisasupersecretkey",
+            &Config::original(),
+        )
+        .unwrap(),
+        ..User::fixture("jane@example.com")
+    };
+    let users = app_state.users.clone();
+    users.create(&jane).await.unwrap();
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+    let authorization = login(&app, ADMIN_EMAIL, ADMIN_PASSWORD).await;
+    let admin = users.find_by_email(ADMIN_EMAIL).await.unwrap().unwrap();
+    assert!(admin.last_login_at.is_some());
+    let purge = |uri: &str| {
+        TestRequest::post()
+            .uri(uri)
+            .insert_header(authorization.clone())
+            .to_request()
+    };
+
+    // Deleted long ago, past the grace period.
+    let long_ago = mongodb::bson::DateTime::from_millis(0);
+    users
+        .soft_delete(&jane._id, &ObjectId::new(), long_ago)
+        .await
+        .unwrap();
+    let report: Vec<PurgeEntryResponse> =
+        read_body_json(call_service(&app, purge("/retention/purge?dry_run=true")).await).await;
+    assert_eq!(report.len(), 1);
+    assert_eq!(
+        (
+            report[0].collection.as_str(),
+            report[0].rule,
+            report[0].dry_run
+        ),
+        ("users", RetentionRule::Deleted, true)
+    );
+    assert_eq!(report[0].purged_ids, [jane._id.to_hex()]);
+    assert!(users.find_deleted_by_id(&jane._id).await.unwrap().is_some());
+
+    let report: Vec<PurgeEntryResponse> =
+        read_body_json(call_service(&app, purge("/retention/purge")).await).await;
+    assert_eq!((report[0].purged, report[0].dry_run), (1, false));
+    assert_eq!(users.find_deleted_by_id(&jane._id).await.unwrap(), None);
+    // Nothing left to purge, nothing more is logged.
+    let report: Vec<PurgeEntryResponse> =
+        read_body_json(call_service(&app, purge("/retention/purge")).await).await;
+    assert_eq!(report[0].purged, 0);
+
+    let req = TestRequest::get()
+        .uri("/retention/log")
+        .insert_header(authorization.clone())
+        .to_request();
+    let page: PurgeLogPage = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(page.total, 2);
+    let dry_runs: Vec<_> = page.entries.iter().map(|entry| entry.dry_run).collect();
+    assert_eq!(dry_runs.len(), 2);
+    assert!(dry_runs.contains(&true) && dry_runs.contains(&false));
+
+    let mut admin = users.find_by_email(ADMIN_EMAIL).await.unwrap().unwrap();
+    admin.role = "admin".into();
+    users.update(&admin, None).await.unwrap();
+    assert_eq!(
+        call_service(&app, purge("/retention/purge?dry_run=true"))
+            .await
+            .status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_web::test]
+async fn test_purge_log_keeps_a_sample_of_the_ids() {
+    let (app_state, _) = memory_app_state().await;
+    let long_ago = mongodb::bson::DateTime::from_millis(0);
+    let users: Vec<_> = (0..MAX_LOGGED_IDS + 2)
+        .map(|index| User {
+            deleted_at: Some(long_ago),
+            ..User::fixture(&format!("user{index}@example.com"))
+        })
+        .collect();
+    for created in app_state.users.create_many(&users).await {
+        created.unwrap();
+    }
+    let policies = RetentionPolicy::parse("users:deleted:1").unwrap();
+
+    let report = retention::enforce(&app_state, &policies, false)
+        .await
+        .unwrap();
+    let entry = report[0].to_response();
+    assert_eq!(entry.purged, users.len() as i64);
+    assert_eq!(entry.purged_ids.len(), MAX_LOGGED_IDS);
+    let (logged, total) = app_state.purge_log.page(0, 10).await.unwrap();
+    assert_eq!((logged, total), (report, 1));
+}
+
+#[actix_web::test]
+async fn test_retention_task() {
+    let (app_state, _) = memory_app_state().await;
+    let now = mongodb::bson::DateTime::now();
+    let long_ago = mongodb::bson::DateTime::from_millis(0);
+    for created_at in [long_ago, now] {
+        app_state
+            .audit_log
+            .record::<User>(
+                None,
+                AuditAction::Login,
+                ObjectId::new(),
+                None,
+                None,
+                created_at,
+            )
+            .await
+            .unwrap();
+    }
+
+    let task = RetentionTask::start(
+        app_state.clone(),
+        vec![RetentionPolicy {
+            collection: "audit_log",
+            rule: RetentionRule::Age,
+            days: 30,
+        }],
+        Duration::from_secs(3600),
+        false,
+    );
+    // The policies are enforced as soon as the task starts.
+    let mut entries = Vec::new();
+    for _ in 0..50 {
+        (entries, _) = app_state.purge_log.page(0, 10).await.unwrap();
+        if !entries.is_empty() {
+            break;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+    task.stop().await;
+
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        (
+            entries[0].collection.as_str(),
+            entries[0].rule,
+            entries[0].purged
+        ),
+        ("audit_log", RetentionRule::Age, 1)
+    );
+    let (remaining, total) = app_state.audit_log.page(0, 10).await.unwrap();
+    assert_eq!(total, 1);
+    assert_eq!(remaining[0].created_at, now);
+}
+
+#[actix_web::test]
+async fn test_routes_unavailable_while_database_down() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let health = app_state.database.status().health.clone();
+    let app = init_service(
+        App::new()
+            .app_data(app_state)
+            .configure(configure_routes(auth_data)),
+    )
+    .await;
+
+    let auth_request = || {
+        TestRequest::post()
+            .uri("/auth")
+            .set_json(AuthReq {
+                email: ADMIN_EMAIL.into(),
+                password: ADMIN_PASSWORD.into(),
+            })
+            .to_request()
+    };
+
+    health.mark_down();
+    let error = try_call_service(&app, auth_request()).await.unwrap_err();
+    assert_eq!(
+        error.as_response_error().status_code(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
     let req = TestRequest::get()
-        .uri(&format!("/get_user/{}", &user.email))
+        .uri(&format!("/users/{ADMIN_EMAIL}"))
         .to_request();
+    let error = try_call_service(&app, req).await.unwrap_err();
+    assert_eq!(
+        error.as_response_error().status_code(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    health.mark_up();
+    assert_eq!(
+        call_service(&app, auth_request()).await.status(),
+        StatusCode::OK
+    );
+}
+
+#[actix_web::test]
+async fn test_websocket() {
+    let (app_state, auth_data) = memory_app_state().await;
+    let users = app_state.users.clone();
+    let organization = Organization {
+        _id: ObjectId::new(),
+        name: "Acme".into(),
+        created_at: mongodb::bson::DateTime::now(),
+    };
+    app_state.organizations.create(&organization).await.unwrap();
+    let member = User {
+        role: "admin".into(),
+        org_id: Some(organization._id),
+        password: argon2::hash_encoded(
+            ADMIN_PASSWORD.as_bytes(),
+            b"thisisasupersecretkey",
+            &Config::original(),
+        )
+        .unwrap(),
+        ..User::fixture("member@example.com")
+    };
+    users.create(&member).await.unwrap();
+    let app = init_service(
+        App::new()
+            .app_data(app_state.clone())
+            .configure(configure_routes(auth_data.clone())),
+    )
+    .await;
+    let (_, authorization) = login(&app, "member@example.com", ADMIN_PASSWORD).await;
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .configure(configure_routes(auth_data.clone()))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let address = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let url = format!("ws://{address}/ws");
+    match tokio_tungstenite::connect_async(url.as_str()).await {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED.as_u16())
+        }
+        other => panic!("anonymous clients must be refused, got {other:?}"),
+    }
+    let mut request = url.into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("Authorization", authorization.parse().unwrap());
+    let (mut socket, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .expect("websocket handshake should succeed");
+
+    socket
+        .send(tungstenite::Message::Ping(b"ping".to_vec().into()))
+        .await
+        .unwrap();
+    let pong = socket.next().await.unwrap().unwrap();
+    assert_eq!(pong, tungstenite::Message::Pong(b"ping".to_vec().into()));
+
+    let mut command = Vec::new();
+    doc! { "id": 0, "data": { "timestamp": 1, "target": 2, "command": 3 } }
+        .to_writer(&mut command)
+        .unwrap();
+    socket
+        .send(tungstenite::Message::Binary(command.into()))
+        .await
+        .unwrap();
+
+    // The command is handled without dropping the connection.
+    socket
+        .send(tungstenite::Message::Ping(b"still there".to_vec().into()))
+        .await
+        .unwrap();
+    let pong = loop {
+        match socket.next().await.unwrap().unwrap() {
+            tungstenite::Message::Ping(_) => continue,
+            message => break message,
+        }
+    };
+    assert_eq!(
+        pong,
+        tungstenite::Message::Pong(b"still there".to_vec().into())
+    );
+
+    // The changes of the store reach the client, only for its organization.
+    users
+        .create(&User::fixture("john@example.com"))
+        .await
+        .unwrap();
+    let user = User {
+        org_id: Some(organization._id),
+        ..User::fixture("jane@example.com")
+    };
+    users.create(&user).await.unwrap();
+    let packet = loop {
+        match socket.next().await.unwrap().unwrap() {
+            tungstenite::Message::Ping(_) => continue,
+            tungstenite::Message::Binary(packet) => break packet,
+            message => panic!("unexpected message {message:?}"),
+        }
+    };
+    let packet = mongodb::bson::Document::from_reader(packet.as_ref()).unwrap();
+    assert_eq!(
+        packet,
+        doc! {
+            "id": 0,
+            "data": { "collection": "users", "kind": "created", "id": user._id, "version": 0_i64 },
+        }
+    );
 
-    let response: User = call_and_read_body_json(&app, req).await;
-    assert_eq!(response, user);
+    socket.close(None).await.unwrap();
+    handle.stop(false).await;
 }
@@ -1,7 +1,7 @@
 pub mod handlers;
 
 use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
-use actix_http::ws::{Item, Message};
+use actix_http::ws::Item;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 use anyhow::Result;
@@ -9,8 +9,11 @@ use bson::Document;
 use futures::Stream;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
 
-use crate::{ProgramAppState, MAX_FRAME_SIZE};
+use crate::{
+    controllers::authentication::Authenticated, store::Tenant, ProgramAppState, MAX_FRAME_SIZE,
+};
 
 /// How long before lack of client response causes a timeout.
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
@@ -24,9 +27,19 @@ pub enum RequestId {
     BasicCommand = 0,
 }
 
+#[repr(u8)]
+#[derive(Debug, IntoPrimitive, TryFromPrimitive, PartialEq, Eq, Clone, Copy)]
+/// The kind of an outgoing packet, see [`crate::store::events`].
+pub enum EventId {
+    Change = 0,
+}
+
+/// A packet of the UI sender channel, to send to the client.
+struct Broadcast(Vec<u8>);
+
 /// An active websocket connection.
 pub struct WebsocketConnection {
-    /// The state of Api, containing Ntp, MongoDB & UI sender channel.
+    /// The state of Api, containing Ntp, the stores & UI sender channel.
     state: web::Data<ProgramAppState>,
     /// Client must send ping at least once [`CLIENT_TIMEOUT`], otherwise we drop the connection.
     pub last_heartbeat: Instant,
@@ -34,6 +47,8 @@ pub struct WebsocketConnection {
     pub current_fragmented_message: Option<Vec<u8>>,
     /// The address of the other side of this websocket.
     address: String,
+    /// The organizations of the authenticated user, whose change events are forwarded.
+    tenant: Tenant,
 }
 
 impl Actor for WebsocketConnection {
@@ -57,8 +72,10 @@ impl Actor for WebsocketConnection {
             context.stop();
         });
 
-        // Start outgoing response task.
-        context.add_stream(handle_response(self.state.clone()));
+        // Forward the packets of the UI sender channel and the change events, they are handled
+        // apart from the incoming messages.
+        context.add_stream(broadcasts(&self.state));
+        context.add_stream(changes(&self.state, self.tenant));
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
@@ -66,20 +83,48 @@ impl Actor for WebsocketConnection {
     }
 }
 
-fn handle_response(
-    state: web::Data<ProgramAppState>,
-) -> impl Stream<Item = Result<ws::Message, ws::ProtocolError>> {
+/// Subscribes right away, so that nothing published once the connection is started is missed.
+fn broadcasts(state: &web::Data<ProgramAppState>) -> impl Stream<Item = Broadcast> {
+    let mut ui_output_channel = state.ui_sender_channel.subscribe();
     async_stream::stream! {
-        let mut ui_output_channel = state.ui_sender_channel.subscribe();
         loop {
             match ui_output_channel.recv().await {
-                Err(error) => log::warn!("Failed to receive UI output message: {error}"),
-                Ok(message) => yield Ok(Message::Binary(message.into())),
+                Ok(packet) => yield Broadcast(packet),
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Websocket client too slow, {skipped} UI output messages skipped");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+/// The change events of the organizations of the tenant, subscribed to right away as well.
+fn changes(state: &web::Data<ProgramAppState>, tenant: Tenant) -> impl Stream<Item = Broadcast> {
+    let mut changes = state.change_sender.subscribe();
+    async_stream::stream! {
+        loop {
+            match changes.recv().await {
+                Ok(event) if event.visible_to(&tenant) => match event.encode() {
+                    Ok(packet) => yield Broadcast(packet),
+                    Err(error) => log::error!("Failed to encode the change event {event:?}: {error}"),
+                },
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Websocket client too slow, {skipped} change events skipped");
+                }
+                Err(RecvError::Closed) => break,
             }
         }
     }
 }
 
+impl StreamHandler<Broadcast> for WebsocketConnection {
+    fn handle(&mut self, Broadcast(packet): Broadcast, context: &mut Self::Context) {
+        context.binary(packet);
+    }
+}
+
 /// WebSocket message handler.
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketConnection {
     fn handle(
@@ -203,8 +248,9 @@ fn handle_requests(state: &web::Data<ProgramAppState>, request: Document) {
     }
 }
 
-/// Handshake and start WebSocket handler.
+/// Handshake and start WebSocket handler, for authenticated users only.
 pub async fn handle_ws(
+    auth: Authenticated,
     request: HttpRequest,
     stream: web::Payload,
     state: web::Data<ProgramAppState>,
@@ -217,6 +263,7 @@ pub async fn handle_ws(
             address: request
                 .peer_addr()
                 .map_or_else(|| String::from("<?>"), |address| address.to_string()),
+            tenant: auth.tenant(),
         },
         &request,
         stream,